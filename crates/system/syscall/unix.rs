@@ -36,6 +36,7 @@ pub const SYS_FUTEX: usize = 240;
     pub const FUTEX_WAKE: usize = 1;
     pub const FUTEX_REQUEUE: usize = 2;
 pub const SYS_GETPID: usize = 20;
+pub const SYS_GETRANDOM: usize = 355;
 pub const SYS_IOPL: usize = 110;
 pub const SYS_LINK: usize = 9;
 pub const SYS_LSEEK: usize = 19;
@@ -58,6 +59,12 @@ pub const SYS_OPEN: usize = 5;
     pub const O_TRUNC: usize = 0x400;
     pub const O_EXCL: usize = 0x800;
 pub const SYS_PIPE2: usize = 331;
+pub const SYS_POLL: usize = 168;
+    pub const POLLIN: usize = 0x1;
+    pub const POLLOUT: usize = 0x4;
+    pub const POLLERR: usize = 0x8;
+    pub const POLLHUP: usize = 0x10;
+    pub const POLLNVAL: usize = 0x20;
 pub const SYS_READ: usize = 3;
 pub const SYS_RMDIR: usize = 84;
 pub const SYS_UNLINK: usize = 10;
@@ -81,6 +88,14 @@ pub struct Stat {
     pub st_ctime: u32
 }
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(packed)]
+pub struct PollFd {
+    pub fd: usize,
+    pub events: usize,
+    pub revents: usize,
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(packed)]
 pub struct TimeSpec {
@@ -109,7 +124,11 @@ pub fn sys_clock_gettime(clock: usize, tp: &mut TimeSpec) -> Result<usize> {
 }
 
 pub fn sys_dup(fd: usize) -> Result<usize> {
-    unsafe { syscall1(SYS_DUP, fd) }
+    unsafe { syscall3(SYS_DUP, fd, 0, 0) }
+}
+
+pub fn sys_dup_path(fd: usize, path: &str) -> Result<usize> {
+    unsafe { syscall3(SYS_DUP, fd, path.as_ptr() as usize, path.len()) }
 }
 
 pub unsafe fn sys_execve(path: *const u8, args: *const *const u8) -> Result<usize> {
@@ -144,6 +163,10 @@ pub fn sys_getpid() -> Result<usize> {
     unsafe { syscall0(SYS_GETPID) }
 }
 
+pub fn sys_getrandom(buf: &mut [u8]) -> Result<usize> {
+    unsafe { syscall2(SYS_GETRANDOM, buf.as_mut_ptr() as usize, buf.len()) }
+}
+
 pub unsafe fn sys_iopl(level: usize) -> Result<usize> {
     syscall1(SYS_IOPL, level)
 }
@@ -172,6 +195,14 @@ pub fn sys_pipe2(fds: &mut [usize; 2], flags: usize) -> Result<usize> {
     unsafe { syscall2(SYS_PIPE2, fds.as_ptr() as usize, flags) }
 }
 
+pub fn sys_poll(fds: &mut [PollFd], timeout: Option<&TimeSpec>) -> Result<usize> {
+    let timeout_ptr = match timeout {
+        Some(timeout) => timeout as *const TimeSpec as usize,
+        None => 0,
+    };
+    unsafe { syscall3(SYS_POLL, fds.as_mut_ptr() as usize, fds.len(), timeout_ptr) }
+}
+
 pub fn sys_read(fd: usize, buf: &mut [u8]) -> Result<usize> {
     unsafe { syscall3(SYS_READ, fd, buf.as_mut_ptr() as usize, buf.len()) }
 }
@@ -84,6 +84,11 @@ impl KScheme for Acpi {
         if url.splitn(2, ":").nth(1).unwrap_or("") == "off" && flags & O_CREAT == O_CREAT {
             match self.fadt {
                 Some(fadt) => {
+                    debugln!("Syncing Disks");
+                    if let Err(err) = unsafe { &mut *::env().block_cache.get() }.sync_all() {
+                        debugln!("Unable to sync disks: {}", err);
+                    }
+
                     debugln!("Powering Off");
                     unsafe {
                         asm!("out dx, ax" : : "{edx}"(fadt.pm1a_control_block), "{ax}"(0 | 1 << 13) : : "intel", "volatile")
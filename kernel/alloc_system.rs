@@ -5,7 +5,15 @@ use arch::paging::Page;
 #[no_mangle]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
     unsafe {
-        let address = alloc_aligned(size, align);
+        let mut address = alloc_aligned(size, align);
+        if address == 0 {
+            // Out of memory, evict from the block cache and try again
+            if let Some(ref env) = ::ENV_PTR {
+                if (&mut *env.block_cache.get()).reclaim() > 0 {
+                    address = alloc_aligned(size, align);
+                }
+            }
+        }
         if address > 0 {
             for page in 0..(size + CLUSTER_SIZE - 1)/CLUSTER_SIZE {
                 let physical_address = address + page * CLUSTER_SIZE;
@@ -205,6 +205,19 @@ pub unsafe fn context_switch() {
     }
 }
 
+/// Block the current context for a time
+pub fn context_sleep(reason: &str, time: Duration) {
+    {
+        let contexts = unsafe { &mut *::env().contexts.get() };
+        if let Ok(mut current) = contexts.current_mut() {
+            current.block(reason);
+            current.wake = Some(Duration::monotonic() + time);
+        }
+    }
+
+    unsafe { context_switch() };
+}
+
 pub unsafe fn context_clone(regs: &Regs) -> Result<usize> {
     let contexts = &mut *::env().contexts.get();
     let flags = regs.bx;
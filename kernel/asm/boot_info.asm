@@ -0,0 +1,88 @@
+SECTION .text
+USE16
+;Describe the boot to the kernel at 0x5600 to 0x5800, see kernel/common/boot.rs
+;   0x5600: physical address of the RAM disk image, or 0
+;   0x5604: length of the RAM disk image in bytes
+;   0x5608: boot arguments, null terminated
+;The RAM disk image is loaded below the page tables at 16MiB, so it must be smaller than 8MiB
+boot_info:
+.start  equ 0x5600
+.end    equ 0x5800
+.length equ .end - .start
+.ramdisk equ 0x800000
+
+%ifndef BOOT_ARGS
+    %define BOOT_ARGS ""
+%endif
+
+    xor eax, eax
+    mov di, .start
+    mov ecx, .length / 4 ; moving 4 Bytes at once
+    cld
+    rep stosd
+
+    mov si, .args
+    mov di, .start + 8
+.args_lp:
+    lodsb
+    stosb
+    test al, al
+    jnz .args_lp
+
+%ifdef RAMDISK
+%if RAMDISK_SIZE > 0x800000
+    %error "The RAM disk image is larger than 8MiB"
+%endif
+
+    ; load the RAM disk image in the same way as the kernel
+    mov ecx, ramdisk_file.length_sectors / buffer_size_sectors
+    mov ax, (ramdisk_file - boot) / 512
+    mov edi, .ramdisk
+    cld
+    test cx, cx
+    jz .remainder
+.lp:
+    push cx
+
+        mov cx, buffer_size_sectors
+        mov bx, startup_end
+        mov dx, 0x0
+
+        push ax
+        call load
+
+        call unreal
+        pop ax
+
+        mov esi, startup_end
+        mov ecx, buffer_size_bytes / 4
+        a32 rep movsd
+
+        add ax, buffer_size_sectors
+
+    pop cx
+    loop .lp
+
+.remainder:
+    mov cx, ramdisk_file.length_sectors % buffer_size_sectors
+    test cx, cx
+    jz .done
+
+    mov bx, startup_end
+    mov dx, 0x0
+    call load
+
+    call unreal
+
+    mov esi, startup_end
+    mov ecx, (ramdisk_file.length_sectors % buffer_size_sectors) * 512 / 4
+    a32 rep movsd
+
+.done:
+    mov dword [.start], .ramdisk
+    mov dword [.start + 4], ramdisk_file.length
+%endif
+
+    ret
+
+.args: db BOOT_ARGS, 0
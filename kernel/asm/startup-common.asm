@@ -69,6 +69,7 @@ kernel_base equ 0x100000
     a32 rep movsd
 finished_loading:
 
+    call boot_info
 
     call memory_map
 
@@ -85,5 +86,6 @@ finished_loading:
 %include "asm/gdt_entry.inc"
 %include "asm/unreal.asm"
 %include "asm/memory_map.asm"
+%include "asm/boot_info.asm"
 %include "asm/vesa.asm"
 %include "asm/initialize.asm"
@@ -0,0 +1,37 @@
+use core::{ptr, slice, str};
+
+/// Boot information written by the bootloader, see `asm/boot_info.asm`
+const BOOT_INFO: usize = 0x5600;
+const BOOT_INFO_END: usize = 0x5800;
+
+/// The RAM disk image loaded by the bootloader, as a physical address and length
+pub fn ramdisk_image() -> Option<(usize, usize)> {
+    let base = unsafe { ptr::read(BOOT_INFO as *const u32) } as usize;
+    let len = unsafe { ptr::read((BOOT_INFO + 4) as *const u32) } as usize;
+    if base > 0 && len > 0 {
+        Some((base, len))
+    } else {
+        None
+    }
+}
+
+/// The boot arguments, separated by spaces
+pub fn args() -> &'static str {
+    let start = BOOT_INFO + 8;
+    let mut len = 0;
+    while start + len < BOOT_INFO_END && unsafe { ptr::read((start + len) as *const u8) } != 0 {
+        len += 1;
+    }
+    str::from_utf8(unsafe { slice::from_raw_parts(start as *const u8, len) }).unwrap_or("")
+}
+
+/// Find the value of a `key=value` boot argument
+pub fn arg(key: &str) -> Option<&'static str> {
+    for arg in args().split(' ') {
+        let mut parts = arg.splitn(2, '=');
+        if parts.next() == Some(key) {
+            return Some(parts.next().unwrap_or(""));
+        }
+    }
+    None
+}
@@ -0,0 +1,26 @@
+/// Read a little endian `u16` at a byte offset
+pub fn le16(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+/// Read a little endian `u32` at a byte offset
+pub fn le32(data: &[u8], offset: usize) -> u32 {
+    le16(data, offset) as u32 | (le16(data, offset + 2) as u32) << 16
+}
+
+/// Read a little endian `u64` at a byte offset
+pub fn le64(data: &[u8], offset: usize) -> u64 {
+    le32(data, offset) as u64 | (le32(data, offset + 4) as u64) << 32
+}
+
+/// Write a little endian `u16` at a byte offset
+pub fn set_le16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset] = value as u8;
+    data[offset + 1] = (value >> 8) as u8;
+}
+
+/// Write a little endian `u32` at a byte offset
+pub fn set_le32(data: &mut [u8], offset: usize, value: u32) {
+    set_le16(data, offset, value as u16);
+    set_le16(data, offset + 2, (value >> 16) as u16);
+}
@@ -1,11 +1,15 @@
+/// Boot information from the bootloader
+pub mod boot;
 /// Debug
 #[macro_use]
 pub mod debug;
+/// Little endian integers in byte slices
+pub mod endian;
 /// Event input
 pub mod event;
 /// Slice-related traits
 pub mod slice;
-/// A module for pseudorandom generator
+/// Kernel entropy pool and CSPRNG
 pub mod random;
 /// A module for time
 pub mod time;
@@ -1,19 +1,258 @@
-static mut seed: u64 = 19940046431; //259261034506304368955239; //1706322144714608529217229883707268827757977089;
+//! Kernel entropy pool and ChaCha20 based CSPRNG.
+//!
+//! Entropy is collected from RDRAND/RDSEED (when CPUID reports them), the TSC at every
+//! interrupt, and the RTC at boot. Events are folded into a pool, which is mixed into the
+//! generator key after every `RESEED_EVENTS` events. The generator rekeys itself after every
+//! request, so a captured key does not reveal earlier output.
 
-/// Generate pseudo random number
-pub fn rand() -> usize {
-    unsafe {
-        seed ^= seed << 12;
-        seed ^= seed << 25;
-        seed ^= seed << 27;
-        seed = seed * 82724793451 + 12345;
-        seed as usize % ::core::usize::MAX
+use core::{cmp, mem, ptr, slice};
+
+/// Number of entropy events collected before the pool is folded into the key
+const RESEED_EVENTS: usize = 64;
+
+/// ChaCha constants, "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+/// Entropy pool and generator state
+struct Entropy {
+    /// Generator key
+    key: [u32; 8],
+    /// Block counter
+    counter: u64,
+    /// Accumulated entropy that has not been mixed into the key
+    pool: [u32; 16],
+    /// Next pool word to mix into
+    pool_i: usize,
+    /// Events since the last reseed
+    events: usize,
+    /// CPUID reported RDRAND
+    rdrand: bool,
+    /// CPUID reported RDSEED
+    rdseed: bool,
+}
+
+static mut ENTROPY: Entropy = Entropy {
+    key: [0; 8],
+    counter: 0,
+    pool: [0; 16],
+    pool_i: 0,
+    events: 0,
+    rdrand: false,
+    rdseed: false,
+};
+
+#[inline(always)]
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(7);
+}
+
+/// Produce one ChaCha20 block
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut input = [0; 16];
+    input[0] = SIGMA[0];
+    input[1] = SIGMA[1];
+    input[2] = SIGMA[2];
+    input[3] = SIGMA[3];
+    for i in 0..8 {
+        input[4 + i] = key[i];
+    }
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce as u32;
+    input[15] = (nonce >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    for i in 0..16 {
+        state[i] = state[i].wrapping_add(input[i]);
     }
+
+    state
+}
+
+/// Read the time stamp counter
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) : : : "intel", "volatile"); }
+    (high as u64) << 32 | low as u64
 }
 
-/// Set the seed
-pub fn srand(s: usize) {
-    unsafe {
-        seed = s as u64;
+/// Execute CPUID, returning EAX, EBX, ECX, EDX
+unsafe fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let eax: u32;
+    let ebx: u32;
+    let ecx: u32;
+    let edx: u32;
+    asm!("cpuid"
+        : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+        : "{eax}"(leaf), "{ecx}"(0)
+        :
+        : "intel", "volatile");
+    (eax, ebx, ecx, edx)
+}
+
+/// Read RDRAND, retrying a few times as recommended by Intel
+unsafe fn rdrand() -> Option<usize> {
+    for _ in 0..10 {
+        let value: usize;
+        let ok: u8;
+        asm!("rdrand $0 ; setc $1" : "=r"(value), "=r"(ok) : : "cc" : "intel", "volatile");
+        if ok != 0 {
+            return Some(value);
+        }
     }
+    None
+}
+
+/// Read RDSEED, which may fail more often than RDRAND when the conditioner is drained
+unsafe fn rdseed() -> Option<usize> {
+    for _ in 0..10 {
+        let value: usize;
+        let ok: u8;
+        asm!("rdseed $0 ; setc $1" : "=r"(value), "=r"(ok) : : "cc" : "intel", "volatile");
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+impl Entropy {
+    fn mix(&mut self, value: u64) {
+        let i = self.pool_i;
+        self.pool[i] = self.pool[i].rotate_left(7) ^ (value as u32);
+        self.pool[(i + 1) % 16] = self.pool[(i + 1) % 16].rotate_left(13) ^ ((value >> 32) as u32);
+        self.pool_i = (i + 2) % 16;
+
+        self.events += 1;
+        if self.events >= RESEED_EVENTS {
+            self.reseed();
+        }
+    }
+
+    /// Fold the pool and any hardware randomness into the key
+    fn reseed(&mut self) {
+        if self.rdseed {
+            if let Some(value) = unsafe { rdseed() } {
+                self.pool[self.pool_i] ^= value as u32;
+            }
+        } else if self.rdrand {
+            if let Some(value) = unsafe { rdrand() } {
+                self.pool[self.pool_i] ^= value as u32;
+            }
+        }
+
+        let mut key = self.key;
+        for i in 0..8 {
+            key[i] ^= self.pool[i];
+        }
+        let nonce = (self.pool[8] as u64) << 32 | self.pool[9] as u64;
+        let block = chacha20_block(&key, rdtsc(), nonce);
+        for i in 0..8 {
+            self.key[i] ^= block[i];
+        }
+        for i in 0..16 {
+            self.pool[i] = block[i] ^ self.pool[(i + 10) % 16];
+        }
+
+        self.events = 0;
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        if self.events > 0 {
+            self.reseed();
+        }
+
+        let mut i = 0;
+        while i < buf.len() {
+            let block = chacha20_block(&self.key, self.counter, 0);
+            self.counter = self.counter.wrapping_add(1);
+
+            let count = cmp::min(buf.len() - i, 64);
+            unsafe { ptr::copy(block.as_ptr() as *const u8, buf.as_mut_ptr().offset(i as isize), count) };
+            i += count;
+        }
+
+        // Fast key erasure, the key used for this request is replaced before returning
+        let block = chacha20_block(&self.key, self.counter, 0);
+        self.counter = self.counter.wrapping_add(1);
+        for i in 0..8 {
+            self.key[i] = block[i];
+        }
+    }
+}
+
+/// Detect hardware random number generators and seed the pool
+///
+/// This should be called once the realtime clock has been read. Returns whether RDRAND and
+/// RDSEED are available.
+pub fn init(realtime: u64) -> (bool, bool) {
+    let entropy = unsafe { &mut ENTROPY };
+
+    let (max_leaf, _, _, _) = unsafe { cpuid(0) };
+    if max_leaf >= 1 {
+        let (_, _, ecx, _) = unsafe { cpuid(1) };
+        entropy.rdrand = ecx & 1 << 30 == 1 << 30;
+    }
+    if max_leaf >= 7 {
+        let (_, ebx, _, _) = unsafe { cpuid(7) };
+        entropy.rdseed = ebx & 1 << 18 == 1 << 18;
+    }
+
+    entropy.mix(realtime);
+    entropy.mix(rdtsc());
+    for _ in 0..RESEED_EVENTS {
+        if entropy.rdseed {
+            if let Some(value) = unsafe { rdseed() } {
+                entropy.mix(value as u64);
+                continue;
+            }
+        }
+        if entropy.rdrand {
+            if let Some(value) = unsafe { rdrand() } {
+                entropy.mix(value as u64);
+                continue;
+            }
+        }
+        entropy.mix(rdtsc());
+    }
+    entropy.reseed();
+
+    (entropy.rdrand, entropy.rdseed)
+}
+
+/// Add an arbitrary value to the entropy pool
+pub fn add_entropy(value: u64) {
+    unsafe { ENTROPY.mix(value) };
+}
+
+/// Add the timing of an interrupt to the entropy pool
+pub fn add_interrupt_entropy(interrupt: usize) {
+    unsafe { ENTROPY.mix(rdtsc() ^ (interrupt as u64) << 56) };
+}
+
+/// Fill a buffer with cryptographically secure random bytes
+pub fn fill(buf: &mut [u8]) {
+    unsafe { ENTROPY.fill(buf) };
+}
+
+/// Generate a cryptographically secure random number
+pub fn rand() -> usize {
+    let mut value = 0usize;
+    fill(unsafe { slice::from_raw_parts_mut(&mut value as *mut usize as *mut u8, mem::size_of::<usize>()) });
+    value
 }
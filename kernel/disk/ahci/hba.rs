@@ -1,10 +1,12 @@
 use arch::memory::{self, Memory};
 
-use collections::String;
+use collections::Vec;
 
 use core::mem::size_of;
 use core::u32;
 
+use disk::identity::DiskIdentity;
+
 use drivers::io::{Io, Mmio};
 
 use system::error::{Error, Result, EIO};
@@ -13,6 +15,10 @@ use super::fis::{FIS_TYPE_REG_H2D, FisRegH2D};
 
 const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
 const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_CMD_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const ATA_CMD_PACKET: u8 = 0xA0;
+const ATA_CMD_IDENTIFY_PACKET: u8 = 0xA1;
 const ATA_CMD_IDENTIFY: u8 = 0xEC;
 const ATA_DEV_BUSY: u8 = 0x80;
 const ATA_DEV_DRQ: u8 = 0x08;
@@ -21,13 +27,45 @@ const HBA_PORT_CMD_CR: u32 = 1 << 15;
 const HBA_PORT_CMD_FR: u32 = 1 << 14;
 const HBA_PORT_CMD_FRE: u32 = 1 << 4;
 const HBA_PORT_CMD_ST: u32 = 1;
-const HBA_PORT_IS_TFES: u32 = 1 << 30;
+pub const HBA_PORT_IS_DHRS: u32 = 1;
+pub const HBA_PORT_IS_PSS: u32 = 1 << 1;
+pub const HBA_PORT_IS_DSS: u32 = 1 << 2;
+pub const HBA_PORT_IS_SDBS: u32 = 1 << 3;
+pub const HBA_PORT_IS_DPS: u32 = 1 << 5;
+pub const HBA_PORT_IS_PCS: u32 = 1 << 6;
+pub const HBA_PORT_IS_PRCS: u32 = 1 << 22;
+pub const HBA_PORT_IS_IFS: u32 = 1 << 27;
+pub const HBA_PORT_IS_HBDS: u32 = 1 << 28;
+pub const HBA_PORT_IS_HBFS: u32 = 1 << 29;
+pub const HBA_PORT_IS_TFES: u32 = 1 << 30;
+/// Interrupts that stop the port until it is restarted
+pub const HBA_PORT_IS_ERROR: u32 = HBA_PORT_IS_IFS | HBA_PORT_IS_HBDS | HBA_PORT_IS_HBFS | HBA_PORT_IS_TFES;
+/// Interrupts for a change of the attached device
+pub const HBA_PORT_IS_HOTPLUG: u32 = HBA_PORT_IS_PCS | HBA_PORT_IS_PRCS;
+const HBA_PORT_IE: u32 = HBA_PORT_IS_DHRS | HBA_PORT_IS_PSS | HBA_PORT_IS_DSS | HBA_PORT_IS_SDBS | HBA_PORT_IS_DPS
+                         | HBA_PORT_IS_HOTPLUG | HBA_PORT_IS_ERROR;
 const HBA_SSTS_PRESENT: u32 = 0x3;
 const HBA_SIG_ATA: u32 = 0x00000101;
 const HBA_SIG_ATAPI: u32 = 0xEB140101;
 const HBA_SIG_PM: u32 = 0x96690101;
 const HBA_SIG_SEMB: u32 = 0xC33C0101;
 
+/// Physical region descriptor table entries per command, filling a page with the command table
+pub const PRDT_ENTRIES: usize = 248;
+/// Largest region of one PRDT entry
+const PRDT_ENTRY_MAX: usize = 4 * 1024 * 1024;
+/// Largest transfer of one command, in sectors
+pub const MAX_SECTORS: usize = 65535;
+
+/// Identity of an ATA device
+pub struct AtaIdentity {
+    /// Size in bytes
+    pub size: u64,
+    /// Native command queuing depth, zero if it is not supported
+    pub queue_depth: u32,
+    pub identity: DiskIdentity,
+}
+
 #[derive(Debug)]
 pub enum HbaPortType {
     None,
@@ -79,7 +117,7 @@ impl HbaPort {
         self.stop();
 
         // debugln!("Port Command List");
-        let clb = unsafe { memory::alloc_aligned(size_of::<HbaCmdHeader>(), 1024) };
+        let clb = unsafe { memory::alloc_aligned(32 * size_of::<HbaCmdHeader>(), 1024) };
         self.clb.write(clb as u64);
 
         // debugln!("Port FIS");
@@ -94,10 +132,34 @@ impl HbaPort {
             cmdheader.prdtl.write(0);
         }
 
+        self.serr.write(u32::MAX);
+        self.is.write(u32::MAX);
+        self.ie.write(HBA_PORT_IE);
+
         self.start();
     }
 
-    pub unsafe fn identify(&mut self, port: usize) -> Option<u64> {
+    /// Restart the port after an error, abandoning issued commands
+    pub fn recover(&mut self) {
+        self.stop();
+        self.serr.write(u32::MAX);
+        self.is.write(u32::MAX);
+        self.start();
+    }
+
+    /// Identify the device, polling for completion with port interrupts disabled
+    ///
+    /// Packet devices are identified with `IDENTIFY PACKET DEVICE`, and have no size.
+    pub unsafe fn identify(&mut self, port: usize, packet: bool) -> Option<AtaIdentity> {
+        let ie = self.ie.read();
+        self.ie.write(0);
+        let identity = self.identify_inner(port, packet);
+        self.is.write(u32::MAX);
+        self.ie.write(ie);
+        identity
+    }
+
+    unsafe fn identify_inner(&mut self, port: usize, packet: bool) -> Option<AtaIdentity> {
         self.is.write(u32::MAX);
 
         let mut destination = Memory::<u16>::new(256).unwrap();
@@ -118,13 +180,13 @@ impl HbaPort {
 
             let prdt_entry = &mut cmdtbl.prdt_entry[0];
             prdt_entry.dba.write(destination.as_mut_ptr() as u64);
-            prdt_entry.dbc.write(512 | 1);
+            prdt_entry.dbc.write(512 - 1);
 
             let cmdfis = &mut *(cmdtbl.cfis.as_ptr() as *mut FisRegH2D);
 
             cmdfis.fis_type.write(FIS_TYPE_REG_H2D);
             cmdfis.pm.write(1 << 7);
-            cmdfis.command.write(ATA_CMD_IDENTIFY);
+            cmdfis.command.write(if packet { ATA_CMD_IDENTIFY_PACKET } else { ATA_CMD_IDENTIFY });
             cmdfis.device.write(0);
             cmdfis.countl.write(1);
             cmdfis.counth.write(0);
@@ -145,61 +207,40 @@ impl HbaPort {
                 return None;
             }
 
-            let mut serial = String::new();
-            for word in 10..20 {
-                let d = destination.read(word);
-                let a = ((d >> 8) as u8) as char;
-                if a != '\0' {
-                    serial.push(a);
-                }
-                let b = (d as u8) as char;
-                if b != '\0' {
-                    serial.push(b);
-                }
-            }
+            let words: Vec<u16> = (0..256).map(|word| destination.read(word)).collect();
+            let identity = DiskIdentity::from_ata(&words);
 
-            let mut firmware = String::new();
-            for word in 23..27 {
-                let d = destination.read(word);
-                let a = ((d >> 8) as u8) as char;
-                if a != '\0' {
-                    firmware.push(a);
-                }
-                let b = (d as u8) as char;
-                if b != '\0' {
-                    firmware.push(b);
-                }
-            }
-
-            let mut model = String::new();
-            for word in 27..47 {
-                let d = destination.read(word);
-                let a = ((d >> 8) as u8) as char;
-                if a != '\0' {
-                    model.push(a);
-                }
-                let b = (d as u8) as char;
-                if b != '\0' {
-                    model.push(b);
-                }
-            }
-
-            let mut sectors = (destination.read(100) as u64) |
-                              ((destination.read(101) as u64) << 16) |
-                              ((destination.read(102) as u64) << 32) |
-                              ((destination.read(103) as u64) << 48);
+            let mut sectors = (words[100] as u64) |
+                              ((words[101] as u64) << 16) |
+                              ((words[102] as u64) << 32) |
+                              ((words[103] as u64) << 48);
 
             let lba_bits = if sectors == 0 {
-                sectors = (destination.read(60) as u64) | ((destination.read(61) as u64) << 16);
+                sectors = (words[60] as u64) | ((words[61] as u64) << 16);
                 28
             } else {
                 48
             };
 
-            syslog_info!("   + Port {}: Serial: {} Firmware: {} Model: {} {}-bit LBA Size: {} MB",
-                        port, serial.trim(), firmware.trim(), model.trim(), lba_bits, sectors / 2048);
+            if packet {
+                syslog_info!("   + Port {}: Serial: {} Firmware: {} Model: {} ATAPI",
+                            port, identity.serial, identity.firmware, identity.model);
+
+                return Some(AtaIdentity {
+                    size: 0,
+                    queue_depth: 0,
+                    identity: identity,
+                });
+            }
+
+            syslog_info!("   + Port {}: Serial: {} Firmware: {} Model: {} {}-bit LBA Size: {} MB NCQ: {}",
+                        port, identity.serial, identity.firmware, identity.model, lba_bits, sectors / 2048, identity.queue_depth);
 
-            Some(sectors * 512)
+            Some(AtaIdentity {
+                size: sectors * 512,
+                queue_depth: identity.queue_depth,
+                identity: identity,
+            })
         } else {
             debugln!("No Command Slots");
             None
@@ -235,113 +276,150 @@ impl HbaPort {
         None
     }
 
-    pub fn ata_dma_small(&mut self, block: u64, sectors: usize, mut buf: usize, write: bool) -> Result<usize> {
-        if buf >= 0x80000000 {
-            buf -= 0x80000000;
+    /// Slots with commands the device has not completed
+    pub fn active(&self) -> u32 {
+        self.sact.read() | self.ci.read()
+    }
+
+    /// Build and issue a DMA command in a slot, without waiting for it to complete
+    ///
+    /// The buffer is a physically contiguous region, described by as many PRDT entries as
+    /// needed. Queued commands use the slot as their tag.
+    pub fn ata_dma_start(&mut self, slot: u32, block: u64, sectors: usize, buf: usize, write: bool, queued: bool) -> Result<()> {
+        let bytes = sectors * 512;
+        let entries = (bytes + PRDT_ENTRY_MAX - 1) / PRDT_ENTRY_MAX;
+        if buf == 0 || sectors == 0 || sectors > MAX_SECTORS || entries > PRDT_ENTRIES {
+            debugln!("Invalid request");
+            return Err(Error::new(EIO));
         }
 
-        // TODO: PRDTL for files larger than 4MB
-        let entries = 1;
+        let clb = self.clb.read() as usize;
+        let cmdheader = unsafe { &mut *(clb as *mut HbaCmdHeader).offset(slot as isize) };
 
-        if buf > 0 && sectors > 0 {
-            self.is.write(u32::MAX);
+        cmdheader.cfl.write(((size_of::<FisRegH2D>() / size_of::<u32>()) as u8));
+        cmdheader.cfl.writef(1 << 6, write);
 
-            if let Some(slot) = self.slot() {
-                // debugln!("Slot {}", slot);
+        cmdheader.prdtl.write(entries as u16);
+        cmdheader.prdbc.write(0);
 
-                let clb = self.clb.read() as usize;
-                let cmdheader = unsafe { &mut *(clb as *mut HbaCmdHeader).offset(slot as isize) };
+        let ctba = cmdheader.ctba.read() as usize;
+        unsafe { ::memset(ctba as *mut u8, 0, size_of::<HbaCmdTable>()) };
+        let cmdtbl = unsafe { &mut *(ctba as *mut HbaCmdTable) };
 
-                cmdheader.cfl.write(((size_of::<FisRegH2D>() / size_of::<u32>()) as u8));
-                cmdheader.cfl.writef(1 << 6, write);
+        for i in 0..entries {
+            let offset = i * PRDT_ENTRY_MAX;
+            let len = if bytes - offset > PRDT_ENTRY_MAX {
+                PRDT_ENTRY_MAX
+            } else {
+                bytes - offset
+            };
 
-                cmdheader.prdtl.write(entries);
+            let prdt_entry = &mut cmdtbl.prdt_entry[i];
+            prdt_entry.dba.write((buf + offset) as u64);
+            // Byte count minus one, interrupting after the last entry
+            prdt_entry.dbc.write((len - 1) as u32 | if i + 1 == entries { 1 << 31 } else { 0 });
+        }
 
-                let ctba = cmdheader.ctba.read() as usize;
-                unsafe { ::memset(ctba as *mut u8, 0, size_of::<HbaCmdTable>()) };
-                let cmdtbl = unsafe { &mut *(ctba as *mut HbaCmdTable) };
+        let cmdfis = unsafe { &mut *(cmdtbl.cfis.as_ptr() as *mut FisRegH2D) };
 
-                let prdt_entry = &mut cmdtbl.prdt_entry[0];
-                prdt_entry.dba.write(buf as u64);
-                prdt_entry.dbc.write(((sectors * 512) as u32) | 1);
+        cmdfis.fis_type.write(FIS_TYPE_REG_H2D);
+        cmdfis.pm.write(1 << 7);
 
-                let cmdfis = unsafe { &mut *(cmdtbl.cfis.as_ptr() as *mut FisRegH2D) };
+        cmdfis.lba0.write(block as u8);
+        cmdfis.lba1.write((block >> 8) as u8);
+        cmdfis.lba2.write((block >> 16) as u8);
 
-                cmdfis.fis_type.write(FIS_TYPE_REG_H2D);
-                cmdfis.pm.write(1 << 7);
-                if write {
-                    cmdfis.command.write(ATA_CMD_WRITE_DMA_EXT);
-                } else {
-                    cmdfis.command.write(ATA_CMD_READ_DMA_EXT);
-                }
+        cmdfis.device.write(1 << 6);
+
+        cmdfis.lba3.write((block >> 24) as u8);
+        cmdfis.lba4.write((block >> 32) as u8);
+        cmdfis.lba5.write((block >> 40) as u8);
+
+        if queued {
+            cmdfis.command.write(if write { ATA_CMD_WRITE_FPDMA_QUEUED } else { ATA_CMD_READ_FPDMA_QUEUED });
+            // The sector count is in the feature register, and the tag in the count register
+            cmdfis.featurel.write(sectors as u8);
+            cmdfis.featureh.write((sectors >> 8) as u8);
+            cmdfis.countl.write((slot << 3) as u8);
+            cmdfis.counth.write(0);
 
-                cmdfis.lba0.write(block as u8);
-                cmdfis.lba1.write((block >> 8) as u8);
-                cmdfis.lba2.write((block >> 16) as u8);
+            self.sact.write(1 << slot);
+        } else {
+            cmdfis.command.write(if write { ATA_CMD_WRITE_DMA_EXT } else { ATA_CMD_READ_DMA_EXT });
+            cmdfis.countl.write(sectors as u8);
+            cmdfis.counth.write((sectors >> 8) as u8);
 
-                cmdfis.device.write(1 << 6);
+            // Non-queued commands cannot be issued while the device is busy
+            while self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) {
+                if self.is.read() & HBA_PORT_IS_ERROR != 0 {
+                    return Err(Error::new(EIO));
+                }
+            }
+        }
 
-                cmdfis.lba3.write((block >> 24) as u8);
-                cmdfis.lba4.write((block >> 32) as u8);
-                cmdfis.lba5.write((block >> 40) as u8);
+        self.ci.write(1 << slot);
 
-                cmdfis.countl.write(sectors as u8);
-                cmdfis.counth.write((sectors >> 8) as u8);
+        Ok(())
+    }
 
-                // debugln!("Busy Wait");
-                while self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) {}
+    /// Build and issue an ATAPI packet command in a slot, reading into a physically contiguous buffer
+    pub fn atapi_start(&mut self, slot: u32, packet: &[u8; 12], buf: usize, len: usize) -> Result<()> {
+        let entries = (len + PRDT_ENTRY_MAX - 1) / PRDT_ENTRY_MAX;
+        if entries > PRDT_ENTRIES || len & 1 == 1 {
+            debugln!("Invalid request");
+            return Err(Error::new(EIO));
+        }
 
-                self.ci.writef(1 << slot, true);
+        let clb = self.clb.read() as usize;
+        let cmdheader = unsafe { &mut *(clb as *mut HbaCmdHeader).offset(slot as isize) };
 
-                // debugln!("Completion Wait");
-                while self.ci.readf(1 << slot) {
-                    if self.is.readf(HBA_PORT_IS_TFES) {
-                        return Err(Error::new(EIO));
-                    }
-                }
+        // The ATAPI bit marks a packet in the command table
+        cmdheader.cfl.write(((size_of::<FisRegH2D>() / size_of::<u32>()) as u8) | 1 << 5);
 
-                if self.is.readf(HBA_PORT_IS_TFES) {
-                    return Err(Error::new(EIO));
-                }
+        cmdheader.prdtl.write(entries as u16);
+        cmdheader.prdbc.write(0);
 
-                Ok(sectors * 512)
+        let ctba = cmdheader.ctba.read() as usize;
+        unsafe { ::memset(ctba as *mut u8, 0, size_of::<HbaCmdTable>()) };
+        let cmdtbl = unsafe { &mut *(ctba as *mut HbaCmdTable) };
+
+        for i in 0..entries {
+            let offset = i * PRDT_ENTRY_MAX;
+            let entry_len = if len - offset > PRDT_ENTRY_MAX {
+                PRDT_ENTRY_MAX
             } else {
-                debugln!("No Command Slots");
-                Err(Error::new(EIO))
-            }
-        } else {
-            debugln!("Invalid request");
-            Err(Error::new(EIO))
+                len - offset
+            };
+
+            let prdt_entry = &mut cmdtbl.prdt_entry[i];
+            prdt_entry.dba.write((buf + offset) as u64);
+            prdt_entry.dbc.write((entry_len - 1) as u32 | if i + 1 == entries { 1 << 31 } else { 0 });
         }
-    }
 
-    pub fn ata_dma(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
-        // debugln!("AHCI {:X} DMA BLOCK: {:X} SECTORS: {} BUF: {:X} WRITE: {}", (self as *mut HbaPort) as usize, block, sectors, buf, write);
+        for i in 0..packet.len() {
+            cmdtbl.acmd[i].write(packet[i]);
+        }
 
-        if sectors > 0 {
-            let contexts = unsafe { & *::env().contexts.get() };
-            let current = try!(contexts.current());
-            let physical_address = try!(current.translate(buf, sectors * 512));
+        let cmdfis = unsafe { &mut *(cmdtbl.cfis.as_ptr() as *mut FisRegH2D) };
 
-            let mut sector: usize = 0;
-            while sectors - sector >= 255 {
-                if let Err(err) = self.ata_dma_small(block + sector as u64, 255, physical_address + sector * 512, write) {
-                    return Err(err);
-                }
+        cmdfis.fis_type.write(FIS_TYPE_REG_H2D);
+        cmdfis.pm.write(1 << 7);
+        cmdfis.command.write(ATA_CMD_PACKET);
+        // DMA data transfer, with the byte count limit in the LBA registers
+        cmdfis.featurel.write(if len > 0 { 1 } else { 0 });
+        cmdfis.lba1.write(len as u8);
+        cmdfis.lba2.write((len >> 8) as u8);
+        cmdfis.device.write(0);
 
-                sector += 255;
-            }
-            if sector < sectors {
-                if let Err(err) = self.ata_dma_small(block + sector as u64, sectors - sector, physical_address + sector * 512, write) {
-                    return Err(err);
-                }
+        while self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) {
+            if self.is.read() & HBA_PORT_IS_ERROR != 0 {
+                return Err(Error::new(EIO));
             }
-
-            Ok(sectors * 512)
-        } else {
-            debugln!("Invalid request");
-            Err(Error::new(EIO))
         }
+
+        self.ci.write(1 << slot);
+
+        Ok(())
     }
 }
 
@@ -382,7 +460,7 @@ struct HbaCmdTable {
     rsv: [Mmio<u8>; 48], // Reserved
 
     // 0x80
-    prdt_entry: [HbaPrdtEntry; 65536], // Physical region descriptor table entries, 0 ~ 65535
+    prdt_entry: [HbaPrdtEntry; PRDT_ENTRIES], // Physical region descriptor table entries
 }
 
 #[repr(packed)]
@@ -3,18 +3,31 @@ use alloc::boxed::Box;
 use collections::string::String;
 use collections::vec::Vec;
 
-use disk::Disk;
+use common::time::{Duration, NANOS_PER_MILLI};
+
+use core::cmp;
+
+use disk::{dma_address, Disk};
+use disk::atapi::{AtapiDevice, AtapiDisk};
+use disk::identity::DiskIdentity;
 
 use drivers::io::Io;
 use drivers::pci::config::PciConfig;
 
-use system::error::Result;
+use sync::WaitCondition;
+
+use system::error::{Error, Result, EIO};
 
-use self::hba::{HbaMem, HbaPort, HbaPortType};
+use self::hba::{HbaMem, HbaPort, HbaPortType, HBA_PORT_IS_ERROR, HBA_PORT_IS_HOTPLUG, MAX_SECTORS};
 
 pub mod fis;
 pub mod hba;
 
+/// Global interrupt enable
+const HBA_GHC_IE: u32 = 1 << 1;
+/// Supports native command queuing
+const HBA_CAP_SNCQ: u32 = 1 << 30;
+
 pub struct Ahci;
 
 impl Ahci {
@@ -24,7 +37,12 @@ impl Ahci {
 
         syslog_info!(" + AHCI on: {:X} IRQ: {:X}", base as usize, irq);
 
-        let pi = unsafe { &mut *(base as *mut HbaMem) }.pi.read();
+        let hba = unsafe { &mut *(base as *mut HbaMem) };
+        let pi = hba.pi.read();
+        let cap = hba.cap.read();
+        let hba_slots = ((cap >> 8) & 0x1F) + 1;
+        let hba_ncq = cap & HBA_CAP_SNCQ == HBA_CAP_SNCQ;
+
         let ret: Vec<Box<Disk>> = (0..32)
                                       .filter(|&i| pi & 1 << i as i32 == 1 << i as i32)
                                       .filter_map(|i| {
@@ -33,49 +51,283 @@ impl Ahci {
                                           match port_type {
                                               HbaPortType::SATA => {
                                                   disk.port.init();
-                                                  if let Some(size) = unsafe { disk.port.identify(i) } {
-                                                      disk.size = size;
+                                                  if disk.identify(hba_slots, hba_ncq) {
                                                       Some(disk as Box<Disk>)
                                                   } else {
                                                       None
                                                   }
                                               }
+                                              HbaPortType::SATAPI => {
+                                                  disk.atapi = true;
+                                                  disk.port.init();
+                                                  if disk.identify(hba_slots, hba_ncq) {
+                                                      Some(box AtapiDisk::new(disk) as Box<Disk>)
+                                                  } else {
+                                                      None
+                                                  }
+                                              }
                                               _ => None,
                                           }
                                       })
                                       .collect();
 
+        hba.is.write(0xFFFFFFFF);
+        hba.ghc.writef(HBA_GHC_IE, true);
+
         ret
     }
 }
 
 pub struct AhciDisk {
+    hba: &'static mut HbaMem,
     port: &'static mut HbaPort,
     port_index: usize,
     irq: u8,
     size: u64,
+    /// Number of command slots that can be in use at once
+    slots: u32,
+    /// Commands are native queued
+    queued: bool,
+    /// The device is a packet device, used through `AtapiDisk`
+    atapi: bool,
+    identity: DiskIdentity,
+    /// Slots with issued commands, cleared on completion
+    issued: u32,
+    /// Slots whose commands failed, cleared by the issuer
+    failed: u32,
+    /// An error interrupt arrived
+    error: bool,
+    /// A device is attached and identified
+    present: bool,
+    /// A device was attached, and is identified before the next command
+    attached: bool,
+    condition: WaitCondition,
 }
 
 impl AhciDisk {
     fn new(base: usize, port_index: usize, irq: u8) -> Self {
         AhciDisk {
+            hba: unsafe { &mut *(base as *mut HbaMem) },
             port: &mut unsafe { &mut *(base as *mut HbaMem) }.ports[port_index],
             port_index: port_index,
             irq: irq,
-            size: 0
+            size: 0,
+            slots: 1,
+            queued: false,
+            atapi: false,
+            identity: DiskIdentity::default(),
+            issued: 0,
+            failed: 0,
+            error: false,
+            present: false,
+            attached: false,
+            condition: WaitCondition::new(),
+        }
+    }
+
+    /// Identify the device, choosing native command queuing if both sides support it
+    fn identify(&mut self, hba_slots: u32, hba_ncq: bool) -> bool {
+        match unsafe { self.port.identify(self.port_index, self.atapi) } {
+            Some(identity) => {
+                self.size = identity.size;
+                self.queued = ! self.atapi && hba_ncq && identity.queue_depth > 0;
+                self.slots = if self.queued {
+                    cmp::min(hba_slots, identity.queue_depth)
+                } else {
+                    // Commands that are not queued cannot overlap
+                    1
+                };
+                self.identity = identity.identity;
+                self.present = true;
+            },
+            None => self.present = false,
+        }
+        self.present
+    }
+
+    /// Find a free slot
+    fn reserve(&self) -> Option<u32> {
+        (0..self.slots).find(|&i| self.issued & 1 << i == 0)
+    }
+
+    /// Collect completed commands, returning true if any completed or failed
+    fn poll(&mut self) -> bool {
+        let done = self.issued & ! self.port.active();
+        self.issued &= ! done;
+
+        if self.error || self.port.is.read() & HBA_PORT_IS_ERROR != 0 {
+            // An error stops the port, failing the commands still issued
+            debugln!("AHCI Port {}: error, task file {:X}, failing slots {:X}", self.port_index, self.port.tfd.read(), self.issued);
+            self.error = false;
+            self.failed |= self.issued;
+            self.issued = 0;
+            self.port.recover();
+            return true;
+        }
+
+        done != 0
+    }
+
+    /// Collect completed commands, blocking until the next interrupt if there were none
+    fn wait(&mut self) {
+        if ! self.poll() && unsafe { & *::env().contexts.get() }.enabled {
+            // An interrupt can arrive between polling and waiting, so do not wait long
+            self.condition.wait_for("AhciDisk::wait", Duration::new(0, 100 * NANOS_PER_MILLI));
+        }
+    }
+
+    /// Identify a device attached since the last command, then check one is present
+    fn check_present(&mut self) -> Result<()> {
+        if self.attached && self.issued == 0 {
+            self.attached = false;
+            self.port.recover();
+            let hba_slots = ((self.hba.cap.read() >> 8) & 0x1F) + 1;
+            let hba_ncq = self.hba.cap.read() & HBA_CAP_SNCQ == HBA_CAP_SNCQ;
+            self.identify(hba_slots, hba_ncq);
+        }
+
+        if self.present {
+            Ok(())
+        } else {
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// Reserve a free slot, waiting for one if all are in use
+    fn reserve_wait(&mut self) -> u32 {
+        let mut slot = self.reserve();
+        while slot.is_none() {
+            self.wait();
+            slot = self.reserve();
+        }
+        let slot = slot.unwrap_or(0);
+        self.issued |= 1 << slot;
+        slot
+    }
+
+    /// Wait for the commands in the slots of `mask` to complete
+    fn complete(&mut self, mask: u32) -> Result<()> {
+        while self.issued & mask != 0 {
+            self.wait();
+        }
+
+        if self.failed & mask != 0 {
+            self.failed &= ! mask;
+            Err(Error::new(EIO))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn ata_dma(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        if sectors == 0 {
+            debugln!("Invalid request");
+            return Err(Error::new(EIO));
+        }
+
+        try!(self.check_present());
+
+        let physical_address = try!(dma_address(buf, sectors * 512));
+
+        // Issue as many commands at once as there are free slots
+        let mut result = Ok(sectors * 512);
+        let mut mask = 0;
+        let mut sector = 0;
+        while sector < sectors {
+            let count = cmp::min(sectors - sector, MAX_SECTORS);
+
+            let slot = self.reserve_wait();
+            mask |= 1 << slot;
+            if let Err(err) = self.port.ata_dma_start(slot, block + sector as u64, count,
+                                                      physical_address + sector * 512, write, self.queued) {
+                self.issued &= ! (1 << slot);
+                mask &= ! (1 << slot);
+                result = Err(err);
+                break;
+            }
+
+            sector += count;
+        }
+
+        if let Err(err) = self.complete(mask) {
+            result = Err(err);
+        }
+
+        result
+    }
+
+    fn on_port_irq(&mut self, irq: u8) {
+        if irq == self.irq && self.hba.is.read() & 1 << self.port_index != 0 {
+            let is = self.port.is.read();
+
+            if is & HBA_PORT_IS_ERROR != 0 {
+                self.error = true;
+            }
+
+            if is & HBA_PORT_IS_HOTPLUG != 0 {
+                // Clear the PhyRdy and exchanged diagnostics, which hold the interrupt
+                self.port.serr.write(0xFFFFFFFF);
+                match self.port.probe() {
+                    HbaPortType::SATA | HbaPortType::SATAPI => if ! self.present {
+                        debugln!("AHCI Port {}: device attached", self.port_index);
+                        self.attached = true;
+                    },
+                    _ => if self.present {
+                        debugln!("AHCI Port {}: device detached", self.port_index);
+                        self.present = false;
+                        self.error = true;
+                    },
+                }
+            }
+
+            self.port.is.write(is);
+            self.hba.is.write(1 << self.port_index);
+
+            self.condition.notify("AhciDisk::on_irq");
         }
     }
 }
 
-impl Disk for AhciDisk {
+impl AtapiDevice for AhciDisk {
     fn name(&self) -> String {
         format!("AHCI Port {}", self.port_index)
     }
 
     fn on_irq(&mut self, irq: u8) {
-        if irq == self.irq {
-            //debugln!("AHCI IRQ");
+        self.on_port_irq(irq);
+    }
+
+    fn identity(&self) -> DiskIdentity {
+        self.identity.clone()
+    }
+
+    fn packet(&mut self, packet: &[u8; 12], buf: &mut [u8]) -> Result<usize> {
+        try!(self.check_present());
+
+        let physical_address = try!(dma_address(buf.as_ptr() as usize, buf.len()));
+
+        let slot = self.reserve_wait();
+        if let Err(err) = self.port.atapi_start(slot, packet, physical_address, buf.len()) {
+            self.issued &= ! (1 << slot);
+            return Err(err);
         }
+        try!(self.complete(1 << slot));
+
+        Ok(buf.len())
+    }
+}
+
+impl Disk for AhciDisk {
+    fn name(&self) -> String {
+        format!("AHCI Port {}", self.port_index)
+    }
+
+    fn on_irq(&mut self, irq: u8) {
+        self.on_port_irq(irq);
+    }
+
+    fn identity(&self) -> DiskIdentity {
+        self.identity.clone()
     }
 
     fn size(&self) -> u64 {
@@ -83,10 +335,10 @@ impl Disk for AhciDisk {
     }
 
     fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        self.port.ata_dma(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
+        self.ata_dma(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
     }
 
     fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
-        self.port.ata_dma(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+        self.ata_dma(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
     }
 }
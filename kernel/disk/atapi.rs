@@ -0,0 +1,162 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+
+use core::{cmp, ptr};
+
+use disk::Disk;
+use disk::identity::DiskIdentity;
+
+use system::error::{Error, Result, EIO, EROFS};
+
+/// Size of an optical disc sector
+pub const SECTOR_SIZE: usize = 2048;
+
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
+/// Largest read of one command, in sectors
+const MAX_SECTORS: usize = 32;
+/// Commands after a reset or media change report a unit attention first, so they are retried
+const RETRIES: usize = 4;
+
+/// A device that accepts ATAPI packet commands
+pub trait AtapiDevice {
+    fn name(&self) -> String;
+    fn on_irq(&mut self, irq: u8);
+    /// Identity reported by IDENTIFY PACKET DEVICE
+    fn identity(&self) -> DiskIdentity;
+    /// Send a 12 byte command packet, reading any data into `buf`
+    fn packet(&mut self, packet: &[u8; 12], buf: &mut [u8]) -> Result<usize>;
+}
+
+/// A read-only disk on an ATAPI device, such as an optical drive
+///
+/// The device uses 2048 byte sectors, so reads that do not cover whole sectors go through a
+/// bounce buffer.
+pub struct AtapiDisk {
+    device: Box<AtapiDevice>,
+    size: u64,
+}
+
+impl AtapiDisk {
+    pub fn new(device: Box<AtapiDevice>) -> AtapiDisk {
+        let mut disk = AtapiDisk {
+            device: device,
+            size: 0,
+        };
+
+        match disk.capacity() {
+            Ok(size) => {
+                syslog_info!("     + {}: ATAPI Size: {} MB", disk.device.name(), size / 1024 / 1024);
+                disk.size = size;
+            },
+            Err(err) => syslog_info!("     + {}: ATAPI No Media: {}", disk.device.name(), err),
+        }
+
+        disk
+    }
+
+    fn command(&mut self, packet: &[u8; 12], buf: &mut [u8]) -> Result<usize> {
+        let mut result = Err(Error::new(EIO));
+        for _ in 0..RETRIES {
+            result = self.device.packet(packet, buf);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Read the size of the media in bytes
+    fn capacity(&mut self) -> Result<u64> {
+        let packet = [SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut data = vec![0; 8];
+        try!(self.command(&packet, &mut data));
+
+        let last = (data[0] as u64) << 24 | (data[1] as u64) << 16 | (data[2] as u64) << 8 | data[3] as u64;
+        let block_size = (data[4] as u64) << 24 | (data[5] as u64) << 16 | (data[6] as u64) << 8 | data[7] as u64;
+        if block_size != SECTOR_SIZE as u64 {
+            debugln!("ATAPI: unexpected block size {}", block_size);
+        }
+
+        Ok((last + 1) * SECTOR_SIZE as u64)
+    }
+
+    /// Read whole sectors
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<usize> {
+        let sectors = buf.len() / SECTOR_SIZE;
+        let mut sector = 0;
+        while sector < sectors {
+            let count = cmp::min(sectors - sector, MAX_SECTORS);
+            let address = lba + sector as u64;
+            let packet = [SCSI_READ_10, 0,
+                          (address >> 24) as u8, (address >> 16) as u8, (address >> 8) as u8, address as u8,
+                          0, (count >> 8) as u8, count as u8,
+                          0, 0, 0];
+            try!(self.command(&packet, &mut buf[sector * SECTOR_SIZE .. (sector + count) * SECTOR_SIZE]));
+            sector += count;
+        }
+        Ok(sectors * SECTOR_SIZE)
+    }
+}
+
+impl Disk for AtapiDisk {
+    fn name(&self) -> String {
+        format!("{} ATAPI", self.device.name())
+    }
+
+    fn on_irq(&mut self, irq: u8) {
+        self.device.on_irq(irq);
+    }
+
+    fn identity(&self) -> DiskIdentity {
+        self.device.identity()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn probe(&mut self) {
+        if self.size == 0 {
+            if let Ok(size) = self.capacity() {
+                syslog_info!(" + {}: ATAPI Size: {} MB", self.device.name(), size / 1024 / 1024);
+                self.size = size;
+            }
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        // Media may have been inserted since the last read
+        if self.size == 0 {
+            self.size = try!(self.capacity());
+        }
+
+        let start = block * 512;
+        if start >= self.size {
+            return Ok(0);
+        }
+        let len = cmp::min(buffer.len() as u64 / 512 * 512, self.size - start) as usize;
+        let lba = start / SECTOR_SIZE as u64;
+
+        if start % SECTOR_SIZE as u64 == 0 && len % SECTOR_SIZE == 0 {
+            self.read_sectors(lba, &mut buffer[..len])
+        } else {
+            let offset = (start % SECTOR_SIZE as u64) as usize;
+            let sectors = (offset + len + SECTOR_SIZE - 1) / SECTOR_SIZE;
+            let mut bounce = vec![0; sectors * SECTOR_SIZE];
+            try!(self.read_sectors(lba, &mut bounce));
+            unsafe { ptr::copy(bounce.as_ptr().offset(offset as isize), buffer.as_mut_ptr(), len) };
+            Ok(len)
+        }
+    }
+
+    fn write(&mut self, _block: u64, _buffer: &[u8]) -> Result<usize> {
+        Err(Error::new(EROFS))
+    }
+}
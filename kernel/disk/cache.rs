@@ -0,0 +1,397 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use collections::{BTreeMap, Vec};
+
+use core::cell::UnsafeCell;
+use core::{cmp, ptr};
+
+use disk::Disk;
+
+use system::error::Result;
+
+/// Size of a cache block in bytes, one allocator cluster
+pub const BLOCK_SIZE: usize = 4096;
+/// Number of cache blocks read at once on a miss
+const READ_AHEAD: u64 = 8;
+/// Maximum number of cached blocks, 8 MiB
+const CAPACITY: usize = 2048;
+
+/// A cached block of a disk
+struct CacheBlock {
+    disk: Arc<UnsafeCell<Box<Disk>>>,
+    /// The valid bytes of the block, shorter than `BLOCK_SIZE` at the end of a disk
+    data: Vec<u8>,
+    /// Modified since it was read or written back
+    dirty: bool,
+    /// Counts writes to the block, so that a write back can tell if it raced with one
+    version: u64,
+    /// Tick of the last access, the key in `BlockCache::lru`
+    used: u64,
+}
+
+/// Identify a disk by the address of its cell
+fn disk_id(disk: &Arc<UnsafeCell<Box<Disk>>>) -> usize {
+    disk.get() as usize
+}
+
+/// A write-back block cache shared by all disks
+///
+/// Blocks are keyed by disk and block number, and evicted in least recently used order. Misses
+/// read ahead up to `READ_AHEAD` blocks. Dirty blocks are written back on `sync`, on eviction
+/// and on shutdown, while reclaiming memory drops only clean blocks.
+///
+/// Disk I/O sleeps, and other contexts can use the cache meanwhile. So no iterator or reference
+/// into the maps is held across I/O, and blocks are looked up again after it.
+pub struct BlockCache {
+    blocks: BTreeMap<(usize, u64), CacheBlock>,
+    /// Blocks by tick of last access, oldest first
+    lru: BTreeMap<u64, (usize, u64)>,
+    tick: u64,
+    /// Operations in progress, so that reclaiming from the allocator cannot reenter
+    busy: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache {
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            busy: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The number of cached blocks
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The number of dirty blocks
+    pub fn dirty(&self) -> usize {
+        self.blocks.values().filter(|block| block.dirty).count()
+    }
+
+    fn touch(&mut self, key: (usize, u64)) {
+        self.tick += 1;
+        if let Some(block) = self.blocks.get_mut(&key) {
+            self.lru.remove(&block.used);
+            block.used = self.tick;
+            self.lru.insert(self.tick, key);
+        }
+    }
+
+    /// Write back a block if it is dirty
+    ///
+    /// The data is copied first, as the block can be written or evicted while the disk sleeps.
+    /// It stays dirty if it was written meanwhile.
+    fn write_back(&mut self, key: (usize, u64)) -> Result<()> {
+        let (disk, data, version) = match self.blocks.get(&key) {
+            Some(block) if block.dirty => (block.disk.clone(), block.data.clone(), block.version),
+            _ => return Ok(()),
+        };
+
+        try!(unsafe { &mut *disk.get() }.write(key.1 * (BLOCK_SIZE / 512) as u64, &data));
+
+        if let Some(block) = self.blocks.get_mut(&key) {
+            if block.version == version {
+                block.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop a block if it is cached and clean
+    fn drop_clean(&mut self, key: (usize, u64)) -> bool {
+        let used = match self.blocks.get(&key) {
+            Some(block) if ! block.dirty => block.used,
+            _ => return false,
+        };
+        self.lru.remove(&used);
+        self.blocks.remove(&key);
+        true
+    }
+
+    /// Evict the least recently used block that can be dropped, returning false if there is none
+    ///
+    /// Clean blocks are dropped first. Only if there are none, and `write_back` is set, are dirty
+    /// blocks written back, oldest first, until one can be dropped. A block that fails to write
+    /// back stays cached and dirty, so that its data is not lost and a failing disk cannot stop
+    /// other blocks from being evicted.
+    fn evict(&mut self, write_back: bool) -> bool {
+        let clean = {
+            let blocks = &self.blocks;
+            self.lru.values().find(|key| blocks.get(*key).map_or(false, |block| ! block.dirty)).cloned()
+        };
+        if let Some(key) = clean {
+            return self.drop_clean(key);
+        }
+        if ! write_back {
+            return false;
+        }
+
+        let keys: Vec<(usize, u64)> = self.lru.values().cloned().collect();
+        for &key in keys.iter() {
+            match self.write_back(key) {
+                Ok(()) => if self.drop_clean(key) {
+                    return true;
+                },
+                Err(err) => debugln!("Block Cache: write back of block {} failed: {}", key.1, err),
+            }
+        }
+        false
+    }
+
+    /// Cache a block, unless it was cached by another context while this one slept
+    fn insert(&mut self, key: (usize, u64), disk: &Arc<UnsafeCell<Box<Disk>>>, data: Vec<u8>, dirty: bool) -> Result<()> {
+        while self.blocks.len() >= CAPACITY {
+            if ! self.evict(true) {
+                break;
+            }
+        }
+        if self.blocks.contains_key(&key) {
+            return Ok(());
+        }
+
+        self.tick += 1;
+        self.lru.insert(self.tick, key);
+        self.blocks.insert(key, CacheBlock {
+            disk: disk.clone(),
+            data: data,
+            dirty: dirty,
+            version: 0,
+            used: self.tick,
+        });
+        Ok(())
+    }
+
+    /// The number of valid bytes in a block
+    fn block_len(disk: &Arc<UnsafeCell<Box<Disk>>>, block: u64) -> usize {
+        let size = unsafe { & *disk.get() }.size();
+        let offset = block * BLOCK_SIZE as u64;
+        if offset >= size {
+            0
+        } else {
+            cmp::min(BLOCK_SIZE as u64, size - offset) as usize / 512 * 512
+        }
+    }
+
+    /// Make sure a block is cached, reading it and the following uncached blocks on a miss
+    fn load(&mut self, disk: &Arc<UnsafeCell<Box<Disk>>>, block: u64) -> Result<()> {
+        let id = disk_id(disk);
+        if self.blocks.contains_key(&(id, block)) {
+            self.hits += 1;
+            self.touch((id, block));
+            return Ok(());
+        }
+        self.misses += 1;
+
+        let mut count = 1;
+        while count < READ_AHEAD
+              && ! self.blocks.contains_key(&(id, block + count))
+              && BlockCache::block_len(disk, block + count) == BLOCK_SIZE {
+            count += 1;
+        }
+
+        let len = (count - 1) as usize * BLOCK_SIZE + BlockCache::block_len(disk, block);
+        let mut data = vec![0; len];
+        let read = try!(unsafe { &mut *disk.get() }.read(block * (BLOCK_SIZE / 512) as u64, &mut data));
+
+        for i in 0..count {
+            let start = i as usize * BLOCK_SIZE;
+            if start >= read {
+                break;
+            }
+            let end = cmp::min(start + BLOCK_SIZE, read);
+            try!(self.insert((id, block + i), disk, data[start..end].to_vec(), false));
+        }
+
+        // The requested block is the most recently used, not the blocks read ahead of it
+        self.touch((id, block));
+
+        Ok(())
+    }
+
+    /// Read from a disk through the cache, starting at a byte offset
+    ///
+    /// A disk without media is probed again first, so that media inserted since can be read.
+    pub fn read(&mut self, disk: &Arc<UnsafeCell<Box<Disk>>>, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if unsafe { & *disk.get() }.size() == 0 {
+            unsafe { &mut *disk.get() }.probe();
+        }
+        let size = unsafe { & *disk.get() }.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, size - offset) as usize;
+
+        self.busy += 1;
+        let id = disk_id(disk);
+        let mut i = 0;
+        while i < len {
+            let position = offset + i as u64;
+            let block = position / BLOCK_SIZE as u64;
+            let block_offset = (position % BLOCK_SIZE as u64) as usize;
+
+            if let Err(err) = self.load(disk, block) {
+                self.busy -= 1;
+                return Err(err);
+            }
+
+            let data = match self.blocks.get(&(id, block)) {
+                Some(cached) => &cached.data,
+                None => break,
+            };
+            if block_offset >= data.len() {
+                break;
+            }
+
+            let count = cmp::min(len - i, data.len() - block_offset);
+            unsafe { ptr::copy(data.as_ptr().offset(block_offset as isize), buf.as_mut_ptr().offset(i as isize), count) };
+            i += count;
+        }
+        self.busy -= 1;
+
+        Ok(i)
+    }
+
+    /// Write to a disk through the cache, starting at a byte offset
+    ///
+    /// Blocks that are only partly written are read first, so any offset and length can be used.
+    pub fn write(&mut self, disk: &Arc<UnsafeCell<Box<Disk>>>, offset: u64, buf: &[u8]) -> Result<usize> {
+        let size = unsafe { & *disk.get() }.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, size - offset) as usize;
+
+        self.busy += 1;
+        let id = disk_id(disk);
+        let mut i = 0;
+        while i < len {
+            let position = offset + i as u64;
+            let block = position / BLOCK_SIZE as u64;
+            let block_offset = (position % BLOCK_SIZE as u64) as usize;
+            let block_len = BlockCache::block_len(disk, block);
+            if block_offset >= block_len {
+                break;
+            }
+            let count = cmp::min(len - i, block_len - block_offset);
+
+            let result = if count == block_len && ! self.blocks.contains_key(&(id, block)) {
+                self.insert((id, block), disk, vec![0; block_len], true)
+            } else {
+                self.load(disk, block)
+            };
+            if let Err(err) = result {
+                self.busy -= 1;
+                return Err(err);
+            }
+
+            if let Some(cached) = self.blocks.get_mut(&(id, block)) {
+                unsafe { ptr::copy(buf.as_ptr().offset(i as isize), cached.data.as_mut_ptr().offset(block_offset as isize), count) };
+                cached.dirty = true;
+                cached.version += 1;
+            }
+            i += count;
+        }
+        self.busy -= 1;
+
+        Ok(i)
+    }
+
+    /// Write back the dirty blocks of a disk, in block order, then flush the disk
+    pub fn sync(&mut self, disk: &Arc<UnsafeCell<Box<Disk>>>) -> Result<()> {
+        let id = disk_id(disk);
+
+        self.busy += 1;
+        let mut result = Ok(());
+        let keys: Vec<(usize, u64)> = self.blocks.iter()
+                                          .filter(|&(key, cached)| key.0 == id && cached.dirty)
+                                          .map(|(&key, _)| key).collect();
+        for &key in keys.iter() {
+            if let Err(err) = self.write_back(key) {
+                result = Err(err);
+            }
+        }
+        if let Err(err) = unsafe { &mut *disk.get() }.flush() {
+            result = Err(err);
+        }
+        self.busy -= 1;
+
+        result
+    }
+
+    /// Write back the dirty blocks of every disk, then flush the disks
+    pub fn sync_all(&mut self) -> Result<()> {
+        self.busy += 1;
+        let mut result = Ok(());
+        let mut keys: Vec<(usize, u64)> = Vec::new();
+        let mut disks: Vec<Arc<UnsafeCell<Box<Disk>>>> = Vec::new();
+        for (&key, cached) in self.blocks.iter() {
+            if cached.dirty {
+                keys.push(key);
+            }
+            if ! disks.iter().any(|disk| disk_id(disk) == key.0) {
+                disks.push(cached.disk.clone());
+            }
+        }
+        for &key in keys.iter() {
+            if let Err(err) = self.write_back(key) {
+                result = Err(err);
+            }
+        }
+        for disk in disks.iter() {
+            if let Err(err) = unsafe { &mut *disk.get() }.flush() {
+                result = Err(err);
+            }
+        }
+        self.busy -= 1;
+
+        result
+    }
+
+    /// Write back and drop every block of a disk
+    pub fn invalidate(&mut self, disk: &Arc<UnsafeCell<Box<Disk>>>) -> Result<()> {
+        let result = self.sync(disk);
+
+        let id = disk_id(disk);
+        let keys: Vec<(usize, u64)> = self.blocks.keys().filter(|key| key.0 == id).cloned().collect();
+        for key in keys.iter() {
+            if let Some(cached) = self.blocks.remove(key) {
+                self.lru.remove(&cached.used);
+            }
+        }
+
+        result
+    }
+
+    /// Free memory by dropping up to half of the cache, oldest first
+    ///
+    /// This is called by the allocator when it runs out of memory, returning the number of
+    /// blocks dropped. Only clean blocks are dropped, as writing back could sleep or allocate
+    /// inside the failed allocation. Nothing is dropped if the cache is in use.
+    pub fn reclaim(&mut self) -> usize {
+        if self.busy > 0 {
+            return 0;
+        }
+
+        self.busy += 1;
+        let mut count = 0;
+        let target = (self.blocks.len() + 1) / 2;
+        while count < target {
+            if self.evict(false) {
+                count += 1;
+            } else {
+                break;
+            }
+        }
+        self.busy -= 1;
+
+        count
+    }
+}
@@ -1,17 +1,25 @@
+use alloc::arc::Arc;
 use alloc::boxed::Box;
 
 use collections::string::String;
 use collections::vec::Vec;
 
-use core::ptr;
+use common::time::{Duration, NANOS_PER_MILLI};
+
+use core::cell::UnsafeCell;
+use core::{cmp, ptr};
 
 use arch::memory::Memory;
 
-use disk::Disk;
+use disk::{dma_address, Disk};
+use disk::atapi::{AtapiDevice, AtapiDisk};
+use disk::identity::DiskIdentity;
 
 use drivers::pci::config::PciConfig;
 use drivers::io::{Io, Pio, ReadOnly, WriteOnly};
 
+use sync::WaitCondition;
+
 use system::error::{Error, Result, EIO};
 
 /// An disk extent
@@ -42,6 +50,14 @@ const STS_ACT: u8 = 1;
 /// PRDT End of Table
 const PRD_EOT: u8 = 1 << 7;
 
+/// Device control software reset
+const CTRL_SRST: u8 = 1 << 2;
+
+/// Sectors moved by one command
+const MAX_SECTORS: usize = 255;
+/// Seconds a command may take before the channel is reset
+const COMMAND_TIMEOUT: i64 = 5;
+
 /// Physical Region Descriptor
 #[repr(packed)]
 struct Prd {
@@ -155,6 +171,9 @@ impl Ide {
             }
         };
 
+        // Without a bus master, drives use PIO
+        let dma = bar4 > 0;
+
         {
             let busmaster = bar4;
             let data = port_or(bar0, 0x1F0);
@@ -163,12 +182,14 @@ impl Ide {
 
             syslog_info!("   + Primary on: {:X}, {:X}, {:X}, IRQ {:X}", busmaster, data, control, irq);
 
-            if let Some(disk) = IdeDisk::new(busmaster, data, control, irq, true) {
-                ret.push(box disk);
+            let channel = Arc::new(UnsafeCell::new(IdeChannel::new(busmaster, data, control, irq, dma)));
+
+            if let Some(disk) = IdeDisk::new(channel.clone(), data, control, true) {
+                ret.push(disk);
             }
 
-            if let Some(disk) = IdeDisk::new(busmaster, data, control, irq, false) {
-                ret.push(box disk);
+            if let Some(disk) = IdeDisk::new(channel, data, control, false) {
+                ret.push(disk);
             }
         }
 
@@ -180,12 +201,14 @@ impl Ide {
 
             syslog_info!("   + Secondary on: {:X}, {:X}, {:X}, IRQ {:X}", busmaster, data, control, irq);
 
-            if let Some(disk) = IdeDisk::new(busmaster, data, control, irq, true) {
-                ret.push(box disk);
+            let channel = Arc::new(UnsafeCell::new(IdeChannel::new(busmaster, data, control, irq, dma)));
+
+            if let Some(disk) = IdeDisk::new(channel.clone(), data, control, true) {
+                ret.push(disk);
             }
 
-            if let Some(disk) = IdeDisk::new(busmaster, data, control, irq, false) {
-                ret.push(box disk);
+            if let Some(disk) = IdeDisk::new(channel, data, control, false) {
+                ret.push(disk);
             }
         }
 
@@ -193,13 +216,196 @@ impl Ide {
     }
 }
 
-/// A disk (data storage)
-pub struct IdeDisk {
+/// The bus master and interrupt of a channel, shared by its master and slave drives
+///
+/// Only one drive on a channel can have a command in progress, so drives take the channel
+/// with `acquire` for the length of a request.
+pub struct IdeChannel {
     buscmd: Pio<u8>,
     bussts: Pio<u8>,
-    prdt: Prdt,
+    /// The descriptor table, if the controller has a bus master
+    prdt: Option<Prdt>,
+    control: WriteOnly<Pio<u8>>,
+    alt_sts: ReadOnly<Pio<u8>>,
+    sts: ReadOnly<Pio<u8>>,
+    irq: u8,
+    /// A drive has taken the channel
+    busy: bool,
+    /// Bus master status collected since the last transfer started, including by the interrupt handler
+    dma_status: u8,
+    condition: WaitCondition,
+}
+
+impl IdeChannel {
+    fn new(busmaster: u16, base: u16, ctrl: u16, irq: u8, dma: bool) -> IdeChannel {
+        IdeChannel {
+            buscmd: Pio::new(busmaster),
+            bussts: Pio::new(busmaster + 2),
+            prdt: if dma {
+                Some(Prdt::new(busmaster + 4))
+            } else {
+                None
+            },
+            control: WriteOnly::new(Pio::new(ctrl + 2)),
+            alt_sts: ReadOnly::new(Pio::new(ctrl + 2)),
+            sts: ReadOnly::new(Pio::new(base + 7)),
+            irq: irq,
+            busy: false,
+            dma_status: 0,
+            condition: WaitCondition::new(),
+        }
+    }
+
+    /// Take the channel, waiting for the other drive to finish its request
+    fn acquire(&mut self) {
+        while self.busy {
+            self.condition.wait_for("IdeChannel::acquire", Duration::new(0, 100 * NANOS_PER_MILLI));
+        }
+        self.busy = true;
+    }
+
+    fn release(&mut self) {
+        self.busy = false;
+        self.condition.notify("IdeChannel::release");
+    }
+
+    /// Sleep until `done` holds, checking again on every interrupt
+    ///
+    /// Returns false if `COMMAND_TIMEOUT` passes first.
+    fn wait<F: Fn(&mut IdeChannel) -> bool>(&mut self, reason: &str, done: F) -> bool {
+        let end = Duration::monotonic() + Duration::new(COMMAND_TIMEOUT, 0);
+        while ! done(self) {
+            if Duration::monotonic() > end {
+                return false;
+            }
+
+            if unsafe { & *::env().contexts.get() }.enabled {
+                // An interrupt can arrive between checking and waiting, so do not wait long
+                self.condition.wait_for(reason, Duration::new(0, 10 * NANOS_PER_MILLI));
+            }
+        }
+        true
+    }
+
+    /// Collect the bus master status, including what the interrupt handler cleared
+    fn dma_status(&mut self) -> u8 {
+        self.dma_status |= self.bussts.read();
+        self.dma_status
+    }
+
+    /// Point the bus master at a physically contiguous buffer, without starting it
+    fn start_dma(&mut self, address: usize, len: usize, write: bool) -> Result<()> {
+        if address + len > 0xFFFFFFFF {
+            debugln!("IDE: DMA buffer {:X} is above 4 GB", address);
+            return Err(Error::new(EIO));
+        }
+
+        let prdt = match self.prdt {
+            Some(ref mut prdt) => prdt,
+            None => return Err(Error::new(EIO)),
+        };
+
+        self.buscmd.writef(CMD_ACT, false);
+
+        // Writing the interrupt and error bits clears them
+        let status = self.bussts.read();
+        self.bussts.write(status);
+        self.dma_status = 0;
+
+        // Entries cannot cross a 64 KB boundary
+        let mut offset = 0;
+        let mut i = 0;
+        while offset < len {
+            let entry = address + offset;
+            let size = cmp::min(len - offset, 0x10000 - (entry & 0xFFFF));
+            offset += size;
+
+            prdt.mem.write(i, Prd {
+                addr: entry as u32,
+                // A size of zero is 64 KB
+                size: size as u16,
+                rsv: 0,
+                eot: if offset == len {
+                    PRD_EOT
+                } else {
+                    0
+                },
+            });
+            i += 1;
+        }
+
+        prdt.reg.write(prdt.mem.address() as u32);
+
+        self.buscmd.writef(CMD_DIR, ! write);
+
+        Ok(())
+    }
+
+    /// Stop the bus master, returning its status
+    fn stop_dma(&mut self) -> u8 {
+        if self.prdt.is_none() {
+            return 0;
+        }
+
+        self.buscmd.writef(CMD_ACT, false);
+
+        let status = self.dma_status();
+        self.bussts.write(self.bussts.read());
+
+        status
+    }
+
+    /// Reset both drives, abandoning any command in progress
+    fn reset(&mut self) {
+        self.stop_dma();
+
+        self.control.write(CTRL_SRST);
+        // Hold reset for at least 5 microseconds, each status read taking at least 100 nanoseconds
+        for _ in 0..64 {
+            self.alt_sts.read();
+        }
+        self.control.write(0);
+        for _ in 0..64 {
+            self.alt_sts.read();
+        }
+
+        let end = Duration::monotonic() + Duration::new(COMMAND_TIMEOUT, 0);
+        while self.alt_sts.readf(ATA_SR_BSY) && Duration::monotonic() < end {}
+
+        self.sts.read();
+    }
+
+    fn on_irq(&mut self, irq: u8) {
+        if irq == self.irq {
+            if self.prdt.is_some() {
+                // Clear the interrupt, keeping the status for the waiting transfer
+                let status = self.bussts.read();
+                self.dma_status |= status;
+                self.bussts.write(status);
+            }
+
+            // Reading the status register acknowledges the interrupt
+            self.sts.read();
+
+            self.condition.notify("IdeChannel::on_irq");
+        }
+    }
+}
+
+/// What identify found on a channel
+enum IdeIdentity {
+    /// An ATA disk of this size in bytes
+    Ata(u64),
+    /// An ATAPI device
+    Atapi,
+}
+
+/// A disk (data storage)
+pub struct IdeDisk {
+    channel: Arc<UnsafeCell<IdeChannel>>,
     data: Pio<u16>,
     error: ReadOnly<Pio<u8>>,
+    features: WriteOnly<Pio<u8>>,
     seccount: Pio<u8>,
     sector0: Pio<u8>,
     sector1: Pio<u8>,
@@ -208,19 +414,19 @@ pub struct IdeDisk {
     sts: ReadOnly<Pio<u8>>,
     cmd: WriteOnly<Pio<u8>>,
     alt_sts: ReadOnly<Pio<u8>>,
-    irq: u8,
     master: bool,
     size: u64,
+    identity: DiskIdentity,
 }
 
 impl IdeDisk {
-    pub fn new(busmaster: u16, base: u16, ctrl: u16, irq: u8, master: bool) -> Option<Self> {
+    /// Identify a drive, returning an ATA disk or a read-only ATAPI disk
+    pub fn new(channel: Arc<UnsafeCell<IdeChannel>>, base: u16, ctrl: u16, master: bool) -> Option<Box<Disk>> {
         let mut ret = IdeDisk {
-            buscmd: Pio::new(busmaster),
-            bussts: Pio::new(busmaster + 2),
-            prdt: Prdt::new(busmaster + 4),
+            channel: channel,
             data: Pio::new(base),
             error: ReadOnly::new(Pio::new(base + 1)),
+            features: WriteOnly::new(Pio::new(base + 1)),
             seccount: Pio::new(base + 2),
             sector0: Pio::new(base + 3),
             sector1: Pio::new(base + 4),
@@ -229,21 +435,36 @@ impl IdeDisk {
             sts: ReadOnly::new(Pio::new(base + 7)),
             cmd: WriteOnly::new(Pio::new(base + 7)),
             alt_sts: ReadOnly::new(Pio::new(ctrl + 2)),
-            irq: irq,
             master: master,
             size: 0,
+            identity: DiskIdentity::default(),
         };
 
-        if let Some(size) = unsafe { ret.identify() } {
-            ret.size = size;
-            Some(ret)
-        } else {
-            None
+        match unsafe { ret.identify() } {
+            Some(IdeIdentity::Ata(size)) => {
+                ret.size = size;
+                Some(box ret)
+            },
+            Some(IdeIdentity::Atapi) => Some(box AtapiDisk::new(box ret)),
+            None => None,
+        }
+    }
+
+    /// Spin until the selected drive clears busy, failing after `COMMAND_TIMEOUT`
+    fn wait_not_busy(&self) -> Result<()> {
+        let end = Duration::monotonic() + Duration::new(COMMAND_TIMEOUT, 0);
+        while self.alt_sts.readf(ATA_SR_BSY) {
+            if Duration::monotonic() > end {
+                return Err(Error::new(EIO));
+            }
         }
+        Ok(())
     }
 
     unsafe fn ide_poll(&self, check_error: bool) -> u8 {
-        while self.alt_sts.readf(ATA_SR_BSY) {}
+        if self.wait_not_busy().is_err() {
+            return 4;
+        }
 
         if check_error {
             let state = self.alt_sts.read();
@@ -261,26 +482,66 @@ impl IdeDisk {
         0
     }
 
-    pub fn ata(&mut self, cmd: u8, block: u64, len: u16) {
-        while self.alt_sts.readf(ATA_SR_BSY) {}
+    /// Sleep until the drive clears busy, and with `data` until it also asks for data or fails
+    ///
+    /// Returns the status, or resets the channel and fails after `COMMAND_TIMEOUT`.
+    fn wait_ready(&mut self, data: bool) -> Result<u8> {
+        let channel = unsafe { &mut *self.channel.get() };
+        let done = channel.wait("IdeDisk::wait_ready", |channel| {
+            let status = channel.alt_sts.read();
+            status & ATA_SR_BSY == 0 && (! data || status & (ATA_SR_DRQ | ATA_SR_ERR | ATA_SR_DF) != 0)
+        });
+
+        if done {
+            // Reading the status register acknowledges the interrupt
+            Ok(self.sts.read())
+        } else {
+            debugln!("{}: timed out, resetting channel", Disk::name(self));
+            channel.reset();
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// Run `f` with the channel taken by this drive
+    fn exclusive<T, F: FnOnce(&mut IdeDisk) -> Result<T>>(&mut self, f: F) -> Result<T> {
+        let channel = unsafe { &mut *self.channel.get() };
+        channel.acquire();
+        let result = f(self);
+        channel.release();
+        result
+    }
 
+    /// Choose the 48-bit form of a command only if the request needs it
+    fn command(&self, block: u64, sectors: usize, cmd: u8, cmd_ext: u8) -> u8 {
+        if self.identity.lba48 && block + sectors as u64 > 0xFFFFFFF {
+            cmd_ext
+        } else {
+            cmd
+        }
+    }
+
+    pub fn ata(&mut self, cmd: u8, block: u64, len: u16) -> Result<()> {
+        try!(self.wait_not_busy());
+
+        // The top of a 28-bit address goes in the device register
         self.devsel.write(if self.master {
             0b11100000
         } else {
             0b11110000
-        });
+        } | (block >> 24) as u8 & 0xF);
 
         self.alt_sts.read();
         self.alt_sts.read();
         self.alt_sts.read();
         self.alt_sts.read();
 
-        while self.alt_sts.readf(ATA_SR_BSY) {}
+        try!(self.wait_not_busy());
 
-        /*self.seccount.write((len >> 8) as u8);
+        // The high bytes are written first, and only used by 48-bit commands
+        self.seccount.write((len >> 8) as u8);
         self.sector0.write((block >> 24) as u8);
         self.sector1.write((block >> 32) as u8);
-        self.sector2.write((block >> 40) as u8);*/
+        self.sector2.write((block >> 40) as u8);
 
         self.seccount.write(len as u8);
         self.sector0.write(block as u8);
@@ -288,17 +549,21 @@ impl IdeDisk {
         self.sector2.write((block >> 16) as u8);
 
         self.cmd.write(cmd);
+
+        Ok(())
     }
 
     /// Identify
-    pub unsafe fn identify(&mut self) -> Option<u64> {
+    unsafe fn identify(&mut self) -> Option<IdeIdentity> {
         let name = if self.master { "Master" } else { "Slave" };
 
         if self.alt_sts.read() == 0xFF {
             return None;
         }
 
-        self.ata(ATA_CMD_IDENTIFY, 0, 0);
+        if self.ata(ATA_CMD_IDENTIFY, 0, 0).is_err() {
+            return None;
+        }
 
         let status = self.alt_sts.read();
 
@@ -308,268 +573,293 @@ impl IdeDisk {
 
         let err = self.ide_poll(true);
         if err > 0 {
+            // Packet devices abort identify, leaving a signature in the LBA registers
+            if self.sector1.read() == 0x14 && self.sector2.read() == 0xEB {
+                return self.identify_packet();
+            }
+
             syslog_info!("     + {}: Error: {:X}", name, err);
 
             return None;
         }
 
-        let mut destination = Memory::<u16>::new(256).unwrap();
-        for word in 0..256 {
-            destination.write(word, self.data.read());
-        }
-
-        let mut serial = String::new();
-        for word in 10..20 {
-            let d = destination.read(word);
-            let a = ((d >> 8) as u8) as char;
-            if a != '\0' {
-                serial.push(a);
-            }
-            let b = (d as u8) as char;
-            if b != '\0' {
-                serial.push(b);
-            }
-        }
-
-        let mut firmware = String::new();
-        for word in 23..27 {
-            let d = destination.read(word);
-            let a = ((d >> 8) as u8) as char;
-            if a != '\0' {
-                firmware.push(a);
-            }
-            let b = (d as u8) as char;
-            if b != '\0' {
-                firmware.push(b);
-            }
+        let mut words = vec![0; 256];
+        for word in words.iter_mut() {
+            *word = self.data.read();
         }
 
-        let mut model = String::new();
-        for word in 27..47 {
-            let d = destination.read(word);
-            let a = ((d >> 8) as u8) as char;
-            if a != '\0' {
-                model.push(a);
-            }
-            let b = (d as u8) as char;
-            if b != '\0' {
-                model.push(b);
-            }
-        }
+        self.identity = DiskIdentity::from_ata(&words);
 
-        let mut sectors = (destination.read(100) as u64) |
-                          ((destination.read(101) as u64) << 16) |
-                          ((destination.read(102) as u64) << 32) |
-                          ((destination.read(103) as u64) << 48);
+        let mut sectors = (words[100] as u64) |
+                          ((words[101] as u64) << 16) |
+                          ((words[102] as u64) << 32) |
+                          ((words[103] as u64) << 48);
 
         let lba_bits = if sectors == 0 {
-            sectors = (destination.read(60) as u64) | ((destination.read(61) as u64) << 16);
+            sectors = (words[60] as u64) | ((words[61] as u64) << 16);
             28
         } else {
             48
         };
 
         syslog_info!("     + {}: Serial: {} Firmware: {} Model: {} {}-bit LBA Size: {} MB",
-                    name, serial.trim(), firmware.trim(), model.trim(), lba_bits, sectors / 2048);
+                    name, self.identity.serial, self.identity.firmware, self.identity.model, lba_bits, sectors / 2048);
 
-        Some(sectors * 512)
+        Some(IdeIdentity::Ata(sectors * 512))
     }
 
-    unsafe fn ata_pio_small(&mut self, block: u64, sectors: u16, mut buf: usize, write: bool) -> Result<usize> {
-        if buf >= 0x80000000 {
-            buf -= 0x80000000;
+    /// Identify a packet device
+    unsafe fn identify_packet(&mut self) -> Option<IdeIdentity> {
+        let name = if self.master { "Master" } else { "Slave" };
+
+        if self.ata(ATA_CMD_IDENTIFY_PACKET, 0, 0).is_err() {
+            return None;
         }
 
-        if buf > 0 && sectors > 0 {
-            self.ata(if write {
-                ATA_CMD_WRITE_PIO //_EXT
-            } else {
-                ATA_CMD_READ_PIO //_EXT
-            }, block, sectors);
-
-            for sector in 0..sectors as usize {
-                let err = self.ide_poll(true);
-                if err > 0 {
-                    debugln!("IDE Error: {:X}={:X}", err, self.error.read());
-                    return Err(Error::new(EIO));
-                }
+        let err = self.ide_poll(true);
+        if err > 0 {
+            syslog_info!("     + {}: Packet Error: {:X}", name, err);
 
-                if write {
-                    for word in 0..256 {
-                        self.data.write(ptr::read((buf + sector * 512 + word * 2) as *const u16));
-                    }
+            return None;
+        }
 
-                    self.cmd.write(ATA_CMD_CACHE_FLUSH_EXT);
-                    self.ide_poll(false);
-                } else {
-                    for word in 0..256 {
-                        ptr::write((buf + sector * 512 + word * 2) as *mut u16, self.data.read());
-                    }
-                }
-            }
+        let mut words = vec![0; 256];
+        for word in words.iter_mut() {
+            *word = self.data.read();
+        }
+
+        self.identity = DiskIdentity::from_ata(&words);
+
+        syslog_info!("     + {}: Model: {} ATAPI", name, self.identity.model);
+
+        Some(IdeIdentity::Atapi)
+    }
 
-            Ok(sectors as usize * 512)
+    /// Send a packet command, reading data with PIO
+    fn atapi_packet(&mut self, packet: &[u8; 12], buf: &mut [u8]) -> Result<usize> {
+        // Largest byte count per data request, which must be even
+        let limit = cmp::min(buf.len(), 0xFFFE) & !1;
+
+        try!(self.wait_not_busy());
+
+        self.devsel.write(if self.master {
+            0b10100000
         } else {
-            debugln!("IDE: ata_pio_small: Invalid request {:X} {}", buf, sectors);
-            Err(Error::new(EIO))
+            0b10110000
+        });
+
+        self.alt_sts.read();
+        self.alt_sts.read();
+        self.alt_sts.read();
+        self.alt_sts.read();
+
+        try!(self.wait_not_busy());
+
+        // PIO transfer, and the byte count limit in the LBA registers
+        self.features.write(0);
+        self.sector1.write(limit as u8);
+        self.sector2.write((limit >> 8) as u8);
+        self.cmd.write(ATA_CMD_PACKET);
+
+        let err = unsafe { self.ide_poll(true) };
+        if err > 0 {
+            debugln!("IDE ATAPI: packet {:X} rejected: {:X}={:X}", packet[0], err, self.error.read());
+            return Err(Error::new(EIO));
         }
-    }
 
-    fn ata_pio(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
-        // debugln!("IDE PIO BLOCK: {} SECTORS: {} BUF: {:X} WRITE: {}", block, sectors, buf, write);
-
-        if buf > 0 && sectors > 0 {
-            let mut sector: usize = 0;
-            while sectors - sector >= 255 {
-                if let Err(err) = unsafe {
-                    self.ata_pio_small(block + sector as u64, 255, buf + sector * 512, write)
-                } {
-                    return Err(err);
-                }
+        for i in 0..6 {
+            self.data.write(packet[i * 2] as u16 | (packet[i * 2 + 1] as u16) << 8);
+        }
 
-                sector += 255;
+        // Give the drive 400 nanoseconds to set busy
+        self.alt_sts.read();
+        self.alt_sts.read();
+        self.alt_sts.read();
+        self.alt_sts.read();
+
+        let mut count = 0;
+        loop {
+            let status = try!(self.wait_ready(false));
+            if status & ATA_SR_ERR == ATA_SR_ERR {
+                // The sense key is in the upper bits of the error register
+                debugln!("IDE ATAPI: packet {:X} failed: sense key {:X}", packet[0], self.error.read() >> 4);
+                return Err(Error::new(EIO));
             }
-            if sector < sectors {
-                if let Err(err) = unsafe {
-                    self.ata_pio_small(block + sector as u64,
-                                       (sectors - sector) as u16,
-                                       buf + sector * 512,
-                                       write)
-                } {
-                    return Err(err);
-                }
+            if status & ATA_SR_DRQ != ATA_SR_DRQ {
+                break;
             }
 
-            Ok(sectors * 512)
-        } else {
-            debugln!("IDE: ata_pio: Invalid request {:X} {}", buf, sectors);
-            Err(Error::new(EIO))
-        }
-    }
+            let bytes = self.sector1.read() as usize | (self.sector2.read() as usize) << 8;
+            for i in 0..(bytes + 1) / 2 {
+                let word = self.data.read();
+                let offset = count + i * 2;
+                if offset < buf.len() {
+                    buf[offset] = word as u8;
+                }
+                if offset + 1 < buf.len() {
+                    buf[offset + 1] = (word >> 8) as u8;
+                }
+            }
+            count += bytes;
 
-    unsafe fn ata_dma_small(&mut self, block: u64, sectors: u16, mut buf: usize, write: bool) -> Result<usize> {
-        if buf >= 0x80000000 {
-            buf -= 0x80000000;
+            self.alt_sts.read();
+            self.alt_sts.read();
+            self.alt_sts.read();
+            self.alt_sts.read();
         }
 
-        if buf > 0 && sectors > 0 {
-            self.buscmd.writef(CMD_ACT, false);
+        Ok(cmp::min(count, buf.len()))
+    }
 
-            self.prdt.reg.write(0);
+    /// Move up to `MAX_SECTORS` with PIO, sleeping until the drive is ready for each sector
+    fn ata_pio_small(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<()> {
+        let cmd = if write {
+            self.command(block, sectors, ATA_CMD_WRITE_PIO, ATA_CMD_WRITE_PIO_EXT)
+        } else {
+            self.command(block, sectors, ATA_CMD_READ_PIO, ATA_CMD_READ_PIO_EXT)
+        };
+        try!(self.ata(cmd, block, sectors as u16));
 
-            let status = self.bussts.read();
-            self.bussts.write(status);
+        for sector in 0..sectors {
+            let status = try!(self.wait_ready(true));
+            if status & (ATA_SR_ERR | ATA_SR_DF) != 0 || status & ATA_SR_DRQ != ATA_SR_DRQ {
+                debugln!("{}: PIO error: status {:X} error {:X}", Disk::name(self), status, self.error.read());
+                return Err(Error::new(EIO));
+            }
 
-            let entries = if sectors == 0 {
-                512
+            let address = buf + sector * 512;
+            if write {
+                for word in 0..256 {
+                    self.data.write(unsafe { ptr::read((address + word * 2) as *const u16) });
+                }
             } else {
-                sectors as usize / 128
-            };
-
-            let remainder = (sectors % 128) * 512;
-
-            let mut offset = 0;
-            for i in 0..entries {
-                self.prdt.mem.write(i,
-                                    Prd {
-                                        addr: buf as u32 + offset,
-                                        size: 0,
-                                        rsv: 0,
-                                        eot: if i == entries - 1 && remainder == 0 {
-                                            PRD_EOT
-                                        } else {
-                                            0
-                                        },
-                                    });
-                offset += 65536
+                for word in 0..256 {
+                    unsafe { ptr::write((address + word * 2) as *mut u16, self.data.read()) };
+                }
             }
+        }
 
-            if remainder > 0 {
-                self.prdt.mem.write(entries,
-                                    Prd {
-                                        addr: buf as u32 + offset,
-                                        size: remainder,
-                                        rsv: 0,
-                                        eot: PRD_EOT,
-                                    });
+        if write {
+            // The drive interrupts once the last sector is written
+            let status = try!(self.wait_ready(false));
+            if status & (ATA_SR_ERR | ATA_SR_DF) != 0 {
+                debugln!("{}: PIO write error: status {:X} error {:X}", Disk::name(self), status, self.error.read());
+                return Err(Error::new(EIO));
             }
+        }
 
-            self.prdt.reg.write(self.prdt.mem.address() as u32);
-
-            self.buscmd.writef(CMD_DIR, !write);
-
+        Ok(())
+    }
 
-            self.ata(if write {
-                ATA_CMD_WRITE_DMA //_EXT
-            } else {
-                ATA_CMD_READ_DMA //_EXT
-            }, block, sectors);
+    fn ata_pio(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        if buf == 0 || sectors == 0 {
+            debugln!("IDE: ata_pio: Invalid request {:X} {}", buf, sectors);
+            return Err(Error::new(EIO));
+        }
 
-            self.buscmd.writef(CMD_ACT, true);
+        self.exclusive(|disk| {
+            let mut sector = 0;
+            while sector < sectors {
+                let count = cmp::min(sectors - sector, MAX_SECTORS);
+                try!(disk.ata_pio_small(block + sector as u64, count, buf + sector * 512, write));
+                sector += count;
+            }
+            Ok(sectors * 512)
+        })
+    }
 
-            while self.bussts.readf(STS_ACT) && !self.bussts.readf(STS_INT) && !self.bussts.readf(STS_ERR) {}
+    /// Move up to `MAX_SECTORS` with DMA, sleeping until the channel interrupts
+    fn ata_dma_small(&mut self, block: u64, sectors: usize, address: usize, write: bool) -> Result<()> {
+        let channel = unsafe { &mut *self.channel.get() };
+        try!(channel.start_dma(address, sectors * 512, write));
 
-            self.buscmd.writef(CMD_ACT, false);
+        let cmd = if write {
+            self.command(block, sectors, ATA_CMD_WRITE_DMA, ATA_CMD_WRITE_DMA_EXT)
+        } else {
+            self.command(block, sectors, ATA_CMD_READ_DMA, ATA_CMD_READ_DMA_EXT)
+        };
+        if let Err(err) = self.ata(cmd, block, sectors as u16) {
+            channel.stop_dma();
+            return Err(err);
+        }
 
-            self.prdt.reg.write(0);
+        channel.buscmd.writef(CMD_ACT, true);
 
-            let status = self.bussts.read();
-            self.bussts.write(status);
+        let done = channel.wait("IdeDisk::ata_dma", |channel| channel.dma_status() & (STS_INT | STS_ERR) != 0);
+        let dma_status = channel.stop_dma();
 
-            if status & STS_ERR == STS_ERR {
-                debugln!("IDE DMA Read Error");
-                return Err(Error::new(EIO));
-            }
+        if ! done {
+            debugln!("{}: DMA timed out, resetting channel", Disk::name(self));
+            channel.reset();
+            return Err(Error::new(EIO));
+        }
 
-            Ok(sectors as usize * 512)
-        } else {
-            debugln!("IDE: ata_dma_small: Invalid request {:X} {}", buf, sectors);
-            Err(Error::new(EIO))
+        // Reading the status register acknowledges the interrupt
+        let status = self.sts.read();
+        if dma_status & STS_ERR == STS_ERR {
+            debugln!("{}: DMA bus error, resetting channel", Disk::name(self));
+            channel.reset();
+            return Err(Error::new(EIO));
+        }
+        if status & (ATA_SR_ERR | ATA_SR_DF) != 0 {
+            debugln!("{}: DMA error: status {:X} error {:X}", Disk::name(self), status, self.error.read());
+            return Err(Error::new(EIO));
         }
+
+        Ok(())
     }
 
     fn ata_dma(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
-        // debugln!("IDE DMA BLOCK: {} SECTORS: {} BUF: {:X} WRITE: {}", block, sectors, buf, write);
-
-        if sectors > 0 {
-            let contexts = unsafe { & *::env().contexts.get() };
-            let current = try!(contexts.current());
-            let physical_address = try!(current.translate(buf, sectors * 512));
+        if sectors == 0 {
+            debugln!("IDE: ata_dma: Invalid request {:X} {}", buf, sectors);
+            return Err(Error::new(EIO));
+        }
 
-            // debugln!("IDE DMA TRANSLATED {:X}", physical_address);
+        let address = try!(dma_address(buf, sectors * 512));
 
-            let mut sector: usize = 0;
-            while sectors - sector >= 255 {
-                if let Err(err) = unsafe {
-                    self.ata_dma_small(block + sector as u64, 255, physical_address + sector * 512, write)
-                } {
-                    return Err(err);
-                }
-
-                sector += 255;
-            }
-            if sector < sectors {
-                if let Err(err) = unsafe {
-                    self.ata_dma_small(block + sector as u64,
-                                       (sectors - sector) as u16,
-                                       physical_address + sector * 512,
-                                       write)
-                } {
-                    return Err(err);
-                }
+        self.exclusive(|disk| {
+            let mut sector = 0;
+            while sector < sectors {
+                let count = cmp::min(sectors - sector, MAX_SECTORS);
+                try!(disk.ata_dma_small(block + sector as u64, count, address + sector * 512, write));
+                sector += count;
             }
-
             Ok(sectors * 512)
+        })
+    }
+
+    /// Transfer with DMA if both the controller and drive support it
+    fn transfer(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        let dma = unsafe { & *self.channel.get() }.prdt.is_some() && self.identity.mwdma | self.identity.udma != 0;
+        if dma {
+            self.ata_dma(block, sectors, buf, write)
         } else {
-            debugln!("IDE: ata_dma: Invalid request {:X} {}", buf, sectors);
-            Err(Error::new(EIO))
+            self.ata_pio(block, sectors, buf, write)
         }
     }
 }
 
+impl AtapiDevice for IdeDisk {
+    fn name(&self) -> String {
+        Disk::name(self)
+    }
+
+    fn on_irq(&mut self, irq: u8) {
+        Disk::on_irq(self, irq);
+    }
+
+    fn identity(&self) -> DiskIdentity {
+        self.identity.clone()
+    }
+
+    fn packet(&mut self, packet: &[u8; 12], buf: &mut [u8]) -> Result<usize> {
+        self.exclusive(|disk| disk.atapi_packet(packet, buf))
+    }
+}
+
 impl Disk for IdeDisk {
     fn name(&self) -> String {
-        format!("IDE {} {}", if self.irq == 0xE {
+        format!("IDE {} {}", if unsafe { & *self.channel.get() }.irq == 0xE {
             "Primary"
         } else {
             "Secondary"
@@ -581,9 +871,11 @@ impl Disk for IdeDisk {
     }
 
     fn on_irq(&mut self, irq: u8) {
-        if irq == self.irq {
-            //debugln!("IDE IRQ");
-        }
+        unsafe { &mut *self.channel.get() }.on_irq(irq);
+    }
+
+    fn identity(&self) -> DiskIdentity {
+        self.identity.clone()
     }
 
     fn size(&self) -> u64 {
@@ -591,10 +883,27 @@ impl Disk for IdeDisk {
     }
 
     fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        self.ata_pio(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
+        self.transfer(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
     }
 
     fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
-        self.ata_pio(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+        self.transfer(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if ! self.identity.write_cache {
+            return Ok(());
+        }
+
+        self.exclusive(|disk| {
+            let cmd = disk.command(0, 0, ATA_CMD_CACHE_FLUSH, ATA_CMD_CACHE_FLUSH_EXT);
+            try!(disk.ata(cmd, 0, 0));
+            let status = try!(disk.wait_ready(false));
+            if status & (ATA_SR_ERR | ATA_SR_DF) != 0 {
+                debugln!("{}: flush error: status {:X} error {:X}", Disk::name(disk), status, disk.error.read());
+                return Err(Error::new(EIO));
+            }
+            Ok(())
+        })
     }
 }
@@ -0,0 +1,102 @@
+use collections::borrow::ToOwned;
+use collections::String;
+
+/// Identity and capabilities reported by a disk
+#[derive(Clone, Default)]
+pub struct DiskIdentity {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    /// Supports 48-bit LBA
+    pub lba48: bool,
+    /// Supported multiword DMA modes, bit N for mode N
+    pub mwdma: u8,
+    /// Supported Ultra DMA modes, bit N for mode N
+    pub udma: u8,
+    /// The DMA mode in use, such as `udma5`
+    pub dma_selected: Option<String>,
+    /// Commands that can be queued at once, zero without command queuing
+    pub queue_depth: u32,
+    /// Has a volatile write cache that is flushed
+    pub write_cache: bool,
+}
+
+/// Read a byte swapped, space padded string from ATA identify data
+fn ata_string(words: &[u16]) -> String {
+    let mut string = String::new();
+    for &word in words.iter() {
+        for &c in [(word >> 8) as u8, word as u8].iter() {
+            if c != 0 {
+                string.push(c as char);
+            }
+        }
+    }
+    string.trim().to_owned()
+}
+
+/// The highest set bit of a mode mask
+fn highest_mode(mask: u8) -> Option<u8> {
+    (0..8).rev().find(|&mode| mask & 1 << mode == 1 << mode)
+}
+
+impl DiskIdentity {
+    /// Parse the 256 words of IDENTIFY DEVICE or IDENTIFY PACKET DEVICE data
+    pub fn from_ata(words: &[u16]) -> DiskIdentity {
+        let mwdma = (words[63] & 0x7) as u8;
+        // Word 88 is valid if word 53 bit 2 is set
+        let udma = if words[53] & 1 << 2 == 1 << 2 {
+            (words[88] & 0x7F) as u8
+        } else {
+            0
+        };
+
+        let dma_selected = if let Some(mode) = highest_mode((words[88] >> 8) as u8 & udma) {
+            Some(format!("udma{}", mode))
+        } else if let Some(mode) = highest_mode((words[63] >> 8) as u8 & mwdma) {
+            Some(format!("mwdma{}", mode))
+        } else {
+            None
+        };
+
+        DiskIdentity {
+            model: ata_string(&words[27..47]),
+            serial: ata_string(&words[10..20]),
+            firmware: ata_string(&words[23..27]),
+            lba48: words[83] & 1 << 10 == 1 << 10,
+            mwdma: mwdma,
+            udma: udma,
+            dma_selected: dma_selected,
+            // Word 76 bit 8 is set with native command queuing, with the depth minus one in word 75
+            queue_depth: if words[76] & 1 << 8 == 1 << 8 {
+                (words[75] & 0x1F) as u32 + 1
+            } else {
+                0
+            },
+            // Word 82 bit 5 is set with a write cache
+            write_cache: words[82] & 1 << 5 == 1 << 5,
+        }
+    }
+
+    /// Describe the identity, one `key: value` per line
+    pub fn info(&self) -> String {
+        let mut dma = String::new();
+        for mode in 0..8 {
+            if self.mwdma & 1 << mode == 1 << mode {
+                dma.push_str(&format!(" mwdma{}", mode));
+            }
+        }
+        for mode in 0..8 {
+            if self.udma & 1 << mode == 1 << mode {
+                dma.push_str(&format!(" udma{}", mode));
+            }
+        }
+
+        format!("model: {}\nserial: {}\nfirmware: {}\nlba48: {}\ndma:{}\ndma_selected: {}\nqueue_depth: {}\nwrite_cache: {}\n",
+                self.model, self.serial, self.firmware, self.lba48, dma,
+                match self.dma_selected {
+                    Some(ref mode) => &mode[..],
+                    None => "none",
+                },
+                self.queue_depth, self.write_cache)
+    }
+}
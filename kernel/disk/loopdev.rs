@@ -0,0 +1,86 @@
+use alloc::boxed::Box;
+
+use collections::String;
+
+use fs::{Resource, ResourceSeek};
+
+use system::error::{Error, Result, EROFS};
+
+use super::Disk;
+
+/// A disk backed by a seekable resource, such as an image file
+pub struct LoopDisk {
+    /// The URL of the backing resource
+    pub path: String,
+    resource: Box<Resource>,
+    size: u64,
+    read_only: bool,
+}
+
+impl LoopDisk {
+    /// Wrap a resource, using its length as the disk size
+    pub fn new(path: String, mut resource: Box<Resource>, read_only: bool) -> Result<LoopDisk> {
+        let size = try!(resource.seek(ResourceSeek::End(0))) as u64;
+
+        Ok(LoopDisk {
+            path: path,
+            resource: resource,
+            size: size / 512 * 512,
+            read_only: read_only,
+        })
+    }
+}
+
+impl Disk for LoopDisk {
+    fn name(&self) -> String {
+        format!("Loop {}", self.path)
+    }
+
+    fn on_irq(&mut self, _irq: u8) {}
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        try!(self.resource.seek(ResourceSeek::Start((block * 512) as usize)));
+
+        let mut i = 0;
+        while i < buffer.len() {
+            match try!(self.resource.read(&mut buffer[i..])) {
+                0 => break,
+                count => i += count,
+            }
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+
+        try!(self.resource.seek(ResourceSeek::Start((block * 512) as usize)));
+
+        let mut i = 0;
+        while i < buffer.len() {
+            match try!(self.resource.write(&buffer[i..])) {
+                0 => break,
+                count => i += count,
+            }
+        }
+        Ok(i)
+    }
+}
+
+impl Drop for LoopDisk {
+    fn drop(&mut self) {
+        if ! self.read_only {
+            let _ = self.resource.sync();
+        }
+    }
+}
@@ -1,14 +1,60 @@
+use arch::memory;
+
 use collections::string::String;
 
 use system::error::Result;
 
+use self::identity::DiskIdentity;
+use self::stats::DiskStats;
+
 pub mod ahci;
+pub mod atapi;
+pub mod cache;
 pub mod ide;
+pub mod identity;
+pub mod loopdev;
+pub mod nvme;
+pub mod partition;
+pub mod ramdisk;
+pub mod stats;
+pub mod virtio;
+pub mod volume;
 
 pub trait Disk {
     fn name(&self) -> String;
     fn on_irq(&mut self, irq: u8);
     fn size(&self) -> u64;
+    /// Check for media inserted since the size was last read, for removable disks
+    fn probe(&mut self) {}
+    /// Writes to read-only disks fail with `EROFS`
+    fn read_only(&self) -> bool {
+        false
+    }
+    /// Identity and capabilities reported by the device
+    fn identity(&self) -> DiskIdentity {
+        DiskIdentity::default()
+    }
+    /// I/O counters, kept for disks registered with the environment
+    fn stats(&self) -> Option<DiskStats> {
+        None
+    }
     fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize>;
     fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize>;
+    /// Make completed writes durable, for disks with a volatile write cache
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Get the physical address of a buffer for DMA, or for mapping it into a scheme
+///
+/// Kernel heap buffers are offset mapped, and other buffers must be in the current context.
+pub fn dma_address(buf: usize, len: usize) -> Result<usize> {
+    if buf >= memory::LOGICAL_OFFSET {
+        Ok(buf - memory::LOGICAL_OFFSET)
+    } else {
+        let contexts = unsafe { & *::env().contexts.get() };
+        let current = try!(contexts.current());
+        current.translate(buf, len)
+    }
 }
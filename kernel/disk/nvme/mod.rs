@@ -0,0 +1,572 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use arch::memory;
+
+use collections::borrow::ToOwned;
+use collections::string::String;
+use collections::vec::Vec;
+
+use common::endian::{le32, le64};
+use common::time::{Duration, NANOS_PER_MILLI};
+
+use core::cell::UnsafeCell;
+use core::{cmp, slice, str};
+
+use disk::{dma_address, Disk};
+use disk::identity::DiskIdentity;
+
+use drivers::io::{Io, Mmio};
+use drivers::pci::config::PciConfig;
+use drivers::pci::msi;
+
+use sync::WaitCondition;
+
+use system::error::{Error, Result, EIO, ENOMEM, ENODEV, ETIMEDOUT};
+
+use self::queue::{Command, QueuePair};
+
+pub mod queue;
+
+// Admin commands
+const ADMIN_CREATE_SQ: u32 = 0x01;
+const ADMIN_CREATE_CQ: u32 = 0x05;
+const ADMIN_IDENTIFY: u32 = 0x06;
+const ADMIN_SET_FEATURES: u32 = 0x09;
+
+// I/O commands
+const IO_FLUSH: u32 = 0x00;
+const IO_WRITE: u32 = 0x01;
+const IO_READ: u32 = 0x02;
+
+// Identify structures
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+// Controller configuration
+const CC_EN: u32 = 1;
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+
+// Controller status
+const CSTS_RDY: u32 = 1;
+const CSTS_CFS: u32 = 1 << 1;
+
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 64;
+/// Most I/O queue pairs created, namespaces are spread over them
+const MAX_IO_QUEUES: usize = 4;
+
+const PAGE_SIZE: usize = 4096;
+/// Largest transfer in one command, so the PRP list fits in one page
+const MAX_TRANSFER: usize = 128 * PAGE_SIZE;
+
+/// Controller registers
+#[repr(packed)]
+struct NvmeRegs {
+    cap_low: Mmio<u32>,
+    cap_high: Mmio<u32>,
+    vs: Mmio<u32>,
+    intms: Mmio<u32>,
+    intmc: Mmio<u32>,
+    cc: Mmio<u32>,
+    _rsvd: Mmio<u32>,
+    csts: Mmio<u32>,
+    nssr: Mmio<u32>,
+    aqa: Mmio<u32>,
+    asq_low: Mmio<u32>,
+    asq_high: Mmio<u32>,
+    acq_low: Mmio<u32>,
+    acq_high: Mmio<u32>,
+}
+
+/// A page of identity mapped memory for identify data
+struct Page {
+    address: usize,
+}
+
+impl Page {
+    fn new() -> Result<Page> {
+        let address = unsafe { memory::alloc_aligned(PAGE_SIZE, PAGE_SIZE) };
+        if address == 0 {
+            Err(Error::new(ENOMEM))
+        } else {
+            Ok(Page {
+                address: address,
+            })
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.address as *const u8, PAGE_SIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        unsafe { memory::unalloc(self.address) };
+    }
+}
+
+/// Wait until the controller is ready, or until it is not, for at most `timeout`
+///
+/// Fails with `EIO` if the controller reports a fatal status while enabling, and with
+/// `ETIMEDOUT` if the time runs out.
+fn wait_ready(regs: &NvmeRegs, ready: bool, timeout: Duration) -> Result<()> {
+    let end = Duration::monotonic() + timeout;
+    loop {
+        let csts = regs.csts.read();
+        if ready && csts & CSTS_CFS == CSTS_CFS {
+            return Err(Error::new(EIO));
+        }
+        if (csts & CSTS_RDY == CSTS_RDY) == ready {
+            return Ok(());
+        }
+        if Duration::monotonic() > end {
+            return Err(Error::new(ETIMEDOUT));
+        }
+    }
+}
+
+/// Read a space padded ASCII string from identify data
+fn read_string(bytes: &[u8]) -> String {
+    str::from_utf8(bytes).unwrap_or("").trim().to_owned()
+}
+
+pub struct Nvme;
+
+impl Nvme {
+    pub fn disks(mut pci: PciConfig) -> Vec<Box<Disk>> {
+        let mut ret: Vec<Box<Disk>> = Vec::new();
+
+        unsafe { pci.flag(4, 4, true) }; // Bus mastering
+
+        let bar0 = unsafe { pci.read(0x10) };
+        let bar1 = unsafe { pci.read(0x14) };
+        if bar0 & 1 == 1 || (bar0 & 0b110 == 0b100 && bar1 != 0) {
+            syslog_warning!(" - NVMe registers are not reachable: {:X} {:X}", bar1, bar0);
+            return ret;
+        }
+        let base = (bar0 & 0xFFFFFFF0) as usize;
+
+        let (irq, interrupt) = match unsafe { msi::enable(&mut pci) } {
+            Some(irq) => (irq, "MSI"),
+            None => (unsafe { pci.read(0x3C) } as u8 & 0xF, "IRQ"),
+        };
+
+        syslog_info!(" + NVMe on: {:X} {}: {:X}", base, interrupt, irq);
+
+        let controller = match NvmeController::new(base, irq) {
+            Ok(controller) => Arc::new(UnsafeCell::new(controller)),
+            Err(err) => {
+                syslog_warning!("   - Failed to initialize: {}", err);
+                return ret;
+            }
+        };
+
+        let namespaces = match unsafe { &mut *controller.get() }.namespaces() {
+            Ok(namespaces) => namespaces,
+            Err(err) => {
+                syslog_warning!("   - Failed to list namespaces: {}", err);
+                return ret;
+            }
+        };
+
+        if let Err(err) = unsafe { &mut *controller.get() }.create_io_queues(namespaces.len()) {
+            syslog_warning!("   - Failed to create I/O queues: {}", err);
+            return ret;
+        }
+
+        let queues = unsafe { & *controller.get() }.io.len();
+        for (i, &(nsid, blocks, block_size)) in namespaces.iter().enumerate() {
+            if block_size != 512 {
+                syslog_warning!("   - Namespace {}: {} byte blocks are not supported", nsid, block_size);
+                continue;
+            }
+
+            syslog_info!("   + Namespace {}: {} blocks", nsid, blocks);
+            ret.push(box NvmeDisk {
+                controller: controller.clone(),
+                nsid: nsid,
+                queue: i % queues,
+                size: blocks * 512,
+            });
+        }
+
+        ret
+    }
+}
+
+/// An NVMe controller, shared by the disks of its namespaces
+pub struct NvmeController {
+    regs: &'static mut NvmeRegs,
+    /// Address of the first doorbell
+    doorbells: usize,
+    /// Distance between doorbells in bytes
+    stride: usize,
+    admin: QueuePair,
+    io: Vec<QueuePair>,
+    irq: u8,
+    /// Largest transfer in bytes
+    max_transfer: usize,
+    /// The controller has a volatile write cache
+    write_cache: bool,
+    model: String,
+    serial: String,
+    firmware: String,
+    condition: WaitCondition,
+}
+
+impl NvmeController {
+    fn new(base: usize, irq: u8) -> Result<NvmeController> {
+        let regs = unsafe { &mut *(base as *mut NvmeRegs) };
+
+        let cap_low = regs.cap_low.read();
+        let cap_high = regs.cap_high.read();
+        let max_entries = (cap_low & 0xFFFF) as u16;
+        let stride = 4 << (cap_high & 0xF);
+        // Only 4 KiB pages are used
+        if (cap_high >> 16) & 0xF != 0 {
+            return Err(Error::new(ENODEV));
+        }
+
+        // The longest the controller takes to become ready or not, in units of 500 ms
+        let ready_timeout = cmp::max(1, cap_low >> 24) as i32 * 500;
+        let ready_timeout = Duration::new((ready_timeout / 1000) as i64, (ready_timeout % 1000) * NANOS_PER_MILLI);
+
+        // Reset
+        regs.cc.write(0);
+        try!(wait_ready(regs, false, ready_timeout));
+
+        let admin = try!(QueuePair::new(0, cmp::min(ADMIN_QUEUE_SIZE, max_entries.saturating_add(1)), base + 0x1000, stride));
+        regs.aqa.write(((admin.size() as u32 - 1) << 16) | (admin.size() as u32 - 1));
+        regs.asq_low.write(admin.sq as u32);
+        regs.asq_high.write(0);
+        regs.acq_low.write(admin.cq as u32);
+        regs.acq_high.write(0);
+
+        // Interrupts are masked until a command waits for one
+        regs.intms.write(1);
+
+        regs.cc.write(CC_EN | CC_IOSQES | CC_IOCQES);
+        try!(wait_ready(regs, true, ready_timeout));
+
+        let mut controller = NvmeController {
+            regs: regs,
+            doorbells: base + 0x1000,
+            stride: stride,
+            admin: admin,
+            io: Vec::new(),
+            irq: irq,
+            max_transfer: MAX_TRANSFER,
+            write_cache: false,
+            model: String::new(),
+            serial: String::new(),
+            firmware: String::new(),
+            condition: WaitCondition::new(),
+        };
+
+        let page = try!(Page::new());
+        try!(controller.admin_command(Command {
+            cdw0: ADMIN_IDENTIFY,
+            prp1: page.address as u64,
+            cdw10: IDENTIFY_CONTROLLER,
+            ..Command::default()
+        }));
+
+        {
+            let data = page.data();
+            controller.model = read_string(&data[24..64]);
+            controller.serial = read_string(&data[4..24]);
+            controller.firmware = read_string(&data[64..72]);
+
+            // Maximum data transfer size, as a power of two of the minimum page size
+            let mdts = data[77];
+            if mdts > 0 && mdts < 20 {
+                controller.max_transfer = cmp::min(MAX_TRANSFER, PAGE_SIZE << mdts);
+            }
+
+            controller.write_cache = data[525] & 1 == 1;
+
+            syslog_info!("   + {} Serial: {} Firmware: {}", controller.model, controller.serial, controller.firmware);
+        }
+
+        Ok(controller)
+    }
+
+    /// List the active namespaces as ID, blocks and block size
+    fn namespaces(&mut self) -> Result<Vec<(u32, u64, u32)>> {
+        let page = try!(Page::new());
+
+        let mut ids = Vec::new();
+        let active = self.admin_command(Command {
+            cdw0: ADMIN_IDENTIFY,
+            prp1: page.address as u64,
+            cdw10: IDENTIFY_ACTIVE_NAMESPACES,
+            ..Command::default()
+        });
+        if active.is_ok() {
+            let data = page.data();
+            for i in 0..PAGE_SIZE / 4 {
+                let nsid = le32(data, i * 4);
+                if nsid == 0 {
+                    break;
+                }
+                ids.push(nsid);
+            }
+        } else {
+            // Controllers before 1.1 only report the number of namespaces
+            try!(self.admin_command(Command {
+                cdw0: ADMIN_IDENTIFY,
+                prp1: page.address as u64,
+                cdw10: IDENTIFY_CONTROLLER,
+                ..Command::default()
+            }));
+            let count = le32(page.data(), 516);
+            for nsid in 1..cmp::min(count, 1024) + 1 {
+                ids.push(nsid);
+            }
+        }
+
+        let mut namespaces = Vec::new();
+        for &nsid in ids.iter() {
+            try!(self.admin_command(Command {
+                cdw0: ADMIN_IDENTIFY,
+                nsid: nsid,
+                prp1: page.address as u64,
+                cdw10: IDENTIFY_NAMESPACE,
+                ..Command::default()
+            }));
+
+            let data = page.data();
+            let blocks = le64(data, 0);
+            if blocks == 0 {
+                continue;
+            }
+
+            let format = (data[26] & 0xF) as usize;
+            let lba_data_size = (le32(data, 128 + format * 4) >> 16) & 0xFF;
+            namespaces.push((nsid, blocks, 1 << lba_data_size));
+        }
+
+        Ok(namespaces)
+    }
+
+    /// Create up to one I/O queue pair per namespace, all completing on the one interrupt
+    fn create_io_queues(&mut self, namespaces: usize) -> Result<()> {
+        let wanted = cmp::max(1, cmp::min(namespaces, MAX_IO_QUEUES)) as u32;
+        let granted = try!(self.admin_command(Command {
+            cdw0: ADMIN_SET_FEATURES,
+            cdw10: FEATURE_NUMBER_OF_QUEUES,
+            cdw11: (wanted - 1) << 16 | (wanted - 1),
+            ..Command::default()
+        }));
+        let count = cmp::min(wanted, cmp::min(granted & 0xFFFF, granted >> 16) + 1) as u16;
+
+        let max_entries = (self.regs.cap_low.read() & 0xFFFF) as u16;
+        for id in 1..count + 1 {
+            let queue = try!(QueuePair::new(id, cmp::min(IO_QUEUE_SIZE, max_entries.saturating_add(1)), self.doorbells, self.stride));
+
+            // Physically contiguous, interrupts enabled on vector 0
+            try!(self.admin_command(Command {
+                cdw0: ADMIN_CREATE_CQ,
+                prp1: queue.cq as u64,
+                cdw10: (queue.size() as u32 - 1) << 16 | id as u32,
+                cdw11: 1 << 1 | 1,
+                ..Command::default()
+            }));
+
+            // Physically contiguous, completing to the queue with the same ID
+            try!(self.admin_command(Command {
+                cdw0: ADMIN_CREATE_SQ,
+                prp1: queue.sq as u64,
+                cdw10: (queue.size() as u32 - 1) << 16 | id as u32,
+                cdw11: (id as u32) << 16 | 1,
+                ..Command::default()
+            }));
+
+            self.io.push(queue);
+        }
+
+        Ok(())
+    }
+
+    fn admin_command(&mut self, command: Command) -> Result<u32> {
+        self.command(None, command)
+    }
+
+    /// Submit a command to the admin queue or an I/O queue, and wait for it to complete
+    fn command(&mut self, io: Option<usize>, command: Command) -> Result<u32> {
+        let enabled = unsafe { & *::env().contexts.get() }.enabled;
+
+        let mut submitted = None;
+        while submitted.is_none() {
+            submitted = match io {
+                Some(i) => self.io[i].submit(command),
+                None => self.admin.submit(command),
+            };
+            if submitted.is_none() {
+                // The queue is full
+                self.wait(io, enabled);
+            }
+        }
+        let cid = submitted.unwrap_or(0);
+
+        loop {
+            let completed = match io {
+                Some(i) => self.io[i].take(cid),
+                None => self.admin.take(cid),
+            };
+            if let Some((status, result)) = completed {
+                // Status code type and status code
+                return if status & 0x7FF == 0 {
+                    Ok(result)
+                } else {
+                    debugln!("NVMe: command {:X} failed with status {:X}", command.cdw0 & 0xFF, status);
+                    Err(Error::new(EIO))
+                };
+            }
+            self.wait(io, enabled);
+        }
+    }
+
+    /// Collect completions, waiting for the interrupt if there were none
+    fn wait(&mut self, io: Option<usize>, enabled: bool) {
+        let any = match io {
+            Some(i) => self.io[i].poll(),
+            None => self.admin.poll(),
+        };
+
+        if ! any && enabled {
+            self.regs.intmc.write(1);
+            // An interrupt can arrive between polling and waiting, so do not wait long
+            self.condition.wait_for("NvmeController::wait", Duration::new(0, 100 * NANOS_PER_MILLI));
+        }
+    }
+
+    fn on_irq(&mut self, irq: u8) {
+        if irq == self.irq {
+            // Mask until a command waits again, so a level triggered interrupt does not repeat
+            self.regs.intms.write(1);
+            self.condition.notify("NvmeController::on_irq");
+        }
+    }
+
+    /// Fill the PRP entries for a physically contiguous buffer
+    ///
+    /// A PRP list page is allocated when the buffer spans more than two pages, and returned so
+    /// it can be freed after the command completes.
+    fn prps(&self, command: &mut Command, address: usize, len: usize) -> Result<Option<Page>> {
+        command.prp1 = address as u64;
+
+        let first = PAGE_SIZE - address % PAGE_SIZE;
+        if len <= first {
+            Ok(None)
+        } else if len <= first + PAGE_SIZE {
+            command.prp2 = (address + first) as u64;
+            Ok(None)
+        } else {
+            let list = try!(Page::new());
+            let entries = (len - first + PAGE_SIZE - 1) / PAGE_SIZE;
+            for i in 0..entries {
+                unsafe { *(list.address as *mut u64).offset(i as isize) = (address + first + i * PAGE_SIZE) as u64 };
+            }
+            command.prp2 = list.address as u64;
+            Ok(Some(list))
+        }
+    }
+}
+
+/// A namespace of an NVMe controller
+pub struct NvmeDisk {
+    controller: Arc<UnsafeCell<NvmeController>>,
+    nsid: u32,
+    /// Index of the I/O queue pair used
+    queue: usize,
+    size: u64,
+}
+
+impl NvmeDisk {
+    fn transfer(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        if sectors == 0 {
+            return Ok(0);
+        }
+
+        let physical_address = try!(dma_address(buf, sectors * 512));
+
+        let controller = unsafe { &mut *self.controller.get() };
+        let max_sectors = controller.max_transfer / 512;
+
+        let mut sector = 0;
+        while sector < sectors {
+            let count = cmp::min(sectors - sector, max_sectors);
+            let lba = block + sector as u64;
+
+            let mut command = Command {
+                cdw0: if write { IO_WRITE } else { IO_READ },
+                nsid: self.nsid,
+                cdw10: lba as u32,
+                cdw11: (lba >> 32) as u32,
+                cdw12: count as u32 - 1,
+                ..Command::default()
+            };
+            let list = try!(controller.prps(&mut command, physical_address + sector * 512, count * 512));
+            let result = controller.command(Some(self.queue), command);
+            drop(list);
+            try!(result);
+
+            sector += count;
+        }
+
+        Ok(sectors * 512)
+    }
+}
+
+impl Disk for NvmeDisk {
+    fn name(&self) -> String {
+        format!("NVMe {} Namespace {}", unsafe { & *self.controller.get() }.model, self.nsid)
+    }
+
+    fn on_irq(&mut self, irq: u8) {
+        unsafe { &mut *self.controller.get() }.on_irq(irq);
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn identity(&self) -> DiskIdentity {
+        let controller = unsafe { & *self.controller.get() };
+        DiskIdentity {
+            model: controller.model.clone(),
+            serial: controller.serial.clone(),
+            firmware: controller.firmware.clone(),
+            queue_depth: controller.io.get(self.queue).map_or(0, |queue| queue.size() as u32 - 1),
+            write_cache: controller.write_cache,
+            ..DiskIdentity::default()
+        }
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        self.transfer(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        self.transfer(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let controller = unsafe { &mut *self.controller.get() };
+        if controller.write_cache {
+            try!(controller.command(Some(self.queue), Command {
+                cdw0: IO_FLUSH,
+                nsid: self.nsid,
+                ..Command::default()
+            }));
+        }
+        Ok(())
+    }
+}
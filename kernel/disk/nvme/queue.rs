@@ -0,0 +1,159 @@
+use arch::memory;
+
+use collections::BTreeMap;
+
+use core::intrinsics::{volatile_load, volatile_store};
+use core::mem::size_of;
+
+use system::error::{Error, Result, ENOMEM};
+
+/// A submission queue entry
+#[derive(Copy, Clone, Default)]
+#[repr(packed)]
+pub struct Command {
+    /// Opcode in bits 0-7, command identifier in bits 16-31
+    pub cdw0: u32,
+    pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub mptr: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+/// A completion queue entry
+#[repr(packed)]
+struct Completion {
+    result: u32,
+    reserved: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    /// Phase tag in bit 0, status in bits 1-15
+    status: u16,
+}
+
+/// A submission queue and the completion queue it posts to
+pub struct QueuePair {
+    pub id: u16,
+    size: u16,
+    /// Physical address of the submission queue
+    pub sq: usize,
+    /// Physical address of the completion queue
+    pub cq: usize,
+    sq_tail: u16,
+    /// Last submission queue head reported by the controller
+    sq_head: u16,
+    cq_head: u16,
+    /// Expected phase tag of new completions
+    phase: bool,
+    sq_doorbell: usize,
+    cq_doorbell: usize,
+    next_cid: u16,
+    /// Status and result of completed commands, by identifier
+    completed: BTreeMap<u16, (u16, u32)>,
+}
+
+impl QueuePair {
+    /// Allocate a queue pair with `size` entries, using doorbells `stride` bytes apart
+    pub fn new(id: u16, size: u16, doorbells: usize, stride: usize) -> Result<QueuePair> {
+        let sq = unsafe { memory::alloc_aligned(size as usize * size_of::<Command>(), 4096) };
+        if sq == 0 {
+            return Err(Error::new(ENOMEM));
+        }
+
+        let cq = unsafe { memory::alloc_aligned(size as usize * size_of::<Completion>(), 4096) };
+        if cq == 0 {
+            unsafe { memory::unalloc(sq) };
+            return Err(Error::new(ENOMEM));
+        }
+
+        Ok(QueuePair {
+            id: id,
+            size: size,
+            sq: sq,
+            cq: cq,
+            sq_tail: 0,
+            sq_head: 0,
+            cq_head: 0,
+            phase: true,
+            sq_doorbell: doorbells + (2 * id as usize) * stride,
+            cq_doorbell: doorbells + (2 * id as usize + 1) * stride,
+            next_cid: 0,
+            completed: BTreeMap::new(),
+        })
+    }
+
+    /// The number of entries
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Submit a command, returning its identifier, or None if the queue is full
+    pub fn submit(&mut self, mut command: Command) -> Option<u16> {
+        if (self.sq_tail + 1) % self.size == self.sq_head {
+            return None;
+        }
+
+        let cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        command.cdw0 = (command.cdw0 & 0xFFFF) | (cid as u32) << 16;
+
+        unsafe {
+            volatile_store((self.sq as *mut Command).offset(self.sq_tail as isize), command);
+            self.sq_tail = (self.sq_tail + 1) % self.size;
+            volatile_store(self.sq_doorbell as *mut u32, self.sq_tail as u32);
+        }
+
+        Some(cid)
+    }
+
+    /// Collect new completions, returning true if there were any
+    pub fn poll(&mut self) -> bool {
+        let mut any = false;
+        loop {
+            let entry = unsafe { &*(self.cq as *const Completion).offset(self.cq_head as isize) };
+            let status = unsafe { volatile_load(&entry.status) };
+            if (status & 1 == 1) != self.phase {
+                break;
+            }
+
+            let result = unsafe { volatile_load(&entry.result) };
+            self.sq_head = unsafe { volatile_load(&entry.sq_head) } % self.size;
+            self.completed.insert(unsafe { volatile_load(&entry.cid) }, (status >> 1, result));
+
+            self.cq_head += 1;
+            if self.cq_head == self.size {
+                self.cq_head = 0;
+                self.phase = ! self.phase;
+            }
+            any = true;
+        }
+
+        if any {
+            unsafe { volatile_store(self.cq_doorbell as *mut u32, self.cq_head as u32) };
+        }
+
+        any
+    }
+
+    /// Take the status and result of a completed command
+    pub fn take(&mut self, cid: u16) -> Option<(u16, u32)> {
+        self.completed.remove(&cid)
+    }
+}
+
+impl Drop for QueuePair {
+    fn drop(&mut self) {
+        unsafe {
+            memory::unalloc(self.sq);
+            memory::unalloc(self.cq);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use collections::{String, Vec};
+
+use core::cell::UnsafeCell;
+use core::char;
+
+use common::endian::{le16, le32, le64};
+
+use disk::Disk;
+
+/// Extended partitions are followed for at most this many logical partitions
+const MAX_LOGICAL: usize = 128;
+/// GPT tables with more entries than this are rejected
+const MAX_GPT_ENTRIES: usize = 1024;
+/// GPT entries larger than this are rejected
+const MAX_GPT_ENTRY_SIZE: usize = 4096;
+
+/// Where a partition entry came from, and its type
+#[derive(Copy, Clone)]
+pub enum PartitionType {
+    /// MBR partition type byte
+    Mbr(u8),
+    /// GPT partition type GUID
+    Gpt([u8; 16]),
+}
+
+/// A partition on a disk
+pub struct Partition {
+    /// The disk containing this partition
+    pub disk: Arc<UnsafeCell<Box<Disk>>>,
+    /// Partition number, 1-4 for MBR primary, 5+ for MBR logical, 1+ for GPT
+    pub number: usize,
+    /// First block
+    pub start: u64,
+    /// Length in blocks
+    pub blocks: u64,
+    /// Partition type
+    pub kind: PartitionType,
+    /// Unique GUID, for GPT partitions
+    pub guid: Option<[u8; 16]>,
+    /// Label, for GPT partitions
+    pub label: String,
+    /// Bootable flag, for MBR partitions
+    pub bootable: bool,
+}
+
+impl Partition {
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.blocks * 512
+    }
+
+    /// Describe this partition, one `key: value` per line
+    pub fn info(&self) -> String {
+        let mut string = format!("number: {}\nstart: {}\nblocks: {}\nsize: {}\n",
+                                 self.number, self.start, self.blocks, self.size());
+        match self.kind {
+            PartitionType::Mbr(kind) => {
+                string.push_str(&format!("scheme: mbr\ntype: {:02X}\nbootable: {}\n", kind, self.bootable));
+            },
+            PartitionType::Gpt(ref kind) => {
+                string.push_str(&format!("scheme: gpt\ntype: {}\n", guid_string(kind)));
+                if let Some(ref guid) = self.guid {
+                    string.push_str(&format!("guid: {}\n", guid_string(guid)));
+                }
+                string.push_str(&format!("label: {}\n", self.label));
+            }
+        }
+        string
+    }
+}
+
+/// Format a mixed-endian GUID as found on disk
+pub fn guid_string(guid: &[u8; 16]) -> String {
+    format!("{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            guid[3], guid[2], guid[1], guid[0],
+            guid[5], guid[4],
+            guid[7], guid[6],
+            guid[8], guid[9],
+            guid[10], guid[11], guid[12], guid[13], guid[14], guid[15])
+}
+
+/// CRC32 as used by GPT (IEEE 802.3, reflected)
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for &b in bytes.iter() {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xEDB88320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
+
+/// Parse the partition table of a disk
+pub fn scan(disk: &Arc<UnsafeCell<Box<Disk>>>) -> Vec<Partition> {
+    let mut mbr = vec![0; 512];
+    match unsafe { &mut *disk.get() }.read(0, &mut mbr) {
+        Ok(count) if count == 512 => (),
+        _ => return Vec::new(),
+    }
+
+    if mbr[510] != 0x55 || mbr[511] != 0xAA {
+        return Vec::new();
+    }
+
+    for i in 0..4 {
+        if mbr[446 + i * 16 + 4] == 0xEE {
+            if let Some(partitions) = scan_gpt(disk) {
+                return partitions;
+            }
+            break;
+        }
+    }
+
+    scan_mbr(disk, &mbr)
+}
+
+fn is_extended(kind: u8) -> bool {
+    kind == 0x05 || kind == 0x0F || kind == 0x85
+}
+
+fn scan_mbr(disk: &Arc<UnsafeCell<Box<Disk>>>, mbr: &[u8]) -> Vec<Partition> {
+    let disk_blocks = unsafe { & *disk.get() }.size() / 512;
+
+    let mut partitions = Vec::new();
+    for i in 0..4 {
+        let entry = &mbr[446 + i * 16 .. 446 + (i + 1) * 16];
+        let kind = entry[4];
+        let start = le32(entry, 8) as u64;
+        let blocks = le32(entry, 12) as u64;
+
+        if kind == 0 || blocks == 0 || start + blocks > disk_blocks {
+            continue;
+        }
+
+        if is_extended(kind) {
+            scan_ebr(disk, start, blocks, &mut partitions);
+        } else {
+            partitions.push(Partition {
+                disk: disk.clone(),
+                number: i + 1,
+                start: start,
+                blocks: blocks,
+                kind: PartitionType::Mbr(kind),
+                guid: None,
+                label: String::new(),
+                bootable: entry[0] & 0x80 == 0x80,
+            });
+        }
+    }
+    partitions
+}
+
+/// Follow the chain of extended boot records
+fn scan_ebr(disk: &Arc<UnsafeCell<Box<Disk>>>, extended_start: u64, extended_blocks: u64, partitions: &mut Vec<Partition>) {
+    let mut ebr_block = extended_start;
+    let mut number = 5;
+
+    for _ in 0..MAX_LOGICAL {
+        let mut ebr = vec![0; 512];
+        match unsafe { &mut *disk.get() }.read(ebr_block, &mut ebr) {
+            Ok(count) if count == 512 => (),
+            _ => break,
+        }
+
+        if ebr[510] != 0x55 || ebr[511] != 0xAA {
+            break;
+        }
+
+        {
+            let entry = &ebr[446 .. 462];
+            let kind = entry[4];
+            let start = ebr_block + le32(entry, 8) as u64;
+            let blocks = le32(entry, 12) as u64;
+
+            if kind != 0 && blocks > 0 && start + blocks <= extended_start + extended_blocks {
+                partitions.push(Partition {
+                    disk: disk.clone(),
+                    number: number,
+                    start: start,
+                    blocks: blocks,
+                    kind: PartitionType::Mbr(kind),
+                    guid: None,
+                    label: String::new(),
+                    bootable: entry[0] & 0x80 == 0x80,
+                });
+                number += 1;
+            }
+        }
+
+        let next = &ebr[462 .. 478];
+        let next_start = le32(next, 8) as u64;
+        if next[4] == 0 || next_start == 0 || next_start >= extended_blocks {
+            break;
+        }
+        ebr_block = extended_start + next_start;
+    }
+}
+
+/// Check the signature and CRC of a GPT header
+fn gpt_header_valid(header: &mut [u8]) -> bool {
+    if &header[0..8] != b"EFI PART" {
+        return false;
+    }
+
+    let header_size = le32(header, 12) as usize;
+    if header_size < 92 || header_size > header.len() {
+        return false;
+    }
+
+    let crc = le32(header, 16);
+    for i in 16..20 {
+        header[i] = 0;
+    }
+    crc32(&header[..header_size]) == crc
+}
+
+fn scan_gpt(disk: &Arc<UnsafeCell<Box<Disk>>>) -> Option<Vec<Partition>> {
+    let disk_blocks = unsafe { & *disk.get() }.size() / 512;
+
+    let mut header = vec![0; 512];
+    let primary = match unsafe { &mut *disk.get() }.read(1, &mut header) {
+        Ok(count) => count == 512 && gpt_header_valid(&mut header),
+        Err(_) => false,
+    };
+    if ! primary {
+        syslog_info!("   - Primary GPT header invalid, trying backup");
+        match unsafe { &mut *disk.get() }.read(disk_blocks - 1, &mut header) {
+            Ok(count) => if count != 512 || ! gpt_header_valid(&mut header) {
+                return None;
+            },
+            Err(_) => return None,
+        }
+    }
+
+    let entries_block = le64(&header, 72);
+    let entry_count = le32(&header, 80) as usize;
+    let entry_size = le32(&header, 84) as usize;
+    let entries_crc = le32(&header, 88);
+
+    // Entries are a multiple of 128 bytes, and the table must lie on the disk
+    if entry_size < 128 || entry_size % 128 != 0 || entry_size > MAX_GPT_ENTRY_SIZE
+       || entry_count > MAX_GPT_ENTRIES || entries_block >= disk_blocks {
+        return None;
+    }
+    let table_size = match entry_count.checked_mul(entry_size) {
+        Some(table_size) => table_size,
+        None => return None,
+    };
+    let table_blocks = (table_size as u64 + 511) / 512;
+    if table_blocks > disk_blocks - entries_block {
+        return None;
+    }
+
+    let mut table = vec![0; table_blocks as usize * 512];
+    match unsafe { &mut *disk.get() }.read(entries_block, &mut table) {
+        Ok(count) if count == table.len() => (),
+        _ => return None,
+    }
+
+    if crc32(&table[..table_size]) != entries_crc {
+        syslog_info!("   - GPT partition entries have an invalid CRC");
+        return None;
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..entry_count {
+        let entry = &table[i * entry_size .. (i + 1) * entry_size];
+
+        let mut kind = [0; 16];
+        let mut guid = [0; 16];
+        for j in 0..16 {
+            kind[j] = entry[j];
+            guid[j] = entry[16 + j];
+        }
+
+        if kind.iter().all(|&b| b == 0) {
+            continue;
+        }
+
+        let first = le64(entry, 32);
+        let last = le64(entry, 40);
+        if last < first || last >= disk_blocks {
+            continue;
+        }
+
+        let mut name = Vec::new();
+        for j in 0..36 {
+            let unit = le16(entry, 56 + j * 2);
+            if unit == 0 {
+                break;
+            }
+            name.push(unit);
+        }
+        let label: String = char::decode_utf16(name.iter().cloned()).map(|c| c.unwrap_or('\u{FFFD}')).collect();
+
+        partitions.push(Partition {
+            disk: disk.clone(),
+            number: i + 1,
+            start: first,
+            blocks: last - first + 1,
+            kind: PartitionType::Gpt(kind),
+            guid: Some(guid),
+            label: label,
+            bootable: false,
+        });
+    }
+
+    Some(partitions)
+}
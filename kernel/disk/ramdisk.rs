@@ -0,0 +1,108 @@
+use collections::{String, Vec};
+
+use core::{cmp, ptr};
+
+use arch::memory;
+
+use common::boot;
+
+use system::error::{Error, Result, ENOMEM};
+
+use super::Disk;
+
+/// Parse a size in bytes, with an optional `K`, `M` or `G` suffix
+pub fn parse_size(string: &str) -> Option<u64> {
+    let (number, multiplier) = match string.chars().last() {
+        Some('K') | Some('k') => (&string[..string.len() - 1], 1024),
+        Some('M') | Some('m') => (&string[..string.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&string[..string.len() - 1], 1024 * 1024 * 1024),
+        _ => (string, 1),
+    };
+
+    number.parse::<u64>().ok().and_then(|number| number.checked_mul(multiplier))
+}
+
+/// A disk in kernel memory
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// Create a zeroed RAM disk, rounding the size up to a whole block
+    ///
+    /// Fails with `ENOMEM` if the disk would take more than half of the free memory.
+    pub fn new(size: u64) -> Result<RamDisk> {
+        let size = match size.checked_add(511) {
+            Some(size) => size / 512 * 512,
+            None => return Err(Error::new(ENOMEM)),
+        };
+        if size > memory::memory_free() as u64 / 2 {
+            return Err(Error::new(ENOMEM));
+        }
+
+        Ok(RamDisk {
+            data: vec![0; size as usize],
+        })
+    }
+
+    /// Create the RAM disk requested by the `ramdisk=<size>` boot argument, and fill it with the
+    /// image loaded by the bootloader
+    ///
+    /// The disk is as large as the larger of the two, and is not created if neither is present.
+    pub fn boot() -> Option<RamDisk> {
+        let size = boot::arg("ramdisk").and_then(parse_size).unwrap_or(0);
+        let image = boot::ramdisk_image();
+
+        let image_len = image.map_or(0, |(_, len)| len as u64);
+        if size == 0 && image_len == 0 {
+            return None;
+        }
+
+        let mut disk = match RamDisk::new(cmp::max(size, image_len)) {
+            Ok(disk) => disk,
+            Err(err) => {
+                syslog_warning!(" + RAM disk of {} KB: {}", cmp::max(size, image_len) / 1024, err);
+                return None;
+            }
+        };
+        if let Some((base, len)) = image {
+            syslog_info!(" + RAM disk image at {:X}, {} KB", base, len / 1024);
+            unsafe { ptr::copy(base as *const u8, disk.data.as_mut_ptr(), len) };
+        }
+        Some(disk)
+    }
+}
+
+impl Disk for RamDisk {
+    fn name(&self) -> String {
+        format!("RAM Disk {} KB", self.data.len() / 1024)
+    }
+
+    fn on_irq(&mut self, _irq: u8) {}
+
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let offset = block * 512;
+        if offset >= self.size() {
+            return Ok(0);
+        }
+
+        let count = cmp::min(buffer.len() as u64, self.size() - offset) as usize;
+        unsafe { ptr::copy(self.data.as_ptr().offset(offset as isize), buffer.as_mut_ptr(), count) };
+        Ok(count)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let offset = block * 512;
+        if offset >= self.size() {
+            return Ok(0);
+        }
+
+        let count = cmp::min(buffer.len() as u64, self.size() - offset) as usize;
+        unsafe { ptr::copy(buffer.as_ptr(), self.data.as_mut_ptr().offset(offset as isize), count) };
+        Ok(count)
+    }
+}
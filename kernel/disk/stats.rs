@@ -0,0 +1,151 @@
+use alloc::boxed::Box;
+
+use collections::String;
+
+use common::time::{Duration, NANOS_PER_SEC};
+
+use disk::Disk;
+use disk::identity::DiskIdentity;
+
+use system::error::Result;
+
+/// Running I/O counters of a disk
+#[derive(Copy, Clone, Default)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    /// Requests that failed
+    pub errors: u64,
+    /// Total time taken by reads and writes, in nanoseconds
+    pub read_time: u64,
+    pub write_time: u64,
+    /// Requests in progress
+    pub in_flight: u64,
+    /// Most requests in progress at once
+    pub max_in_flight: u64,
+}
+
+impl DiskStats {
+    /// Describe the counters, one `key: value` per line
+    pub fn info(&self) -> String {
+        let average = |time: u64, count: u64| if count > 0 { time / count / 1000 } else { 0 };
+        format!("reads: {}\nwrites: {}\nflushes: {}\nread_sectors: {}\nwrite_sectors: {}\nread_bytes: {}\nwrite_bytes: {}\n\
+                 errors: {}\nread_latency_us: {}\nwrite_latency_us: {}\nin_flight: {}\nmax_in_flight: {}\n",
+                self.reads, self.writes, self.flushes, self.read_bytes / 512, self.write_bytes / 512,
+                self.read_bytes, self.write_bytes, self.errors,
+                average(self.read_time, self.reads), average(self.write_time, self.writes),
+                self.in_flight, self.max_in_flight)
+    }
+}
+
+/// Nanoseconds since a monotonic time
+fn elapsed(start: Duration) -> u64 {
+    let time = Duration::monotonic() - start;
+    if time.secs < 0 {
+        0
+    } else {
+        time.secs as u64 * NANOS_PER_SEC as u64 + time.nanos as u64
+    }
+}
+
+/// A disk that counts the requests made to it
+///
+/// Every disk registered with the environment is wrapped in one of these.
+pub struct CountedDisk {
+    disk: Box<Disk>,
+    stats: DiskStats,
+}
+
+impl CountedDisk {
+    pub fn new(disk: Box<Disk>) -> CountedDisk {
+        CountedDisk {
+            disk: disk,
+            stats: DiskStats::default(),
+        }
+    }
+
+    fn begin(&mut self) -> Duration {
+        self.stats.in_flight += 1;
+        if self.stats.in_flight > self.stats.max_in_flight {
+            self.stats.max_in_flight = self.stats.in_flight;
+        }
+        Duration::monotonic()
+    }
+
+    /// Finish a request, returning the time it took
+    fn end<T>(&mut self, start: Duration, result: &Result<T>) -> u64 {
+        self.stats.in_flight -= 1;
+        if result.is_err() {
+            self.stats.errors += 1;
+        }
+        elapsed(start)
+    }
+}
+
+impl Disk for CountedDisk {
+    fn name(&self) -> String {
+        self.disk.name()
+    }
+
+    fn on_irq(&mut self, irq: u8) {
+        self.disk.on_irq(irq);
+    }
+
+    fn size(&self) -> u64 {
+        self.disk.size()
+    }
+
+    fn probe(&mut self) {
+        self.disk.probe();
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+
+    fn identity(&self) -> DiskIdentity {
+        self.disk.identity()
+    }
+
+    fn stats(&self) -> Option<DiskStats> {
+        Some(self.stats)
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let start = self.begin();
+        let result = self.disk.read(block, buffer);
+        let time = self.end(start, &result);
+
+        self.stats.reads += 1;
+        self.stats.read_time += time;
+        if let Ok(count) = result {
+            self.stats.read_bytes += count as u64;
+        }
+        result
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let start = self.begin();
+        let result = self.disk.write(block, buffer);
+        let time = self.end(start, &result);
+
+        self.stats.writes += 1;
+        self.stats.write_time += time;
+        if let Ok(count) = result {
+            self.stats.write_bytes += count as u64;
+        }
+        result
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let start = self.begin();
+        let result = self.disk.flush();
+        self.end(start, &result);
+
+        self.stats.flushes += 1;
+        result
+    }
+}
@@ -0,0 +1,251 @@
+use alloc::boxed::Box;
+
+use arch::memory;
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use common::time::{Duration, NANOS_PER_MILLI};
+
+use core::intrinsics::{volatile_load, volatile_store};
+
+use disk::{dma_address, Disk};
+
+use drivers::pci::config::PciConfig;
+use drivers::virtio::{Transport, Virtqueue, ISR_QUEUE};
+
+use sync::WaitCondition;
+
+use system::error::{Error, Result, EIO, ENOMEM, EOPNOTSUPP};
+
+/// The disk is read-only
+const F_RO: u64 = 1 << 5;
+/// The disk supports flushing its write cache
+const F_FLUSH: u64 = 1 << 9;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+/// Largest request, in sectors
+const MAX_SECTORS: usize = 256;
+/// Largest queue used, as only one request is in flight at a time
+const QUEUE_SIZE: u16 = 16;
+
+/// Request header, followed by the data buffers and a status byte
+#[repr(packed)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+pub struct VirtioBlk;
+
+impl VirtioBlk {
+    pub fn disks(mut pci: PciConfig) -> Vec<Box<Disk>> {
+        let mut ret: Vec<Box<Disk>> = Vec::new();
+
+        unsafe { pci.flag(4, 4, true) }; // Bus mastering
+
+        let irq = unsafe { pci.read(0x3C) } as u8 & 0xF;
+
+        match unsafe { Transport::new(&mut pci) } {
+            Some(transport) => {
+                syslog_info!(" + Virtio Block {} IRQ: {:X}", if transport.is_modern() {
+                    "1.0"
+                } else {
+                    "Legacy"
+                }, irq);
+
+                match VirtioBlkDisk::new(transport, irq) {
+                    Ok(disk) => ret.push(box disk),
+                    Err(err) => syslog_warning!("   - Failed to initialize: {}", err),
+                }
+            },
+            None => syslog_warning!(" - Virtio Block without usable registers"),
+        }
+
+        ret
+    }
+}
+
+/// A virtio block device
+pub struct VirtioBlkDisk {
+    transport: Transport,
+    queue: Virtqueue,
+    irq: u8,
+    size: u64,
+    read_only: bool,
+    flush: bool,
+    /// Physical memory holding the request header and status byte
+    request: usize,
+    /// Set while a request is in flight, as the header is shared
+    busy: bool,
+    condition: WaitCondition,
+}
+
+impl VirtioBlkDisk {
+    fn new(mut transport: Transport, irq: u8) -> Result<Self> {
+        let features = try!(transport.init(F_RO | F_FLUSH));
+        let queue = try!(transport.setup_queue(0, QUEUE_SIZE));
+
+        let request = unsafe { memory::alloc_aligned(4096, 4096) };
+        if request == 0 {
+            return Err(Error::new(ENOMEM));
+        }
+
+        let size = transport.config_u64(0) * 512;
+        transport.driver_ok();
+
+        Ok(VirtioBlkDisk {
+            transport: transport,
+            queue: queue,
+            irq: irq,
+            size: size,
+            read_only: features & F_RO == F_RO,
+            flush: features & F_FLUSH == F_FLUSH,
+            request: request,
+            busy: false,
+            condition: WaitCondition::new(),
+        })
+    }
+
+    fn header(&self) -> *mut RequestHeader {
+        self.request as *mut RequestHeader
+    }
+
+    fn status(&self) -> *mut u8 {
+        (self.request + 16) as *mut u8
+    }
+
+    /// Submit a request and wait for the device to complete it
+    ///
+    /// The data buffer is given as a physical address and length, and is empty for flushes.
+    fn request(&mut self, kind: u32, sector: u64, data: usize, len: usize) -> Result<()> {
+        // Another context may be waiting for its own request to complete
+        while self.busy {
+            self.condition.wait_for("VirtioBlk::request busy", Duration::new(0, 100 * NANOS_PER_MILLI));
+        }
+        self.busy = true;
+
+        unsafe {
+            let header = &mut *self.header();
+            header.kind = kind;
+            header.reserved = 0;
+            header.sector = sector;
+            volatile_store(self.status(), 0xFF);
+        }
+
+        let pushed = if len > 0 {
+            self.queue.push(&[(self.request, 16, false), (data, len, kind == T_IN), (self.request + 16, 1, true)])
+        } else {
+            self.queue.push(&[(self.request, 16, false), (self.request + 16, 1, true)])
+        };
+        if pushed.is_none() {
+            self.busy = false;
+            return Err(Error::new(EIO));
+        }
+        self.transport.notify(&self.queue);
+
+        while self.queue.pop().is_none() {
+            if unsafe { & *::env().contexts.get() }.enabled {
+                // An interrupt can arrive between checking the queue and waiting, so do not wait long
+                self.condition.wait_for("VirtioBlk::request", Duration::new(0, 100 * NANOS_PER_MILLI));
+            }
+        }
+
+        let status = unsafe { volatile_load(self.status()) };
+        self.busy = false;
+        self.condition.notify("VirtioBlk::request done");
+
+        match status {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(Error::new(EOPNOTSUPP)),
+            status => {
+                debugln!("Virtio Block: request {} at {} failed with status {}", kind, sector, status);
+                Err(Error::new(EIO))
+            }
+        }
+    }
+
+    /// Transfer whole sectors, splitting into requests of at most `MAX_SECTORS`
+    fn transfer(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        if sectors == 0 {
+            return Ok(0);
+        }
+
+        let physical_address = try!(dma_address(buf, sectors * 512));
+
+        let mut sector = 0;
+        while sector < sectors {
+            let count = if sectors - sector > MAX_SECTORS {
+                MAX_SECTORS
+            } else {
+                sectors - sector
+            };
+
+            try!(self.request(if write { T_OUT } else { T_IN },
+                              block + sector as u64,
+                              physical_address + sector * 512,
+                              count * 512));
+
+            sector += count;
+        }
+
+        Ok(sectors * 512)
+    }
+}
+
+impl Disk for VirtioBlkDisk {
+    fn name(&self) -> String {
+        format!("Virtio Block{}", if self.read_only {
+            " (Read Only)"
+        } else {
+            ""
+        })
+    }
+
+    fn on_irq(&mut self, irq: u8) {
+        if irq == self.irq {
+            // Reading the ISR status acknowledges the interrupt
+            if self.transport.isr() & ISR_QUEUE == ISR_QUEUE {
+                self.condition.notify("VirtioBlk::on_irq");
+            }
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        self.transfer(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        self.transfer(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.flush {
+            self.request(T_FLUSH, 0, 0, 0)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for VirtioBlkDisk {
+    fn drop(&mut self) {
+        self.transport.set_status(0);
+        unsafe { memory::unalloc(self.request) };
+    }
+}
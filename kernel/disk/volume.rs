@@ -0,0 +1,147 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use collections::borrow::ToOwned;
+use collections::{String, Vec};
+
+use core::cell::UnsafeCell;
+use core::cmp;
+
+use disk::Disk;
+
+use system::error::{Error, Result, EIO, ENOENT, EROFS};
+
+/// A byte range of a disk holding a filesystem, either a whole disk or one partition
+///
+/// Volumes are named `N` for disk `N`, and `NpM` for partition `M` on it. All access goes through
+/// the block cache.
+#[derive(Clone)]
+pub struct Volume {
+    pub name: String,
+    pub disk: Arc<UnsafeCell<Box<Disk>>>,
+    /// Offset of the first byte on the disk
+    pub start: u64,
+    /// Size in bytes
+    pub size: u64,
+}
+
+impl Volume {
+    /// Find a volume by name
+    pub fn find(name: &str) -> Result<Volume> {
+        let mut parts = name.splitn(2, 'p');
+        let number = try!(parts.next().and_then(|part| part.parse::<usize>().ok()).ok_or(Error::new(ENOENT)));
+        let disk = try!(unsafe { & *::env().disks.get() }.get(&number).ok_or(Error::new(ENOENT)));
+
+        match parts.next() {
+            Some(part) => {
+                let partition_number = try!(part.parse::<usize>().map_err(|_| Error::new(ENOENT)));
+                let partition = try!(unsafe { & *::env().partitions.get() }.iter()
+                                         .find(|partition| partition.number == partition_number && partition.disk.get() == disk.get())
+                                         .ok_or(Error::new(ENOENT)));
+                Ok(Volume {
+                    name: name.to_owned(),
+                    disk: disk.clone(),
+                    start: partition.start * 512,
+                    size: partition.size(),
+                })
+            },
+            None => {
+                // Removable media may have been inserted since the disk was last used
+                unsafe { &mut *disk.get() }.probe();
+                Ok(Volume {
+                    name: name.to_owned(),
+                    disk: disk.clone(),
+                    start: 0,
+                    size: unsafe { & *disk.get() }.size(),
+                })
+            }
+        }
+    }
+
+    /// All volumes, every disk followed by its partitions
+    pub fn all() -> Vec<Volume> {
+        let mut volumes = Vec::new();
+        let partitions = unsafe { & *::env().partitions.get() };
+        for (number, disk) in unsafe { & *::env().disks.get() }.iter() {
+            volumes.push(Volume {
+                name: format!("{}", number),
+                disk: disk.clone(),
+                start: 0,
+                size: unsafe { & *disk.get() }.size(),
+            });
+
+            for partition in partitions.iter().filter(|partition| partition.disk.get() == disk.get()) {
+                volumes.push(Volume {
+                    name: format!("{}p{}", number, partition.number),
+                    disk: disk.clone(),
+                    start: partition.start * 512,
+                    size: partition.size(),
+                });
+            }
+        }
+        volumes
+    }
+
+    pub fn read_only(&self) -> bool {
+        unsafe { & *self.disk.get() }.read_only()
+    }
+
+    /// Read at a byte offset in the volume, failing with `EIO` if the range is not all there
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        if offset + buf.len() as u64 > self.size {
+            return Err(Error::new(EIO));
+        }
+
+        let count = try!(unsafe { &mut *::env().block_cache.get() }.read(&self.disk, self.start + offset, buf));
+        if count == buf.len() {
+            Ok(())
+        } else {
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// Read `len` bytes at a byte offset in the volume
+    ///
+    /// The range is checked before allocating, as lengths often come from the disk.
+    pub fn read_vec(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if offset.checked_add(len as u64).map_or(true, |end| end > self.size) {
+            return Err(Error::new(EIO));
+        }
+
+        let mut data = vec![0; len];
+        try!(self.read(offset, &mut data));
+        Ok(data)
+    }
+
+    /// Write at a byte offset in the volume
+    pub fn write(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        if self.read_only() {
+            return Err(Error::new(EROFS));
+        }
+        if offset + buf.len() as u64 > self.size {
+            return Err(Error::new(EIO));
+        }
+
+        let count = try!(unsafe { &mut *::env().block_cache.get() }.write(&self.disk, self.start + offset, buf));
+        if count == buf.len() {
+            Ok(())
+        } else {
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// Write back cached blocks of the disk
+    pub fn sync(&self) -> Result<()> {
+        unsafe { &mut *::env().block_cache.get() }.sync(&self.disk)
+    }
+
+    /// Read at most `buf.len()` bytes at a byte offset, stopping at `end`
+    pub fn read_to(&self, offset: u64, end: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= end {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, end - offset) as usize;
+        try!(self.read(offset, &mut buf[..len]));
+        Ok(len)
+    }
+}
@@ -0,0 +1,104 @@
+use core::intrinsics::{volatile_load, volatile_store};
+
+/// First interrupt vector handed out for message signalled interrupts
+pub const MSI_VECTOR_START: u8 = 0x30;
+/// End of the interrupt vectors handed out for message signalled interrupts
+pub const MSI_VECTOR_END: u8 = 0x3F;
+/// Vector of spurious interrupts, which are not acknowledged
+///
+/// Vector 0xFF is used by the kernel to start processors, so it cannot be used here.
+pub const SPURIOUS_VECTOR: u8 = 0x3F;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SPURIOUS: usize = 0xF0;
+
+/// Address of the local APIC registers, zero if it is not enabled
+static mut ADDRESS: usize = 0;
+/// Next free message signalled interrupt vector
+static mut NEXT_VECTOR: u8 = MSI_VECTOR_START;
+
+unsafe fn rdmsr(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) : : "intel", "volatile");
+    (high as u64) << 32 | low as u64
+}
+
+unsafe fn cpuid_edx(leaf: u32) -> u32 {
+    let edx: u32;
+    asm!("cpuid"
+        : "={edx}"(edx)
+        : "{eax}"(leaf), "{ecx}"(0)
+        : "eax", "ebx", "ecx"
+        : "intel", "volatile");
+    edx
+}
+
+unsafe fn read(reg: usize) -> u32 {
+    volatile_load((ADDRESS + reg) as *const u32)
+}
+
+unsafe fn write(reg: usize, value: u32) {
+    volatile_store((ADDRESS + reg) as *mut u32, value);
+}
+
+/// Software enable the local APIC, so that it accepts message signalled interrupts
+///
+/// Legacy interrupts still arrive through the PIC. Returns false if there is no local APIC.
+pub unsafe fn init() -> bool {
+    if ADDRESS != 0 {
+        return true;
+    }
+
+    // CPUID.1:EDX bit 9
+    if cpuid_edx(1) & 1 << 9 != 1 << 9 {
+        return false;
+    }
+
+    let base = rdmsr(IA32_APIC_BASE);
+    // The global enable bit, and an address the kernel can reach
+    if base & 1 << 11 != 1 << 11 || base >> 32 != 0 {
+        return false;
+    }
+
+    ADDRESS = (base & 0xFFFFF000) as usize;
+
+    // Spurious vector with the software enable bit
+    let spurious = read(REG_SPURIOUS) & !0xFF;
+    write(REG_SPURIOUS, spurious | 1 << 8 | SPURIOUS_VECTOR as u32);
+
+    true
+}
+
+/// The local APIC is enabled
+pub fn enabled() -> bool {
+    unsafe { ADDRESS != 0 }
+}
+
+/// The APIC ID of this processor
+pub fn id() -> u8 {
+    unsafe { (read(REG_ID) >> 24) as u8 }
+}
+
+/// Allocate an interrupt vector for a message signalled interrupt
+pub fn alloc_vector() -> Option<u8> {
+    unsafe {
+        if NEXT_VECTOR < MSI_VECTOR_END {
+            let vector = NEXT_VECTOR;
+            NEXT_VECTOR += 1;
+            Some(vector)
+        } else {
+            None
+        }
+    }
+}
+
+/// Signal the end of an interrupt delivered by the local APIC
+pub fn eoi() {
+    if enabled() {
+        unsafe { write(REG_EOI, 0) };
+    }
+}
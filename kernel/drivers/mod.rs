@@ -1,5 +1,7 @@
 /// IO primitives
 pub mod io;
+/// Local APIC
+pub mod lapic;
 /// PCI
 pub mod pci;
 /// PS2
@@ -8,5 +10,7 @@ pub mod ps2;
 pub mod rtc;
 /// Serial
 pub mod serial;
+/// Virtio
+pub mod virtio;
 /// Layouts
 pub mod kb_layouts;
@@ -91,6 +91,9 @@ pub mod programming_interface {
     /// PCI SATA Programming Interface
     pub const AHCI: u8 = 0x01;
 
+    /// PCI NVM Programming Interface
+    pub const NVME: u8 = 0x02;
+
     /// PCI USB Programming Interface
     pub const UHCI: u8 = 0x00;
     pub const OHCI: u8 = 0x10;
@@ -114,4 +117,10 @@ pub mod deviceid {
     pub const AC97_82801AA: u16 = 0x2415;   // 82801AA AC'97 Audio Controller
     pub const AC97_ICH4: u16 = 0x24C5;      // 82801DB/DBL/DBM (ICH4/ICH4-L/ICH4-M) AC'97 Audio
     pub const INTELHDA_ICH6: u16 = 0x2668;  // 82801FB/FBM/FR/FW/FRW High Definition Audio
+
+    // Red Hat
+    pub const VIRTIO_NET_LEGACY: u16 = 0x1000;  // Virtio network device (transitional)
+    pub const VIRTIO_BLK_LEGACY: u16 = 0x1001;  // Virtio block device (transitional)
+    pub const VIRTIO_NET: u16 = 0x1041;         // Virtio 1.0 network device
+    pub const VIRTIO_BLK: u16 = 0x1042;         // Virtio 1.0 block device
 }
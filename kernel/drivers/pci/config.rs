@@ -1,3 +1,5 @@
+use collections::Vec;
+
 use drivers::io::{Io, Pio};
 
 /// A PCI configuration
@@ -51,5 +53,23 @@ impl PciConfig {
         self.write(offset, value);
     }
 
+    /// List the capabilities as pairs of offset and ID
+    pub unsafe fn capabilities(&mut self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+
+        // The status register indicates a capabilities list
+        if self.read(0x04) & 1 << 20 == 1 << 20 {
+            let mut ptr = (self.read(0x34) & 0xFC) as u8;
+            // Limit the walk in case the list has a loop
+            while ptr != 0 && capabilities.len() < 48 {
+                let header = self.read(ptr);
+                capabilities.push((ptr, header as u8));
+                ptr = ((header >> 8) & 0xFC) as u8;
+            }
+        }
+
+        capabilities
+    }
+
     // TODO: Write functions to get data structures
 }
@@ -1,8 +1,7 @@
-use alloc::arc::Arc;
-use core::cell::UnsafeCell;
-
 use disk::ahci::Ahci;
 use disk::ide::Ide;
+use disk::nvme::Nvme;
+use disk::virtio::VirtioBlk;
 
 use env::Environment;
 
@@ -19,6 +18,7 @@ use audio::intelhda::IntelHda;
 
 use network::rtl8139::Rtl8139;
 use network::intel8254x::Intel8254x;
+use network::virtio::VirtioNet;
 
 use usb::uhci::Uhci;
 use usb::ohci::Ohci;
@@ -35,10 +35,13 @@ pub unsafe fn pci_device(env: &mut Environment,
                          device_code: u16) {
     match (class_id, subclass_id, interface_id) {
         (MASS_STORAGE, IDE, _) => for disk in Ide::disks(pci) {
-            (&mut *env.disks.get()).push(Arc::new(UnsafeCell::new(disk)));
+            env.add_disk(disk);
         },
         (MASS_STORAGE, SATA, AHCI) => for disk in Ahci::disks(pci) {
-            (&mut *env.disks.get()).push(Arc::new(UnsafeCell::new(disk)));
+            env.add_disk(disk);
+        },
+        (MASS_STORAGE, NVM, NVME) => for disk in Nvme::disks(pci) {
+            env.add_disk(disk);
         },
         (SERIAL_BUS, USB, UHCI) => (&mut *env.schemes.get()).push(Uhci::new(pci)),
         (SERIAL_BUS, USB, OHCI) => (&mut *env.schemes.get()).push(Ohci::new(pci)),
@@ -50,6 +53,12 @@ pub unsafe fn pci_device(env: &mut Environment,
             (INTEL, AC97_82801AA) => (&mut *env.schemes.get()).push(Ac97::new(pci)),
             (INTEL, AC97_ICH4) => (&mut *env.schemes.get()).push(Ac97::new(pci)),
             (INTEL, INTELHDA_ICH6) => (&mut *env.schemes.get()).push(IntelHda::new(pci)),
+            (REDHAT, VIRTIO_BLK_LEGACY) | (REDHAT, VIRTIO_BLK) => for disk in VirtioBlk::disks(pci) {
+                env.add_disk(disk);
+            },
+            (REDHAT, VIRTIO_NET_LEGACY) | (REDHAT, VIRTIO_NET) => if let Some(nic) = VirtioNet::new(pci) {
+                (&mut *env.schemes.get()).push(nic);
+            },
             _ => syslog_info!(" ? CLASS {:02X}.{:02X}.{:02X} ID {:04X}:{:04X}", class_id, subclass_id, interface_id, vendor_code, device_code),
         }
     }
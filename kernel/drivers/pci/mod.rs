@@ -1,5 +1,6 @@
 pub mod config;
 pub mod common;
+pub mod msi;
 mod init;
 
 pub use drivers::pci::init::pci_init;
@@ -0,0 +1,51 @@
+use drivers::lapic;
+
+use super::config::PciConfig;
+
+/// MSI capability ID
+const CAP_MSI: u8 = 0x05;
+
+/// Message control bits
+const MSI_ENABLE: u32 = 1 << 16;
+const MSI_64BIT: u32 = 1 << 23;
+const MSI_MULTIPLE_ENABLE: u32 = 0b111 << 20;
+
+/// Command register bit that disables the legacy interrupt pin
+const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+
+/// Enable MSI on a device, returning the IRQ its interrupts are passed to `on_irq` as
+///
+/// A single vector is allocated and delivered to this processor. Returns None, leaving the
+/// legacy interrupt pin in use, if the device or processor does not support MSI.
+pub unsafe fn enable(pci: &mut PciConfig) -> Option<u8> {
+    let ptr = match pci.capabilities().iter().find(|&&(_, id)| id == CAP_MSI) {
+        Some(&(ptr, _)) => ptr,
+        None => return None,
+    };
+
+    if ! lapic::init() {
+        return None;
+    }
+
+    let vector = match lapic::alloc_vector() {
+        Some(vector) => vector,
+        None => return None,
+    };
+
+    let control = pci.read(ptr);
+
+    // Fixed delivery, edge triggered, physical destination
+    pci.write(ptr + 4, 0xFEE00000 | (lapic::id() as u32) << 12);
+    if control & MSI_64BIT == MSI_64BIT {
+        pci.write(ptr + 8, 0);
+        pci.write(ptr + 12, vector as u32);
+    } else {
+        pci.write(ptr + 8, vector as u32);
+    }
+
+    pci.write(ptr, (control & !MSI_MULTIPLE_ENABLE) | MSI_ENABLE);
+    pci.flag(4, COMMAND_INTX_DISABLE, true);
+
+    // IRQs are numbered from the first PIC vector
+    Some(vector - 0x20)
+}
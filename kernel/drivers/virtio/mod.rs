@@ -0,0 +1,327 @@
+use drivers::io::{Io, Mmio, Pio};
+use drivers::pci::config::PciConfig;
+
+use system::error::{Error, Result, EIO, ENODEV};
+
+pub use self::queue::Virtqueue;
+
+pub mod queue;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 0x80;
+
+/// The device implements the 1.0 interface
+pub const F_VERSION_1: u64 = 1 << 32;
+
+/// A queue interrupt is pending
+pub const ISR_QUEUE: u8 = 1;
+/// The device configuration changed
+pub const ISR_CONFIG: u8 = 2;
+
+const PCI_CAP_VENDOR: u8 = 0x09;
+const PCI_CAP_COMMON_CFG: u32 = 1;
+const PCI_CAP_NOTIFY_CFG: u32 = 2;
+const PCI_CAP_ISR_CFG: u32 = 3;
+const PCI_CAP_DEVICE_CFG: u32 = 4;
+
+/// Legacy registers in I/O space
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_GUEST_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+/// Modern common configuration
+#[repr(packed)]
+struct CommonCfg {
+    device_feature_select: Mmio<u32>,
+    device_feature: Mmio<u32>,
+    driver_feature_select: Mmio<u32>,
+    driver_feature: Mmio<u32>,
+    msix_config: Mmio<u16>,
+    num_queues: Mmio<u16>,
+    device_status: Mmio<u8>,
+    config_generation: Mmio<u8>,
+    queue_select: Mmio<u16>,
+    queue_size: Mmio<u16>,
+    queue_msix_vector: Mmio<u16>,
+    queue_enable: Mmio<u16>,
+    queue_notify_off: Mmio<u16>,
+    queue_desc_low: Mmio<u32>,
+    queue_desc_high: Mmio<u32>,
+    queue_driver_low: Mmio<u32>,
+    queue_driver_high: Mmio<u32>,
+    queue_device_low: Mmio<u32>,
+    queue_device_high: Mmio<u32>,
+}
+
+/// How the registers of a virtio device are reached
+pub enum Transport {
+    /// The legacy interface, in I/O space at BAR0
+    Legacy {
+        base: u16,
+    },
+    /// The 1.0 interface, in memory described by PCI capabilities
+    Modern {
+        common: usize,
+        notify: usize,
+        notify_multiplier: u32,
+        isr: usize,
+        device: usize,
+    },
+}
+
+/// Get the address of a memory BAR
+unsafe fn memory_bar(pci: &mut PciConfig, bar: u32) -> Option<usize> {
+    if bar > 5 {
+        return None;
+    }
+
+    let value = pci.read(0x10 + bar as u8 * 4);
+    if value & 1 == 1 {
+        return None;
+    }
+
+    // 64-bit BARs are only usable if they are mapped below 4 GiB
+    if value & 0b110 == 0b100 && bar < 5 && pci.read(0x10 + bar as u8 * 4 + 4) != 0 {
+        return None;
+    }
+
+    Some((value & 0xFFFFFFF0) as usize)
+}
+
+impl Transport {
+    /// Find the registers of a device, preferring the 1.0 interface
+    pub unsafe fn new(pci: &mut PciConfig) -> Option<Transport> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+
+        for (ptr, id) in pci.capabilities() {
+            if id == PCI_CAP_VENDOR {
+                let cfg_type = (pci.read(ptr) >> 24) & 0xFF;
+                let bar = pci.read(ptr + 4) & 0xFF;
+                let offset = pci.read(ptr + 8) as usize;
+                if let Some(address) = memory_bar(pci, bar) {
+                    match cfg_type {
+                        PCI_CAP_COMMON_CFG => common = Some(address + offset),
+                        PCI_CAP_NOTIFY_CFG => notify = Some((address + offset, pci.read(ptr + 16))),
+                        PCI_CAP_ISR_CFG => isr = Some(address + offset),
+                        PCI_CAP_DEVICE_CFG => device = Some(address + offset),
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        if let (Some(common), Some((notify, notify_multiplier)), Some(isr), Some(device)) = (common, notify, isr, device) {
+            Some(Transport::Modern {
+                common: common,
+                notify: notify,
+                notify_multiplier: notify_multiplier,
+                isr: isr,
+                device: device,
+            })
+        } else {
+            let bar0 = pci.read(0x10);
+            if bar0 & 1 == 1 {
+                Some(Transport::Legacy {
+                    base: (bar0 & 0xFFFC) as u16,
+                })
+            } else {
+                None
+            }
+        }
+    }
+
+    pub fn is_modern(&self) -> bool {
+        match *self {
+            Transport::Legacy { .. } => false,
+            Transport::Modern { .. } => true,
+        }
+    }
+
+    fn common(&self) -> &'static mut CommonCfg {
+        match *self {
+            Transport::Modern { common, .. } => unsafe { &mut *(common as *mut CommonCfg) },
+            Transport::Legacy { .. } => unreachable!(),
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { base } => Pio::<u8>::new(base + LEGACY_DEVICE_STATUS).read(),
+            Transport::Modern { .. } => self.common().device_status.read(),
+        }
+    }
+
+    pub fn set_status(&mut self, status: u8) {
+        match *self {
+            Transport::Legacy { base } => Pio::<u8>::new(base + LEGACY_DEVICE_STATUS).write(status),
+            Transport::Modern { .. } => self.common().device_status.write(status),
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy { base } => Pio::<u32>::new(base + LEGACY_DEVICE_FEATURES).read() as u64,
+            Transport::Modern { .. } => {
+                let common = self.common();
+                common.device_feature_select.write(0);
+                let low = common.device_feature.read() as u64;
+                common.device_feature_select.write(1);
+                let high = common.device_feature.read() as u64;
+                high << 32 | low
+            }
+        }
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        match *self {
+            Transport::Legacy { base } => Pio::<u32>::new(base + LEGACY_GUEST_FEATURES).write(features as u32),
+            Transport::Modern { .. } => {
+                let common = self.common();
+                common.driver_feature_select.write(0);
+                common.driver_feature.write(features as u32);
+                common.driver_feature_select.write(1);
+                common.driver_feature.write((features >> 32) as u32);
+            }
+        }
+    }
+
+    /// Reset the device and negotiate features, returning those accepted
+    ///
+    /// `F_VERSION_1` is always requested on the 1.0 interface.
+    pub fn init(&mut self, wanted: u64) -> Result<u64> {
+        self.set_status(0);
+        while self.status() != 0 {}
+
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let features = if self.is_modern() {
+            self.device_features() & (wanted | F_VERSION_1)
+        } else {
+            self.device_features() & wanted & 0xFFFFFFFF
+        };
+        self.set_driver_features(features);
+
+        if self.is_modern() {
+            if features & F_VERSION_1 != F_VERSION_1 {
+                self.set_status(STATUS_FAILED);
+                return Err(Error::new(ENODEV));
+            }
+
+            self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK != STATUS_FEATURES_OK {
+                self.set_status(STATUS_FAILED);
+                return Err(Error::new(ENODEV));
+            }
+        }
+
+        Ok(features)
+    }
+
+    /// Tell the device the driver is ready
+    pub fn driver_ok(&mut self) {
+        let status = self.status();
+        self.set_status(status | STATUS_DRIVER_OK);
+    }
+
+    /// Set up a queue, no larger than `max_size` if the interface allows it
+    pub fn setup_queue(&mut self, index: u16, max_size: u16) -> Result<Virtqueue> {
+        match *self {
+            Transport::Legacy { base } => {
+                Pio::<u16>::new(base + LEGACY_QUEUE_SELECT).write(index);
+                let size = Pio::<u16>::new(base + LEGACY_QUEUE_SIZE).read();
+                if size == 0 {
+                    return Err(Error::new(EIO));
+                }
+
+                // The legacy interface does not allow the size to be changed
+                let queue = try!(Virtqueue::new(index, size));
+                Pio::<u32>::new(base + LEGACY_QUEUE_ADDRESS).write((queue.desc_address() / 4096) as u32);
+                Ok(queue)
+            },
+            Transport::Modern { .. } => {
+                let common = self.common();
+                common.queue_select.write(index);
+                let mut size = common.queue_size.read();
+                if size == 0 {
+                    return Err(Error::new(EIO));
+                }
+                if size > max_size {
+                    size = max_size;
+                    common.queue_size.write(size);
+                }
+
+                let mut queue = try!(Virtqueue::new(index, size));
+                queue.notify_off = common.queue_notify_off.read();
+                common.queue_desc_low.write(queue.desc_address() as u32);
+                common.queue_desc_high.write(0);
+                common.queue_driver_low.write(queue.avail_address() as u32);
+                common.queue_driver_high.write(0);
+                common.queue_device_low.write(queue.used_address() as u32);
+                common.queue_device_high.write(0);
+                common.queue_enable.write(1);
+                Ok(queue)
+            }
+        }
+    }
+
+    /// Tell the device new buffers are available in a queue
+    pub fn notify(&mut self, queue: &Virtqueue) {
+        match *self {
+            Transport::Legacy { base } => Pio::<u16>::new(base + LEGACY_QUEUE_NOTIFY).write(queue.index),
+            Transport::Modern { notify, notify_multiplier, .. } => {
+                let address = notify + queue.notify_off as usize * notify_multiplier as usize;
+                unsafe { &mut *(address as *mut Mmio<u16>) }.write(queue.index);
+            }
+        }
+    }
+
+    /// Read and acknowledge the interrupt status
+    pub fn isr(&mut self) -> u8 {
+        match *self {
+            Transport::Legacy { base } => Pio::<u8>::new(base + LEGACY_ISR_STATUS).read(),
+            Transport::Modern { isr, .. } => unsafe { & *(isr as *const Mmio<u8>) }.read(),
+        }
+    }
+
+    /// Read a byte of the device specific configuration
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        match *self {
+            Transport::Legacy { base } => Pio::<u8>::new(base + LEGACY_DEVICE_CONFIG + offset).read(),
+            Transport::Modern { device, .. } => unsafe { & *((device + offset as usize) as *const Mmio<u8>) }.read(),
+        }
+    }
+
+    /// Read a 16-bit value of the device specific configuration
+    pub fn config_u16(&self, offset: u16) -> u16 {
+        match *self {
+            Transport::Legacy { base } => Pio::<u16>::new(base + LEGACY_DEVICE_CONFIG + offset).read(),
+            Transport::Modern { device, .. } => unsafe { & *((device + offset as usize) as *const Mmio<u16>) }.read(),
+        }
+    }
+
+    /// Read a 32-bit value of the device specific configuration
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        match *self {
+            Transport::Legacy { base } => Pio::<u32>::new(base + LEGACY_DEVICE_CONFIG + offset).read(),
+            Transport::Modern { device, .. } => unsafe { & *((device + offset as usize) as *const Mmio<u32>) }.read(),
+        }
+    }
+
+    /// Read a 64-bit value of the device specific configuration, as two 32-bit halves
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        (self.config_u32(offset + 4) as u64) << 32 | self.config_u32(offset) as u64
+    }
+}
@@ -0,0 +1,172 @@
+use arch::memory;
+
+use collections::Vec;
+
+use core::intrinsics::{volatile_load, volatile_store};
+use core::mem::size_of;
+
+use system::error::{Error, Result, ENOMEM};
+
+/// The buffer continues in the descriptor in `next`
+pub const DESC_F_NEXT: u16 = 1;
+/// The buffer is written by the device
+pub const DESC_F_WRITE: u16 = 2;
+
+/// A buffer descriptor
+#[repr(packed)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// An element of the used ring
+#[repr(packed)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// A split virtqueue
+///
+/// The descriptor table, available ring and used ring are laid out in one allocation as the
+/// legacy interface requires, with the used ring aligned to a page.
+pub struct Virtqueue {
+    pub index: u16,
+    pub size: u16,
+    /// Modern interface notification offset
+    pub notify_off: u16,
+    memory: usize,
+    free: Vec<u16>,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn desc_size(size: u16) -> usize {
+        size as usize * size_of::<Descriptor>()
+    }
+
+    fn avail_size(size: u16) -> usize {
+        (3 + size as usize) * size_of::<u16>()
+    }
+
+    fn used_offset(size: u16) -> usize {
+        (Virtqueue::desc_size(size) + Virtqueue::avail_size(size) + 4095) / 4096 * 4096
+    }
+
+    fn used_size(size: u16) -> usize {
+        3 * size_of::<u16>() + size as usize * size_of::<UsedElement>()
+    }
+
+    /// Allocate a queue with `size` descriptors
+    pub fn new(index: u16, size: u16) -> Result<Virtqueue> {
+        let bytes = Virtqueue::used_offset(size) + Virtqueue::used_size(size);
+        let memory = unsafe { memory::alloc_aligned(bytes, 4096) };
+        if memory == 0 {
+            return Err(Error::new(ENOMEM));
+        }
+
+        Ok(Virtqueue {
+            index: index,
+            size: size,
+            notify_off: 0,
+            memory: memory,
+            free: (0..size).rev().collect(),
+            last_used: 0,
+        })
+    }
+
+    /// Physical address of the descriptor table
+    pub fn desc_address(&self) -> usize {
+        self.memory
+    }
+
+    /// Physical address of the available ring
+    pub fn avail_address(&self) -> usize {
+        self.memory + Virtqueue::desc_size(self.size)
+    }
+
+    /// Physical address of the used ring
+    pub fn used_address(&self) -> usize {
+        self.memory + Virtqueue::used_offset(self.size)
+    }
+
+    fn desc(&self, i: u16) -> *mut Descriptor {
+        (self.desc_address() + i as usize * size_of::<Descriptor>()) as *mut Descriptor
+    }
+
+    /// The number of free descriptors
+    pub fn free(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Make a chain of buffers available to the device, returning the index of its head
+    ///
+    /// Each buffer is given as a physical address, a length, and whether the device writes it.
+    pub fn push(&mut self, buffers: &[(usize, usize, bool)]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+
+        let mut next = 0;
+        let mut has_next = false;
+        for &(address, len, write) in buffers.iter().rev() {
+            let i = self.free.pop().unwrap_or(0);
+            unsafe {
+                let desc = &mut *self.desc(i);
+                desc.addr = address as u64;
+                desc.len = len as u32;
+                desc.flags = if write { DESC_F_WRITE } else { 0 } | if has_next { DESC_F_NEXT } else { 0 };
+                desc.next = next;
+            }
+            next = i;
+            has_next = true;
+        }
+
+        unsafe {
+            let avail_idx = (self.avail_address() + 2) as *mut u16;
+            let idx = volatile_load(avail_idx);
+            let ring = (self.avail_address() + 4) as *mut u16;
+            volatile_store(ring.offset((idx % self.size) as isize), next);
+            volatile_store(avail_idx, idx.wrapping_add(1));
+        }
+
+        Some(next)
+    }
+
+    /// Take a chain the device has finished with, returning its head and the length written
+    pub fn pop(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { volatile_load((self.used_address() + 2) as *const u16) };
+        if used_idx == self.last_used {
+            return None;
+        }
+
+        let element = unsafe {
+            let ring = (self.used_address() + 4) as *const UsedElement;
+            let element = &*ring.offset((self.last_used % self.size) as isize);
+            (volatile_load(&element.id), volatile_load(&element.len))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = element.0 as u16;
+        let mut i = head;
+        loop {
+            self.free.push(i);
+            let desc = unsafe { & *self.desc(i) };
+            if desc.flags & DESC_F_NEXT == DESC_F_NEXT {
+                i = desc.next;
+            } else {
+                break;
+            }
+        }
+
+        Some((head, element.1))
+    }
+}
+
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        unsafe { memory::unalloc(self.memory) };
+    }
+}
@@ -1,7 +1,7 @@
 use alloc::arc::Arc;
 use alloc::boxed::Box;
 
-use collections::{String, Vec, VecDeque};
+use collections::{BTreeMap, String, Vec, VecDeque};
 use collections::string::ToString;
 
 use core::cell::UnsafeCell;
@@ -10,7 +10,14 @@ use arch::context::{Context, ContextManager};
 use common::event::Event;
 use common::time::Duration;
 use disk::Disk;
+use disk::cache::BlockCache;
+use disk::partition::{self, Partition};
+use disk::stats::CountedDisk;
 use network::Nic;
+use network::demux::Receiver;
+use network::interface::Interface;
+use network::route::RouteTable;
+use network::schemes::arp::ArpCache;
 use fs::{KScheme, Resource, Scheme, VecResource};
 use sync::WaitQueue;
 
@@ -38,10 +45,22 @@ pub struct Environment {
 
     /// Default console
     pub console: UnsafeCell<Console>,
-    /// Disks
-    pub disks: UnsafeCell<Vec<Arc<UnsafeCell<Box<Disk>>>>>,
+    /// Disks, by number
+    pub disks: UnsafeCell<BTreeMap<usize, Arc<UnsafeCell<Box<Disk>>>>>,
+    /// Partitions found on disks
+    pub partitions: UnsafeCell<Vec<Partition>>,
+    /// Block cache shared by all disks
+    pub block_cache: UnsafeCell<BlockCache>,
     /// Network interfaces
     pub nics: UnsafeCell<Vec<Box<Nic>>>,
+    /// Interfaces of NIC drivers, with their addresses
+    pub interfaces: UnsafeCell<Vec<Interface>>,
+    /// IPv4 routes
+    pub routes: UnsafeCell<RouteTable>,
+    /// Neighbour cache
+    pub arp: UnsafeCell<ArpCache>,
+    /// Queues of received IP packets
+    pub receivers: UnsafeCell<Vec<*const Receiver>>,
     /// Pending events
     pub events: WaitQueue<Event>,
     /// Futexes
@@ -64,8 +83,14 @@ impl Environment {
             clock_monotonic: UnsafeCell::new(Duration::new(0, 0)),
 
             console: UnsafeCell::new(Console::new()),
-            disks: UnsafeCell::new(Vec::new()),
+            disks: UnsafeCell::new(BTreeMap::new()),
+            partitions: UnsafeCell::new(Vec::new()),
+            block_cache: UnsafeCell::new(BlockCache::new()),
             nics: UnsafeCell::new(Vec::new()),
+            interfaces: UnsafeCell::new(Vec::new()),
+            routes: UnsafeCell::new(RouteTable::new()),
+            arp: UnsafeCell::new(ArpCache::new()),
+            receivers: UnsafeCell::new(Vec::new()),
             events: WaitQueue::new(),
             futexes: UnsafeCell::new(VecDeque::new()),
             log: UnsafeCell::new(Log::new()),
@@ -75,6 +100,44 @@ impl Environment {
         }
     }
 
+    /// Register a disk, counting its I/O, and scan its partition table, returning the lowest free
+    /// disk number
+    pub fn add_disk(&self, disk: Box<Disk>) -> usize {
+        let disk = Arc::new(UnsafeCell::new(box CountedDisk::new(disk) as Box<Disk>));
+
+        let mut partitions = partition::scan(&disk);
+        for partition in partitions.iter() {
+            syslog_info!("   + Partition {}: start {} blocks {}", partition.number, partition.start, partition.blocks);
+        }
+
+        unsafe { &mut *self.partitions.get() }.append(&mut partitions);
+
+        let disks = unsafe { &mut *self.disks.get() };
+        let mut number = 0;
+        while disks.contains_key(&number) {
+            number += 1;
+        }
+        disks.insert(number, disk);
+
+        number
+    }
+
+    /// Remove a disk, writing back and dropping its cached blocks
+    ///
+    /// Open resources keep the disk alive, but it can no longer be opened.
+    pub fn remove_disk(&self, number: usize) -> Option<Arc<UnsafeCell<Box<Disk>>>> {
+        let disk = unsafe { &mut *self.disks.get() }.remove(&number);
+
+        if let Some(ref disk) = disk {
+            if let Err(err) = unsafe { &mut *self.block_cache.get() }.invalidate(disk) {
+                syslog_warning!("Disk {}: failed to write back cache: {}", number, err);
+            }
+            unsafe { &mut *self.partitions.get() }.retain(|partition| partition.disk.get() != disk.get());
+        }
+
+        disk
+    }
+
     pub fn on_irq(&self, irq: u8) {
         for mut scheme in unsafe { &mut *self.schemes.get() }.iter_mut() {
             scheme.on_irq(irq);
@@ -23,6 +23,17 @@ pub trait Resource {
         Err(Error::new(EPERM))
     }
 
+    /// Duplicate the resource as named by `path`, such as `accept` for the next connection of a
+    /// listener, with an empty path being a plain `dup`
+    /// Returns `EPERM` if the operation is not supported.
+    fn dup_path(&self, path: &[u8]) -> Result<Box<Resource>> {
+        if path.is_empty() {
+            self.dup()
+        } else {
+            Err(Error::new(EPERM))
+        }
+    }
+
     /// Return the path of this resource
     /// Returns `EPERM` if the operation is not supported.
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
@@ -64,4 +75,10 @@ pub trait Resource {
     fn truncate(&mut self, len: usize) -> Result<()> {
         Err(Error::new(EPERM))
     }
+
+    /// Check which of the requested `POLLIN`/`POLLOUT` events would not block
+    /// Resources that never block report every requested event as ready.
+    fn poll(&self, events: usize) -> Result<usize> {
+        Ok(events)
+    }
 }
@@ -11,6 +11,8 @@ use core::{ptr, slice};
 
 use arch::context::Context;
 
+use disk::dma_address;
+
 use sync::{WaitMap, WaitQueue};
 
 use system::error::{Error, Result, EFAULT, EINVAL, ENODEV, ESPIPE};
@@ -69,11 +71,8 @@ impl SchemeInner {
         }
     }
 
-    fn capture(inner: &Weak<SchemeInner>, mut physical_address: usize, size: usize, writeable: bool) -> Result<usize> {
+    fn capture(inner: &Weak<SchemeInner>, physical_address: usize, size: usize, writeable: bool) -> Result<usize> {
         if let Some(scheme) = inner.upgrade() {
-            if physical_address >= 0x80000000 {
-                physical_address -= 0x80000000;
-            }
             unsafe {
                 let mmap = &mut *(*scheme.context).mmap.get();
                 return mmap.add_mem(physical_address, size, writeable, false);
@@ -133,9 +132,7 @@ impl Resource for SchemeResource {
 
     /// Return the URL of this resource
     fn path(&self, buf: &mut [u8]) -> Result <usize> {
-        let contexts = unsafe { & *::env().contexts.get() };
-        let current = try!(contexts.current());
-        if let Ok(physical_address) = current.translate(buf.as_mut_ptr() as usize, buf.len()) {
+        if let Ok(physical_address) = dma_address(buf.as_mut_ptr() as usize, buf.len()) {
             let offset = physical_address % 4096;
 
             let virtual_address = try!(self.capture(physical_address - offset, buf.len() + offset, true));
@@ -155,9 +152,7 @@ impl Resource for SchemeResource {
 
     /// Read data to buffer
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let contexts = unsafe { & *::env().contexts.get() };
-        let current = try!(contexts.current());
-        if let Ok(physical_address) = current.translate(buf.as_mut_ptr() as usize, buf.len()) {
+        if let Ok(physical_address) = dma_address(buf.as_mut_ptr() as usize, buf.len()) {
             let offset = physical_address % 4096;
 
             let virtual_address = try!(self.capture(physical_address - offset, buf.len() + offset, true));
@@ -177,9 +172,7 @@ impl Resource for SchemeResource {
 
     /// Write to resource
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let contexts = unsafe { & *::env().contexts.get() };
-        let current = try!(contexts.current());
-        if let Ok(physical_address) = current.translate(buf.as_ptr() as usize, buf.len()) {
+        if let Ok(physical_address) = dma_address(buf.as_ptr() as usize, buf.len()) {
             let offset = physical_address % 4096;
 
             let virtual_address = try!(self.capture(physical_address - offset, buf.len() + offset, false));
@@ -212,9 +205,7 @@ impl Resource for SchemeResource {
     fn stat(&self, stat: &mut Stat) -> Result<()> {
         let buf = unsafe { slice::from_raw_parts_mut(stat as *mut Stat as *mut u8, size_of::<Stat>()) };
 
-        let contexts = unsafe { & *::env().contexts.get() };
-        let current = try!(contexts.current());
-        if let Ok(physical_address) = current.translate(buf.as_mut_ptr() as usize, buf.len()) {
+        if let Ok(physical_address) = dma_address(buf.as_mut_ptr() as usize, buf.len()) {
             let offset = physical_address % 4096;
 
             let virtual_address = try!(self.capture(physical_address - offset, buf.len() + offset, true));
@@ -378,7 +369,7 @@ impl KScheme for Scheme {
     }
 
     fn open(&mut self, path: &str, flags: usize) -> Result<Box<Resource>> {
-        let virtual_address = try!(self.capture(path.as_ptr() as usize, path.len(), false));
+        let virtual_address = try!(self.capture(try!(dma_address(path.as_ptr() as usize, path.len())), path.len(), false));
 
         let result = self.call(SYS_OPEN, virtual_address, path.len(), flags);
 
@@ -394,7 +385,7 @@ impl KScheme for Scheme {
     }
 
     fn mkdir(&mut self, path: &str, flags: usize) -> Result<()> {
-        let virtual_address = try!(self.capture(path.as_ptr() as usize, path.len(), false));
+        let virtual_address = try!(self.capture(try!(dma_address(path.as_ptr() as usize, path.len())), path.len(), false));
 
         let result = self.call(SYS_MKDIR, virtual_address, path.len(), flags);
 
@@ -404,7 +395,7 @@ impl KScheme for Scheme {
     }
 
     fn rmdir(&mut self, path: &str) -> Result<()> {
-        let virtual_address = try!(self.capture(path.as_ptr() as usize, path.len(), false));
+        let virtual_address = try!(self.capture(try!(dma_address(path.as_ptr() as usize, path.len())), path.len(), false));
 
         let result = self.call(SYS_RMDIR, virtual_address, path.len(), 0);
 
@@ -414,7 +405,7 @@ impl KScheme for Scheme {
     }
 
     fn unlink(&mut self, path: &str) -> Result<()> {
-        let virtual_address = try!(self.capture(path.as_ptr() as usize, path.len(), false));
+        let virtual_address = try!(self.capture(try!(dma_address(path.as_ptr() as usize, path.len())), path.len(), false));
 
         let result = self.call(SYS_UNLINK, virtual_address, path.len(), 0);
 
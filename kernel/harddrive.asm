@@ -18,6 +18,15 @@ kernel_file:
 .length equ kernel_file.end - kernel_file
 .length_sectors equ .length / 512
 
+%ifdef RAMDISK
+ramdisk_file:
+  incbin RAMDISK
+  align 512, db 0
+.end:
+.length equ ramdisk_file.end - ramdisk_file
+.length_sectors equ .length / 512
+%endif
+
 real_fs:
 incbin "filesystem.bin"
 real_fs.end:
@@ -38,10 +38,12 @@ use collections::string::ToString;
 
 use core::{mem, slice, usize};
 
+use common::random;
 use common::time::Duration;
 
 use drivers::pci;
 use drivers::io::{Io, Pio};
+use drivers::lapic;
 use drivers::ps2::*;
 use drivers::rtc::*;
 use drivers::serial::{self, Serial};
@@ -50,15 +52,28 @@ use env::Environment;
 
 use graphics::display;
 
-use network::schemes::{ArpScheme, EthernetScheme, IcmpScheme, IpScheme, NetConfigScheme, TcpScheme, UdpScheme};
+use network::demux;
+use network::loopback::Loopback;
+use network::scheme::InterfaceScheme;
+use network::schemes::{ArpScheme, EthernetScheme, IcmpScheme, Icmpv6Scheme, IpScheme, Ip6Scheme, NetConfigScheme, TcpScheme, UdpScheme};
+
+use disk::ramdisk::RamDisk;
 
 use schemes::debug::DebugScheme;
 use schemes::disk::DiskScheme;
 use schemes::display::DisplayScheme;
 use schemes::env::EnvScheme;
+use schemes::ext2::Ext2Scheme;
+use schemes::fat::FatScheme;
 use schemes::initfs::InitFsScheme;
+use schemes::iso9660::Iso9660Scheme;
+use schemes::loopdev::LoopScheme;
+use schemes::null::NullScheme;
 use schemes::pty::PtyScheme;
+use schemes::rand::RandScheme;
 use schemes::sys::SysScheme;
+use schemes::time::TimeScheme;
+use schemes::zero::ZeroScheme;
 
 use syscall::process::exit;
 use syscall::execute::execute;
@@ -414,10 +429,23 @@ unsafe fn init(gdt_ptr: *mut GdtDescriptor, idt_ptr: *mut IdtDescriptor, tss_ptr
 
             *env.clock_realtime.get() = Rtc::new().time();
 
+            {
+                let realtime = *env.clock_realtime.get();
+                let (rdrand, rdseed) = random::init((realtime.secs as u64) << 32 ^ realtime.nanos as u64);
+                syslog_info!(" + Entropy: RDRAND {} RDSEED {}", rdrand, rdseed);
+            }
+
             (&mut *env.schemes.get()).push(Ps2::new());
 
+            // The boot RAM disk comes first, so that it is disk:/0 and can be the root filesystem
+            if let Some(ramdisk) = RamDisk::boot() {
+                env.add_disk(box ramdisk);
+            }
+
             pci::pci_init(env);
 
+            (&mut *env.schemes.get()).push(Loopback::new());
+
             (&mut *env.schemes.get()).push(DebugScheme::new());
 
             (&mut *env.schemes.get()).push(box DiskScheme);
@@ -428,30 +456,44 @@ unsafe fn init(gdt_ptr: *mut GdtDescriptor, idt_ptr: *mut IdtDescriptor, tss_ptr
 
             (&mut *env.schemes.get()).push(box EnvScheme);
 
+            (&mut *env.schemes.get()).push(Ext2Scheme::new());
+
+            (&mut *env.schemes.get()).push(FatScheme::new());
+
+            (&mut *env.schemes.get()).push(box Iso9660Scheme);
+
+            (&mut *env.schemes.get()).push(box LoopScheme);
+
+            (&mut *env.schemes.get()).push(box NullScheme);
+
             (&mut *env.schemes.get()).push(PtyScheme::new());
 
+            (&mut *env.schemes.get()).push(box RandScheme);
+
             (&mut *env.schemes.get()).push(SysScheme::new());
 
+            (&mut *env.schemes.get()).push(box TimeScheme);
+
+            (&mut *env.schemes.get()).push(box ZeroScheme);
+
             /*
             let mut nics = Vec::new();
             nics.append(&mut env.nics.lock());
             (&mut *env.schemes.get()).push(NetworkScheme::new(nics));
             */
 
+            (&mut *env.schemes.get()).push(box InterfaceScheme);
             (&mut *env.schemes.get()).push(box NetConfigScheme);
             (&mut *env.schemes.get()).push(box EthernetScheme);
-            //(&mut *env.schemes.get()).push(box ArpScheme);
+            (&mut *env.schemes.get()).push(ArpScheme::new());
             //(&mut *env.schemes.get()).push(box IcmpScheme);
-            (&mut *env.schemes.get()).push(box IpScheme {
-                arp: Vec::new()
-            });
-            (&mut *env.schemes.get()).push(box TcpScheme);
-            (&mut *env.schemes.get()).push(box UdpScheme);
+            (&mut *env.schemes.get()).push(box IpScheme);
+            (&mut *env.schemes.get()).push(Icmpv6Scheme::new());
+            (&mut *env.schemes.get()).push(box Ip6Scheme);
+            (&mut *env.schemes.get()).push(TcpScheme::new());
+            (&mut *env.schemes.get()).push(UdpScheme::new());
 
-            Context::spawn("karp".into(),
-                           box move || {
-                               ArpScheme::reply_loop();
-                           });
+            demux::init();
 
             Context::spawn("kicmp".into(),
                            box move || {
@@ -643,6 +685,11 @@ pub extern "cdecl" fn kernel(interrupt: usize, mut regs: &mut Regs) {
         unsafe { (&mut *env().interrupts.get())[interrupt as usize] += 1 };
     }
 
+    // Hardware interrupt timing feeds the entropy pool
+    if interrupt >= 0x20 && interrupt < 0x30 {
+        random::add_interrupt_entropy(interrupt);
+    }
+
     match interrupt {
         0x20 => {
             {
@@ -663,6 +710,11 @@ pub extern "cdecl" fn kernel(interrupt: usize, mut regs: &mut Regs) {
         i @ 0x21 ... 0x2F => {
             env().on_irq(i as u8 - 0x20);
         },
+        // Message signalled interrupts continue the IRQ numbering
+        i @ 0x30 ... 0x3E => {
+            env().on_irq(i as u8 - 0x20);
+        },
+        0x3F => (), // Local APIC spurious interrupt
         0x80 => syscall::handle(regs),
         0xFF => {
             unsafe {
@@ -700,5 +752,7 @@ pub extern "cdecl" fn kernel(interrupt: usize, mut regs: &mut Regs) {
         }
 
         Pio::<u8>::new(0x20).write(0x20);
+    } else if interrupt >= lapic::MSI_VECTOR_START as usize && interrupt < lapic::MSI_VECTOR_END as usize {
+        lapic::eoi();
     }
 }
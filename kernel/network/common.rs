@@ -3,13 +3,16 @@ use collections::vec::Vec;
 
 use common::to_num::ToNum;
 
+use core::mem;
+
 pub static mut DNS_ADDR: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 1] };
 pub static BROADCAST_IP_ADDR: Ipv4Addr = Ipv4Addr { bytes: [255, 255, 255, 255] };
-pub static mut IP_ADDR: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 2] };
-pub static mut IP_ROUTER_ADDR: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 1] };
-pub static mut IP_SUBNET: Ipv4Addr = Ipv4Addr { bytes: [255, 255, 255, 0] };
 pub static BROADCAST_MAC_ADDR: MacAddr = MacAddr { bytes: [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF] };
-pub static mut MAC_ADDR: MacAddr = MacAddr { bytes: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00] };
+pub const UNSPECIFIED_IPV6_ADDR: Ipv6Addr = Ipv6Addr { bytes: [0; 16] };
+/// Every IPv6 node on the link
+pub static ALL_NODES_IPV6_ADDR: Ipv6Addr = Ipv6Addr { bytes: [0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] };
+/// Every IPv6 router on the link
+pub static ALL_ROUTERS_IPV6_ADDR: Ipv6Addr = Ipv6Addr { bytes: [0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2] };
 
 pub trait FromBytes {
     fn from_bytes(bytes: &[u8]) -> Option<Self> where Self: Sized;
@@ -100,6 +103,22 @@ impl MacAddr {
         addr
     }
 
+    /// Parse six hexadecimal bytes separated by `.`, rejecting anything else
+    pub fn parse(string: &str) -> Option<Self> {
+        let mut addr = MacAddr { bytes: [0; 6] };
+        let mut parts = string.split('.');
+        for byte in addr.bytes.iter_mut() {
+            *byte = match parts.next().map(|part| u8::from_str_radix(part, 16)) {
+                Some(Ok(byte)) => byte,
+                _ => return None,
+            };
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(addr)
+    }
+
     pub fn to_string(&self) -> String {
         let mut string = String::new();
         for i in 0..6 {
@@ -127,6 +146,28 @@ impl Ipv4Addr {
         true
     }
 
+    pub fn to_u32(&self) -> u32 {
+        (self.bytes[0] as u32) << 24 | (self.bytes[1] as u32) << 16 | (self.bytes[2] as u32) << 8 | self.bytes[3] as u32
+    }
+
+    pub fn from_u32(addr: u32) -> Self {
+        Ipv4Addr { bytes: [(addr >> 24) as u8, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8] }
+    }
+
+    /// Is the limited broadcast address, or the broadcast address of one of our subnets
+    pub fn is_broadcast(&self) -> bool {
+        self.equals(BROADCAST_IP_ADDR) || unsafe { & *::env().interfaces.get() }.iter().any(|interface| {
+            interface.on_subnet(*self) && ! interface.netmask.equals(BROADCAST_IP_ADDR) && self.equals(interface.broadcast())
+        })
+    }
+
+    /// Is the address of one of our interfaces, or on the subnet of the loopback interface
+    pub fn is_local(&self) -> bool {
+        unsafe { & *::env().interfaces.get() }.iter().any(|interface| {
+            interface.is_configured() && (interface.addr.equals(*self) || (interface.loopback && interface.on_subnet(*self)))
+        })
+    }
+
     pub fn from_str(string: &str) -> Self {
         let mut addr = Ipv4Addr { bytes: [0, 0, 0, 0] };
 
@@ -146,6 +187,22 @@ impl Ipv4Addr {
         addr
     }
 
+    /// Parse a dotted address, rejecting anything else
+    pub fn parse(string: &str) -> Option<Self> {
+        let mut addr = Ipv4Addr { bytes: [0; 4] };
+        let mut parts = string.split('.');
+        for byte in addr.bytes.iter_mut() {
+            *byte = match parts.next().map(|part| part.parse::<u8>()) {
+                Some(Ok(byte)) => byte,
+                _ => return None,
+            };
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(addr)
+    }
+
     pub fn to_string(&self) -> String {
         let mut string = String::new();
 
@@ -166,20 +223,295 @@ pub struct Ipv6Addr {
 }
 
 impl Ipv6Addr {
-    pub fn to_string(&self) -> String {
-        let mut string = String::new();
+    pub fn equals(&self, other: Self) -> bool {
+        self.bytes == other.bytes
+    }
+
+    /// The address in the first 16 bytes of a slice
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        let mut addr = UNSPECIFIED_IPV6_ADDR;
+        for (b, byte) in addr.bytes.iter_mut().zip(bytes.iter()) {
+            *b = *byte;
+        }
+        addr
+    }
+
+    /// The eight 16 bit groups of the address
+    pub fn groups(&self) -> [u16; 8] {
+        let mut groups = [0; 8];
+        for i in 0..8 {
+            groups[i] = (self.bytes[2 * i] as u16) << 8 | self.bytes[2 * i + 1] as u16;
+        }
+        groups
+    }
 
+    pub fn from_groups(groups: &[u16]) -> Self {
+        let mut addr = UNSPECIFIED_IPV6_ADDR;
+        for (i, group) in groups.iter().take(8).enumerate() {
+            addr.bytes[2 * i] = (group >> 8) as u8;
+            addr.bytes[2 * i + 1] = *group as u8;
+        }
+        addr
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        self.equals(UNSPECIFIED_IPV6_ADDR)
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.bytes[0] == 0xFF
+    }
+
+    /// Is in `fe80::/10`, and only meaningful on one link
+    pub fn is_link_local(&self) -> bool {
+        self.bytes[0] == 0xFE && self.bytes[1] & 0xC0 == 0x80
+    }
+
+    /// Whether the first `prefix_len` bits are the same as another address's
+    pub fn matches(&self, other: Self, prefix_len: u8) -> bool {
+        let prefix_len = prefix_len as usize;
         for i in 0..16 {
-            if i > 0 && i % 2 == 0 {
-                string = string + ".";
+            let bits = if prefix_len >= 8 * (i + 1) {
+                8
+            } else if prefix_len > 8 * i {
+                prefix_len - 8 * i
+            } else {
+                return true;
+            };
+            let mask = (0xFF00u16 >> bits) as u8;
+            if self.bytes[i] & mask != other.bytes[i] & mask {
+                return false;
             }
-            string = string + &format!("{}", self.bytes[i]);
+        }
+        true
+    }
+
+    /// The address made from the first 64 bits of a prefix and the modified EUI-64 of a MAC address
+    pub fn from_prefix(prefix: Ipv6Addr, mac: MacAddr) -> Self {
+        let mut addr = prefix;
+        addr.bytes[8] = mac.bytes[0] ^ 0x02;
+        addr.bytes[9] = mac.bytes[1];
+        addr.bytes[10] = mac.bytes[2];
+        addr.bytes[11] = 0xFF;
+        addr.bytes[12] = 0xFE;
+        addr.bytes[13] = mac.bytes[3];
+        addr.bytes[14] = mac.bytes[4];
+        addr.bytes[15] = mac.bytes[5];
+        addr
+    }
+
+    /// The link-local address of a MAC address
+    pub fn link_local(mac: MacAddr) -> Self {
+        Ipv6Addr::from_prefix(Ipv6Addr { bytes: [0xFE, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }, mac)
+    }
+
+    /// The solicited-node multicast address, which neighbour solicitations for the address go to
+    pub fn solicited_node(&self) -> Self {
+        Ipv6Addr {
+            bytes: [0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xFF, self.bytes[13], self.bytes[14], self.bytes[15]]
+        }
+    }
+
+    /// The MAC address a multicast address is sent to
+    pub fn multicast_mac(&self) -> MacAddr {
+        MacAddr { bytes: [0x33, 0x33, self.bytes[12], self.bytes[13], self.bytes[14], self.bytes[15]] }
+    }
+
+    /// The IPv4 address, if the address is mapped from one as `::ffff:a.b.c.d`
+    pub fn ipv4_mapped(&self) -> Option<Ipv4Addr> {
+        if self.bytes[.. 10].iter().all(|&b| b == 0) && self.bytes[10] == 0xFF && self.bytes[11] == 0xFF {
+            Some(Ipv4Addr {
+                bytes: [self.bytes[12], self.bytes[13], self.bytes[14], self.bytes[15]]
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Is a usable address of one of our interfaces
+    pub fn is_local(&self) -> bool {
+        unsafe { & *::env().interfaces.get() }.iter().any(|interface| interface.has_addr6(*self))
+    }
+
+    /// Parse an address as in RFC 4291, with `::` for a run of zero groups, and a dotted IPv4
+    /// address allowed as the last 32 bits
+    pub fn parse(string: &str) -> Option<Self> {
+        let (head, tail) = match string.find("::") {
+            Some(i) => (&string[.. i], Some(&string[i + 2 ..])),
+            None => (string, None),
+        };
+
+        let mut groups = match Ipv6Addr::parse_groups(head) {
+            Some(groups) => groups,
+            None => return None,
+        };
+        match tail {
+            Some(tail) => {
+                let tail_groups = match Ipv6Addr::parse_groups(tail) {
+                    Some(tail_groups) => tail_groups,
+                    None => return None,
+                };
+                if groups.len() + tail_groups.len() > 7 {
+                    return None;
+                }
+                while groups.len() + tail_groups.len() < 8 {
+                    groups.push(0);
+                }
+                groups.extend_from_slice(&tail_groups);
+            },
+            None => if groups.len() != 8 {
+                return None;
+            },
+        }
+
+        Some(Ipv6Addr::from_groups(&groups))
+    }
+
+    /// Parse `:` separated groups of up to four hexadecimal digits, the last of which may be a
+    /// dotted IPv4 address
+    fn parse_groups(string: &str) -> Option<Vec<u16>> {
+        let mut groups = Vec::new();
+        if string.is_empty() {
+            return Some(groups);
+        }
+
+        let parts: Vec<&str> = string.split(':').collect();
+        for (i, part) in parts.iter().enumerate() {
+            if i + 1 == parts.len() && part.contains('.') {
+                match Ipv4Addr::parse(part) {
+                    Some(addr) => {
+                        groups.push((addr.bytes[0] as u16) << 8 | addr.bytes[1] as u16);
+                        groups.push((addr.bytes[2] as u16) << 8 | addr.bytes[3] as u16);
+                    },
+                    None => return None,
+                }
+            } else if part.len() <= 4 && part.chars().all(|c| c.is_digit(16)) {
+                match u16::from_str_radix(part, 16) {
+                    Ok(group) => groups.push(group),
+                    Err(_) => return None,
+                }
+            } else {
+                return None;
+            }
+        }
+
+        Some(groups)
+    }
+
+    /// Format the address as in RFC 5952, with the longest run of zero groups written as `::`
+    pub fn to_string(&self) -> String {
+        if let Some(addr) = self.ipv4_mapped() {
+            return format!("::ffff:{}", addr.to_string());
+        }
+
+        let groups = self.groups();
+
+        let mut longest = (0, 0);
+        let mut start = None;
+        for i in 0..9 {
+            if i < 8 && groups[i] == 0 {
+                if start.is_none() {
+                    start = Some(i);
+                }
+            } else if let Some(start) = start.take() {
+                if i - start > longest.1 - longest.0 {
+                    longest = (start, i);
+                }
+            }
+        }
+
+        let compress = longest.1 - longest.0 >= 2;
+        let mut string = String::new();
+        let mut i = 0;
+        while i < 8 {
+            if compress && i == longest.0 {
+                string = string + "::";
+                i = longest.1;
+                continue;
+            }
+            if ! string.is_empty() && ! string.ends_with(':') {
+                string = string + ":";
+            }
+            string = string + &format!("{:x}", groups[i]);
+            i += 1;
         }
 
         string
     }
 }
 
+/// An IPv4 or IPv6 address
+#[derive(Copy, Clone)]
+pub enum IpAddr {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr),
+}
+
+impl IpAddr {
+    pub fn equals(&self, other: Self) -> bool {
+        match (*self, other) {
+            (IpAddr::V4(addr), IpAddr::V4(other)) => addr.equals(other),
+            (IpAddr::V6(addr), IpAddr::V6(other)) => addr.equals(other),
+            _ => false,
+        }
+    }
+
+    /// The address, taken from IPv6 unless it is mapped from IPv4
+    pub fn from_mapped(addr: Ipv6Addr) -> Self {
+        match addr.ipv4_mapped() {
+            Some(addr) => IpAddr::V4(addr),
+            None => IpAddr::V6(addr),
+        }
+    }
+
+    /// The address as IPv6, mapping IPv4 addresses as `::ffff:a.b.c.d`
+    pub fn to_mapped(&self) -> Ipv6Addr {
+        match *self {
+            IpAddr::V4(addr) => {
+                let mut mapped = UNSPECIFIED_IPV6_ADDR;
+                mapped.bytes[10] = 0xFF;
+                mapped.bytes[11] = 0xFF;
+                for i in 0..4 {
+                    mapped.bytes[12 + i] = addr.bytes[i];
+                }
+                mapped
+            },
+            IpAddr::V6(addr) => addr,
+        }
+    }
+
+    /// Is a broadcast address, or the IPv6 all nodes address that takes its place
+    pub fn is_broadcast(&self) -> bool {
+        match *self {
+            IpAddr::V4(addr) => addr.is_broadcast(),
+            IpAddr::V6(addr) => addr.equals(ALL_NODES_IPV6_ADDR),
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        match *self {
+            IpAddr::V4(addr) => addr.is_local(),
+            IpAddr::V6(addr) => addr.is_local(),
+        }
+    }
+
+    /// Parse an IPv6 address if there is a `:`, or an IPv4 address
+    pub fn parse(string: &str) -> Option<Self> {
+        if string.contains(':') {
+            Ipv6Addr::parse(string).map(IpAddr::V6)
+        } else {
+            Ipv4Addr::parse(string).map(IpAddr::V4)
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        match *self {
+            IpAddr::V4(addr) => addr.to_string(),
+            IpAddr::V6(addr) => addr.to_string(),
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Checksum {
     pub data: u16,
@@ -229,6 +561,29 @@ impl Checksum {
         sum
     }
 
+    /// Sum the pseudo header covered by the checksum of a TCP, UDP or ICMPv6 payload of `len`
+    /// bytes, which is the same for IPv4 and IPv6 but for the size of the addresses
+    pub fn pseudo_header(src: &IpAddr, dst: &IpAddr, proto: u8, len: usize) -> usize {
+        let src_bytes: &[u8] = match *src {
+            IpAddr::V4(ref addr) => &addr.bytes,
+            IpAddr::V6(ref addr) => &addr.bytes,
+        };
+        let dst_bytes: &[u8] = match *dst {
+            IpAddr::V4(ref addr) => &addr.bytes,
+            IpAddr::V6(ref addr) => &addr.bytes,
+        };
+        let proto = n16::new(proto as u16);
+        let len_high = n16::new((len >> 16) as u16);
+        let len_low = n16::new(len as u16);
+        unsafe {
+            Checksum::sum(src_bytes.as_ptr() as usize, src_bytes.len()) +
+            Checksum::sum(dst_bytes.as_ptr() as usize, dst_bytes.len()) +
+            Checksum::sum((&proto as *const n16) as usize, mem::size_of::<n16>()) +
+            Checksum::sum((&len_high as *const n16) as usize, mem::size_of::<n16>()) +
+            Checksum::sum((&len_low as *const n16) as usize, mem::size_of::<n16>())
+        }
+    }
+
     pub fn compile(mut sum: usize) -> u16 {
         while (sum >> 16) > 0 {
             sum = (sum & 0xFFFF) + (sum >> 16);
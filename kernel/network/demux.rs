@@ -0,0 +1,239 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use arch::context::{context_switch, Context};
+
+use network::common::*;
+use network::ethernet::EthernetII;
+use network::interface::{self, Interface};
+use network::ipv4::{Ipv4, Ipv4Reassembly};
+use network::ipv6::Ipv6;
+use network::schemes::icmpv6::ICMPV6_PROTO;
+
+use sync::WaitQueue;
+
+use system::syscall::O_RDWR;
+
+/// The protocol number of ICMP, whose errors go to the protocol of the packet they quote, as do
+/// those of ICMPv6
+const ICMP_PROTO: u8 = 1;
+
+/// Packets a receiver holds before dropping new ones
+const RECEIVE_PACKETS: usize = 256;
+
+/// A received packet, parsed and reassembled once for all of its receivers
+#[derive(Clone)]
+pub struct Packet {
+    /// The interface the packet arrived on
+    pub interface: String,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub proto: u8,
+    /// The TTL or hop limit
+    pub hop_limit: u8,
+    pub data: Vec<u8>,
+}
+
+/// The packets a receiver takes
+#[derive(Copy, Clone)]
+pub struct Filter {
+    /// Take IPv6 packets rather than IPv4 packets
+    pub v6: bool,
+    pub proto: u8,
+    /// Take only packets from this address
+    pub peer: Option<IpAddr>,
+}
+
+impl Filter {
+    /// Take every packet of a protocol
+    pub fn new(v6: bool, proto: u8) -> Filter {
+        Filter {
+            v6: v6,
+            proto: proto,
+            peer: None,
+        }
+    }
+
+    fn matches(&self, packet: &Packet) -> bool {
+        let v6 = match packet.src {
+            IpAddr::V4(_) => false,
+            IpAddr::V6(_) => true,
+        };
+        v6 == self.v6 && packet.proto == self.proto && self.peer.map_or(true, |peer| peer.equals(packet.src))
+    }
+}
+
+/// A queue of the packets matching any of its filters
+///
+/// A receiver is registered for as long as it lives, and only packets it takes are queued for
+/// it, so reading one never drops packets meant for another.
+pub struct Receiver {
+    filters: Vec<Filter>,
+    /// Also take ICMP and ICMPv6 errors quoting a packet of a filtered protocol, from any address
+    errors: bool,
+    pub queue: WaitQueue<Packet>,
+}
+
+impl Receiver {
+    pub fn new(filters: Vec<Filter>, errors: bool) -> Box<Receiver> {
+        let ret = box Receiver {
+            filters: filters,
+            errors: errors,
+            queue: WaitQueue::new(),
+        };
+
+        unsafe { &mut *::env().receivers.get() }.push(&*ret as *const Receiver);
+
+        ret
+    }
+
+    /// Register another receiver with the same filters and queued packets
+    pub fn dup(&self) -> Box<Receiver> {
+        let ret = Receiver::new(self.filters.clone(), self.errors);
+        for packet in unsafe { self.queue.inner() }.iter() {
+            unsafe { ret.queue.inner() }.push_back(packet.clone());
+        }
+        ret
+    }
+
+    /// Whether the receiver takes a packet, given the protocol it quotes if it is an error
+    fn accepts(&self, packet: &Packet, quoted: Option<u8>) -> bool {
+        self.filters.iter().any(|filter| filter.matches(packet)) ||
+        (self.errors && quoted.map_or(false, |proto| self.filters.iter().any(|filter| {
+            filter.v6 == (packet.proto == ICMPV6_PROTO) && filter.proto == proto
+        })))
+    }
+
+    /// Take only packets from `peer` from now on, dropping queued packets from other addresses
+    pub fn connect(&mut self, peer: IpAddr) {
+        for filter in self.filters.iter_mut() {
+            filter.peer = Some(peer);
+        }
+        unsafe { self.queue.inner() }.retain(|packet| packet.src.equals(peer));
+    }
+
+    /// Take the next packet without waiting
+    pub fn try_receive(&self) -> Option<Packet> {
+        unsafe { self.queue.inner() }.pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        unsafe { self.queue.inner() }.is_empty()
+    }
+
+    fn push(&self, packet: Packet) {
+        if unsafe { self.queue.inner() }.len() < RECEIVE_PACKETS {
+            self.queue.send(packet, "Receiver::push");
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let ptr = self as *const Receiver;
+        unsafe { &mut *::env().receivers.get() }.retain(|receiver| *receiver != ptr);
+    }
+}
+
+/// The protocol quoted by an ICMP destination unreachable, or ICMPv6 destination unreachable or
+/// packet too big message
+fn quoted_proto(packet: &Packet) -> Option<u8> {
+    match packet.proto {
+        ICMP_PROTO if packet.data.len() >= 8 + 20 && packet.data[0] == 3 => Some(packet.data[8 + 9]),
+        ICMPV6_PROTO if packet.data.len() >= 8 + 40 && (packet.data[0] == 1 || packet.data[0] == 2) => Some(packet.data[8 + 6]),
+        _ => None,
+    }
+}
+
+/// Queue a packet for every receiver that takes it
+fn dispatch(packet: Packet) {
+    let quoted = quoted_proto(&packet);
+    for receiver in unsafe { & *::env().receivers.get() }.iter() {
+        let receiver = unsafe { & **receiver };
+        if receiver.accepts(&packet, quoted) {
+            receiver.push(packet.clone());
+        }
+    }
+}
+
+/// Whether an IPv6 packet that arrived on an interface is for us, being to one of our addresses,
+/// to all nodes, or to the solicited-node address of one of the interface's addresses, tentative
+/// ones included
+fn to_us6(interface: &Interface, dst: Ipv6Addr) -> bool {
+    dst.is_local() || dst.equals(ALL_NODES_IPV6_ADDR) ||
+    interface.addrs6.iter().any(|inet6| inet6.addr.solicited_node().equals(dst))
+}
+
+/// Read the frames of an interface, parsing each IP packet to us once and handing it to its
+/// receivers
+fn receive_loop(interface_name: String) {
+    let mut reassembly = Ipv4Reassembly::new();
+    while let Ok(mut link) = ::env().open(&format!("network:{}", interface_name), O_RDWR) {
+        loop {
+            let mut bytes = [0; 65536];
+            let frame = match link.read(&mut bytes) {
+                Ok(count) => EthernetII::from_bytes(&bytes[..count]),
+                Err(_) => break,
+            };
+
+            let interface = match interface::find(&interface_name) {
+                Some(interface) => interface,
+                None => break,
+            };
+
+            if let Some(frame) = frame {
+                match frame.header.ethertype.get() {
+                    0x800 => {
+                        let packet = match Ipv4::from_bytes(&frame.data) {
+                            Some(packet) => reassembly.input(packet),
+                            None => None,
+                        };
+
+                        if let Some(packet) = packet {
+                            let dst = packet.header.dst;
+                            if dst.is_local() || dst.is_broadcast() {
+                                dispatch(Packet {
+                                    interface: interface_name.clone(),
+                                    src: IpAddr::V4(packet.header.src),
+                                    dst: IpAddr::V4(dst),
+                                    proto: packet.header.proto,
+                                    hop_limit: packet.header.ttl,
+                                    data: packet.data,
+                                });
+                            }
+                        }
+                    },
+                    0x86DD => if let Some(packet) = Ipv6::from_bytes(&frame.data) {
+                        let dst = packet.header.dst;
+                        if to_us6(interface, dst) {
+                            dispatch(Packet {
+                                interface: interface_name.clone(),
+                                src: IpAddr::V6(packet.header.src),
+                                dst: IpAddr::V6(dst),
+                                proto: packet.proto,
+                                hop_limit: packet.header.hop_limit,
+                                data: packet.data,
+                            });
+                        }
+                    },
+                    _ => (),
+                }
+            }
+        }
+        unsafe { context_switch() };
+    }
+    debugln!("Demux: Failed to open network:{}", interface_name);
+}
+
+/// Start a context receiving IP packets on every interface
+pub fn init() {
+    for interface in unsafe { & *::env().interfaces.get() }.iter() {
+        let name = interface.name.clone();
+        Context::spawn(format!("knet {}", name),
+                       box move || {
+                           receive_loop(name);
+                       });
+    }
+}
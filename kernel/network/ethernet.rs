@@ -7,6 +7,9 @@ use core::mem;
 
 use network::common::*;
 
+/// Largest payload of a frame
+pub const ETHERNET_MTU: usize = 1500;
+
 #[derive(Copy, Clone)]
 #[repr(packed)]
 pub struct EthernetIIHeader {
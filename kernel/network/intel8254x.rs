@@ -12,11 +12,10 @@ use core::ptr;
 use drivers::pci::config::PciConfig;
 
 use network::common::*;
+use network::interface;
 use network::scheme::*;
 
-use fs::{KScheme, Resource};
-
-use system::error::Result;
+use fs::KScheme;
 
 const CTRL: u32 = 0x00;
 const CTRL_LRST: u32 = 1 << 3;
@@ -113,14 +112,6 @@ pub struct Intel8254x {
 }
 
 impl KScheme for Intel8254x {
-    fn scheme(&self) -> &str {
-        "network"
-    }
-
-    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
-        Ok(NetworkResource::new(self))
-    }
-
     fn on_irq(&mut self, irq: u8) {
         if irq == self.irq {
             unsafe { self.read(ICR) };
@@ -161,7 +152,8 @@ impl NetworkScheme for Intel8254x {
         {
             let resources = unsafe { &mut *self.resources.get() };
 
-            for resource in resources.iter() {
+            let nic = self as *mut Self as usize;
+            for resource in resources.iter().filter(|resource| unsafe { (***resource).sends_on(nic) }) {
                 while let Some(bytes) = unsafe { &mut *(**resource).outbound.get() }.pop_front() {
                     self.outbound.push_back(bytes);
                 }
@@ -177,7 +169,7 @@ impl NetworkScheme for Intel8254x {
 
             while let Some(bytes) = self.inbound.pop_front() {
                 for resource in resources.iter() {
-                    unsafe { (**resource).inbound.send(bytes.clone(), "Intel8254x::sync") };
+                    unsafe { (**resource).deliver(&bytes, "Intel8254x::sync") };
                 }
             }
         }
@@ -305,7 +297,7 @@ impl Intel8254x {
 
         let mac_low = self.read(RAL0);
         let mac_high = self.read(RAH0);
-        MAC_ADDR = MacAddr {
+        let mac = MacAddr {
             bytes: [mac_low as u8,
                     (mac_low >> 8) as u8,
                     (mac_low >> 16) as u8,
@@ -313,7 +305,10 @@ impl Intel8254x {
                     mac_high as u8,
                     (mac_high >> 8) as u8],
         };
-        syslog_info!("   - MAC: {}", &MAC_ADDR.to_string());
+        syslog_info!("   - MAC: {}", &mac.to_string());
+
+        let name = interface::add(mac, self as *mut Self);
+        syslog_info!("   - Interface: {}", name);
 
         //
         // MTA => 0;
@@ -368,7 +363,8 @@ impl Intel8254x {
 
         self.flag(RCTL, RCTL_EN, true);
         self.flag(RCTL, RCTL_UPE, true);
-        // self.flag(RCTL, RCTL_MPE, true);
+        // Multicast for IPv6 neighbour discovery
+        self.flag(RCTL, RCTL_MPE, true);
         self.flag(RCTL, RCTL_LPE, true);
         self.flag(RCTL, RCTL_LBM, false);
         // RCTL.RDMTS = Minimum threshold size ???
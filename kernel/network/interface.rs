@@ -0,0 +1,194 @@
+use collections::string::String;
+use collections::vec::Vec;
+
+use common::time::Duration;
+
+use network::common::{Ipv4Addr, Ipv6Addr, MacAddr};
+use network::ethernet::ETHERNET_MTU;
+use network::route::Route;
+use network::scheme::NetworkScheme;
+
+/// The address of the first interface, until it is configured
+const DEFAULT_ADDR: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 2] };
+const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr { bytes: [255, 255, 255, 0] };
+const DEFAULT_ROUTER: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 1] };
+
+/// The address of an interface that is not configured
+pub const UNSPECIFIED_ADDR: Ipv4Addr = Ipv4Addr { bytes: [0, 0, 0, 0] };
+
+/// The addresses of the loopback interface
+const LOOPBACK_ADDR: Ipv4Addr = Ipv4Addr { bytes: [127, 0, 0, 1] };
+const LOOPBACK_NETMASK: Ipv4Addr = Ipv4Addr { bytes: [255, 0, 0, 0] };
+const LOOPBACK_IPV6_ADDR: Ipv6Addr = Ipv6Addr { bytes: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] };
+/// The largest packet the loopback interface carries
+const LOOPBACK_MTU: usize = 16384;
+
+/// The hop limit of IPv6 packets, until a router advertises another
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
+/// An IPv6 address of an interface
+#[derive(Copy, Clone)]
+pub struct Inet6Addr {
+    pub addr: Ipv6Addr,
+    pub prefix_len: u8,
+    /// When duplicate address detection ends, while it runs and the address is not used
+    pub tentative: Option<Duration>,
+    /// When an address from a router advertisement expires, or `None` if it does not
+    pub expires: Option<Duration>,
+}
+
+/// A network interface, added by the driver of its NIC
+///
+/// The interface's subnet is routed to it directly, without a route in the table.
+pub struct Interface {
+    /// The name, `eth0` for the first NIC found, or `lo` for the loopback interface
+    pub name: String,
+    pub mac: MacAddr,
+    /// The address, or `UNSPECIFIED_ADDR` if the interface is not configured
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// The largest packet the link carries
+    pub mtu: usize,
+    /// IPv6 addresses, starting with the link-local address
+    pub addrs6: Vec<Inet6Addr>,
+    /// The IPv6 default router learned from router advertisements, and when it expires
+    pub router6: Option<(Ipv6Addr, Duration)>,
+    /// The hop limit of IPv6 packets sent on the link
+    pub hop_limit6: u8,
+    /// The NIC, for opening `network:` resources on it
+    pub nic: *mut NetworkScheme,
+    /// Frames sent on the interface come back to it, so it has no neighbours to resolve
+    pub loopback: bool,
+}
+
+impl Interface {
+    pub fn is_configured(&self) -> bool {
+        ! self.addr.equals(UNSPECIFIED_ADDR)
+    }
+
+    /// Whether an address is on the interface's subnet
+    pub fn on_subnet(&self, addr: Ipv4Addr) -> bool {
+        self.is_configured() && addr.to_u32() & self.netmask.to_u32() == self.addr.to_u32() & self.netmask.to_u32()
+    }
+
+    /// The broadcast address of the interface's subnet
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.addr.to_u32() | ! self.netmask.to_u32())
+    }
+
+    /// Whether an IPv6 address is one of the interface's, and not tentative
+    pub fn has_addr6(&self, addr: Ipv6Addr) -> bool {
+        self.addrs6.iter().any(|inet6| inet6.tentative.is_none() && inet6.addr.equals(addr))
+    }
+
+    /// Whether an IPv6 address is on the link, being link-local or in the prefix of one of the
+    /// interface's addresses
+    pub fn on_link6(&self, addr: Ipv6Addr) -> bool {
+        addr.is_link_local() || self.addrs6.iter().any(|inet6| ! inet6.addr.is_link_local() && inet6.addr.matches(addr, inet6.prefix_len))
+    }
+
+    /// The address IPv6 packets to `dst` are sent from
+    ///
+    /// Link-local and link-scope multicast destinations get the link-local address, and others
+    /// an address in their prefix or else any global address.
+    pub fn src6(&self, dst: Ipv6Addr) -> Option<Ipv6Addr> {
+        let usable = || self.addrs6.iter().filter(|inet6| inet6.tentative.is_none());
+
+        if dst.is_link_local() || (dst.is_multicast() && dst.bytes[1] & 0xF == 2) {
+            return usable().find(|inet6| inet6.addr.is_link_local()).map(|inet6| inet6.addr);
+        }
+
+        usable().find(|inet6| ! inet6.addr.is_link_local() && inet6.addr.matches(dst, inet6.prefix_len))
+            .or_else(|| usable().find(|inet6| ! inet6.addr.is_link_local()))
+            .map(|inet6| inet6.addr)
+    }
+}
+
+/// Add the interface of a NIC, returning its name
+///
+/// The first interface starts with the default address and a default route, so a single NIC
+/// works without configuration. Later ones start unconfigured. Every interface starts with a
+/// tentative IPv6 link-local address, which is checked for duplicates once the network is up.
+pub fn add(mac: MacAddr, nic: *mut NetworkScheme) -> String {
+    let interfaces = unsafe { &mut *::env().interfaces.get() };
+    let count = interfaces.iter().filter(|interface| ! interface.loopback).count();
+    let name = format!("eth{}", count);
+
+    let first = count == 0;
+    interfaces.push(Interface {
+        name: name.clone(),
+        mac: mac,
+        addr: if first { DEFAULT_ADDR } else { UNSPECIFIED_ADDR },
+        netmask: if first { DEFAULT_NETMASK } else { UNSPECIFIED_ADDR },
+        mtu: ETHERNET_MTU,
+        addrs6: vec![Inet6Addr {
+            addr: Ipv6Addr::link_local(mac),
+            prefix_len: 64,
+            tentative: Some(Duration::new(0, 0)),
+            expires: None,
+        }],
+        router6: None,
+        hop_limit6: DEFAULT_HOP_LIMIT,
+        nic: nic,
+        loopback: false,
+    });
+
+    if first {
+        unsafe { &mut *::env().routes.get() }.add(Route {
+            dst: UNSPECIFIED_ADDR,
+            netmask: UNSPECIFIED_ADDR,
+            gateway: Some(DEFAULT_ROUTER),
+            interface: name.clone(),
+        });
+    }
+
+    name
+}
+
+/// Add the loopback interface, `lo`, with `127.0.0.1/8` and `::1`, returning its name
+pub fn add_loopback(nic: *mut NetworkScheme) -> String {
+    let name: String = "lo".into();
+    unsafe { &mut *::env().interfaces.get() }.push(Interface {
+        name: name.clone(),
+        mac: MacAddr { bytes: [0; 6] },
+        addr: LOOPBACK_ADDR,
+        netmask: LOOPBACK_NETMASK,
+        mtu: LOOPBACK_MTU,
+        addrs6: vec![Inet6Addr {
+            addr: LOOPBACK_IPV6_ADDR,
+            prefix_len: 128,
+            tentative: None,
+            expires: None,
+        }],
+        router6: None,
+        hop_limit6: DEFAULT_HOP_LIMIT,
+        nic: nic,
+        loopback: true,
+    });
+    name
+}
+
+/// Find an interface by name
+pub fn find(name: &str) -> Option<&'static mut Interface> {
+    unsafe { &mut *::env().interfaces.get() }.iter_mut().find(|interface| interface.name == name)
+}
+
+/// The interface with an address, where the loopback interface has every address on its subnet
+pub fn by_addr(addr: Ipv4Addr) -> Option<&'static mut Interface> {
+    unsafe { &mut *::env().interfaces.get() }.iter_mut().find(|interface| {
+        interface.is_configured() && (interface.addr.equals(addr) || (interface.loopback && interface.on_subnet(addr)))
+    })
+}
+
+/// The interface with a MAC address
+pub fn by_mac(mac: MacAddr) -> Option<&'static mut Interface> {
+    unsafe { &mut *::env().interfaces.get() }.iter_mut().find(|interface| interface.mac.equals(mac))
+}
+
+/// The interface frames go out on when none is given, which is the first that is not loopback,
+/// or the loopback interface if there is no other
+pub fn default() -> Option<&'static mut Interface> {
+    let interfaces = unsafe { &mut *::env().interfaces.get() };
+    let i = interfaces.iter().position(|interface| ! interface.loopback).unwrap_or(0);
+    interfaces.get_mut(i)
+}
@@ -1,4 +1,5 @@
 use common::slice::GetSlice;
+use common::time::Duration;
 
 use collections::slice;
 use collections::vec::Vec;
@@ -7,6 +8,22 @@ use core::mem;
 
 use network::common::*;
 
+/// Don't fragment
+pub const IP_DF: u16 = 0x4000;
+/// More fragments follow
+pub const IP_MF: u16 = 0x2000;
+/// The offset of a fragment, in units of 8 bytes
+pub const IP_OFFSET: u16 = 0x1FFF;
+
+/// Seconds a partly reassembled packet is kept
+const REASSEMBLY_TIMEOUT: i64 = 30;
+/// Bytes of fragments held at once
+const REASSEMBLY_MEMORY: usize = 256 * 1024;
+/// Packets reassembled at once
+const REASSEMBLY_FLOWS: usize = 64;
+/// Largest packet, less its header
+const MAX_DATA: usize = 65535 - 20;
+
 #[derive(Copy, Clone)]
 #[repr(packed)]
 pub struct Ipv4Header {
@@ -46,6 +63,243 @@ impl FromBytes for Ipv4 {
     }
 }
 
+impl Ipv4 {
+    /// Is one fragment of a larger packet
+    pub fn is_fragment(&self) -> bool {
+        self.header.flags_fragment.get() & (IP_MF | IP_OFFSET) != 0
+    }
+
+    /// Compute the header checksum
+    pub fn checksum(&mut self) {
+        unsafe {
+            self.header.checksum.data = 0;
+
+            let header_ptr: *const Ipv4Header = &self.header;
+            self.header.checksum.data =
+                Checksum::compile(Checksum::sum(header_ptr as usize, mem::size_of::<Ipv4Header>()) +
+                                  Checksum::sum(self.options.as_ptr() as usize, self.options.len()));
+        }
+    }
+
+    /// Split into fragments of at most `mtu` bytes, with their checksums computed
+    ///
+    /// Only the first fragment carries the options.
+    pub fn fragment(&self, mtu: usize) -> Vec<Ipv4> {
+        let mut fragments = Vec::new();
+
+        let mut offset = 0;
+        while offset < self.data.len() || offset == 0 {
+            let options = if offset == 0 {
+                self.options.clone()
+            } else {
+                Vec::new()
+            };
+            let header_len = mem::size_of::<Ipv4Header>() + options.len();
+
+            // Every fragment but the last holds a multiple of 8 bytes
+            let mut len = self.data.len() - offset;
+            let mut flags = self.header.flags_fragment.get() & IP_DF;
+            if header_len + len > mtu {
+                len = (mtu - header_len) & !7;
+                flags |= IP_MF;
+            }
+
+            let mut fragment = Ipv4 {
+                header: self.header,
+                options: options,
+                data: self.data[offset .. offset + len].to_vec(),
+            };
+            fragment.header.ver_hlen = 0x40 | (header_len / 4 & 0xF) as u8;
+            fragment.header.len.set((header_len + len) as u16);
+            fragment.header.flags_fragment.set(flags | (offset / 8) as u16);
+            fragment.checksum();
+            fragments.push(fragment);
+
+            offset += len;
+            if len == 0 {
+                break;
+            }
+        }
+
+        fragments
+    }
+}
+
+/// The fragments of one packet
+struct Flow {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    proto: u8,
+    id: u16,
+    /// The header and options of the first fragment
+    first: Option<(Ipv4Header, Vec<u8>)>,
+    /// Fragment data by offset, in order
+    fragments: Vec<(usize, Vec<u8>)>,
+    /// The length of the data, once the last fragment arrived
+    total: Option<usize>,
+    /// Bytes of data held
+    size: usize,
+    /// When the flow is dropped
+    expires: Duration,
+}
+
+impl Flow {
+    fn matches(&self, header: &Ipv4Header) -> bool {
+        self.id == header.id.get() && self.proto == header.proto && self.src.equals(header.src) && self.dst.equals(header.dst)
+    }
+
+    /// The packet, if every fragment arrived
+    fn complete(&self) -> Option<Ipv4> {
+        let total = match self.total {
+            Some(total) => total,
+            None => return None,
+        };
+        let &(ref header, ref options) = match self.first {
+            Some(ref first) => first,
+            None => return None,
+        };
+        if self.size != total {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(total);
+        for &(_, ref fragment) in self.fragments.iter() {
+            data.extend_from_slice(fragment);
+        }
+
+        let mut packet = Ipv4 {
+            header: *header,
+            options: options.clone(),
+            data: data,
+        };
+        let header_len = mem::size_of::<Ipv4Header>() + packet.options.len();
+        packet.header.len.set((header_len + total) as u16);
+        packet.header.flags_fragment.set(header.flags_fragment.get() & IP_DF);
+        packet.checksum();
+        Some(packet)
+    }
+}
+
+/// Reassembles fragmented packets
+///
+/// Overlapping fragments drop the whole packet, rather than guess which copy of the data is
+/// right. Packets missing fragments for `REASSEMBLY_TIMEOUT` are dropped, as are the oldest ones
+/// when `REASSEMBLY_MEMORY` or `REASSEMBLY_FLOWS` would be exceeded.
+pub struct Ipv4Reassembly {
+    flows: Vec<Flow>,
+    /// Bytes of data held by every flow
+    memory: usize,
+}
+
+impl Ipv4Reassembly {
+    pub fn new() -> Ipv4Reassembly {
+        Ipv4Reassembly {
+            flows: Vec::new(),
+            memory: 0,
+        }
+    }
+
+    fn remove(&mut self, i: usize) {
+        let flow = self.flows.remove(i);
+        self.memory -= flow.size;
+    }
+
+    /// Take a packet, returning it if it is whole or the packet it completes
+    pub fn input(&mut self, packet: Ipv4) -> Option<Ipv4> {
+        if ! packet.is_fragment() {
+            return Some(packet);
+        }
+
+        let now = Duration::monotonic();
+        let mut i = 0;
+        while i < self.flows.len() {
+            if now >= self.flows[i].expires {
+                self.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        let flags = packet.header.flags_fragment.get();
+        let offset = (flags & IP_OFFSET) as usize * 8;
+        let more = flags & IP_MF == IP_MF;
+        let end = offset + packet.data.len();
+        if packet.data.is_empty() || end > MAX_DATA || (more && packet.data.len() % 8 != 0) {
+            return None;
+        }
+
+        let i = match self.flows.iter().position(|flow| flow.matches(&packet.header)) {
+            Some(i) => i,
+            None => {
+                if self.flows.len() >= REASSEMBLY_FLOWS {
+                    self.remove(0);
+                }
+                self.flows.push(Flow {
+                    src: packet.header.src,
+                    dst: packet.header.dst,
+                    proto: packet.header.proto,
+                    id: packet.header.id.get(),
+                    first: None,
+                    fragments: Vec::new(),
+                    total: None,
+                    size: 0,
+                    expires: now + Duration::new(REASSEMBLY_TIMEOUT, 0),
+                });
+                self.flows.len() - 1
+            }
+        };
+
+        // Check the fragment agrees with the others
+        let valid = {
+            let flow = &self.flows[i];
+            let overlaps = flow.fragments.iter().any(|&(start, ref data)| offset < start + data.len() && start < end);
+            let beyond = match flow.total {
+                Some(total) => end > total || (! more && end != total),
+                None => ! more && flow.fragments.iter().any(|&(start, ref data)| start + data.len() > end),
+            };
+            ! overlaps && ! beyond
+        };
+        if ! valid {
+            self.remove(i);
+            return None;
+        }
+
+        // Make room, dropping the oldest other packets
+        let mut i = i;
+        while self.memory + packet.data.len() > REASSEMBLY_MEMORY {
+            if self.flows.len() <= 1 {
+                self.remove(i);
+                return None;
+            }
+            let oldest = if i == 0 { 1 } else { 0 };
+            self.remove(oldest);
+            if oldest < i {
+                i -= 1;
+            }
+        }
+
+        self.memory += packet.data.len();
+        let complete = {
+            let flow = &mut self.flows[i];
+            if ! more {
+                flow.total = Some(end);
+            }
+            if offset == 0 {
+                flow.first = Some((packet.header, packet.options));
+            }
+            flow.size += packet.data.len();
+            let position = flow.fragments.iter().position(|&(start, _)| start > offset).unwrap_or(flow.fragments.len());
+            flow.fragments.insert(position, (offset, packet.data));
+            flow.complete()
+        };
+
+        if complete.is_some() {
+            self.remove(i);
+        }
+        complete
+    }
+}
+
 impl ToBytes for Ipv4 {
     fn to_bytes(&self) -> Vec<u8> {
         unsafe {
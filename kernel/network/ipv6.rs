@@ -1,13 +1,119 @@
-// TODO
+use collections::slice;
+use collections::vec::Vec;
+
+use core::mem;
 
 use network::common::*;
 
+/// Hop by hop options, routing, fragment, authentication and destination options
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTHENTICATION: u8 = 51;
+const DESTINATION: u8 = 60;
+/// No header follows
+const NO_NEXT_HEADER: u8 = 59;
+
+/// Smallest MTU of a link carrying IPv6
+pub const IPV6_MIN_MTU: usize = 1280;
+
 #[derive(Copy, Clone)]
-pub struct Ipv6 {
-    pub version: n32, // also has traffic class and flow label, TODO
+#[repr(packed)]
+pub struct Ipv6Header {
+    /// The version, traffic class and flow label
+    pub version: n32,
+    /// The length of the payload, including extension headers
     pub len: n16,
     pub next_header: u8,
     pub hop_limit: u8,
     pub src: Ipv6Addr,
     pub dst: Ipv6Addr,
 }
+
+/// An IPv6 packet
+///
+/// Extension headers are skipped when a packet is read, leaving the protocol and data of the
+/// upper layer. Fragments of larger packets are not reassembled, and are dropped.
+pub struct Ipv6 {
+    pub header: Ipv6Header,
+    /// The protocol of `data`, after any extension headers
+    pub proto: u8,
+    pub data: Vec<u8>,
+}
+
+impl Ipv6 {
+    /// A packet with no extension headers
+    pub fn new(src: Ipv6Addr, dst: Ipv6Addr, proto: u8, hop_limit: u8, data: Vec<u8>) -> Ipv6 {
+        Ipv6 {
+            header: Ipv6Header {
+                version: n32::new(6 << 28),
+                len: n16::new(data.len() as u16),
+                next_header: proto,
+                hop_limit: hop_limit,
+                src: src,
+                dst: dst,
+            },
+            proto: proto,
+            data: data,
+        }
+    }
+}
+
+impl FromBytes for Ipv6 {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < mem::size_of::<Ipv6Header>() {
+            return None;
+        }
+
+        let header = unsafe { *(bytes.as_ptr() as *const Ipv6Header) };
+        let end = mem::size_of::<Ipv6Header>() + header.len.get() as usize;
+        if header.version.get() >> 28 != 6 || end > bytes.len() {
+            return None;
+        }
+
+        let mut proto = header.next_header;
+        let mut start = mem::size_of::<Ipv6Header>();
+        loop {
+            let len = match proto {
+                HOP_BY_HOP | ROUTING | DESTINATION if start + 2 <= end => (bytes[start + 1] as usize + 1) * 8,
+                AUTHENTICATION if start + 2 <= end => (bytes[start + 1] as usize + 2) * 4,
+                // Only a fragment that is the whole packet is accepted
+                FRAGMENT if start + 8 <= end => {
+                    if (bytes[start + 2] as u16) << 8 | (bytes[start + 3] as u16 & 0xF9) != 0 {
+                        return None;
+                    }
+                    8
+                },
+                HOP_BY_HOP | ROUTING | DESTINATION | AUTHENTICATION | FRAGMENT => return None,
+                NO_NEXT_HEADER => {
+                    start = end;
+                    break;
+                },
+                _ => break,
+            };
+            if start + len > end {
+                return None;
+            }
+            proto = bytes[start];
+            start += len;
+        }
+
+        Some(Ipv6 {
+            header: header,
+            proto: proto,
+            data: bytes[start .. end].to_vec(),
+        })
+    }
+}
+
+impl ToBytes for Ipv6 {
+    fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            let header_ptr: *const Ipv6Header = &self.header;
+            let mut ret = Vec::<u8>::from(slice::from_raw_parts(header_ptr as *const u8,
+                                                                mem::size_of::<Ipv6Header>()));
+            ret.extend_from_slice(&self.data);
+            ret
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use alloc::boxed::Box;
+
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use core::cell::UnsafeCell;
+
+use network::interface;
+use network::scheme::*;
+
+use fs::KScheme;
+
+/// The loopback interface, `lo`, which receives every frame sent on it
+///
+/// Frames are delivered to the resources on the interface as soon as they are written, so the
+/// whole stack works between local services without a NIC.
+pub struct Loopback {
+    resources: UnsafeCell<Vec<*mut NetworkResource>>,
+    inbound: VecDeque<Vec<u8>>,
+}
+
+impl Loopback {
+    pub fn new() -> Box<Self> {
+        let mut module = box Loopback {
+            resources: UnsafeCell::new(Vec::new()),
+            inbound: VecDeque::new(),
+        };
+
+        let name = interface::add_loopback(&mut *module as *mut Self);
+        syslog_info!(" + Loopback interface: {}", name);
+
+        module
+    }
+}
+
+impl KScheme for Loopback {}
+
+impl NetworkScheme for Loopback {
+    fn add(&mut self, resource: *mut NetworkResource) {
+        unsafe { &mut *self.resources.get() }.push(resource);
+    }
+
+    fn remove(&mut self, resource: *mut NetworkResource) {
+        unsafe { &mut *self.resources.get() }.retain(|ptr| *ptr != resource);
+    }
+
+    fn sync(&mut self) {
+        let resources = unsafe { &mut *self.resources.get() };
+
+        let nic = self as *mut Self as usize;
+        for resource in resources.iter().filter(|resource| unsafe { (***resource).sends_on(nic) }) {
+            while let Some(bytes) = unsafe { &mut *(**resource).outbound.get() }.pop_front() {
+                self.inbound.push_back(bytes);
+            }
+        }
+
+        while let Some(bytes) = self.inbound.pop_front() {
+            for resource in resources.iter() {
+                unsafe { (**resource).deliver(&bytes, "Loopback::sync") };
+            }
+        }
+    }
+}
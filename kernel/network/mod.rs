@@ -1,11 +1,16 @@
 pub mod common;
+pub mod demux;
 pub mod ethernet;
 pub mod intel8254x;
+pub mod interface;
 pub mod ipv4;
 pub mod ipv6;
+pub mod loopback;
+pub mod route;
 pub mod rtl8139;
 pub mod scheme;
 pub mod schemes;
+pub mod virtio;
 
 use collections::String;
 
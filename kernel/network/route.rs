@@ -0,0 +1,194 @@
+use collections::string::String;
+use collections::vec::Vec;
+
+use common::time::Duration;
+
+use network::common::{IpAddr, Ipv4Addr, Ipv6Addr, BROADCAST_IP_ADDR};
+use network::interface::{self, Interface};
+
+use system::error::{Error, Result, EADDRNOTAVAIL, ENETUNREACH};
+
+/// A route to the hosts `dst` masked by `netmask`, through a gateway or directly on an interface
+///
+/// A netmask of `0.0.0.0` is the default route, and `255.255.255.255` a host route.
+#[derive(Clone)]
+pub struct Route {
+    pub dst: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    pub interface: String,
+}
+
+impl Route {
+    fn matches(&self, addr: Ipv4Addr) -> bool {
+        addr.to_u32() & self.netmask.to_u32() == self.dst.to_u32() & self.netmask.to_u32()
+    }
+
+    /// The length of the prefix, for longest prefix matching
+    fn prefix(&self) -> u32 {
+        self.netmask.to_u32().count_ones()
+    }
+}
+
+/// Where a packet to a host goes
+pub struct NextHop {
+    /// The outgoing interface
+    pub interface: &'static mut Interface,
+    /// The gateway, or the host itself if it is on the interface's subnet
+    pub addr: Ipv4Addr,
+    /// The source address of packets to the host, which is the outgoing interface's address
+    pub src: Ipv4Addr,
+    /// Whether the packet is broadcast on the link instead of sent to one MAC address
+    pub broadcast: bool,
+}
+
+/// The IPv4 routing table
+pub struct RouteTable {
+    routes: Vec<Route>,
+}
+
+impl RouteTable {
+    pub fn new() -> RouteTable {
+        RouteTable {
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Add a route, replacing any to the same destination
+    pub fn add(&mut self, route: Route) {
+        self.remove(route.dst, route.netmask);
+        self.routes.push(route);
+    }
+
+    /// Remove the route to a destination, returning whether there was one
+    pub fn remove(&mut self, dst: Ipv4Addr, netmask: Ipv4Addr) -> bool {
+        let len = self.routes.len();
+        self.routes.retain(|route| ! (route.dst.equals(dst) && route.netmask.equals(netmask)));
+        self.routes.len() != len
+    }
+
+    /// Find the route to a host, by longest prefix match
+    ///
+    /// The subnets of configured interfaces are routes of their own, which win over routes in
+    /// the table with the same prefix. Routes through interfaces that do not exist are skipped.
+    /// Returns `ENETUNREACH` if no route matches.
+    pub fn lookup(&self, addr: Ipv4Addr) -> Result<NextHop> {
+        let interfaces = unsafe { &mut *::env().interfaces.get() };
+
+        // Limited broadcast has no route, it goes out on the default interface
+        if addr.equals(BROADCAST_IP_ADDR) {
+            if let Some(interface) = interface::default() {
+                let src = interface.addr;
+                return Ok(NextHop {
+                    interface: interface,
+                    addr: addr,
+                    src: src,
+                    broadcast: true,
+                });
+            }
+        }
+
+        let mut best: Option<(usize, u32, Option<Ipv4Addr>)> = None;
+        for (i, interface) in interfaces.iter().enumerate() {
+            if interface.on_subnet(addr) {
+                let prefix = interface.netmask.to_u32().count_ones();
+                if best.map_or(true, |(_, best_prefix, _)| prefix > best_prefix) {
+                    best = Some((i, prefix, None));
+                }
+            }
+        }
+        for route in self.routes.iter() {
+            if route.matches(addr) && best.map_or(true, |(_, best_prefix, _)| route.prefix() > best_prefix) {
+                if let Some(i) = interfaces.iter().position(|interface| interface.name == route.interface) {
+                    best = Some((i, route.prefix(), route.gateway));
+                }
+            }
+        }
+
+        match best {
+            Some((i, _, gateway)) => {
+                let interface = &mut interfaces[i];
+                let src = interface.addr;
+                let broadcast = gateway.is_none() && interface.on_subnet(addr) && addr.equals(interface.broadcast());
+                Ok(NextHop {
+                    interface: interface,
+                    addr: gateway.unwrap_or(addr),
+                    src: src,
+                    broadcast: broadcast,
+                })
+            },
+            None => Err(Error::new(ENETUNREACH)),
+        }
+    }
+}
+
+/// Find the route to a host in the kernel's table
+pub fn lookup(addr: Ipv4Addr) -> Result<NextHop> {
+    unsafe { & *::env().routes.get() }.lookup(addr)
+}
+
+/// Where an IPv6 packet to a host goes
+pub struct NextHop6 {
+    pub interface: &'static mut Interface,
+    /// The router, or the host itself if it is on the link
+    pub addr: Ipv6Addr,
+    pub src: Ipv6Addr,
+}
+
+/// Find the route to an IPv6 host
+///
+/// Hosts on the link of an interface are reached directly, with link-local and multicast
+/// addresses on the default interface, and others through the default router learned from
+/// router advertisements. Returns `ENETUNREACH` if there is no route, and `EADDRNOTAVAIL` if the
+/// interface has no address to send from yet.
+pub fn lookup6(addr: Ipv6Addr) -> Result<NextHop6> {
+    let interfaces = unsafe { &mut *::env().interfaces.get() };
+    let now = Duration::monotonic();
+
+    let direct = if addr.is_link_local() || addr.is_multicast() {
+        interface::default().map(|interface| (interface, addr))
+    } else {
+        let on_link = interfaces.iter().position(|interface| interface.on_link6(addr));
+        match on_link {
+            Some(i) => Some((&mut interfaces[i], addr)),
+            None => interfaces.iter_mut()
+                        .find(|interface| interface.router6.map_or(false, |(_, expires)| now < expires))
+                        .map(|interface| {
+                            let router = interface.router6.map_or(addr, |(router, _)| router);
+                            (interface, router)
+                        }),
+        }
+    };
+
+    match direct {
+        Some((interface, next_hop)) => match interface.src6(addr) {
+            Some(src) => Ok(NextHop6 {
+                interface: interface,
+                addr: next_hop,
+                src: src,
+            }),
+            None => Err(Error::new(EADDRNOTAVAIL)),
+        },
+        None => Err(Error::new(ENETUNREACH)),
+    }
+}
+
+/// The address packets to a host are sent from
+pub fn source(addr: IpAddr) -> Result<IpAddr> {
+    match addr {
+        IpAddr::V4(addr) => lookup(addr).map(|hop| IpAddr::V4(hop.src)),
+        IpAddr::V6(addr) => lookup6(addr).map(|hop| IpAddr::V6(hop.src)),
+    }
+}
+
+/// The URL of the `ip:` or `ip6:` resource exchanging packets of a protocol with a host
+pub fn url(addr: IpAddr, proto: u8) -> String {
+    match addr {
+        IpAddr::V4(addr) => format!("ip:{}/{:X}", addr.to_string(), proto),
+        IpAddr::V6(addr) => format!("ip6:{}/{:X}", addr.to_string(), proto),
+    }
+}
@@ -13,11 +13,10 @@ use drivers::pci::config::PciConfig;
 use drivers::io::{Io, Pio};
 
 use network::common::*;
+use network::interface;
 use network::scheme::*;
 
-use fs::{KScheme, Resource};
-
-use system::error::Result;
+use fs::KScheme;
 
 bitflags! {
     flags TsrFlags: u32 {
@@ -74,6 +73,8 @@ struct Txd {
 
 pub struct Rtl8139Port {
     pub idr: [Pio<u8>; 6],
+    /// The multicast filter, a bit for each hash of a multicast address
+    pub mar: [Pio<u32>; 2],
     pub rbstart: Pio<u32>,
     pub cr: Pio<u8>,
     pub capr: Pio<u16>,
@@ -94,6 +95,8 @@ impl Rtl8139Port {
                   Pio::<u8>::new(base + 0x03),
                   Pio::<u8>::new(base + 0x04),
                   Pio::<u8>::new(base + 0x05)],
+            mar: [Pio::<u32>::new(base + 0x08),
+                  Pio::<u32>::new(base + 0x0C)],
             rbstart: Pio::<u32>::new(base + 0x30),
             cr: Pio::<u8>::new(base + 0x37),
             capr: Pio::<u16>::new(base + 0x38),
@@ -161,7 +164,7 @@ impl Rtl8139 {
         self.port.cr.write(CR_RST.bits);
         while self.port.cr.read() & CR_RST.bits != 0 {}
 
-        MAC_ADDR = MacAddr {
+        let mac = MacAddr {
             bytes: [self.port.idr[0].read(),
                     self.port.idr[1].read(),
                     self.port.idr[2].read(),
@@ -169,7 +172,10 @@ impl Rtl8139 {
                     self.port.idr[4].read(),
                     self.port.idr[5].read()],
         };
-        syslog_info!("   - MAC: {}", &MAC_ADDR.to_string());
+        syslog_info!("   - MAC: {}", &mac.to_string());
+
+        let name = interface::add(mac, self as *mut Self);
+        syslog_info!("   - Interface: {}", name);
 
         let receive_buffer = memory::alloc(10240);
         self.port.rbstart.write(receive_buffer as u32);
@@ -184,6 +190,9 @@ impl Rtl8139 {
 
         self.port.imr.write((ISR_TOK | ISR_ROK).bits);
         self.port.cr.write((CR_RE | CR_TE).bits);
+        // Accept every multicast address, for IPv6 neighbour discovery
+        self.port.mar[0].write(0xFFFFFFFF);
+        self.port.mar[1].write(0xFFFFFFFF);
         self.port.rcr.write((RCR_WRAP | RCR_AR | RCR_AB | RCR_AM | RCR_APM).bits);
         self.port.tcr.writef(TCR_IFG.bits, true);
     }
@@ -239,14 +248,6 @@ impl Rtl8139 {
 }
 
 impl KScheme for Rtl8139 {
-    fn scheme(&self) -> &str {
-        "network"
-    }
-
-    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
-        Ok(NetworkResource::new(self))
-    }
-
     fn on_irq(&mut self, irq: u8) {
         if irq == self.irq {
             let isr = self.port.isr.read();
@@ -291,7 +292,8 @@ impl NetworkScheme for Rtl8139 {
         {
             let resources = unsafe { &mut *self.resources.get() };
 
-            for resource in resources.iter() {
+            let nic = self as *mut Self as usize;
+            for resource in resources.iter().filter(|resource| unsafe { (***resource).sends_on(nic) }) {
                 while let Some(bytes) = unsafe { &mut *(**resource).outbound.get() }.pop_front() {
                     self.outbound.push_back(bytes);
                 }
@@ -307,7 +309,7 @@ impl NetworkScheme for Rtl8139 {
 
             while let Some(bytes) = self.inbound.pop_front() {
                 for resource in resources.iter() {
-                    unsafe { (**resource).inbound.send(bytes.clone(), "Rtl8139::sync") };
+                    unsafe { (**resource).deliver(&bytes, "Rtl8139::sync") };
                 }
             }
         }
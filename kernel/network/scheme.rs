@@ -1,14 +1,20 @@
 use alloc::boxed::Box;
 
+use collections::string::String;
 use collections::vec::Vec;
 use collections::vec_deque::VecDeque;
 
 use core::cell::UnsafeCell;
 use core::ops::DerefMut;
 
-use fs::Resource;
+use common::to_num::ToNum;
 
-use system::error::Result;
+use fs::{KScheme, Resource};
+
+use network::interface;
+
+use system::error::{Error, Result, EAGAIN, ENOENT};
+use system::syscall::{O_NONBLOCK, POLLIN, POLLOUT};
 
 use sync::WaitQueue;
 
@@ -19,51 +25,94 @@ pub trait NetworkScheme {
 }
 
 pub struct NetworkResource {
+    /// The NIC written frames go out on
     pub nic: *mut NetworkScheme,
+    /// The NICs frames are received from
+    pub nics: Vec<*mut NetworkScheme>,
+    pub path: String,
     pub ptr: *mut NetworkResource,
     pub inbound: WaitQueue<Vec<u8>>,
     pub outbound: UnsafeCell<VecDeque<Vec<u8>>>,
+    /// The only ethertype received, or `None` for every frame
+    pub ethertype: Option<u16>,
+    /// Reads return `EAGAIN` instead of waiting for a frame
+    pub nonblock: bool,
 }
 
 impl NetworkResource {
-    pub fn new(nic: *mut NetworkScheme) -> Box<Self> {
+    /// Open a resource sending on `nic`, and receiving frames of `ethertype` from every NIC in `nics`
+    pub fn new(nic: *mut NetworkScheme, nics: Vec<*mut NetworkScheme>, path: String, ethertype: Option<u16>, flags: usize) -> Box<Self> {
         let mut ret = box NetworkResource {
             nic: nic,
+            nics: nics,
+            path: path,
             ptr: 0 as *mut NetworkResource,
             inbound: WaitQueue::new(),
             outbound: UnsafeCell::new(VecDeque::new()),
+            ethertype: ethertype,
+            nonblock: flags & O_NONBLOCK == O_NONBLOCK,
         };
 
         unsafe {
             ret.ptr = ret.deref_mut();
 
-            (*ret.nic).add(ret.ptr);
+            for nic in ret.nics.iter() {
+                (**nic).add(ret.ptr);
+            }
         }
 
         ret
     }
+
+    /// Whether written frames go out on a NIC, given the address of the NIC
+    pub fn sends_on(&self, nic: usize) -> bool {
+        self.nic as *mut u8 as usize == nic
+    }
+
+    /// Queue a frame received by a NIC, if it has the ethertype the resource takes
+    pub fn deliver(&self, bytes: &[u8], reason: &str) {
+        let accepted = match self.ethertype {
+            Some(ethertype) => bytes.len() >= 14 && ((bytes[12] as u16) << 8 | bytes[13] as u16) == ethertype,
+            None => true,
+        };
+        if accepted {
+            self.inbound.send(bytes.to_vec(), reason);
+        }
+    }
+
+    fn sync_all(&self) {
+        for nic in self.nics.iter() {
+            unsafe { (**nic).sync() };
+        }
+    }
 }
 
 impl Resource for NetworkResource {
     fn dup(&self) -> Result<Box<Resource>> {
         let mut ret = box NetworkResource {
             nic: self.nic,
+            nics: self.nics.clone(),
+            path: self.path.clone(),
             ptr: 0 as *mut NetworkResource,
             inbound: self.inbound.clone(),
             outbound: UnsafeCell::new(unsafe { & *self.outbound.get() }.clone()),
+            ethertype: self.ethertype,
+            nonblock: self.nonblock,
         };
 
         unsafe {
             ret.ptr = ret.deref_mut();
 
-            (*ret.nic).add(ret.ptr);
+            for nic in ret.nics.iter() {
+                (**nic).add(ret.ptr);
+            }
         }
 
         Ok(ret)
     }
 
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
-        let path = b"network:";
+        let path = self.path.as_bytes();
 
         let mut i = 0;
         while i < buf.len() && i < path.len() {
@@ -75,9 +124,16 @@ impl Resource for NetworkResource {
     }
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.sync_all();
         let bytes = unsafe {
-            (*self.nic).sync();
-            (*self.ptr).inbound.receive("NetworkResource::read")
+            if self.nonblock {
+                match (*self.ptr).inbound.inner().pop_front() {
+                    Some(bytes) => bytes,
+                    None => return Err(Error::new(EAGAIN)),
+                }
+            } else {
+                (*self.ptr).inbound.receive("NetworkResource::read")
+            }
         };
 
         let mut i = 0;
@@ -105,12 +161,61 @@ impl Resource for NetworkResource {
         }
         Ok(())
     }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        self.sync_all();
+        let ready = ! unsafe { (*self.ptr).inbound.inner() }.is_empty();
+        // Writes are queued, so never block
+        if ready {
+            Ok(events & (POLLIN | POLLOUT))
+        } else {
+            Ok(events & POLLOUT)
+        }
+    }
 }
 
 impl Drop for NetworkResource {
     fn drop(&mut self) {
-        unsafe {
-            (*self.nic).remove(self.ptr);
+        for nic in self.nics.iter() {
+            unsafe { (**nic).remove(self.ptr) };
+        }
+    }
+}
+
+/// The `network:` scheme, for raw frames on the interfaces
+///
+/// `network:IFACE` sends and receives on one interface. `network:` receives from every interface,
+/// and sends on the default one. A `/TYPE` after either, in hexadecimal, receives only frames of
+/// that ethertype, so they are not queued for readers that would drop them.
+pub struct InterfaceScheme;
+
+impl KScheme for InterfaceScheme {
+    fn scheme(&self) -> &str {
+        "network"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let mut parts = url.splitn(2, ":").nth(1).unwrap_or("").splitn(2, '/');
+        let name = parts.next().unwrap_or("");
+        let ethertype = match parts.next() {
+            Some(ethertype_string) if ! ethertype_string.is_empty() => Some(ethertype_string.to_num_radix(16) as u16),
+            _ => None,
+        };
+
+        if name.is_empty() {
+            let interfaces = unsafe { & *::env().interfaces.get() };
+            match interface::default() {
+                Some(default) => {
+                    let nics = interfaces.iter().map(|interface| interface.nic).collect();
+                    Ok(NetworkResource::new(default.nic, nics, "network:".into(), ethertype, flags))
+                },
+                None => Err(Error::new(ENOENT)),
+            }
+        } else {
+            match interface::find(name) {
+                Some(interface) => Ok(NetworkResource::new(interface.nic, vec![interface.nic], format!("network:{}", name), ethertype, flags)),
+                None => Err(Error::new(ENOENT)),
+            }
         }
     }
 }
@@ -1,16 +1,48 @@
+use alloc::boxed::Box;
+
 use common::slice::GetSlice;
+use common::time::{Duration, NANOS_PER_MILLI};
 
+use collections::string::String;
 use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
 
-use core::{mem, slice};
+use core::{cmp, mem, slice};
 
-use arch::context::context_switch;
+use arch::context::{context_sleep, context_switch, Context};
 
 use network::common::*;
+use network::interface::{self, Interface, UNSPECIFIED_ADDR};
+
+use super::ndp;
+
+use fs::{KScheme, Resource, ResourceSeek};
 
-use fs::KScheme;
+use sync::WaitCondition;
 
-use system::syscall::O_RDWR;
+use system::error::{Error, Result, EADDRINUSE, EHOSTUNREACH, EINVAL, ENOENT};
+use system::syscall::{MODE_FILE, O_RDWR, Stat};
+
+/// Requests sent before a neighbour is unreachable
+const ARP_RETRIES: usize = 3;
+/// How long to wait for each reply, in milliseconds
+const ARP_TIMEOUT: i32 = 1000;
+/// How long a reply is trusted before the neighbour is stale, in seconds
+const REACHABLE_TIME: i64 = 30;
+/// How long a stale neighbour is kept if it is not used, in seconds
+const STALE_TIME: i64 = 600;
+/// Packets queued for each neighbour while it is resolved
+const PENDING_PACKETS: usize = 8;
+/// Neighbours the cache holds, though static entries are added past it
+const MAX_ENTRIES: usize = 512;
+/// How often the timers are checked
+const TIMER_INTERVAL: i32 = 100 * NANOS_PER_MILLI;
+/// Probes sent before taking an address, as in RFC 5227
+const PROBE_COUNT: usize = 2;
+/// How long to wait for a conflict after each probe, in milliseconds
+const PROBE_WAIT: i32 = 500;
+/// The least time between defending an address, in seconds
+const DEFEND_INTERVAL: i64 = 10;
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -57,41 +89,660 @@ impl ToBytes for Arp {
     }
 }
 
-pub struct ArpScheme;
+impl Arp {
+    fn new(oper: u16, src_mac: MacAddr, src_ip: Ipv4Addr, dst_mac: MacAddr, dst_ip: Ipv4Addr) -> Arp {
+        Arp {
+            header: ArpHeader {
+                htype: n16::new(1),
+                ptype: n16::new(0x800),
+                hlen: 6,
+                plen: 4,
+                oper: n16::new(oper),
+                src_mac: src_mac,
+                src_ip: src_ip,
+                dst_mac: dst_mac,
+                dst_ip: dst_ip,
+            },
+            data: Vec::new(),
+        }
+    }
+}
 
-impl KScheme for ArpScheme {
-    fn scheme(&self) -> &str {
-        "arp"
+/// Send an ARP packet on an interface, to `dst` or broadcast
+fn send(interface: &str, dst: MacAddr, arp: &Arp) -> Result<()> {
+    let mut link = try!(::env().open(&format!("ethernet:{}/806/{}", dst.to_string(), interface), O_RDWR));
+    link.write(&arp.to_bytes()).and(Ok(()))
+}
+
+/// Send a request for `addr` from an interface, to `dst` or broadcast
+///
+/// IPv6 neighbours are asked with a neighbour solicitation, multicast instead of broadcast.
+fn request(interface: &Interface, addr: IpAddr, dst: MacAddr) {
+    match addr {
+        IpAddr::V4(addr) => {
+            let arp = Arp::new(1, interface.mac, interface.addr, MacAddr { bytes: [0; 6] }, addr);
+            if let Err(err) = send(&interface.name, dst, &arp) {
+                debugln!("ARP: Request on {} failed: {}", interface.name, err);
+            }
+        },
+        IpAddr::V6(addr) => {
+            let dst = if dst.equals(BROADCAST_MAC_ADDR) { None } else { Some(dst) };
+            ndp::solicit(interface, addr, dst);
+        },
     }
 }
 
-impl ArpScheme {
-    pub fn reply_loop() {
-        while let Ok(mut link) = ::env().open("ethernet:/806", O_RDWR) {
-            loop {
-                let mut bytes = [0; 65536];
-                if let Ok(count) = link.read(&mut bytes) {
-                    if let Some(packet) = Arp::from_bytes(&bytes[..count]) {
-                        if packet.header.oper.get() == 1 && packet.header.dst_ip.equals(unsafe { IP_ADDR }) {
-                            let mut response = Arp {
-                                header: packet.header,
-                                data: packet.data.clone(),
-                            };
-                            response.header.oper.set(2);
-                            response.header.dst_mac = packet.header.src_mac;
-                            response.header.dst_ip = packet.header.src_ip;
-                            response.header.src_mac = unsafe { MAC_ADDR };
-                            response.header.src_ip =unsafe { IP_ADDR };
-
-                            let _ = link.write(&response.to_bytes());
-                        }
+/// The ethertype of packets to a neighbour
+fn ethertype(addr: IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "800",
+        IpAddr::V6(_) => "86DD",
+    }
+}
+
+/// Announce an interface's address, as a gratuitous ARP request
+fn announce(interface: &Interface) {
+    let arp = Arp::new(1, interface.mac, interface.addr, MacAddr { bytes: [0; 6] }, interface.addr);
+    if let Err(err) = send(&interface.name, BROADCAST_MAC_ADDR, &arp) {
+        debugln!("ARP: Announcement on {} failed: {}", interface.name, err);
+    }
+}
+
+/// The state of a neighbour, as in the neighbour unreachability detection of RFC 4861
+#[derive(Copy, Clone, PartialEq)]
+pub enum ArpState {
+    /// Requests are being broadcast, and packets to the neighbour are queued
+    Incomplete,
+    /// A reply was received recently
+    Reachable,
+    /// The MAC address is used, but is checked again on the next use
+    Stale,
+    /// The MAC address is used while unicast requests check it
+    Probe,
+    /// Added through `arp:`, and never expires
+    Static,
+}
+
+impl ArpState {
+    fn name(&self) -> &'static str {
+        match *self {
+            ArpState::Incomplete => "incomplete",
+            ArpState::Reachable => "reachable",
+            ArpState::Stale => "stale",
+            ArpState::Probe => "probe",
+            ArpState::Static => "static",
+        }
+    }
+}
+
+/// A neighbour on the network of an interface
+pub struct ArpEntry {
+    pub interface: String,
+    pub ip: IpAddr,
+    pub mac: MacAddr,
+    pub state: ArpState,
+    /// When the state times out
+    deadline: Duration,
+    /// Requests sent in this state
+    retries: usize,
+    /// Packets waiting for the MAC address
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl ArpEntry {
+    /// The MAC address, if it is known
+    fn mac(&self) -> Option<MacAddr> {
+        if self.state == ArpState::Incomplete {
+            None
+        } else {
+            Some(self.mac)
+        }
+    }
+}
+
+/// An address an interface is probing before it takes it
+struct Claim {
+    interface: String,
+    ip: Ipv4Addr,
+    conflict: bool,
+}
+
+/// The neighbour cache, mapping the addresses of hosts on our networks to MAC addresses
+///
+/// IPv4 neighbours are resolved with ARP, and IPv6 neighbours with neighbour discovery, which
+/// feeds the cache through `update`. It holds up to `MAX_ENTRIES` neighbours, evicting old ones
+/// to make room for new ones.
+pub struct ArpCache {
+    entries: Vec<ArpEntry>,
+    claims: Vec<Claim>,
+    /// When an address was last defended against a conflict
+    defended: Option<Duration>,
+    /// Notified when a neighbour is resolved or fails to resolve
+    pub condition: WaitCondition,
+}
+
+impl ArpCache {
+    pub fn new() -> ArpCache {
+        ArpCache {
+            entries: Vec::new(),
+            claims: Vec::new(),
+            defended: None,
+            condition: WaitCondition::new(),
+        }
+    }
+
+    pub fn entries(&self) -> &[ArpEntry] {
+        &self.entries
+    }
+
+    fn position(&self, interface: &str, ip: IpAddr) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.interface == interface && entry.ip.equals(ip))
+    }
+
+    /// Make room for a new entry if the cache is full, returning whether there is room
+    ///
+    /// The stale neighbour that expires first is evicted, or failing that the reachable or probed
+    /// one that does. Neighbours being resolved and static entries are kept.
+    fn make_room(&mut self) -> bool {
+        if self.entries.len() < MAX_ENTRIES {
+            return true;
+        }
+
+        let mut oldest: Option<usize> = None;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.state == ArpState::Incomplete || entry.state == ArpState::Static {
+                continue;
+            }
+            let older = match oldest {
+                Some(j) => {
+                    let other = &self.entries[j];
+                    let stale = entry.state == ArpState::Stale;
+                    let other_stale = other.state == ArpState::Stale;
+                    (stale && ! other_stale) || (stale == other_stale && entry.deadline < other.deadline)
+                },
+                None => true,
+            };
+            if older {
+                oldest = Some(i);
+            }
+        }
+
+        match oldest {
+            Some(i) => {
+                self.entries.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The MAC address of a neighbour, starting to resolve it if it is unknown
+    ///
+    /// Using a stale neighbour starts checking it with unicast requests. Every address on the
+    /// loopback interface resolves to its own MAC address.
+    pub fn resolve(&mut self, interface: &Interface, ip: IpAddr) -> Option<MacAddr> {
+        if interface.loopback {
+            return Some(interface.mac);
+        }
+
+        let now = Duration::monotonic();
+        match self.position(&interface.name, ip) {
+            Some(i) => {
+                let entry = &mut self.entries[i];
+                if entry.state == ArpState::Stale {
+                    entry.state = ArpState::Probe;
+                    entry.retries = 1;
+                    entry.deadline = now + Duration::new(0, ARP_TIMEOUT * NANOS_PER_MILLI);
+                    request(interface, ip, entry.mac);
+                }
+                entry.mac()
+            },
+            None => {
+                if ! self.make_room() {
+                    return None;
+                }
+                self.entries.push(ArpEntry {
+                    interface: interface.name.clone(),
+                    ip: ip,
+                    mac: BROADCAST_MAC_ADDR,
+                    state: ArpState::Incomplete,
+                    deadline: now + Duration::new(0, ARP_TIMEOUT * NANOS_PER_MILLI),
+                    retries: 1,
+                    pending: VecDeque::new(),
+                });
+                request(interface, ip, BROADCAST_MAC_ADDR);
+                None
+            },
+        }
+    }
+
+    /// Whether a neighbour is in the cache
+    pub fn contains(&self, interface: &str, ip: IpAddr) -> bool {
+        self.position(interface, ip).is_some()
+    }
+
+    /// Whether a neighbour is being resolved
+    pub fn resolving(&self, interface: &str, ip: IpAddr) -> bool {
+        match self.position(interface, ip) {
+            Some(i) => self.entries[i].state == ArpState::Incomplete,
+            None => false,
+        }
+    }
+
+    /// Send an IPv4 or IPv6 packet to a neighbour, queueing it until the neighbour is resolved
+    ///
+    /// The oldest queued packet is dropped when `PENDING_PACKETS` are queued.
+    pub fn output(&mut self, interface_name: &str, ip: IpAddr, packet: Vec<u8>) -> Result<()> {
+        let interface = try!(interface::find(interface_name).ok_or(Error::new(ENOENT)));
+        match self.resolve(interface, ip) {
+            Some(mac) => {
+                let mut link = try!(::env().open(&format!("ethernet:{}/{}/{}", mac.to_string(), ethertype(ip), interface_name), O_RDWR));
+                link.write(&packet).and(Ok(()))
+            },
+            None => {
+                if let Some(i) = self.position(interface_name, ip) {
+                    let pending = &mut self.entries[i].pending;
+                    if pending.len() >= PENDING_PACKETS {
+                        pending.pop_front();
                     }
+                    pending.push_back(packet);
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Add a static entry, replacing any other entry for the address
+    pub fn add_static(&mut self, interface: &str, ip: IpAddr, mac: MacAddr) {
+        self.update(interface, ip, mac, ArpState::Static);
+    }
+
+    /// Remove the entries for an address, returning whether there were any
+    pub fn remove(&mut self, ip: IpAddr) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| ! entry.ip.equals(ip));
+        self.entries.len() != len
+    }
+
+    /// Record the MAC address of a neighbour, sending the packets queued for it
+    ///
+    /// A new neighbour is not added if the cache is full of neighbours in use.
+    pub fn update(&mut self, interface: &str, ip: IpAddr, mac: MacAddr, state: ArpState) {
+        let now = Duration::monotonic();
+        let i = match self.position(interface, ip) {
+            Some(i) => i,
+            None => {
+                if ! self.make_room() && state != ArpState::Static {
+                    return;
+                }
+                self.entries.push(ArpEntry {
+                    interface: interface.into(),
+                    ip: ip,
+                    mac: mac,
+                    state: state,
+                    deadline: now,
+                    retries: 0,
+                    pending: VecDeque::new(),
+                });
+                self.entries.len() - 1
+            }
+        };
+
+        let entry = &mut self.entries[i];
+        // Replies do not replace static entries
+        if entry.state == ArpState::Static && state != ArpState::Static {
+            return;
+        }
+        entry.mac = mac;
+        entry.state = state;
+        entry.retries = 0;
+        entry.deadline = now + Duration::new(REACHABLE_TIME, 0);
+
+        if ! entry.pending.is_empty() {
+            match ::env().open(&format!("ethernet:{}/{}/{}", mac.to_string(), ethertype(ip), interface), O_RDWR) {
+                Ok(mut link) => for packet in entry.pending.drain(..) {
+                    let _ = link.write(&packet);
+                },
+                Err(err) => debugln!("ARP: Failed to send queued packets to {}: {}", ip.to_string(), err),
+            }
+            entry.pending.clear();
+        }
+
+        self.condition.notify("ArpCache::update");
+    }
+
+    /// Probe for an address before an interface takes it, then announce it, as in RFC 5227
+    ///
+    /// Returns `EADDRINUSE` if another host has the address, or probes for it at the same time.
+    pub fn claim(&mut self, interface: &Interface, ip: Ipv4Addr) -> Result<()> {
+        self.claims.push(Claim {
+            interface: interface.name.clone(),
+            ip: ip,
+            conflict: false,
+        });
+
+        // Probes have no sender address, so they do not update other hosts' caches
+        let probe = Arp::new(1, interface.mac, UNSPECIFIED_ADDR, MacAddr { bytes: [0; 6] }, ip);
+        let mut conflict = false;
+        for _ in 0..PROBE_COUNT {
+            if let Err(err) = send(&interface.name, BROADCAST_MAC_ADDR, &probe) {
+                debugln!("ARP: Probe on {} failed: {}", interface.name, err);
+            }
+            context_sleep("ArpCache::claim", Duration::new(0, PROBE_WAIT * NANOS_PER_MILLI));
+
+            conflict = self.claims.iter().any(|claim| claim.interface == interface.name && claim.ip.equals(ip) && claim.conflict);
+            if conflict {
+                break;
+            }
+        }
+
+        self.claims.retain(|claim| ! (claim.interface == interface.name && claim.ip.equals(ip)));
+        if conflict {
+            syslog_info!("ARP: {} is already in use on {}", ip.to_string(), interface.name);
+            Err(Error::new(EADDRINUSE))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take an ARP packet, learning the sender and answering requests for our addresses
+    ///
+    /// The interface the packet arrived on is the one with the requested address, or the one
+    /// whose subnet the sender is on.
+    fn input(&mut self, packet: &Arp) {
+        let header = packet.header;
+        if header.htype.get() != 1 || header.ptype.get() != 0x800 {
+            return;
+        }
+
+        // Another host probing for, or using, an address being claimed
+        for claim in self.claims.iter_mut() {
+            if claim.ip.equals(header.src_ip) || (header.src_ip.equals(UNSPECIFIED_ADDR) && claim.ip.equals(header.dst_ip)) {
+                if interface::find(&claim.interface).map_or(false, |interface| ! interface.mac.equals(header.src_mac)) {
+                    claim.conflict = true;
+                }
+            }
+        }
+
+        // Another host using one of our addresses
+        if let Some(interface) = interface::by_addr(header.src_ip) {
+            if ! interface.mac.equals(header.src_mac) {
+                syslog_info!("ARP: {} on {} is also used by {}", header.src_ip.to_string(), interface.name, header.src_mac.to_string());
+                let now = Duration::monotonic();
+                if self.defended.map_or(true, |defended| now >= defended + Duration::new(DEFEND_INTERVAL, 0)) {
+                    self.defended = Some(now);
+                    announce(interface);
+                }
+            }
+            return;
+        }
+
+        let target = interface::by_addr(header.dst_ip);
+
+        // Probes have no sender to learn, but are answered so the prober sees the conflict
+        if ! header.src_ip.equals(UNSPECIFIED_ADDR) {
+            let interface = match target {
+                Some(ref interface) => Some(interface.name.clone()),
+                None => unsafe { & *::env().interfaces.get() }.iter()
+                            .find(|interface| interface.on_subnet(header.src_ip))
+                            .map(|interface| interface.name.clone()),
+            };
+
+            if let Some(name) = interface {
+                // Update a known sender, and add one that is asking for us, as in RFC 826
+                if target.is_some() || self.position(&name, IpAddr::V4(header.src_ip)).is_some() {
+                    self.update(&name, IpAddr::V4(header.src_ip), header.src_mac, ArpState::Reachable);
+                }
+            }
+        }
+
+        if let Some(interface) = target {
+            if header.oper.get() == 1 && ! header.dst_ip.equals(header.src_ip) {
+                let reply = Arp::new(2, interface.mac, interface.addr, header.src_mac, header.src_ip);
+                if let Err(err) = send(&interface.name, header.src_mac, &reply) {
+                    debugln!("ARP: Reply on {} failed: {}", interface.name, err);
+                }
+            }
+        }
+    }
+
+    /// Retry requests, age reachable neighbours, and forget failed and unused ones
+    fn on_timer(&mut self) {
+        let now = Duration::monotonic();
+        let mut failed = false;
+
+        let mut i = 0;
+        while i < self.entries.len() {
+            let remove = {
+                let entry = &mut self.entries[i];
+                if entry.state == ArpState::Static || now < entry.deadline {
+                    false
                 } else {
-                    break;
+                    match entry.state {
+                        ArpState::Incomplete | ArpState::Probe => {
+                            if entry.retries < ARP_RETRIES {
+                                entry.retries += 1;
+                                entry.deadline = now + Duration::new(0, ARP_TIMEOUT * NANOS_PER_MILLI);
+                                if let Some(interface) = interface::find(&entry.interface) {
+                                    let dst = if entry.state == ArpState::Probe { entry.mac } else { BROADCAST_MAC_ADDR };
+                                    request(interface, entry.ip, dst);
+                                }
+                                false
+                            } else {
+                                debugln!("ARP: No reply from {}", entry.ip.to_string());
+                                true
+                            }
+                        },
+                        ArpState::Reachable => {
+                            entry.state = ArpState::Stale;
+                            entry.deadline = now + Duration::new(STALE_TIME, 0);
+                            false
+                        },
+                        _ => true,
+                    }
+                }
+            };
+
+            if remove {
+                self.entries.remove(i);
+                failed = true;
+            } else {
+                i += 1;
+            }
+        }
+
+        if failed {
+            self.condition.notify("ArpCache::on_timer");
+        }
+    }
+
+    /// Read ARP packets from every interface
+    fn receive_loop() {
+        while let Ok(mut link) = ::env().open(&format!("ethernet:{}/806", BROADCAST_MAC_ADDR.to_string()), O_RDWR) {
+            loop {
+                let mut bytes = [0; 65536];
+                match link.read(&mut bytes) {
+                    Ok(count) => if let Some(packet) = Arp::from_bytes(&bytes[..count]) {
+                        unsafe { &mut *::env().arp.get() }.input(&packet);
+                    },
+                    Err(_) => break,
                 }
             }
             unsafe { context_switch() };
         }
         debug!("ARP: Failed to open ethernet:\n");
     }
+
+    /// Check the timers every `TIMER_INTERVAL`
+    fn timer_loop() {
+        loop {
+            context_sleep("ArpCache::timer_loop", Duration::new(0, TIMER_INTERVAL));
+
+            unsafe { &mut *::env().arp.get() }.on_timer();
+        }
+    }
+}
+
+/// Wait for a neighbour to be resolved, for `ARP_RETRIES` requests
+///
+/// Returns `EHOSTUNREACH` if it does not reply.
+pub fn resolve(interface: &Interface, ip: IpAddr) -> Result<MacAddr> {
+    let cache = unsafe { &mut *::env().arp.get() };
+    if let Some(mac) = cache.resolve(interface, ip) {
+        return Ok(mac);
+    }
+
+    while cache.resolving(&interface.name, ip) {
+        cache.condition.wait_for("arp::resolve", Duration::new(0, ARP_TIMEOUT * NANOS_PER_MILLI));
+    }
+
+    match cache.position(&interface.name, ip).and_then(|i| cache.entries[i].mac()) {
+        Some(mac) => Ok(mac),
+        None => Err(Error::new(EHOSTUNREACH)),
+    }
+}
+
+/// Take an address for an interface, probing for conflicts first and announcing it after
+pub fn set_addr(interface: &mut Interface, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
+    if ! addr.equals(UNSPECIFIED_ADDR) && ! addr.equals(interface.addr) {
+        try!(unsafe { &mut *::env().arp.get() }.claim(interface, addr));
+    }
+
+    interface.addr = addr;
+    interface.netmask = netmask;
+    if interface.is_configured() {
+        announce(interface);
+    }
+    Ok(())
+}
+
+/// The neighbour table, read as it was when opened
+pub struct ArpResource {
+    data: Vec<u8>,
+    seek: usize,
+}
+
+impl Resource for ArpResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box ArpResource {
+            data: self.data.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"arp:";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && self.seek < self.data.len() {
+            buf[i] = self.data[self.seek];
+            self.seek += 1;
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    /// Apply lines of `add IP MAC dev IFACE` or `del IP`
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let cache = unsafe { &mut *::env().arp.get() };
+        let text = String::from_utf8_lossy(buf);
+        for line in text.lines().map(|line| line.trim()).filter(|line| ! line.is_empty()) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match (words[0], words.len()) {
+                ("add", 5) if words[3] == "dev" => {
+                    let ip = try!(IpAddr::parse(words[1]).ok_or(Error::new(EINVAL)));
+                    let mac = try!(MacAddr::parse(words[2]).ok_or(Error::new(EINVAL)));
+                    if interface::find(words[4]).is_none() {
+                        return Err(Error::new(ENOENT));
+                    }
+                    cache.add_static(words[4], ip, mac);
+                },
+                ("del", 2) => {
+                    let ip = try!(IpAddr::parse(words[1]).ok_or(Error::new(EINVAL)));
+                    if ! cache.remove(ip) {
+                        return Err(Error::new(ENOENT));
+                    }
+                },
+                _ => return Err(Error::new(EINVAL)),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        self.seek = match pos {
+            ResourceSeek::Start(offset) => offset,
+            ResourceSeek::Current(offset) => cmp::max(0, self.seek as isize + offset) as usize,
+            ResourceSeek::End(offset) => cmp::max(0, self.data.len() as isize + offset) as usize,
+        };
+        self.seek = cmp::min(self.seek, self.data.len());
+        Ok(self.seek)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_size = self.data.len() as u32;
+        stat.st_mode = MODE_FILE;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The `arp:` scheme, listing the neighbour cache
+///
+/// Each line is `IP MAC dev IFACE STATE`, for IPv4 and IPv6 neighbours. Writing `add IP MAC dev IFACE` adds a static entry,
+/// and `del IP` removes the entries for an address.
+pub struct ArpScheme;
+
+impl ArpScheme {
+    /// Create the scheme, starting the contexts that receive ARP packets and run timers
+    pub fn new() -> Box<ArpScheme> {
+        Context::spawn("karp".into(),
+                       box move || {
+                           ArpCache::receive_loop();
+                       });
+
+        Context::spawn("karp_timer".into(),
+                       box move || {
+                           ArpCache::timer_loop();
+                       });
+
+        box ArpScheme
+    }
+}
+
+impl KScheme for ArpScheme {
+    fn scheme(&self) -> &str {
+        "arp"
+    }
+
+    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        if ! url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let mut string = String::new();
+        for entry in unsafe { & *::env().arp.get() }.entries().iter() {
+            let mac = match entry.mac() {
+                Some(mac) => mac.to_string(),
+                None => "-".into(),
+            };
+            string = string + &format!("{} {} dev {} {}\n", entry.ip.to_string(), mac, entry.interface, entry.state.name());
+        }
+
+        Ok(box ArpResource {
+            data: string.into_bytes(),
+            seek: 0,
+        })
+    }
 }
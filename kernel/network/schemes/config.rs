@@ -1,10 +1,346 @@
 use alloc::boxed::Box;
-use fs::{KScheme, Resource, SliceResource, SliceMutResource};
-use network::common::{DNS_ADDR, IP_ADDR, IP_ROUTER_ADDR, IP_SUBNET, MAC_ADDR};
-use system::error::{Error, ENOENT, Result};
-use system::syscall::{MODE_DIR, MODE_FILE};
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use core::cmp;
+
+use fs::{KScheme, Resource, ResourceSeek, SliceResource, SliceMutResource};
+use network::common::{Ipv4Addr, Ipv6Addr, MacAddr, DNS_ADDR};
+use network::interface::{self, Interface, UNSPECIFIED_ADDR};
+use network::route::Route;
+use super::arp;
+use super::ndp;
+
+use system::error::{Error, EINVAL, ENOENT, Result};
+use system::syscall::{MODE_DIR, MODE_FILE, Stat};
+
+/// A file of the network configuration
+#[derive(Copy, Clone, PartialEq)]
+enum NetConfigFile {
+    /// The interfaces, one per line, as `NAME MAC ADDR/PREFIX mtu MTU`, followed by
+    /// `inet6 ADDR/PREFIX` for each IPv6 address
+    Interfaces,
+    /// The routes, one per line, as `DST [via GATEWAY] dev IFACE`
+    Routes,
+    /// The address of the first interface, as 4 bytes
+    Ip,
+    /// The netmask of the first interface, as 4 bytes
+    IpSubnet,
+    /// The gateway of the default route, as 4 bytes
+    IpRouter,
+    /// The MAC address of the first interface, as 6 bytes
+    Mac,
+}
+
+fn parse_addr(string: &str) -> Result<Ipv4Addr> {
+    Ipv4Addr::parse(string).ok_or(Error::new(EINVAL))
+}
+
+/// Parse `ADDR/PREFIX`, or `ADDR` as a host, into an address and netmask
+fn parse_prefix(string: &str) -> Result<(Ipv4Addr, Ipv4Addr)> {
+    if string == "default" {
+        return Ok((UNSPECIFIED_ADDR, UNSPECIFIED_ADDR));
+    }
+
+    let mut parts = string.splitn(2, '/');
+    let addr = try!(parse_addr(parts.next().unwrap_or("")));
+    let prefix = match parts.next() {
+        Some(prefix) => match prefix.parse::<u32>() {
+            Ok(prefix) if prefix <= 32 => prefix,
+            _ => return Err(Error::new(EINVAL)),
+        },
+        None => 32,
+    };
+    let netmask = if prefix == 0 { 0 } else { 0xFFFFFFFF << (32 - prefix) };
+    Ok((addr, Ipv4Addr::from_u32(netmask)))
+}
+
+/// Parse an IPv6 `ADDR/PREFIX`, or `ADDR` as a /64
+fn parse_prefix6(string: &str) -> Result<(Ipv6Addr, u8)> {
+    let mut parts = string.splitn(2, '/');
+    let addr = try!(Ipv6Addr::parse(parts.next().unwrap_or("")).ok_or(Error::new(EINVAL)));
+    let prefix_len = match parts.next() {
+        Some(prefix) => match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= 128 => prefix,
+            _ => return Err(Error::new(EINVAL)),
+        },
+        None => 64,
+    };
+    Ok((addr, prefix_len))
+}
+
+fn format_prefix(addr: Ipv4Addr, netmask: Ipv4Addr) -> String {
+    if netmask.equals(UNSPECIFIED_ADDR) {
+        "default".into()
+    } else {
+        format!("{}/{}", addr.to_string(), netmask.to_u32().count_ones())
+    }
+}
+
+fn first_interface() -> Result<&'static mut Interface> {
+    interface::default().ok_or(Error::new(ENOENT))
+}
+
+impl NetConfigFile {
+    fn path(&self) -> &'static str {
+        match *self {
+            NetConfigFile::Interfaces => "netcfg:ifaces",
+            NetConfigFile::Routes => "netcfg:route",
+            NetConfigFile::Ip => "netcfg:ip",
+            NetConfigFile::IpSubnet => "netcfg:ip_subnet",
+            NetConfigFile::IpRouter => "netcfg:ip_router",
+            NetConfigFile::Mac => "netcfg:mac",
+        }
+    }
+
+    /// The current contents of the file
+    fn read(&self) -> Result<Vec<u8>> {
+        match *self {
+            NetConfigFile::Interfaces => {
+                let mut string = String::new();
+                for interface in unsafe { & *::env().interfaces.get() }.iter() {
+                    string = string + &format!("{} {} {} mtu {}", interface.name, interface.mac.to_string(),
+                                               format_prefix(interface.addr, interface.netmask), interface.mtu);
+                    for inet6 in interface.addrs6.iter() {
+                        string = string + &format!(" inet6 {}/{}", inet6.addr.to_string(), inet6.prefix_len);
+                    }
+                    string = string + "\n";
+                }
+                Ok(string.into_bytes())
+            },
+            NetConfigFile::Routes => {
+                let mut string = String::new();
+                // The subnets of interfaces are routed without entries in the table
+                for interface in unsafe { & *::env().interfaces.get() }.iter().filter(|interface| interface.is_configured()) {
+                    let subnet = Ipv4Addr::from_u32(interface.addr.to_u32() & interface.netmask.to_u32());
+                    string = string + &format!("{} dev {}\n", format_prefix(subnet, interface.netmask), interface.name);
+                }
+                for route in unsafe { & *::env().routes.get() }.routes().iter() {
+                    string = string + &format_prefix(route.dst, route.netmask);
+                    if let Some(gateway) = route.gateway {
+                        string = string + " via " + &gateway.to_string();
+                    }
+                    string = string + " dev " + &route.interface + "\n";
+                }
+                Ok(string.into_bytes())
+            },
+            NetConfigFile::Ip => Ok(try!(first_interface()).addr.bytes.to_vec()),
+            NetConfigFile::IpSubnet => Ok(try!(first_interface()).netmask.bytes.to_vec()),
+            NetConfigFile::IpRouter => {
+                let default = unsafe { & *::env().routes.get() }.routes().iter()
+                                  .find(|route| route.netmask.equals(UNSPECIFIED_ADDR))
+                                  .and_then(|route| route.gateway);
+                Ok(default.unwrap_or(UNSPECIFIED_ADDR).bytes.to_vec())
+            },
+            NetConfigFile::Mac => Ok(try!(first_interface()).mac.bytes.to_vec()),
+        }
+    }
+
+    /// Apply a line written to a text file
+    fn apply_line(&self, line: &str) -> Result<()> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match *self {
+            // NAME ADDR/PREFIX, adding the address if it is IPv6
+            NetConfigFile::Interfaces => {
+                if words.len() != 2 {
+                    return Err(Error::new(EINVAL));
+                }
+                if words[1].contains(':') {
+                    let (addr, prefix_len) = try!(parse_prefix6(words[1]));
+                    let interface = try!(interface::find(words[0]).ok_or(Error::new(ENOENT)));
+                    return ndp::add_addr(interface, addr, prefix_len, None);
+                }
+                let (addr, netmask) = try!(parse_prefix(words[1]));
+                let interface = try!(interface::find(words[0]).ok_or(Error::new(ENOENT)));
+                arp::set_addr(interface, addr, netmask)
+            },
+            // add DST [via GATEWAY] dev IFACE, or del DST
+            NetConfigFile::Routes => {
+                let routes = unsafe { &mut *::env().routes.get() };
+                match (words.get(0).map(|word| *word), words.len()) {
+                    (Some("del"), 2) => {
+                        let (dst, netmask) = try!(parse_prefix(words[1]));
+                        if routes.remove(dst, netmask) {
+                            Ok(())
+                        } else {
+                            Err(Error::new(ENOENT))
+                        }
+                    },
+                    (Some("add"), 4) | (Some("add"), 6) => {
+                        let (dst, netmask) = try!(parse_prefix(words[1]));
+                        let gateway = if words.len() == 6 {
+                            if words[2] != "via" {
+                                return Err(Error::new(EINVAL));
+                            }
+                            Some(try!(parse_addr(words[3])))
+                        } else {
+                            None
+                        };
+                        if words[words.len() - 2] != "dev" {
+                            return Err(Error::new(EINVAL));
+                        }
+                        let name = words[words.len() - 1];
+                        if interface::find(name).is_none() {
+                            return Err(Error::new(ENOENT));
+                        }
+
+                        routes.add(Route {
+                            dst: Ipv4Addr::from_u32(dst.to_u32() & netmask.to_u32()),
+                            netmask: netmask,
+                            gateway: gateway,
+                            interface: name.into(),
+                        });
+                        Ok(())
+                    },
+                    _ => Err(Error::new(EINVAL)),
+                }
+            },
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    /// Apply the whole contents of a binary file
+    fn apply_bytes(&self, data: &[u8]) -> Result<()> {
+        match *self {
+            NetConfigFile::Mac => {
+                let mut mac = MacAddr { bytes: [0; 6] };
+                if data.len() != mac.bytes.len() {
+                    return Err(Error::new(EINVAL));
+                }
+                mac.bytes.clone_from_slice(data);
+                try!(first_interface()).mac = mac;
+                Ok(())
+            },
+            _ => {
+                let mut addr = Ipv4Addr { bytes: [0; 4] };
+                if data.len() != addr.bytes.len() {
+                    return Err(Error::new(EINVAL));
+                }
+                addr.bytes.clone_from_slice(data);
+
+                match *self {
+                    NetConfigFile::Ip => {
+                        let interface = try!(first_interface());
+                        let netmask = interface.netmask;
+                        try!(arp::set_addr(interface, addr, netmask));
+                    },
+                    NetConfigFile::IpSubnet => try!(first_interface()).netmask = addr,
+                    NetConfigFile::IpRouter => {
+                        let name = try!(first_interface()).name.clone();
+                        unsafe { &mut *::env().routes.get() }.add(Route {
+                            dst: UNSPECIFIED_ADDR,
+                            netmask: UNSPECIFIED_ADDR,
+                            gateway: Some(addr),
+                            interface: name,
+                        });
+                    },
+                    _ => return Err(Error::new(EINVAL)),
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// A network configuration file, read as it was when opened
+///
+/// Writes to text files are applied a line at a time. Writes to binary files are applied once
+/// they hold a whole address.
+pub struct NetConfigResource {
+    file: NetConfigFile,
+    data: Vec<u8>,
+    seek: usize,
+}
+
+impl Resource for NetConfigResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box NetConfigResource {
+            file: self.file,
+            data: self.data.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = self.file.path().as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && self.seek < self.data.len() {
+            buf[i] = self.data[self.seek];
+            self.seek += 1;
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.file {
+            NetConfigFile::Interfaces | NetConfigFile::Routes => {
+                let text = String::from_utf8_lossy(buf);
+                for line in text.lines().map(|line| line.trim()).filter(|line| ! line.is_empty()) {
+                    try!(self.file.apply_line(line));
+                }
+            },
+            _ => {
+                for (i, b) in buf.iter().enumerate() {
+                    if self.seek + i < self.data.len() {
+                        self.data[self.seek + i] = *b;
+                    } else {
+                        self.data.push(*b);
+                    }
+                }
+                self.seek += buf.len();
+                try!(self.file.apply_bytes(&self.data));
+            },
+        }
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        self.seek = match pos {
+            ResourceSeek::Start(offset) => offset,
+            ResourceSeek::Current(offset) => cmp::max(0, self.seek as isize + offset) as usize,
+            ResourceSeek::End(offset) => cmp::max(0, self.data.len() as isize + offset) as usize,
+        };
+        self.seek = cmp::min(self.seek, self.data.len());
+        Ok(self.seek)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_size = self.data.len() as u32;
+        stat.st_mode = MODE_FILE;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.data.truncate(len);
+        self.seek = cmp::min(self.seek, self.data.len());
+        Ok(())
+    }
+}
 
 /// Network configuration scheme
+///
+/// `netcfg:ifaces` lists the interfaces, and writing `NAME ADDR/PREFIX` sets an interface's
+/// address, failing with `EADDRINUSE` if ARP finds another host using it. An IPv6 address is
+/// added to the interface's addresses instead, and stays tentative until duplicate address
+/// detection passes. `netcfg:route` lists the routes, including the subnets of the interfaces, and takes
+/// `add DST [via GATEWAY] dev IFACE` and `del DST`, where `DST` is `ADDR/PREFIX`, a host
+/// address, or `default`. `ip`, `ip_subnet`, `ip_router` and `mac` are the binary addresses of
+/// the first interface and the default route.
 pub struct NetConfigScheme;
 
 impl KScheme for NetConfigScheme {
@@ -13,14 +349,22 @@ impl KScheme for NetConfigScheme {
     }
 
     fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
-        match url.splitn(2, ":").nth(1).unwrap_or("") {
-            "dns" => Ok(Box::new(SliceMutResource::new("netcfg:dns", unsafe { &mut DNS_ADDR.bytes }, MODE_FILE))),
-            "ip" => Ok(Box::new(SliceMutResource::new("netcfg:ip", unsafe { &mut IP_ADDR.bytes }, MODE_FILE))),
-            "ip_router" => Ok(Box::new(SliceMutResource::new("netcfg:ip_router", unsafe { &mut IP_ROUTER_ADDR.bytes }, MODE_FILE))),
-            "ip_subnet" => Ok(Box::new(SliceMutResource::new("netcfg:ip_subnet", unsafe { &mut IP_SUBNET.bytes }, MODE_FILE))),
-            "mac" => Ok(Box::new(SliceMutResource::new("netcfg:mac", unsafe { &mut MAC_ADDR.bytes }, MODE_FILE))),
-            "" => Ok(Box::new(SliceResource::new("netcfg:", b"dns\nip\nmac", MODE_DIR))),
-            _ => Err(Error::new(ENOENT))
-        }
+        let file = match url.splitn(2, ":").nth(1).unwrap_or("") {
+            "dns" => return Ok(Box::new(SliceMutResource::new("netcfg:dns", unsafe { &mut DNS_ADDR.bytes }, MODE_FILE))),
+            "" => return Ok(Box::new(SliceResource::new("netcfg:", b"dns\nifaces\nip\nip_router\nip_subnet\nmac\nroute", MODE_DIR))),
+            "ifaces" => NetConfigFile::Interfaces,
+            "route" => NetConfigFile::Routes,
+            "ip" => NetConfigFile::Ip,
+            "ip_router" => NetConfigFile::IpRouter,
+            "ip_subnet" => NetConfigFile::IpSubnet,
+            "mac" => NetConfigFile::Mac,
+            _ => return Err(Error::new(ENOENT))
+        };
+
+        Ok(Box::new(NetConfigResource {
+            file: file,
+            data: try!(file.read()),
+            seek: 0,
+        }))
     }
 }
@@ -1,5 +1,6 @@
 use alloc::boxed::Box;
 
+use collections::string::String;
 use collections::vec::Vec;
 
 use core::{cmp, mem};
@@ -8,11 +9,12 @@ use common::to_num::ToNum;
 
 use network::common::*;
 use network::ethernet::*;
+use network::interface;
 
 use fs::{KScheme, Resource};
 
 use system::error::{Error, Result, ENOENT};
-use system::syscall::O_RDWR;
+use system::syscall::{O_NONBLOCK, O_RDWR, POLLIN};
 
 /// A ethernet resource
 pub struct EthernetResource {
@@ -22,6 +24,10 @@ pub struct EthernetResource {
     data: Vec<u8>,
     /// The MAC addresss
     peer_addr: MacAddr,
+    /// The MAC address of the interface frames are sent on
+    src_addr: MacAddr,
+    /// The interface, or empty for every interface
+    interface: String,
     /// The ethernet type
     ethertype: u16,
 }
@@ -33,6 +39,8 @@ impl Resource for EthernetResource {
                 network: network,
                 data: self.data.clone(),
                 peer_addr: self.peer_addr,
+                src_addr: self.src_addr,
+                interface: self.interface.clone(),
                 ethertype: self.ethertype,
             }),
             Err(err) => Err(err),
@@ -40,7 +48,10 @@ impl Resource for EthernetResource {
     }
 
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
-        let path_string = format!("ethernet:{}/{:X}", self.peer_addr.to_string(), self.ethertype);
+        let mut path_string = format!("ethernet:{}/{:X}", self.peer_addr.to_string(), self.ethertype);
+        if ! self.interface.is_empty() {
+            path_string = path_string + "/" + &self.interface;
+        }
         let path = path_string.as_bytes();
 
         for (b, p) in buf.iter_mut().zip(path.iter()) {
@@ -67,7 +78,7 @@ impl Resource for EthernetResource {
             let count = try!(self.network.read(&mut bytes));
 
             if let Some(frame) = EthernetII::from_bytes(&bytes[..count]) {
-                if frame.header.ethertype.get() == self.ethertype /* && (unsafe { frame.header.dst.equals(MAC_ADDR) }
+                if frame.header.ethertype.get() == self.ethertype /* && (frame.header.dst.equals(self.src_addr)
                     || frame.header.dst.equals(BROADCAST_MAC_ADDR)) && (frame.header.src.equals(self.peer_addr)
                     || self.peer_addr.equals(BROADCAST_MAC_ADDR))*/
                 {
@@ -86,7 +97,7 @@ impl Resource for EthernetResource {
 
         match self.network.write(&EthernetII {
                                       header: EthernetIIHeader {
-                                          src: unsafe { MAC_ADDR },
+                                          src: self.src_addr,
                                           dst: self.peer_addr,
                                           ethertype: n16::new(self.ethertype),
                                       },
@@ -101,8 +112,21 @@ impl Resource for EthernetResource {
     fn sync(&mut self) -> Result<()> {
         self.network.sync()
     }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        if self.data.is_empty() {
+            self.network.poll(events)
+        } else {
+            Ok(events & POLLIN | try!(self.network.poll(events)))
+        }
+    }
 }
 
+/// The `ethernet:` scheme, for frames of one ethertype
+///
+/// `ethernet:MAC/TYPE` sends to `MAC` on the first interface and receives from every interface.
+/// `ethernet:MAC/TYPE/IFACE` uses only the interface `IFACE`. An empty `MAC` waits for a frame
+/// to any of our addresses, and replies to its sender on the interface it arrived on.
 pub struct EthernetScheme;
 
 impl KScheme for EthernetScheme {
@@ -110,18 +134,30 @@ impl KScheme for EthernetScheme {
         "ethernet"
     }
 
-    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
         let parts: Vec<&str> = url.splitn(2, ":").nth(1).unwrap_or("").split("/").collect();
         if let Some(host_string) = parts.get(0) {
             if let Some(ethertype_string) = parts.get(1) {
-                if let Ok(mut network) = ::env().open("network:", O_RDWR) {
-                    let ethertype = ethertype_string.to_num_radix(16) as u16;
-
+                let interface_name = parts.get(2).map_or("", |name| *name);
+                let interface = if interface_name.is_empty() {
+                    interface::default()
+                } else {
+                    interface::find(interface_name)
+                };
+                let src_addr = match interface {
+                    Some(interface) => interface.mac,
+                    None => return Err(Error::new(ENOENT)),
+                };
+
+                let ethertype = ethertype_string.to_num_radix(16) as u16;
+                if let Ok(mut network) = ::env().open(&format!("network:{}/{:X}", interface_name, ethertype), O_RDWR | flags & O_NONBLOCK) {
                     if !host_string.is_empty() {
                         return Ok(box EthernetResource {
                             network: network,
                             data: Vec::new(),
                             peer_addr: MacAddr::from_str(host_string),
+                            src_addr: src_addr,
+                            interface: interface_name.into(),
                             ethertype: ethertype,
                         });
                     } else {
@@ -130,19 +166,37 @@ impl KScheme for EthernetScheme {
                             match network.read(&mut bytes) {
                                 Ok(count) => {
                                     if let Some(frame) = EthernetII::from_bytes(&bytes[..count]) {
-                                        if frame.header.ethertype.get() == ethertype &&
-                                           (unsafe { frame.header.dst.equals(MAC_ADDR) } ||
-                                            frame.header.dst.equals(BROADCAST_MAC_ADDR)) {
+                                        // Broadcast and multicast addresses have the group bit set
+                                        let to_us = frame.header.dst.bytes[0] & 1 == 1 ||
+                                                    interface::by_mac(frame.header.dst).is_some();
+                                        if frame.header.ethertype.get() == ethertype && to_us {
+                                            // Reply on the interface the frame arrived on
+                                            if interface_name.is_empty() {
+                                                if let Some(interface) = interface::by_mac(frame.header.dst) {
+                                                    network = try!(::env().open(&format!("network:{}/{:X}", interface.name, ethertype), O_RDWR | flags & O_NONBLOCK));
+                                                    return Ok(box EthernetResource {
+                                                        network: network,
+                                                        data: frame.data,
+                                                        peer_addr: frame.header.src,
+                                                        src_addr: interface.mac,
+                                                        interface: interface.name.clone(),
+                                                        ethertype: ethertype,
+                                                    });
+                                                }
+                                            }
+
                                             return Ok(box EthernetResource {
                                                 network: network,
                                                 data: frame.data,
                                                 peer_addr: frame.header.src,
+                                                src_addr: src_addr,
+                                                interface: interface_name.into(),
                                                 ethertype: ethertype,
                                             });
                                         }
                                     }
                                 }
-                                Err(_) => break,
+                                Err(err) => return Err(err),
                             }
                         }
                     }
@@ -0,0 +1,193 @@
+use alloc::boxed::Box;
+
+use collections::vec::Vec;
+
+use core::{mem, slice};
+
+use arch::context::Context;
+
+use network::common::*;
+use network::demux::{Filter, Receiver};
+use network::interface;
+use network::ipv6::Ipv6;
+use network::route;
+
+use fs::KScheme;
+
+use super::ip6;
+use super::ndp;
+
+/// The protocol number of ICMPv6
+pub const ICMPV6_PROTO: u8 = 58;
+
+/// Error messages, as in RFC 4443
+pub const ICMPV6_UNREACHABLE: u8 = 1;
+pub const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+/// Informational messages
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct Icmpv6Header {
+    pub _type: u8,
+    pub code: u8,
+    pub checksum: Checksum,
+}
+
+pub struct Icmpv6 {
+    pub header: Icmpv6Header,
+    pub data: Vec<u8>,
+}
+
+impl Icmpv6 {
+    pub fn new(_type: u8, code: u8, data: Vec<u8>) -> Icmpv6 {
+        Icmpv6 {
+            header: Icmpv6Header {
+                _type: _type,
+                code: code,
+                checksum: Checksum { data: 0 },
+            },
+            data: data,
+        }
+    }
+
+    /// Sum the pseudo header and the message
+    fn sum(&self, src: Ipv6Addr, dst: Ipv6Addr) -> usize {
+        let len = mem::size_of::<Icmpv6Header>() + self.data.len();
+        unsafe {
+            Checksum::pseudo_header(&IpAddr::V6(src), &IpAddr::V6(dst), ICMPV6_PROTO, len) +
+            Checksum::sum((&self.header as *const Icmpv6Header) as usize, mem::size_of::<Icmpv6Header>()) +
+            Checksum::sum(self.data.as_ptr() as usize, self.data.len())
+        }
+    }
+
+    pub fn checksum(&mut self, src: Ipv6Addr, dst: Ipv6Addr) {
+        self.header.checksum.data = 0;
+        self.header.checksum.data = Checksum::compile(self.sum(src, dst));
+    }
+
+    /// Check the checksum of a received message
+    pub fn verify(&self, src: Ipv6Addr, dst: Ipv6Addr) -> bool {
+        Checksum::compile(self.sum(src, dst)) == 0
+    }
+}
+
+impl FromBytes for Icmpv6 {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() >= mem::size_of::<Icmpv6Header>() {
+            unsafe {
+                return Some(Icmpv6 {
+                    header: *(bytes.as_ptr() as *const Icmpv6Header),
+                    data: bytes[mem::size_of::<Icmpv6Header>() ..].to_vec(),
+                });
+            }
+        }
+        None
+    }
+}
+
+impl ToBytes for Icmpv6 {
+    fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            let header_ptr: *const Icmpv6Header = &self.header;
+            let mut ret = Vec::from(slice::from_raw_parts(header_ptr as *const u8,
+                                                          mem::size_of::<Icmpv6Header>()));
+            ret.extend_from_slice(&self.data);
+            ret
+        }
+    }
+}
+
+/// Answer an echo request from `peer`, from the address it was sent to if that is ours
+fn echo_reply(peer: Ipv6Addr, dst: Ipv6Addr, request: Icmpv6) {
+    let hop = match route::lookup6(peer) {
+        Ok(hop) => hop,
+        Err(err) => {
+            debugln!("ICMPv6: No route to {}: {}", peer.to_string(), err);
+            return;
+        }
+    };
+    let src = if dst.is_local() {
+        dst
+    } else {
+        hop.src
+    };
+
+    let mut reply = Icmpv6::new(ICMPV6_ECHO_REPLY, 0, request.data);
+    reply.checksum(src, peer);
+
+    let reply_packet = Ipv6::new(src, peer, ICMPV6_PROTO, hop.interface.hop_limit6, reply.to_bytes());
+    if let Err(err) = ip6::send_packet(&hop.interface.name, hop.addr, &reply_packet) {
+        debugln!("ICMPv6: Echo reply to {} failed: {}", peer.to_string(), err);
+    }
+}
+
+/// The `icmpv6:` scheme, which answers echo requests and runs neighbour discovery
+///
+/// Nothing can be opened, the scheme only owns the contexts that receive ICMPv6 messages and run
+/// the neighbour discovery timers.
+pub struct Icmpv6Scheme;
+
+impl Icmpv6Scheme {
+    /// Create the scheme, starting the receive and timer contexts
+    pub fn new() -> Box<Icmpv6Scheme> {
+        Context::spawn("kicmpv6".into(),
+                       box move || {
+                           Icmpv6Scheme::receive_loop();
+                       });
+
+        Context::spawn("kndp_timer".into(),
+                       box move || {
+                           ndp::timer_loop();
+                       });
+
+        box Icmpv6Scheme
+    }
+
+    /// Wait for ICMPv6 messages to us, handing them to the interface they arrived on
+    ///
+    /// Messages are taken when sent to one of the interface's addresses, to all nodes, or to the
+    /// solicited-node address of one of its addresses, tentative ones included.
+    fn receive_loop() {
+        let receiver = Receiver::new(vec![Filter::new(true, ICMPV6_PROTO)], false);
+        loop {
+            let packet = receiver.queue.receive("Icmpv6Scheme::receive_loop");
+            let (src, dst) = match (packet.src, packet.dst) {
+                (IpAddr::V6(src), IpAddr::V6(dst)) => (src, dst),
+                _ => continue,
+            };
+
+            let interface = match interface::find(&packet.interface) {
+                Some(interface) => interface,
+                None => continue,
+            };
+
+            let to_us = interface.has_addr6(dst) || dst.equals(ALL_NODES_IPV6_ADDR) ||
+                        interface.addrs6.iter().any(|inet6| inet6.addr.solicited_node().equals(dst));
+            if ! to_us {
+                continue;
+            }
+
+            if let Some(message) = Icmpv6::from_bytes(&packet.data) {
+                if ! message.verify(src, dst) {
+                    continue;
+                }
+
+                match message.header._type {
+                    ICMPV6_ECHO_REQUEST => echo_reply(src, dst, message),
+                    ndp::ROUTER_ADVERTISEMENT | ndp::NEIGHBOR_SOLICITATION | ndp::NEIGHBOR_ADVERTISEMENT => {
+                        ndp::input(interface, src, packet.hop_limit, &message);
+                    },
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+impl KScheme for Icmpv6Scheme {
+    fn scheme(&self) -> &str {
+        "icmpv6"
+    }
+}
@@ -1,42 +1,77 @@
 use alloc::boxed::Box;
 
+use collections::string::String;
 use collections::vec::Vec;
 
 use core::{cmp, mem};
 
 use network::common::*;
+use network::demux::{Filter, Receiver};
+use network::ethernet::ETHERNET_MTU;
+use network::interface::UNSPECIFIED_ADDR;
 use network::ipv4::*;
+use network::route;
 
 use common::random;
 use common::to_num::ToNum;
 
-use super::arp::{Arp, ArpHeader};
+use super::arp;
 use fs::{KScheme, Resource};
 
-use system::error::{Error, Result, ENOENT};
-use system::syscall::O_RDWR;
+use system::error::{Error, Result, EAGAIN, EINVAL, EMSGSIZE, ENOENT};
+use system::syscall::{O_NONBLOCK, O_RDWR, POLLIN, POLLOUT};
 
 /// A IP (internet protocole) resource
 pub struct IpResource {
-    link: Box<Resource>,
+    /// The queue packets are received from
+    receiver: Box<Receiver>,
     data: Vec<u8>,
     peer_addr: Ipv4Addr,
+    /// The outgoing interface
+    interface: String,
+    /// The interface and next hop that unicast packets are sent to through the neighbour cache,
+    /// or `None` to broadcast packets on the outgoing interface
+    neighbour: Option<(String, Ipv4Addr)>,
+    /// The address of the outgoing interface
+    src_addr: Ipv4Addr,
     proto: u8,
     id: u16,
+    /// The MTU of the outgoing interface
+    mtu: usize,
+    /// Send packets with don't fragment set, failing with `EMSGSIZE` if they do not fit the MTU
+    dont_fragment: bool,
+    /// Reads return `EAGAIN` instead of waiting for a packet
+    nonblock: bool,
+}
+
+impl IpResource {
+    /// Send a packet to the next hop, which may be queued until its MAC address is resolved
+    fn send(&mut self, packet: Vec<u8>) -> Result<()> {
+        match self.neighbour {
+            Some((ref interface, next_hop)) => unsafe { &mut *::env().arp.get() }.output(interface, IpAddr::V4(next_hop), packet),
+            None => {
+                let mut link = try!(::env().open(&format!("ethernet:{}/800/{}", BROADCAST_MAC_ADDR.to_string(), self.interface), O_RDWR));
+                link.write(&packet).and(Ok(()))
+            },
+        }
+    }
 }
 
 impl Resource for IpResource {
     fn dup(&self) -> Result<Box<Resource>> {
-        match self.link.dup() {
-            Ok(link) => Ok(box IpResource {
-                link: link,
-                data: self.data.clone(),
-                peer_addr: self.peer_addr,
-                proto: self.proto,
-                id: self.id,
-            }),
-            Err(err) => Err(err),
-        }
+        Ok(box IpResource {
+            receiver: self.receiver.dup(),
+            data: self.data.clone(),
+            peer_addr: self.peer_addr,
+            interface: self.interface.clone(),
+            neighbour: self.neighbour.clone(),
+            src_addr: self.src_addr,
+            proto: self.proto,
+            id: self.id,
+            mtu: self.mtu,
+            dont_fragment: self.dont_fragment,
+            nonblock: self.nonblock,
+        })
     }
 
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
@@ -62,26 +97,25 @@ impl Resource for IpResource {
             return Ok(cmp::min(buf.len(), data.len()));
         }
 
-        loop {
-            let mut bytes = [0; 65536];
-            let count = try!(self.link.read(&mut bytes));
-
-            if let Some(packet) = Ipv4::from_bytes(&bytes[..count]) {
-                if packet.header.proto == self.proto &&
-                   (packet.header.dst.equals(unsafe { IP_ADDR }) || packet.header.dst.equals(BROADCAST_IP_ADDR)) &&
-                   (packet.header.src.equals(self.peer_addr) || self.peer_addr.equals(BROADCAST_IP_ADDR)) {
-                    for (b, d) in buf.iter_mut().zip(packet.data.iter()) {
-                        *b = *d;
-                    }
+        let packet = if self.nonblock {
+            try!(self.receiver.try_receive().ok_or(Error::new(EAGAIN)))
+        } else {
+            self.receiver.queue.receive("IpResource::read")
+        };
 
-                    return Ok(cmp::min(buf.len(), packet.data.len()));
-                }
-            }
+        for (b, d) in buf.iter_mut().zip(packet.data.iter()) {
+            *b = *d;
         }
+
+        Ok(cmp::min(buf.len(), packet.data.len()))
     }
 
+    /// Send a packet, fragmenting it if it does not fit the MTU
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         let ip_data = Vec::from(buf);
+        if mem::size_of::<Ipv4Header>() + ip_data.len() > 65535 {
+            return Err(Error::new(EMSGSIZE));
+        }
 
         self.id += 1;
         let mut ip = Ipv4 {
@@ -90,52 +124,62 @@ impl Resource for IpResource {
                 services: 0,
                 len: n16::new((mem::size_of::<Ipv4Header>() + ip_data.len()) as u16), // No Options
                 id: n16::new(self.id),
-                flags_fragment: n16::new(0),
+                flags_fragment: n16::new(if self.dont_fragment { IP_DF } else { 0 }),
                 ttl: 128,
                 proto: self.proto,
                 checksum: Checksum { data: 0 },
-                src: unsafe { IP_ADDR },
+                src: self.src_addr,
                 dst: self.peer_addr,
             },
             options: Vec::new(),
             data: ip_data,
         };
 
-        unsafe {
-            let header_ptr: *const Ipv4Header = &ip.header;
-            ip.header.checksum.data =
-                Checksum::compile(Checksum::sum(header_ptr as usize, mem::size_of::<Ipv4Header>()) +
-                                  Checksum::sum(ip.options.as_ptr() as usize, ip.options.len()));
+        if mem::size_of::<Ipv4Header>() + ip.options.len() + ip.data.len() <= self.mtu {
+            ip.checksum();
+            try!(self.send(ip.to_bytes()));
+        } else if self.dont_fragment {
+            return Err(Error::new(EMSGSIZE));
+        } else {
+            for fragment in ip.fragment(self.mtu) {
+                try!(self.send(fragment.to_bytes()));
+            }
         }
 
-        match self.link.write(&ip.to_bytes()) {
-            Ok(_) => Ok(buf.len()),
-            Err(err) => Err(err),
-        }
+        Ok(buf.len())
     }
 
     fn sync(&mut self) -> Result<()> {
-        self.link.sync()
+        Ok(())
     }
-}
 
-/// A ARP entry (MAC + IP)
-pub struct ArpEntry {
-    ip: Ipv4Addr,
-    mac: MacAddr,
+    /// Writes are queued or sent at once, so never block
+    fn poll(&self, events: usize) -> Result<usize> {
+        if self.data.is_empty() && self.receiver.is_empty() {
+            Ok(events & POLLOUT)
+        } else {
+            Ok(events & (POLLIN | POLLOUT))
+        }
+    }
 }
 
 /// A IP scheme
-pub struct IpScheme {
-    pub arp: Vec<ArpEntry>,
-}
+pub struct IpScheme;
 
 impl KScheme for IpScheme {
     fn scheme(&self) -> &str {
         "ip"
     }
 
-    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let mut url_parts = url.splitn(2, '?');
+        let url = url_parts.next().unwrap_or("");
+        let dont_fragment = match url_parts.next() {
+            Some("df") => true,
+            Some(_) => return Err(Error::new(EINVAL)),
+            None => false,
+        };
+
         let parts: Vec<&str> = url.splitn(2, ":").nth(1).unwrap_or("").split('/').collect();
         if let Some(host_string) = parts.get(0) {
             if let Some(proto_string) = parts.get(1) {
@@ -143,105 +187,77 @@ impl KScheme for IpScheme {
 
                 if ! host_string.is_empty() {
                     let peer_addr = Ipv4Addr::from_str(host_string);
-                    let mut route_mac = BROADCAST_MAC_ADDR;
-
-                    if ! peer_addr.equals(BROADCAST_IP_ADDR) {
-                        let mut needs_routing = false;
-
-                        for octet in 0..4 {
-                            let me = unsafe { IP_ADDR.bytes[octet] };
-                            let mask = unsafe { IP_SUBNET.bytes[octet] };
-                            let them = peer_addr.bytes[octet];
-                            if me & mask != them & mask {
-                                needs_routing = true;
-                                break;
-                            }
-                        }
-
-                        let route_addr = if needs_routing {
-                            unsafe { IP_ROUTER_ADDR }
+                    let hop = try!(route::lookup(peer_addr));
+
+                    // Wait for the next hop to resolve, unless packets may queue for it
+                    let neighbour = if hop.broadcast {
+                        None
+                    } else {
+                        if flags & O_NONBLOCK == O_NONBLOCK {
+                            unsafe { &mut *::env().arp.get() }.resolve(hop.interface, IpAddr::V4(hop.addr));
                         } else {
-                            peer_addr
-                        };
-
-                        for entry in self.arp.iter() {
-                            if entry.ip.equals(route_addr) {
-                                route_mac = entry.mac;
-                                break;
-                            }
+                            try!(arp::resolve(hop.interface, IpAddr::V4(hop.addr)));
                         }
+                        Some((hop.interface.name.clone(), hop.addr))
+                    };
 
-                        if route_mac.equals(BROADCAST_MAC_ADDR) {
-                            if let Ok(mut link) = ::env().open(&format!("ethernet:{}/806", &route_mac.to_string()), O_RDWR) {
-                                let arp = Arp {
-                                    header: ArpHeader {
-                                        htype: n16::new(1),
-                                        ptype: n16::new(0x800),
-                                        hlen: 6,
-                                        plen: 4,
-                                        oper: n16::new(1),
-                                        src_mac: unsafe { MAC_ADDR },
-                                        src_ip: unsafe { IP_ADDR },
-                                        dst_mac: route_mac,
-                                        dst_ip: route_addr,
-                                    },
-                                    data: Vec::new(),
-                                };
-
-                                match link.write(&arp.to_bytes()) {
-                                    Ok(_) => loop {
-                                        let mut bytes = [0; 65536];
-                                        match link.read(&mut bytes) {
-                                            Ok(count) => if let Some(packet) = Arp::from_bytes(&bytes[..count]) {
-                                                if packet.header.oper.get() == 2 &&
-                                                   packet.header.src_ip.equals(route_addr) {
-                                                    route_mac = packet.header.src_mac;
-                                                    self.arp.push(ArpEntry {
-                                                        ip: route_addr,
-                                                        mac: route_mac,
-                                                    });
-                                                    break;
-                                                }
-                                            },
-                                            Err(_) => (),
-                                        }
-                                    },
-                                    Err(err) => debugln!("IP: ARP Write Failed: {}", err),
-                                }
-                            }
-                        }
+                    let mut filter = Filter::new(false, proto);
+                    if ! peer_addr.is_broadcast() {
+                        filter.peer = Some(IpAddr::V4(peer_addr));
                     }
 
-                    if let Ok(link) = ::env().open(&format!("ethernet:{}/800", &route_mac.to_string()), O_RDWR) {
-                        return Ok(box IpResource {
-                            link: link,
-                            data: Vec::new(),
-                            peer_addr: peer_addr,
-                            proto: proto,
-                            id: (random::rand() % 65536) as u16,
-                        });
-                    }
+                    return Ok(box IpResource {
+                        receiver: Receiver::new(vec![filter], false),
+                        data: Vec::new(),
+                        peer_addr: peer_addr,
+                        interface: hop.interface.name.clone(),
+                        neighbour: neighbour,
+                        src_addr: hop.src,
+                        proto: proto,
+                        id: (random::rand() % 65536) as u16,
+                        mtu: hop.interface.mtu,
+                        dont_fragment: dont_fragment,
+                        nonblock: flags & O_NONBLOCK == O_NONBLOCK,
+                    });
                 } else {
-                    while let Ok(mut link) = ::env().open("ethernet:/800", O_RDWR) {
-                        let mut bytes = [0; 65536];
-                        match link.read(&mut bytes) {
-                            Ok(count) => {
-                                if let Some(packet) = Ipv4::from_bytes(&bytes[..count]) {
-                                    if packet.header.proto == proto &&
-                                       (packet.header.dst.equals(unsafe { IP_ADDR }) || packet.header.dst.equals(BROADCAST_IP_ADDR)) {
-                                        return Ok(box IpResource {
-                                            link: link,
-                                            data: packet.data,
-                                            peer_addr: packet.header.src,
-                                            proto: proto,
-                                            id: (random::rand() % 65536) as u16,
-                                        });
-                                    }
-                                }
-                            }
-                            Err(_) => break,
-                        }
+                    let mut receiver = Receiver::new(vec![Filter::new(false, proto)], false);
+                    let packet = receiver.queue.receive("IpScheme::open");
+                    let (peer_addr, dst) = match (packet.src, packet.dst) {
+                        (IpAddr::V4(src), IpAddr::V4(dst)) => (src, dst),
+                        _ => return Err(Error::new(ENOENT)),
+                    };
+                    receiver.connect(packet.src);
+
+                    // Without a route, replies are broadcast on the interface the packet arrived on
+                    let (mut src_addr, interface, mtu, neighbour) = match route::lookup(peer_addr) {
+                        Ok(hop) => {
+                            let neighbour = if hop.broadcast {
+                                None
+                            } else {
+                                Some((hop.interface.name.clone(), hop.addr))
+                            };
+                            (hop.src, hop.interface.name.clone(), hop.interface.mtu, neighbour)
+                        },
+                        Err(_) => (UNSPECIFIED_ADDR, packet.interface, ETHERNET_MTU, None),
+                    };
+                    // Reply from the address the packet was sent to, if it was ours
+                    if dst.is_local() {
+                        src_addr = dst;
                     }
+
+                    return Ok(box IpResource {
+                        receiver: receiver,
+                        data: packet.data,
+                        peer_addr: peer_addr,
+                        interface: interface,
+                        neighbour: neighbour,
+                        src_addr: src_addr,
+                        proto: proto,
+                        id: (random::rand() % 65536) as u16,
+                        mtu: mtu,
+                        dont_fragment: dont_fragment,
+                        nonblock: flags & O_NONBLOCK == O_NONBLOCK,
+                    });
                 }
             } else {
                 debug!("IP: No protocol provided\n");
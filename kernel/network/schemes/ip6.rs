@@ -0,0 +1,173 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use core::{cmp, mem};
+
+use network::common::*;
+use network::demux::{Filter, Receiver};
+use network::ipv6::{Ipv6, Ipv6Header};
+use network::route;
+
+use common::to_num::ToNum;
+
+use super::arp;
+use fs::{KScheme, Resource};
+
+use system::error::{Error, Result, EAGAIN, EINVAL, EMSGSIZE, ENOENT};
+use system::syscall::{O_NONBLOCK, O_RDWR, POLLIN, POLLOUT};
+
+/// Send an IPv6 packet on an interface, to a multicast group or through the neighbour cache to
+/// the next hop
+pub fn send_packet(interface: &str, next_hop: Ipv6Addr, packet: &Ipv6) -> Result<()> {
+    if packet.header.dst.is_multicast() {
+        let mut link = try!(::env().open(&format!("ethernet:{}/86DD/{}", packet.header.dst.multicast_mac().to_string(), interface), O_RDWR));
+        link.write(&packet.to_bytes()).and(Ok(()))
+    } else {
+        unsafe { &mut *::env().arp.get() }.output(interface, IpAddr::V6(next_hop), packet.to_bytes())
+    }
+}
+
+/// An IPv6 resource
+pub struct Ip6Resource {
+    /// The queue packets are received from
+    receiver: Box<Receiver>,
+    peer_addr: Ipv6Addr,
+    /// The outgoing interface and the next hop on it
+    interface: String,
+    next_hop: Ipv6Addr,
+    src_addr: Ipv6Addr,
+    proto: u8,
+    hop_limit: u8,
+    /// The MTU of the outgoing interface
+    mtu: usize,
+    /// Reads return `EAGAIN` instead of waiting for a packet
+    nonblock: bool,
+}
+
+impl Resource for Ip6Resource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box Ip6Resource {
+            receiver: self.receiver.dup(),
+            peer_addr: self.peer_addr,
+            interface: self.interface.clone(),
+            next_hop: self.next_hop,
+            src_addr: self.src_addr,
+            proto: self.proto,
+            hop_limit: self.hop_limit,
+            mtu: self.mtu,
+            nonblock: self.nonblock,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = format!("ip6:{}/{:X}", self.peer_addr.to_string(), self.proto);
+        let path = path_string.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let packet = if self.nonblock {
+            try!(self.receiver.try_receive().ok_or(Error::new(EAGAIN)))
+        } else {
+            self.receiver.queue.receive("Ip6Resource::read")
+        };
+
+        for (b, d) in buf.iter_mut().zip(packet.data.iter()) {
+            *b = *d;
+        }
+
+        Ok(cmp::min(buf.len(), packet.data.len()))
+    }
+
+    /// Send a packet, failing with `EMSGSIZE` if it does not fit the MTU, since packets are not
+    /// fragmented
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if mem::size_of::<Ipv6Header>() + buf.len() > self.mtu {
+            return Err(Error::new(EMSGSIZE));
+        }
+
+        let packet = Ipv6::new(self.src_addr, self.peer_addr, self.proto, self.hop_limit, Vec::from(buf));
+        try!(send_packet(&self.interface, self.next_hop, &packet));
+
+        Ok(buf.len())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Writes are queued or sent at once, so never block
+    fn poll(&self, events: usize) -> Result<usize> {
+        if self.receiver.is_empty() {
+            Ok(events & POLLOUT)
+        } else {
+            Ok(events & (POLLIN | POLLOUT))
+        }
+    }
+}
+
+/// The `ip6:` scheme, for IPv6 packets of one protocol
+///
+/// `ip6:ADDR/PROTO` exchanges packets with a host, with `PROTO` in hexadecimal. The address may
+/// be in brackets. A `?df` after the URL is accepted for the same URLs as `ip:`, but has no
+/// effect, since IPv6 packets are never fragmented on the way.
+pub struct Ip6Scheme;
+
+impl KScheme for Ip6Scheme {
+    fn scheme(&self) -> &str {
+        "ip6"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let mut url_parts = url.splitn(2, '?');
+        let url = url_parts.next().unwrap_or("");
+        match url_parts.next() {
+            Some("df") | None => (),
+            Some(_) => return Err(Error::new(EINVAL)),
+        }
+
+        let parts: Vec<&str> = url.splitn(2, ":").nth(1).unwrap_or("").split('/').collect();
+        let host_string = parts.get(0).map_or("", |host| host.trim_matches(|c: char| c == '[' || c == ']'));
+        let proto_string = parts.get(1).map_or("", |proto| *proto);
+        if host_string.is_empty() || proto_string.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let peer_addr = try!(Ipv6Addr::parse(host_string).ok_or(Error::new(EINVAL)));
+        let proto = proto_string.to_num_radix(16) as u8;
+        let hop = try!(route::lookup6(peer_addr));
+
+        // Wait for the next hop to resolve, unless packets may queue for it
+        if ! peer_addr.is_multicast() {
+            if flags & O_NONBLOCK == O_NONBLOCK {
+                unsafe { &mut *::env().arp.get() }.resolve(hop.interface, IpAddr::V6(hop.addr));
+            } else {
+                try!(arp::resolve(hop.interface, IpAddr::V6(hop.addr)));
+            }
+        }
+
+        let mut filter = Filter::new(true, proto);
+        if ! peer_addr.is_multicast() {
+            filter.peer = Some(IpAddr::V6(peer_addr));
+        }
+
+        Ok(box Ip6Resource {
+            receiver: Receiver::new(vec![filter], false),
+            peer_addr: peer_addr,
+            interface: hop.interface.name.clone(),
+            next_hop: hop.addr,
+            src_addr: hop.src,
+            proto: proto,
+            hop_limit: hop.interface.hop_limit6,
+            mtu: hop.interface.mtu,
+            nonblock: flags & O_NONBLOCK == O_NONBLOCK,
+        })
+    }
+}
@@ -2,7 +2,9 @@ pub use self::arp::ArpScheme;
 pub use self::config::NetConfigScheme;
 pub use self::ethernet::EthernetScheme;
 pub use self::icmp::IcmpScheme;
+pub use self::icmpv6::Icmpv6Scheme;
 pub use self::ip::IpScheme;
+pub use self::ip6::Ip6Scheme;
 pub use self::tcp::TcpScheme;
 pub use self::udp::UdpScheme;
 
@@ -10,6 +12,10 @@ pub mod arp;
 pub mod config;
 pub mod ethernet;
 pub mod icmp;
+pub mod icmpv6;
 pub mod ip;
+pub mod ip6;
+pub mod ndp;
+pub mod socket;
 pub mod tcp;
 pub mod udp;
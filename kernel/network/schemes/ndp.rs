@@ -0,0 +1,412 @@
+use collections::vec::Vec;
+
+use common::time::{Duration, NANOS_PER_MILLI};
+
+use arch::context::context_sleep;
+
+use network::common::*;
+use network::interface::{Inet6Addr, Interface};
+use network::ipv6::Ipv6;
+
+use system::error::{Error, Result, EADDRINUSE};
+use system::syscall::O_RDWR;
+
+use super::arp::ArpState;
+use super::icmpv6::{Icmpv6, ICMPV6_PROTO};
+
+/// Message types, as in RFC 4861
+pub const ROUTER_SOLICITATION: u8 = 133;
+pub const ROUTER_ADVERTISEMENT: u8 = 134;
+pub const NEIGHBOR_SOLICITATION: u8 = 135;
+pub const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// Option types
+const SOURCE_LINK_ADDR: u8 = 1;
+const TARGET_LINK_ADDR: u8 = 2;
+const PREFIX_INFO: u8 = 3;
+
+/// Neighbour advertisement flags
+const NA_SOLICITED: u8 = 0x40;
+const NA_OVERRIDE: u8 = 0x20;
+/// Prefix information flags
+const PREFIX_AUTONOMOUS: u8 = 0x40;
+
+/// Neighbour discovery messages are sent with this hop limit, and only accepted with it, so they
+/// cannot come from off the link
+const ND_HOP_LIMIT: u8 = 255;
+/// How long duplicate address detection waits for a reply, in milliseconds
+const RETRANS_TIMER: i32 = 1000;
+/// Router solicitations sent when the network comes up, and the seconds between them
+const MAX_RTR_SOLICITATIONS: usize = 3;
+const RTR_SOLICITATION_INTERVAL: i64 = 4;
+/// A valid lifetime that never ends
+const INFINITE_LIFETIME: u32 = 0xFFFFFFFF;
+/// Seconds of valid lifetime an unauthenticated advertisement cannot shorten an address below
+const MIN_VALID_LIFETIME: i64 = 2 * 60 * 60;
+/// Addresses beyond link-local ones an interface takes from router advertisements
+const MAX_AUTOCONF_ADDRS: usize = 16;
+/// How often the timers are checked
+const TIMER_INTERVAL: i32 = 100 * NANOS_PER_MILLI;
+
+/// Split the options after the fixed part of a message, returning each type and value
+///
+/// Returns `None` if an option has a length of zero, which makes the message invalid.
+fn options(data: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut options = Vec::new();
+    let mut i = 0;
+    while i + 2 <= data.len() {
+        let len = data[i + 1] as usize * 8;
+        if len == 0 || i + len > data.len() {
+            return None;
+        }
+        options.push((data[i], &data[i + 2 .. i + len]));
+        i += len;
+    }
+    Some(options)
+}
+
+/// The MAC address of a source or target link-layer address option
+fn link_addr(options: &[(u8, &[u8])], kind: u8) -> Option<MacAddr> {
+    options.iter().find(|&&(option_kind, value)| option_kind == kind && value.len() >= 6).map(|&(_, value)| {
+        MacAddr { bytes: [value[0], value[1], value[2], value[3], value[4], value[5]] }
+    })
+}
+
+/// A link-layer address option
+fn link_addr_option(kind: u8, mac: MacAddr) -> [u8; 8] {
+    [kind, 1, mac.bytes[0], mac.bytes[1], mac.bytes[2], mac.bytes[3], mac.bytes[4], mac.bytes[5]]
+}
+
+/// Read an IPv6 address from a message
+fn addr_at(data: &[u8], offset: usize) -> Option<Ipv6Addr> {
+    if data.len() < offset + 16 {
+        return None;
+    }
+    Some(Ipv6Addr::from_slice(&data[offset ..]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    (data[offset] as u32) << 24 | (data[offset + 1] as u32) << 16 | (data[offset + 2] as u32) << 8 | data[offset + 3] as u32
+}
+
+/// Send a message from an interface, to a MAC address or else the multicast MAC of `dst`
+fn send(interface: &Interface, src: Ipv6Addr, dst: Ipv6Addr, dst_mac: Option<MacAddr>, mut message: Icmpv6) {
+    message.checksum(src, dst);
+    let packet = Ipv6::new(src, dst, ICMPV6_PROTO, ND_HOP_LIMIT, message.to_bytes());
+
+    let mac = dst_mac.unwrap_or(dst.multicast_mac());
+    match ::env().open(&format!("ethernet:{}/86DD/{}", mac.to_string(), interface.name), O_RDWR) {
+        Ok(mut link) => if let Err(err) = link.write(&packet.to_bytes()) {
+            debugln!("NDP: Send on {} failed: {}", interface.name, err);
+        },
+        Err(err) => debugln!("NDP: Send on {} failed: {}", interface.name, err),
+    }
+}
+
+/// Ask for the MAC address of a neighbour, multicast to its solicited-node address or else
+/// unicast to the MAC address it had
+pub fn solicit(interface: &Interface, target: Ipv6Addr, dst_mac: Option<MacAddr>) {
+    let src = match interface.src6(target) {
+        Some(src) => src,
+        None => return,
+    };
+    let dst = if dst_mac.is_some() { target } else { target.solicited_node() };
+
+    let mut data = vec![0; 4];
+    data.extend_from_slice(&target.bytes);
+    data.extend_from_slice(&link_addr_option(SOURCE_LINK_ADDR, interface.mac));
+    send(interface, src, dst, dst_mac, Icmpv6::new(NEIGHBOR_SOLICITATION, 0, data));
+}
+
+/// Check that no other node has an address, with a solicitation from the unspecified address
+fn probe(interface: &Interface, target: Ipv6Addr) {
+    let mut data = vec![0; 4];
+    data.extend_from_slice(&target.bytes);
+    send(interface, UNSPECIFIED_IPV6_ADDR, target.solicited_node(), None, Icmpv6::new(NEIGHBOR_SOLICITATION, 0, data));
+}
+
+/// Advertise one of our addresses, to a neighbour that solicited it or to all nodes
+fn advertise(interface: &Interface, target: Ipv6Addr, dst: Ipv6Addr, dst_mac: Option<MacAddr>, solicited: bool) {
+    let src = if interface.has_addr6(target) {
+        target
+    } else {
+        return;
+    };
+
+    let flags = if solicited { NA_SOLICITED | NA_OVERRIDE } else { NA_OVERRIDE };
+    let mut data = vec![flags, 0, 0, 0];
+    data.extend_from_slice(&target.bytes);
+    data.extend_from_slice(&link_addr_option(TARGET_LINK_ADDR, interface.mac));
+    send(interface, src, dst, dst_mac, Icmpv6::new(NEIGHBOR_ADVERTISEMENT, 0, data));
+}
+
+/// Ask the routers on the link to advertise themselves
+fn solicit_router(interface: &Interface) {
+    let src = match interface.src6(ALL_ROUTERS_IPV6_ADDR) {
+        Some(src) => src,
+        None => return,
+    };
+
+    let mut data = vec![0; 4];
+    data.extend_from_slice(&link_addr_option(SOURCE_LINK_ADDR, interface.mac));
+    send(interface, src, ALL_ROUTERS_IPV6_ADDR, None, Icmpv6::new(ROUTER_SOLICITATION, 0, data));
+}
+
+/// Add an IPv6 address to an interface, which is tentative until duplicate address detection
+/// finds no other node using it, as in RFC 4862
+///
+/// Returns `EADDRINUSE` if the interface already has the address.
+pub fn add_addr(interface: &mut Interface, addr: Ipv6Addr, prefix_len: u8, expires: Option<Duration>) -> Result<()> {
+    if interface.addrs6.iter().any(|inet6| inet6.addr.equals(addr)) {
+        return Err(Error::new(EADDRINUSE));
+    }
+
+    interface.addrs6.push(Inet6Addr {
+        addr: addr,
+        prefix_len: prefix_len,
+        tentative: Some(Duration::monotonic() + Duration::new(0, RETRANS_TIMER * NANOS_PER_MILLI)),
+        expires: expires,
+    });
+    probe(interface, addr);
+    Ok(())
+}
+
+/// Give up an address another node is using
+fn duplicate(interface: &mut Interface, addr: Ipv6Addr) {
+    syslog_info!("IPv6: {} is already in use on {}", addr.to_string(), interface.name);
+    interface.addrs6.retain(|inet6| ! inet6.addr.equals(addr));
+}
+
+/// Take a router advertisement, learning the default router and configuring addresses from
+/// the prefixes that allow it
+///
+/// Addresses are the prefix and the interface's modified EUI-64, and are used until their valid
+/// lifetime ends. As in RFC 4862, an advertisement can only shorten that lifetime to two hours,
+/// so a forged one cannot remove an address, and no more than `MAX_AUTOCONF_ADDRS` are taken.
+/// Preferred lifetimes are not tracked.
+fn router_advertisement(interface: &mut Interface, src: Ipv6Addr, data: &[u8]) {
+    if ! src.is_link_local() || data.len() < 12 {
+        return;
+    }
+    let options = match options(&data[12 ..]) {
+        Some(options) => options,
+        None => return,
+    };
+
+    let now = Duration::monotonic();
+    if data[0] != 0 {
+        interface.hop_limit6 = data[0];
+    }
+
+    let lifetime = (data[2] as i64) << 8 | data[3] as i64;
+    if lifetime > 0 {
+        if interface.router6.map_or(true, |(router, _)| ! router.equals(src)) {
+            syslog_info!("IPv6: Router {} on {}", src.to_string(), interface.name);
+        }
+        interface.router6 = Some((src, now + Duration::new(lifetime, 0)));
+    } else if interface.router6.map_or(false, |(router, _)| router.equals(src)) {
+        interface.router6 = None;
+    }
+
+    if let Some(mac) = link_addr(&options, SOURCE_LINK_ADDR) {
+        unsafe { &mut *::env().arp.get() }.update(&interface.name, IpAddr::V6(src), mac, ArpState::Stale);
+    }
+
+    for &(kind, value) in options.iter() {
+        if kind != PREFIX_INFO || value.len() < 30 {
+            continue;
+        }
+
+        let prefix_len = value[0];
+        let prefix = match addr_at(value, 14) {
+            Some(prefix) => prefix,
+            None => continue,
+        };
+        if value[1] & PREFIX_AUTONOMOUS == 0 || prefix_len != 64 || prefix.is_link_local() {
+            continue;
+        }
+
+        let valid = u32_at(value, 2);
+        let expires = if valid == INFINITE_LIFETIME {
+            None
+        } else {
+            Some(now + Duration::new(valid as i64, 0))
+        };
+
+        let addr = Ipv6Addr::from_prefix(prefix, interface.mac);
+        let known = interface.addrs6.iter().position(|inet6| inet6.addr.equals(addr));
+        match known {
+            Some(i) => {
+                let inet6 = &mut interface.addrs6[i];
+                let longer = match (expires, inet6.expires) {
+                    (None, _) => true,
+                    (Some(_), None) => false,
+                    (Some(expires), Some(current)) => expires > current,
+                };
+                let min_expires = now + Duration::new(MIN_VALID_LIFETIME, 0);
+                if valid as i64 > MIN_VALID_LIFETIME || longer {
+                    inet6.expires = expires;
+                } else if inet6.expires.map_or(true, |current| current > min_expires) {
+                    inet6.expires = Some(min_expires);
+                }
+            },
+            None => if valid > 0 {
+                let autoconf = interface.addrs6.iter().filter(|inet6| ! inet6.addr.is_link_local()).count();
+                if autoconf < MAX_AUTOCONF_ADDRS {
+                    let _ = add_addr(interface, addr, prefix_len, expires);
+                }
+            },
+        }
+    }
+}
+
+/// Take a neighbour solicitation, answering it if it is for one of our addresses
+fn neighbor_solicitation(interface: &mut Interface, src: Ipv6Addr, data: &[u8]) {
+    let target = match addr_at(data, 4) {
+        Some(target) if ! target.is_multicast() => target,
+        _ => return,
+    };
+    let options = match options(&data[20 ..]) {
+        Some(options) => options,
+        None => return,
+    };
+    let src_mac = link_addr(&options, SOURCE_LINK_ADDR);
+
+    let tentative = interface.addrs6.iter().any(|inet6| inet6.tentative.is_some() && inet6.addr.equals(target));
+    if src.is_unspecified() {
+        // Another node checking for the address, which is a conflict while we check for it too
+        if tentative {
+            duplicate(interface, target);
+        } else {
+            advertise(interface, target, ALL_NODES_IPV6_ADDR, None, false);
+        }
+        return;
+    }
+
+    if ! interface.has_addr6(target) {
+        return;
+    }
+
+    if let Some(mac) = src_mac {
+        unsafe { &mut *::env().arp.get() }.update(&interface.name, IpAddr::V6(src), mac, ArpState::Stale);
+    }
+    advertise(interface, target, src, src_mac, true);
+}
+
+/// Take a neighbour advertisement, updating the neighbour cache and detecting duplicate addresses
+///
+/// Only neighbours already in the cache are updated, and only from advertisements that carry
+/// their MAC address.
+fn neighbor_advertisement(interface: &mut Interface, data: &[u8]) {
+    let target = match addr_at(data, 4) {
+        Some(target) if ! target.is_multicast() => target,
+        _ => return,
+    };
+    let options = match options(&data[20 ..]) {
+        Some(options) => options,
+        None => return,
+    };
+    let mac = link_addr(&options, TARGET_LINK_ADDR);
+
+    if interface.addrs6.iter().any(|inet6| inet6.addr.equals(target)) {
+        if interface.has_addr6(target) {
+            if let Some(mac) = mac {
+                if ! mac.equals(interface.mac) {
+                    syslog_info!("IPv6: {} on {} is also used by {}", target.to_string(), interface.name, mac.to_string());
+                }
+            }
+        } else {
+            duplicate(interface, target);
+        }
+        return;
+    }
+
+    if let Some(mac) = mac {
+        let cache = unsafe { &mut *::env().arp.get() };
+        if cache.contains(&interface.name, IpAddr::V6(target)) {
+            let state = if data[0] & NA_SOLICITED == NA_SOLICITED {
+                ArpState::Reachable
+            } else {
+                ArpState::Stale
+            };
+            cache.update(&interface.name, IpAddr::V6(target), mac, state);
+        }
+    }
+}
+
+/// Take a neighbour discovery message received on an interface, from `src` with `hop_limit`
+pub fn input(interface: &mut Interface, src: Ipv6Addr, hop_limit: u8, message: &Icmpv6) {
+    if hop_limit != ND_HOP_LIMIT || message.header.code != 0 {
+        return;
+    }
+
+    match message.header._type {
+        ROUTER_ADVERTISEMENT => router_advertisement(interface, src, &message.data),
+        NEIGHBOR_SOLICITATION if message.data.len() >= 20 => neighbor_solicitation(interface, src, &message.data),
+        NEIGHBOR_ADVERTISEMENT if message.data.len() >= 20 => neighbor_advertisement(interface, &message.data),
+        _ => (),
+    }
+}
+
+/// Finish duplicate address detection, and forget expired addresses and routers
+fn on_timer(interface: &mut Interface, now: Duration) {
+    let mut ready = Vec::new();
+    for inet6 in interface.addrs6.iter_mut() {
+        if inet6.tentative.map_or(false, |deadline| now >= deadline) {
+            inet6.tentative = None;
+            ready.push(inet6.addr);
+        }
+    }
+    for addr in ready {
+        syslog_info!("IPv6: {} on {}", addr.to_string(), interface.name);
+        advertise(interface, addr, ALL_NODES_IPV6_ADDR, None, false);
+    }
+
+    interface.addrs6.retain(|inet6| inet6.expires.map_or(true, |expires| now < expires));
+    if interface.router6.map_or(false, |(_, expires)| now >= expires) {
+        interface.router6 = None;
+    }
+}
+
+/// Start duplicate address detection on the addresses the interfaces started with, then check
+/// the timers every `TIMER_INTERVAL`, soliciting routers until one advertises itself
+pub fn timer_loop() {
+    let start = Duration::monotonic();
+    for interface in unsafe { &mut *::env().interfaces.get() }.iter_mut() {
+        for inet6 in interface.addrs6.iter_mut() {
+            if inet6.tentative.is_some() {
+                inet6.tentative = Some(start + Duration::new(0, RETRANS_TIMER * NANOS_PER_MILLI));
+            }
+        }
+        for inet6 in interface.addrs6.iter() {
+            if inet6.tentative.is_some() {
+                probe(interface, inet6.addr);
+            }
+        }
+    }
+
+    let mut solicitations = 0;
+    let mut next_solicitation = start;
+    loop {
+        context_sleep("ndp::timer_loop", Duration::new(0, TIMER_INTERVAL));
+
+        let now = Duration::monotonic();
+        for interface in unsafe { &mut *::env().interfaces.get() }.iter_mut() {
+            on_timer(interface, now);
+        }
+
+        if solicitations < MAX_RTR_SOLICITATIONS && now >= next_solicitation {
+            let mut sent = false;
+            for interface in unsafe { & *::env().interfaces.get() }.iter() {
+                if interface.router6.is_none() && interface.src6(ALL_ROUTERS_IPV6_ADDR).is_some() {
+                    solicit_router(interface);
+                    sent = true;
+                }
+            }
+            if sent {
+                solicitations += 1;
+                next_solicitation = now + Duration::new(RTR_SOLICITATION_INTERVAL, 0);
+            }
+        }
+    }
+}
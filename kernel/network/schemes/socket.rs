@@ -0,0 +1,145 @@
+use collections::string::String;
+
+use common::time::{Duration, NANOS_PER_MILLI};
+
+use network::common::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use sync::WaitCondition;
+
+use system::error::{Error, Result, EAGAIN, EINVAL, ETIMEDOUT};
+use system::syscall::O_NONBLOCK;
+
+/// How long a socket waits before checking again, in case a notification was missed
+const RECHECK: i32 = 100 * NANOS_PER_MILLI;
+
+/// How a socket resource waits, set when it is opened
+///
+/// Options follow a `?` at the end of the URL as `&` separated `key=value` pairs, with times in
+/// milliseconds. `timeout` sets the connect, read and write timeouts together, and
+/// `connect_timeout`, `read_timeout` and `write_timeout` set them separately. `broadcast` allows
+/// sending datagrams to broadcast addresses.
+///
+/// Opening with `O_NONBLOCK` makes reads and writes that would wait return `EAGAIN` instead.
+/// Waits that time out return `ETIMEDOUT`.
+#[derive(Copy, Clone, Default)]
+pub struct SocketOptions {
+    pub nonblock: bool,
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub broadcast: bool,
+}
+
+impl SocketOptions {
+    /// Split the options from a URL, returning the URL without them
+    pub fn parse(url: &str, flags: usize) -> Result<(&str, SocketOptions)> {
+        let mut options = SocketOptions::default();
+        options.nonblock = flags & O_NONBLOCK == O_NONBLOCK;
+
+        let mut parts = url.splitn(2, '?');
+        let url = parts.next().unwrap_or("");
+        if let Some(query) = parts.next() {
+            for option in query.split('&').filter(|option| ! option.is_empty()) {
+                let mut pair = option.splitn(2, '=');
+                let key = pair.next().unwrap_or("");
+                if key == "broadcast" {
+                    options.broadcast = match pair.next() {
+                        None | Some("1") => true,
+                        Some("0") => false,
+                        _ => return Err(Error::new(EINVAL)),
+                    };
+                    continue;
+                }
+
+                let millis = match pair.next().unwrap_or("").parse::<u64>() {
+                    Ok(millis) => millis,
+                    Err(_) => return Err(Error::new(EINVAL)),
+                };
+                let time = Some(Duration::new((millis / 1000) as i64, (millis % 1000) as i32 * NANOS_PER_MILLI));
+
+                match key {
+                    "timeout" => {
+                        options.connect_timeout = time;
+                        options.read_timeout = time;
+                        options.write_timeout = time;
+                    },
+                    "connect_timeout" => options.connect_timeout = time,
+                    "read_timeout" => options.read_timeout = time,
+                    "write_timeout" => options.write_timeout = time,
+                    _ => return Err(Error::new(EINVAL)),
+                }
+            }
+        }
+
+        Ok((url, options))
+    }
+
+    /// Check that an operation may wait, given when its timeout ends
+    pub fn may_wait(&self, deadline: Option<Duration>) -> Result<()> {
+        if self.nonblock {
+            Err(Error::new(EAGAIN))
+        } else if expired(deadline) {
+            Err(Error::new(ETIMEDOUT))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// When a wait starting now ends, if it has a timeout
+pub fn deadline(timeout: Option<Duration>) -> Option<Duration> {
+    match timeout {
+        Some(timeout) => Some(Duration::monotonic() + timeout),
+        None => None,
+    }
+}
+
+/// Whether a deadline has passed
+pub fn expired(deadline: Option<Duration>) -> bool {
+    match deadline {
+        Some(deadline) => Duration::monotonic() >= deadline,
+        None => false,
+    }
+}
+
+/// Wait for a socket's condition to be notified, at most until a deadline
+pub fn wait(condition: &WaitCondition, reason: &str, deadline: Option<Duration>) {
+    let mut time = Duration::new(0, RECHECK);
+    if let Some(deadline) = deadline {
+        let remaining = deadline - Duration::monotonic();
+        if remaining < time {
+            time = remaining;
+        }
+    }
+
+    if time > Duration::new(0, 0) {
+        condition.wait_for(reason, time);
+    }
+}
+
+/// Split a peer into its address and port, given as `ADDR:PORT` for IPv4 or `[ADDR]:PORT` for
+/// IPv6
+pub fn split_host(remote: &str) -> Option<(IpAddr, &str)> {
+    if remote.starts_with('[') {
+        let end = match remote.find("]:") {
+            Some(end) => end,
+            None => return None,
+        };
+        Ipv6Addr::parse(&remote[1 .. end]).map(|addr| (IpAddr::V6(addr), &remote[end + 2 ..]))
+    } else {
+        let mut parts = remote.splitn(2, ':');
+        let host = parts.next().unwrap_or("");
+        match parts.next() {
+            Some(port) if ! host.is_empty() => Some((IpAddr::V4(Ipv4Addr::from_str(host)), port)),
+            _ => None,
+        }
+    }
+}
+
+/// Join an address and port, as `split_host` takes them
+pub fn join_host(addr: IpAddr, port: u16) -> String {
+    match addr {
+        IpAddr::V4(addr) => format!("{}:{}", addr.to_string(), port),
+        IpAddr::V6(addr) => format!("[{}]:{}", addr.to_string(), port),
+    }
+}
@@ -0,0 +1,983 @@
+use collections::{Vec, VecDeque};
+
+use common::random::rand;
+use common::time::{Duration, NANOS_PER_MILLI};
+
+use core::cmp;
+
+use network::common::{IpAddr, Ipv4Addr};
+use network::ipv6::IPV6_MIN_MTU;
+use network::schemes::socket;
+
+use sync::WaitCondition;
+
+use system::error::{ECONNREFUSED, ECONNRESET, ETIMEDOUT};
+
+use super::{Tcp, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN};
+
+/// Bytes buffered for the application in each direction
+const SEND_BUFFER: usize = 256 * 1024;
+const RECEIVE_BUFFER: usize = 256 * 1024;
+/// Shift of the windows we advertise, enough to cover `RECEIVE_BUFFER`
+const WINDOW_SHIFT: u8 = 3;
+/// Largest segment we accept over IPv4, for an Ethernet MTU, and less the larger IPv6 header
+const MSS: u16 = 1460;
+const MSS6: u16 = MSS - 20;
+/// Largest segment the peer accepts if it does not say
+const DEFAULT_MSS: usize = 536;
+
+/// Retransmission timeouts in milliseconds, from RFC 6298
+const RTO_INITIAL: u64 = 1000;
+const RTO_MIN: u64 = 200;
+const RTO_MAX: u64 = 60000;
+/// Retransmissions of a SYN, then of anything else, before the connection times out
+const SYN_RETRIES: u32 = 6;
+const MAX_RETRIES: u32 = 12;
+/// Out of order segments queued, beyond which more are dropped
+const REASSEMBLY_SEGMENTS: usize = 64;
+/// Duplicate acknowledgments that trigger a fast retransmit
+const DUP_ACK_THRESHOLD: u32 = 3;
+/// Maximum segment lifetime in seconds, with TIME_WAIT lasting twice this
+const MSL: i64 = 30;
+/// Seconds a connection no resource uses waits in FIN_WAIT_2 for the peer's FIN
+const FIN_WAIT_2_TIMEOUT: i64 = 60;
+
+/// Connection states from RFC 793
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// `a` is before `b` in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// `a` is before or at `b` in sequence space
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+fn millis(ms: u64) -> Duration {
+    Duration::new((ms / 1000) as i64, (ms % 1000) as i32 * NANOS_PER_MILLI)
+}
+
+fn to_millis(duration: Duration) -> u64 {
+    if duration.secs < 0 {
+        0
+    } else {
+        duration.secs as u64 * 1000 + (duration.nanos / NANOS_PER_MILLI) as u64
+    }
+}
+
+/// A transmission control block, driven by incoming segments, the application and a timer
+pub struct TcpConnection {
+    pub state: TcpState,
+    pub local_port: u16,
+    pub peer_addr: IpAddr,
+    pub peer_port: u16,
+    /// Opened by a listen, so a reset in SYN_RECEIVED returns to LISTEN
+    passive: bool,
+
+    iss: u32,
+    /// Oldest unacknowledged sequence number
+    snd_una: u32,
+    /// Next sequence number to send
+    snd_nxt: u32,
+    /// The peer's window, already scaled
+    snd_wnd: u32,
+    /// Sequence and acknowledgment numbers of the segment that last updated the window
+    snd_wl1: u32,
+    snd_wl2: u32,
+    /// Shift of the windows the peer advertises
+    snd_shift: u8,
+    /// Largest segment the peer accepts
+    snd_mss: usize,
+
+    irs: u32,
+    /// Next sequence number expected
+    rcv_nxt: u32,
+    /// Right edge of the last advertised window, which never moves left
+    rcv_adv: u32,
+    /// Shift of the windows we advertise
+    rcv_shift: u8,
+
+    /// Data from `snd_una` onwards, first sent and unacknowledged, then unsent
+    send_buffer: VecDeque<u8>,
+    /// The application closed its side, so a FIN follows the send buffer
+    fin_queued: bool,
+    /// In order data for the application
+    receive_buffer: VecDeque<u8>,
+    /// Out of order segments as sequence number, data and FIN
+    reassembly: Vec<(u32, Vec<u8>, bool)>,
+    /// The peer's FIN arrived in order
+    fin_received: bool,
+
+    /// Retransmission timeout and round trip estimates, in milliseconds
+    rto: u64,
+    srtt: u64,
+    rttvar: u64,
+    /// The segment being timed, as its sequence number and when it was sent
+    rtt_seq: Option<(u32, Duration)>,
+    /// When the retransmission or persist timer fires
+    retransmit_at: Option<Duration>,
+    retries: u32,
+    dup_acks: u32,
+    /// When TIME_WAIT ends
+    time_wait_end: Option<Duration>,
+    /// When FIN_WAIT_2 ends, once no resource uses the connection
+    fin_wait_end: Option<Duration>,
+
+    /// Why the connection failed, as an errno
+    pub error: Option<isize>,
+    /// The last ICMP or routing error, reported instead of `ETIMEDOUT` if the connection times out
+    soft_error: Option<isize>,
+    /// Every resource using the connection was dropped
+    pub released: bool,
+    /// Notified when data, acknowledgments or state changes arrive
+    pub condition: WaitCondition,
+}
+
+impl TcpConnection {
+    pub fn new(local_port: u16, peer_addr: IpAddr, peer_port: u16) -> TcpConnection {
+        TcpConnection {
+            state: TcpState::Closed,
+            local_port: local_port,
+            peer_addr: peer_addr,
+            peer_port: peer_port,
+            passive: false,
+
+            iss: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            snd_shift: 0,
+            snd_mss: DEFAULT_MSS,
+
+            irs: 0,
+            rcv_nxt: 0,
+            rcv_adv: 0,
+            rcv_shift: 0,
+
+            send_buffer: VecDeque::new(),
+            fin_queued: false,
+            receive_buffer: VecDeque::new(),
+            reassembly: Vec::new(),
+            fin_received: false,
+
+            rto: RTO_INITIAL,
+            srtt: 0,
+            rttvar: 0,
+            rtt_seq: None,
+            retransmit_at: None,
+            retries: 0,
+            dup_acks: 0,
+            time_wait_end: None,
+            fin_wait_end: None,
+
+            error: None,
+            soft_error: None,
+            released: false,
+            condition: WaitCondition::new(),
+        }
+    }
+
+    /// Actively open the connection by sending a SYN
+    pub fn connect(&mut self, now: Duration) {
+        self.iss = rand() as u32;
+        self.snd_una = self.iss;
+        self.snd_nxt = self.iss.wrapping_add(1);
+        self.state = TcpState::SynSent;
+
+        let iss = self.iss;
+        self.rtt_seq = Some((iss, now));
+        self.retransmit_at = Some(now + millis(self.rto));
+        self.transmit(iss, TCP_SYN, &[]);
+    }
+
+    /// Passively open the connection, waiting for a SYN from any peer
+    pub fn listen(&mut self) {
+        self.passive = true;
+        self.state = TcpState::Listen;
+    }
+
+    /// Bytes waiting for the application
+    pub fn readable(&self) -> usize {
+        self.receive_buffer.len()
+    }
+
+    /// Room in the send buffer
+    pub fn writable(&self) -> usize {
+        SEND_BUFFER - self.send_buffer.len()
+    }
+
+    /// Bytes the peer has not acknowledged yet
+    pub fn unacknowledged(&self) -> usize {
+        self.send_buffer.len()
+    }
+
+    /// The peer closed its side and everything it sent was read
+    pub fn at_eof(&self) -> bool {
+        self.fin_received && self.receive_buffer.is_empty()
+    }
+
+    /// Data can be sent in this state
+    pub fn can_send(&self) -> bool {
+        ! self.fin_queued && match self.state {
+            TcpState::SynSent | TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => true,
+            _ => false,
+        }
+    }
+
+    /// Queue data to send, returning how much fit in the send buffer
+    pub fn send(&mut self, buf: &[u8], now: Duration) -> usize {
+        let count = cmp::min(buf.len(), self.writable());
+        self.send_buffer.extend(buf[.. count].iter().cloned());
+        self.output(now);
+        count
+    }
+
+    /// Take received data, advertising the window again if reading opened it
+    pub fn recv(&mut self, buf: &mut [u8]) -> usize {
+        let before = self.receive_window();
+
+        let mut count = 0;
+        while count < buf.len() {
+            match self.receive_buffer.pop_front() {
+                Some(byte) => buf[count] = byte,
+                None => break,
+            }
+            count += 1;
+        }
+
+        // Avoid silly window syndrome by only announcing an opening of at least a segment
+        let after = self.receive_window();
+        let mss = self.mss() as usize;
+        if count > 0 && before < mss && after >= mss {
+            match self.state {
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => self.send_ack(),
+                _ => (),
+            }
+        }
+
+        count
+    }
+
+    /// Close the sending side once queued data is sent
+    pub fn close(&mut self, now: Duration) {
+        match self.state {
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent => {
+                self.state = TcpState::Closed;
+                self.retransmit_at = None;
+                self.condition.notify("TcpConnection::close");
+            },
+            TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => {
+                self.fin_queued = true;
+                self.output(now);
+            },
+            _ => (),
+        }
+    }
+
+    /// Drop the connection, resetting it if it was synchronized
+    pub fn abort(&mut self) {
+        match self.state {
+            TcpState::SynReceived | TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 |
+            TcpState::CloseWait => {
+                let snd_nxt = self.snd_nxt;
+                self.transmit(snd_nxt, TCP_RST, &[]);
+            },
+            _ => (),
+        }
+        self.enter_closed();
+    }
+
+    /// End TIME_WAIT early for a new SYN from the same peer above the old sequence space,
+    /// from RFC 1122, so a restarted server can accept a client reusing its port
+    pub fn reuse_time_wait(&mut self, segment: &Tcp) -> bool {
+        let flags = segment.header.flags.get();
+        if self.state == TcpState::TimeWait && flags & (TCP_SYN | TCP_ACK | TCP_RST) == TCP_SYN &&
+           seq_lt(self.rcv_nxt, segment.header.sequence.get()) {
+            self.enter_closed();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn enter_closed(&mut self) {
+        self.state = TcpState::Closed;
+        self.retransmit_at = None;
+        self.time_wait_end = None;
+        self.fin_wait_end = None;
+        self.send_buffer.clear();
+        self.reassembly.clear();
+        self.condition.notify("TcpConnection::closed");
+    }
+
+    fn fail(&mut self, errno: isize) {
+        self.error = Some(errno);
+        self.enter_closed();
+    }
+
+    fn enter_time_wait(&mut self, now: Duration) {
+        self.state = TcpState::TimeWait;
+        self.retransmit_at = None;
+        self.time_wait_end = Some(now + Duration::new(2 * MSL, 0));
+        self.condition.notify("TcpConnection::time_wait");
+    }
+
+    /// Free space in the receive buffer
+    fn receive_window(&self) -> usize {
+        let queued = self.reassembly.iter().fold(0, |total, entry| total + entry.1.len());
+        RECEIVE_BUFFER.saturating_sub(self.receive_buffer.len() + queued)
+    }
+
+    /// Our FIN has been sent and not acknowledged
+    fn fin_in_flight(&self) -> bool {
+        match self.state {
+            TcpState::FinWait1 | TcpState::Closing | TcpState::LastAck => true,
+            _ => false,
+        }
+    }
+
+    /// Bytes of data sent and not acknowledged
+    fn data_in_flight(&self) -> usize {
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        if self.fin_in_flight() {
+            in_flight - 1
+        } else {
+            in_flight
+        }
+    }
+
+    /// The largest segment we accept from the peer
+    fn mss(&self) -> u16 {
+        match self.peer_addr {
+            IpAddr::V4(_) => MSS,
+            IpAddr::V6(_) => MSS6,
+        }
+    }
+
+    /// Send a segment from `seq`, acknowledging everything received
+    fn transmit(&mut self, seq: u32, flags: u16, data: &[u8]) {
+        let mut options = Vec::new();
+        // Windows in SYN segments are never scaled
+        let shift = if flags & TCP_SYN == TCP_SYN {
+            let mss = self.mss();
+            options.extend_from_slice(&[2, 4, (mss >> 8) as u8, mss as u8, 1, 3, 3, WINDOW_SHIFT]);
+            0
+        } else {
+            self.rcv_shift
+        };
+        let window = cmp::min(self.receive_window() >> shift, 0xFFFF) as u16;
+
+        let ack = if flags & TCP_ACK == TCP_ACK {
+            let edge = self.rcv_nxt.wrapping_add((window as u32) << shift);
+            if seq_lt(self.rcv_adv, edge) {
+                self.rcv_adv = edge;
+            }
+            self.rcv_nxt
+        } else {
+            0
+        };
+
+        if let Err(err) = super::send_segment(self.peer_addr, self.local_port, self.peer_port, seq, ack, flags, window, options, data) {
+            self.network_error(err.errno);
+        }
+    }
+
+    /// Handle the peer being unreachable, from ICMP or a failure to send
+    ///
+    /// This ends a connection attempt, but only soft errors an open connection, as in RFC 1122,
+    /// since routes may recover before the connection times out.
+    pub fn network_error(&mut self, errno: isize) {
+        match self.state {
+            TcpState::SynSent => self.fail(errno),
+            TcpState::Closed | TcpState::Listen | TcpState::TimeWait => (),
+            _ => self.soft_error = Some(errno),
+        }
+    }
+
+    /// Shrink segments to fit the MTU reported by ICMP fragmentation needed or ICMPv6 packet too
+    /// big, as in RFC 1191 and RFC 8201
+    ///
+    /// The segment that was too large is lost, so it is resent at the new size straight away.
+    /// Routers that do not report an MTU get the minimum datagram size every host accepts.
+    pub fn path_mtu(&mut self, mtu: usize) {
+        let (mtu, headers) = match self.peer_addr {
+            IpAddr::V4(_) => (if mtu == 0 { 576 } else { cmp::max(mtu, 68) }, 40),
+            IpAddr::V6(_) => (cmp::max(mtu, IPV6_MIN_MTU), 60),
+        };
+        let mss = mtu - headers;
+        if mss < self.snd_mss {
+            debugln!("TCP: {} path MTU {}", socket::join_host(self.peer_addr, self.peer_port), mtu);
+            self.snd_mss = mss;
+            match self.state {
+                TcpState::Closed | TcpState::Listen | TcpState::TimeWait => (),
+                _ => self.retransmit(),
+            }
+        }
+    }
+
+    fn send_ack(&mut self) {
+        let snd_nxt = self.snd_nxt;
+        self.transmit(snd_nxt, TCP_ACK, &[]);
+    }
+
+    /// Send the data in `offset .. offset + len` of the send buffer
+    fn transmit_data(&mut self, seq: u32, offset: usize, len: usize, flags: u16) {
+        let data: Vec<u8> = self.send_buffer.iter().skip(offset).take(len).cloned().collect();
+        self.transmit(seq, flags, &data);
+    }
+
+    /// Send new data the peer's window allows, then a FIN once the application closed
+    fn output(&mut self, now: Duration) {
+        match self.state {
+            TcpState::Established | TcpState::CloseWait => (),
+            _ => return,
+        }
+
+        loop {
+            let sent = self.data_in_flight();
+            let unsent = self.send_buffer.len() - sent;
+            let usable = (self.snd_wnd as usize).saturating_sub(sent);
+            let len = cmp::min(cmp::min(unsent, usable), self.snd_mss);
+            if len == 0 {
+                break;
+            }
+
+            let seq = self.snd_nxt;
+            let flags = if len == unsent {
+                TCP_ACK | TCP_PSH
+            } else {
+                TCP_ACK
+            };
+            self.transmit_data(seq, sent, len, flags);
+
+            if self.rtt_seq.is_none() {
+                self.rtt_seq = Some((seq, now));
+            }
+            self.snd_nxt = seq.wrapping_add(len as u32);
+            if self.retransmit_at.is_none() {
+                self.retransmit_at = Some(now + millis(self.rto));
+            }
+        }
+
+        let unsent = self.send_buffer.len() - self.data_in_flight();
+        if self.fin_queued && unsent == 0 {
+            let seq = self.snd_nxt;
+            self.transmit(seq, TCP_FIN | TCP_ACK, &[]);
+            self.snd_nxt = seq.wrapping_add(1);
+            self.state = if self.state == TcpState::Established {
+                TcpState::FinWait1
+            } else {
+                TcpState::LastAck
+            };
+            if self.retransmit_at.is_none() {
+                self.retransmit_at = Some(now + millis(self.rto));
+            }
+        } else if unsent > 0 && self.snd_wnd == 0 && self.retransmit_at.is_none() {
+            // Probe a closed window when the persist timer fires
+            self.retransmit_at = Some(now + millis(self.rto));
+        }
+    }
+
+    /// Resend from `snd_una`: the SYN, the oldest segment and any FIN after it, or a window probe
+    fn retransmit(&mut self) {
+        let iss = self.iss;
+        match self.state {
+            TcpState::SynSent => self.transmit(iss, TCP_SYN, &[]),
+            TcpState::SynReceived => self.transmit(iss, TCP_SYN | TCP_ACK, &[]),
+            _ => {
+                let seq = self.snd_una;
+                let sent = self.data_in_flight();
+                if sent == 0 && ! self.fin_in_flight() {
+                    if ! self.send_buffer.is_empty() {
+                        // A one byte probe beyond the closed window
+                        self.transmit_data(seq, 0, 1, TCP_ACK);
+                        self.snd_nxt = seq.wrapping_add(1);
+                    }
+                } else {
+                    let len = cmp::min(sent, self.snd_mss);
+                    let mut flags = TCP_ACK;
+                    if len == sent && self.fin_in_flight() {
+                        flags |= TCP_FIN;
+                    }
+                    if len > 0 {
+                        flags |= TCP_PSH;
+                    }
+                    self.transmit_data(seq, 0, len, flags);
+                }
+            },
+        }
+    }
+
+    /// Run the retransmission, persist, TIME_WAIT and FIN_WAIT_2 timers
+    pub fn on_timer(&mut self, now: Duration) {
+        if let Some(end) = self.time_wait_end {
+            if now >= end {
+                self.enter_closed();
+            }
+            return;
+        }
+
+        // Without a resource, nothing would ever close a connection whose peer never sends FIN
+        if self.state == TcpState::FinWait2 && self.released {
+            match self.fin_wait_end {
+                Some(end) => if now >= end {
+                    debugln!("TCP: {} FIN_WAIT_2 timed out", socket::join_host(self.peer_addr, self.peer_port));
+                    self.enter_closed();
+                    return;
+                },
+                None => self.fin_wait_end = Some(now + Duration::new(FIN_WAIT_2_TIMEOUT, 0)),
+            }
+        }
+
+        let expired = match self.retransmit_at {
+            Some(at) => now >= at,
+            None => false,
+        };
+        if ! expired {
+            return;
+        }
+
+        self.retries += 1;
+        let limit = match self.state {
+            TcpState::SynSent | TcpState::SynReceived => SYN_RETRIES,
+            _ => MAX_RETRIES,
+        };
+        if self.retries > limit {
+            debugln!("TCP: {} timed out", socket::join_host(self.peer_addr, self.peer_port));
+            if self.state == TcpState::SynReceived && self.passive {
+                self.return_to_listen();
+            } else {
+                self.error = Some(self.soft_error.unwrap_or(ETIMEDOUT));
+                self.abort();
+            }
+            return;
+        }
+
+        // Back off, and do not time retransmitted segments
+        self.rto = cmp::min(self.rto * 2, RTO_MAX);
+        self.rtt_seq = None;
+        self.retransmit_at = Some(now + millis(self.rto));
+        self.retransmit();
+    }
+
+    /// Update the round trip estimates with a new sample
+    fn sample_rtt(&mut self, rtt: u64) {
+        if self.srtt == 0 {
+            self.srtt = cmp::max(rtt, 1);
+            self.rttvar = rtt / 2;
+        } else {
+            let delta = if self.srtt > rtt {
+                self.srtt - rtt
+            } else {
+                rtt - self.srtt
+            };
+            self.rttvar = (3 * self.rttvar + delta) / 4;
+            self.srtt = cmp::max((7 * self.srtt + rtt) / 8, 1);
+        }
+        self.rto = cmp::min(cmp::max(self.srtt + cmp::max(10, 4 * self.rttvar), RTO_MIN), RTO_MAX);
+    }
+
+    /// Take the MSS and window scale options from the peer's SYN
+    fn syn_options(&mut self, segment: &Tcp) {
+        if let Some(mss) = segment.mss() {
+            self.snd_mss = cmp::min(cmp::max(mss as usize, 64), self.mss() as usize);
+        }
+        match segment.window_scale() {
+            Some(shift) => {
+                self.snd_shift = cmp::min(shift, 14);
+                self.rcv_shift = WINDOW_SHIFT;
+            },
+            None => {
+                self.snd_shift = 0;
+                self.rcv_shift = 0;
+            },
+        }
+    }
+
+    /// Forget the peer of a passively opened connection and wait for another SYN
+    fn return_to_listen(&mut self) {
+        let released = self.released;
+        *self = TcpConnection::new(self.local_port, IpAddr::V4(Ipv4Addr { bytes: [0; 4] }), 0);
+        self.released = released;
+        self.listen();
+    }
+
+    /// Process a segment from `src`
+    pub fn input(&mut self, src: IpAddr, segment: &Tcp, now: Duration) {
+        let flags = segment.header.flags.get();
+        let seq = segment.header.sequence.get();
+        let ack = segment.header.ack_num.get();
+
+        match self.state {
+            TcpState::Closed => return,
+            TcpState::Listen => {
+                if flags & TCP_RST == TCP_RST {
+                    return;
+                }
+                if flags & TCP_ACK == TCP_ACK {
+                    let _ = super::send_segment(src, self.local_port, segment.header.src.get(), ack, 0, TCP_RST, 0, Vec::new(), &[]);
+                    return;
+                }
+                if flags & TCP_SYN == TCP_SYN {
+                    self.peer_addr = src;
+                    self.peer_port = segment.header.src.get();
+                    self.irs = seq;
+                    self.rcv_nxt = seq.wrapping_add(1);
+                    self.rcv_adv = self.rcv_nxt;
+                    self.syn_options(segment);
+                    self.snd_wnd = segment.header.window_size.get() as u32;
+                    self.snd_wl1 = seq;
+                    self.iss = rand() as u32;
+                    self.snd_una = self.iss;
+                    self.snd_nxt = self.iss.wrapping_add(1);
+                    self.state = TcpState::SynReceived;
+
+                    let iss = self.iss;
+                    self.transmit(iss, TCP_SYN | TCP_ACK, &[]);
+                    self.retransmit_at = Some(now + millis(self.rto));
+                }
+                return;
+            },
+            TcpState::SynSent => {
+                self.input_syn_sent(segment, now);
+                return;
+            },
+            _ => (),
+        }
+
+        // Check the segment overlaps the receive window
+        let len = segment.data.len() as u32 + (flags & TCP_SYN == TCP_SYN) as u32 + (flags & TCP_FIN == TCP_FIN) as u32;
+        let window = cmp::max(self.rcv_adv.wrapping_sub(self.rcv_nxt) as i32, 0) as u32;
+        let rcv_nxt = self.rcv_nxt;
+        let in_window = |n: u32| seq_le(rcv_nxt, n) && seq_lt(n, rcv_nxt.wrapping_add(window));
+        let acceptable = if len == 0 {
+            if window == 0 {
+                seq == self.rcv_nxt
+            } else {
+                in_window(seq)
+            }
+        } else {
+            window > 0 && (in_window(seq) || in_window(seq.wrapping_add(len - 1)))
+        };
+
+        if ! acceptable {
+            if flags & TCP_RST != TCP_RST {
+                self.send_ack();
+            }
+            return;
+        }
+
+        if flags & TCP_RST == TCP_RST {
+            // Resets not exactly at the next sequence number get a challenge ACK, from RFC 5961
+            if seq != rcv_nxt {
+                self.send_ack();
+                return;
+            }
+
+            match self.state {
+                TcpState::SynReceived => if self.passive {
+                    self.return_to_listen();
+                } else {
+                    self.fail(ECONNREFUSED);
+                },
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 | TcpState::CloseWait => {
+                    self.fail(ECONNRESET);
+                },
+                _ => self.enter_closed(),
+            }
+            return;
+        }
+
+        if flags & TCP_SYN == TCP_SYN {
+            // A SYN in the window gets a challenge ACK, from RFC 5961
+            self.send_ack();
+            return;
+        }
+
+        if flags & TCP_ACK != TCP_ACK {
+            return;
+        }
+
+        if self.state == TcpState::SynReceived {
+            if seq_lt(self.snd_una, ack) && seq_le(ack, self.snd_nxt) {
+                // Our SYN is acknowledged
+                self.snd_una = self.snd_una.wrapping_add(1);
+                self.snd_wnd = (segment.header.window_size.get() as u32) << self.snd_shift;
+                self.snd_wl1 = seq;
+                self.snd_wl2 = ack;
+                self.retransmit_at = None;
+                self.retries = 0;
+                self.state = TcpState::Established;
+                self.condition.notify("TcpConnection::established");
+            } else {
+                let _ = super::send_segment(src, self.local_port, self.peer_port, ack, 0, TCP_RST, 0, Vec::new(), &[]);
+                return;
+            }
+        }
+
+        let mut fin_acked = false;
+        if seq_lt(self.snd_una, ack) && seq_le(ack, self.snd_nxt) {
+            let mut acked = ack.wrapping_sub(self.snd_una) as usize;
+            if self.fin_in_flight() && ack == self.snd_nxt {
+                acked -= 1;
+                fin_acked = true;
+            }
+            for _ in 0..cmp::min(acked, self.send_buffer.len()) {
+                self.send_buffer.pop_front();
+            }
+            self.snd_una = ack;
+
+            if let Some((rtt_seq, sent)) = self.rtt_seq {
+                if seq_lt(rtt_seq, ack) {
+                    self.sample_rtt(to_millis(now - sent));
+                    self.rtt_seq = None;
+                }
+            }
+
+            self.retries = 0;
+            self.dup_acks = 0;
+            self.retransmit_at = if self.snd_una == self.snd_nxt {
+                None
+            } else {
+                Some(now + millis(self.rto))
+            };
+            self.condition.notify("TcpConnection::acknowledged");
+        } else if ack == self.snd_una {
+            if self.snd_wnd == 0 && (segment.header.window_size.get() as u32) << self.snd_shift == 0 {
+                // The peer answered a window probe, so it is alive however long it keeps the
+                // window closed
+                self.retries = 0;
+            } else if segment.data.is_empty() && flags & TCP_FIN != TCP_FIN && self.snd_una != self.snd_nxt &&
+               (segment.header.window_size.get() as u32) << self.snd_shift == self.snd_wnd {
+                self.dup_acks += 1;
+                if self.dup_acks == DUP_ACK_THRESHOLD {
+                    self.rtt_seq = None;
+                    self.retransmit();
+                }
+            }
+        } else if seq_lt(self.snd_nxt, ack) {
+            // Acknowledges something not yet sent
+            self.send_ack();
+            return;
+        }
+
+        if seq_lt(self.snd_wl1, seq) || (self.snd_wl1 == seq && seq_le(self.snd_wl2, ack)) {
+            self.snd_wnd = (segment.header.window_size.get() as u32) << self.snd_shift;
+            self.snd_wl1 = seq;
+            self.snd_wl2 = ack;
+        }
+
+        if fin_acked {
+            match self.state {
+                TcpState::FinWait1 => self.state = TcpState::FinWait2,
+                TcpState::Closing => self.enter_time_wait(now),
+                TcpState::LastAck => {
+                    self.enter_closed();
+                    return;
+                },
+                _ => (),
+            }
+        }
+
+        let fin = flags & TCP_FIN == TCP_FIN;
+        match self.state {
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => {
+                if ! segment.data.is_empty() || fin {
+                    self.receive(seq, &segment.data, fin, now);
+                }
+            },
+            TcpState::TimeWait => if fin {
+                // The peer did not see our ACK of its FIN
+                self.send_ack();
+                self.enter_time_wait(now);
+            },
+            _ => if fin {
+                self.send_ack();
+            },
+        }
+
+        self.output(now);
+    }
+
+    /// Process a segment while waiting for a SYN-ACK
+    fn input_syn_sent(&mut self, segment: &Tcp, now: Duration) {
+        let flags = segment.header.flags.get();
+        let seq = segment.header.sequence.get();
+        let ack = segment.header.ack_num.get();
+
+        let has_ack = flags & TCP_ACK == TCP_ACK;
+        if has_ack && (seq_le(ack, self.iss) || seq_lt(self.snd_nxt, ack)) {
+            if flags & TCP_RST != TCP_RST {
+                let _ = super::send_segment(self.peer_addr, self.local_port, self.peer_port, ack, 0, TCP_RST, 0, Vec::new(), &[]);
+            }
+            return;
+        }
+
+        if flags & TCP_RST == TCP_RST {
+            if has_ack {
+                self.fail(ECONNREFUSED);
+            }
+            return;
+        }
+
+        if flags & TCP_SYN != TCP_SYN {
+            return;
+        }
+
+        self.irs = seq;
+        self.rcv_nxt = seq.wrapping_add(1);
+        self.rcv_adv = self.rcv_nxt;
+        self.syn_options(segment);
+
+        if has_ack {
+            self.snd_una = ack;
+            // Windows in SYN segments are never scaled
+            self.snd_wnd = segment.header.window_size.get() as u32;
+            self.snd_wl1 = seq;
+            self.snd_wl2 = ack;
+            if let Some((_, sent)) = self.rtt_seq.take() {
+                self.sample_rtt(to_millis(now - sent));
+            }
+            self.retransmit_at = None;
+            self.retries = 0;
+            self.state = TcpState::Established;
+            self.condition.notify("TcpConnection::established");
+
+            if ! segment.data.is_empty() || flags & TCP_FIN == TCP_FIN {
+                self.receive(seq.wrapping_add(1), &segment.data, flags & TCP_FIN == TCP_FIN, now);
+            } else {
+                self.send_ack();
+            }
+            self.output(now);
+        } else {
+            // Simultaneous open
+            self.state = TcpState::SynReceived;
+            let iss = self.iss;
+            self.transmit(iss, TCP_SYN | TCP_ACK, &[]);
+            self.retransmit_at = Some(now + millis(self.rto));
+        }
+    }
+
+    /// Accept data and a FIN from `seq`, queueing what arrives out of order
+    fn receive(&mut self, mut seq: u32, data: &[u8], mut fin: bool, now: Duration) {
+        let mut data = data;
+
+        // Drop what was already received
+        if seq_lt(seq, self.rcv_nxt) {
+            let skip = self.rcv_nxt.wrapping_sub(seq) as usize;
+            if skip > data.len() {
+                // Only a repeated FIN
+                fin = false;
+                data = &[];
+            } else {
+                data = &data[skip ..];
+            }
+            seq = self.rcv_nxt;
+        }
+
+        // Drop what is beyond the window
+        let window = self.rcv_adv.wrapping_sub(seq) as usize;
+        if data.len() > window {
+            data = &data[.. window];
+            fin = false;
+        }
+
+        if seq == self.rcv_nxt {
+            self.accept(data, fin, now);
+
+            // Take queued segments that are now in order
+            loop {
+                let rcv_nxt = self.rcv_nxt;
+                match self.reassembly.iter().position(|entry| seq_le(entry.0, rcv_nxt)) {
+                    Some(i) => {
+                        let (entry_seq, entry_data, entry_fin) = self.reassembly.remove(i);
+                        let skip = rcv_nxt.wrapping_sub(entry_seq) as usize;
+                        if skip <= entry_data.len() && ! self.fin_received {
+                            self.accept(&entry_data[skip ..], entry_fin, now);
+                        }
+                    },
+                    None => break,
+                }
+            }
+        } else if (! data.is_empty() || fin) && self.reassembly.len() < REASSEMBLY_SEGMENTS {
+            // Queue only the parts not queued already, as offsets from rcv_nxt. Overlapping
+            // segments then cannot queue the same bytes twice, and what is queued stays within
+            // the window.
+            let start = seq.wrapping_sub(self.rcv_nxt) as usize;
+            let mut pieces = vec![(start, start + data.len())];
+            for entry in self.reassembly.iter() {
+                let entry_start = entry.0.wrapping_sub(self.rcv_nxt) as usize;
+                let entry_end = entry_start + entry.1.len();
+                let mut remaining = Vec::new();
+                for &(piece_start, piece_end) in pieces.iter() {
+                    if piece_end <= entry_start || entry_end <= piece_start {
+                        remaining.push((piece_start, piece_end));
+                    } else {
+                        if piece_start < entry_start {
+                            remaining.push((piece_start, entry_start));
+                        }
+                        if entry_end < piece_end {
+                            remaining.push((entry_end, piece_end));
+                        }
+                    }
+                }
+                pieces = remaining;
+            }
+
+            let fin_queued = self.reassembly.iter().any(|entry| entry.2);
+            for &(piece_start, piece_end) in pieces.iter() {
+                if piece_start < piece_end {
+                    let piece_seq = self.rcv_nxt.wrapping_add(piece_start as u32);
+                    self.reassembly.push((piece_seq, data[piece_start - start .. piece_end - start].to_vec(), false));
+                }
+            }
+            if fin && ! fin_queued {
+                self.reassembly.push((seq.wrapping_add(data.len() as u32), Vec::new(), true));
+            }
+        }
+
+        // Acknowledge immediately, which also signals a gap with a duplicate ACK
+        self.send_ack();
+    }
+
+    /// Take in order data and a FIN
+    fn accept(&mut self, data: &[u8], fin: bool, now: Duration) {
+        if self.fin_received {
+            return;
+        }
+
+        self.receive_buffer.extend(data.iter().cloned());
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(data.len() as u32);
+
+        if fin {
+            self.fin_received = true;
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.reassembly.clear();
+            match self.state {
+                TcpState::SynReceived | TcpState::Established => self.state = TcpState::CloseWait,
+                TcpState::FinWait1 => self.state = TcpState::Closing,
+                TcpState::FinWait2 => self.enter_time_wait(now),
+                _ => (),
+            }
+        }
+
+        self.condition.notify("TcpConnection::receive");
+    }
+}
@@ -0,0 +1,84 @@
+use alloc::arc::Arc;
+
+use collections::VecDeque;
+
+use core::cell::UnsafeCell;
+
+use sync::WaitCondition;
+
+use super::connection::{TcpConnection, TcpState};
+
+/// Connections a listener holds before the application accepts them
+pub const BACKLOG: usize = 64;
+
+/// A port accepting connections
+///
+/// Each SYN to the port creates a connection in the queue, which the application accepts once it
+/// is established. SYNs arriving with a full queue are dropped, and the peer retries them.
+pub struct TcpListener {
+    pub local_port: u16,
+    /// Connections from SYNs, in order of arrival, until accepted
+    queue: VecDeque<Arc<UnsafeCell<TcpConnection>>>,
+    /// Every resource using the listener was dropped
+    pub released: bool,
+    /// Notified when a queued connection changes
+    pub condition: WaitCondition,
+}
+
+impl TcpListener {
+    pub fn new(local_port: u16) -> TcpListener {
+        TcpListener {
+            local_port: local_port,
+            queue: VecDeque::new(),
+            released: false,
+            condition: WaitCondition::new(),
+        }
+    }
+
+    /// Forget queued connections that failed or were reset before being accepted
+    fn prune(&mut self) {
+        let mut i = 0;
+        while i < self.queue.len() {
+            let state = unsafe { & *self.queue[i].get() }.state;
+            if state == TcpState::Closed || state == TcpState::Listen {
+                self.queue.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Whether another SYN can be queued
+    pub fn has_room(&mut self) -> bool {
+        self.prune();
+        self.queue.len() < BACKLOG
+    }
+
+    pub fn push(&mut self, connection: Arc<UnsafeCell<TcpConnection>>) {
+        self.queue.push_back(connection);
+    }
+
+    /// Whether an established connection is waiting to be accepted
+    pub fn ready(&self) -> bool {
+        self.queue.iter().any(|connection| unsafe { & *connection.get() }.state != TcpState::SynReceived)
+    }
+
+    /// Take the first established connection
+    pub fn accept(&mut self) -> Option<Arc<UnsafeCell<TcpConnection>>> {
+        self.prune();
+        let position = self.queue.iter().position(|connection| unsafe { & *connection.get() }.state != TcpState::SynReceived);
+        match position {
+            Some(i) => self.queue.remove(i),
+            None => None,
+        }
+    }
+
+    /// Reset every connection not handed to the application
+    pub fn close(&mut self) {
+        for connection in self.queue.drain(..) {
+            unsafe { &mut *connection.get() }.abort();
+        }
+        self.released = true;
+        self.condition.notify("TcpListener::close");
+    }
+}
@@ -0,0 +1,772 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use arch::context::{context_sleep, Context};
+
+use collections::Vec;
+
+use common::random::rand;
+use common::time::{Duration, NANOS_PER_MILLI};
+
+use core::{cmp, mem, slice};
+use core::cell::UnsafeCell;
+
+use fs::{KScheme, Resource};
+
+use network::common::{n16, n32, Checksum, IpAddr, Ipv4Addr, Ipv6Addr, FromBytes, ToBytes};
+use network::demux::{Filter, Receiver};
+use network::route;
+
+use system::error::{Error, Result, EADDRINUSE, ECONNREFUSED, EHOSTUNREACH, ENOENT, EPERM, EPIPE, ETIMEDOUT};
+use system::syscall::{O_NONBLOCK, O_RDWR, POLLIN, POLLOUT};
+
+use super::icmpv6::{ICMPV6_PACKET_TOO_BIG, ICMPV6_PROTO, ICMPV6_UNREACHABLE};
+use super::socket::{self, SocketOptions};
+
+use self::connection::{TcpConnection, TcpState};
+use self::listener::TcpListener;
+
+pub mod connection;
+pub mod listener;
+
+/// How often the retransmission and TIME_WAIT timers are checked
+const TIMER_INTERVAL: i32 = 50 * NANOS_PER_MILLI;
+
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct TcpHeader {
+    pub src: n16,
+    pub dst: n16,
+    pub sequence: n32,
+    pub ack_num: n32,
+    pub flags: n16,
+    pub window_size: n16,
+    pub checksum: Checksum,
+    pub urgent_pointer: n16,
+}
+
+pub struct Tcp {
+    pub header: TcpHeader,
+    pub options: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl Tcp {
+    /// Sum the pseudo header and the segment
+    fn sum(&self, src_addr: &IpAddr, dst_addr: &IpAddr) -> usize {
+        let segment_len = mem::size_of::<TcpHeader>() + self.options.len() + self.data.len();
+        unsafe {
+            Checksum::pseudo_header(src_addr, dst_addr, 0x06, segment_len) +
+            Checksum::sum((&self.header as *const TcpHeader) as usize, mem::size_of::<TcpHeader>()) +
+            Checksum::sum(self.options.as_ptr() as usize, self.options.len()) +
+            Checksum::sum(self.data.as_ptr() as usize, self.data.len())
+        }
+    }
+
+    fn checksum(&mut self, src_addr: &IpAddr, dst_addr: &IpAddr) {
+        self.header.checksum.data = 0;
+        self.header.checksum.data = Checksum::compile(self.sum(src_addr, dst_addr));
+    }
+
+    /// Check the checksum of a received segment
+    fn verify(&self, src_addr: &IpAddr, dst_addr: &IpAddr) -> bool {
+        Checksum::compile(self.sum(src_addr, dst_addr)) == 0
+    }
+
+    /// Find an option by kind, returning its value
+    fn option(&self, kind: u8) -> Option<&[u8]> {
+        let mut i = 0;
+        while i < self.options.len() {
+            match self.options[i] {
+                0 => break,
+                1 => i += 1,
+                _ => {
+                    let len = *self.options.get(i + 1).unwrap_or(&0) as usize;
+                    if len < 2 || i + len > self.options.len() {
+                        break;
+                    }
+                    if self.options[i] == kind {
+                        return Some(&self.options[i + 2 .. i + len]);
+                    }
+                    i += len;
+                },
+            }
+        }
+        None
+    }
+
+    /// The maximum segment size option
+    pub fn mss(&self) -> Option<u16> {
+        match self.option(2) {
+            Some(value) if value.len() == 2 => Some((value[0] as u16) << 8 | value[1] as u16),
+            _ => None,
+        }
+    }
+
+    /// The window scale option
+    pub fn window_scale(&self) -> Option<u8> {
+        match self.option(3) {
+            Some(value) if value.len() == 1 => Some(value[0]),
+            _ => None,
+        }
+    }
+}
+
+pub const TCP_FIN: u16 = 1;
+pub const TCP_SYN: u16 = 1 << 1;
+pub const TCP_RST: u16 = 1 << 2;
+pub const TCP_PSH: u16 = 1 << 3;
+pub const TCP_ACK: u16 = 1 << 4;
+
+impl FromBytes for Tcp {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() >= mem::size_of::<TcpHeader>() {
+            unsafe {
+                let header = *(bytes.as_ptr() as *const TcpHeader);
+                let header_len = ((header.flags.get() & 0xF000) >> 10) as usize;
+
+                if header_len >= mem::size_of::<TcpHeader>() && header_len <= bytes.len() {
+                    return Some(Tcp {
+                        header: header,
+                        options: bytes[mem::size_of::<TcpHeader>()..header_len].to_vec(),
+                        data: bytes[header_len..bytes.len()].to_vec(),
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+impl ToBytes for Tcp {
+    fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            let header_ptr: *const TcpHeader = &self.header;
+            let mut ret = Vec::from(slice::from_raw_parts(header_ptr as *const u8,
+                                                          mem::size_of::<TcpHeader>()));
+            ret.extend_from_slice(&self.options);
+            ret.extend_from_slice(&self.data);
+            ret
+        }
+    }
+}
+
+/// Build and send one segment
+///
+/// A new `ip:` or `ip6:` resource is opened for every segment, since one kept open would queue
+/// every packet the NIC receives without ever reading them. It is opened without blocking, so
+/// segments queue in the neighbour cache instead of stalling the receive and timer contexts while
+/// the next hop resolves. Segments are sent with don't fragment set, so routers report a smaller
+/// path MTU instead of fragmenting them.
+pub fn send_segment(peer_addr: IpAddr, local_port: u16, peer_port: u16, seq: u32, ack: u32, flags: u16,
+                    window: u16, options: Vec<u8>, data: &[u8]) -> Result<()> {
+    let mut tcp = Tcp {
+        header: TcpHeader {
+            src: n16::new(local_port),
+            dst: n16::new(peer_port),
+            sequence: n32::new(seq),
+            ack_num: n32::new(ack),
+            flags: n16::new((((mem::size_of::<TcpHeader>() + options.len()) << 10) & 0xF000) as u16 | flags),
+            window_size: n16::new(window),
+            checksum: Checksum { data: 0 },
+            urgent_pointer: n16::new(0),
+        },
+        options: options,
+        data: data.to_vec(),
+    };
+
+    let src_addr = try!(route::source(peer_addr));
+    tcp.checksum(&src_addr, &peer_addr);
+
+    let mut ip = try!(::env().open(&(route::url(peer_addr, 6) + "?df"), O_RDWR | O_NONBLOCK));
+    ip.write(&tcp.to_bytes()).and(Ok(()))
+}
+
+/// Answer a segment for no connection with a reset, as in RFC 793 section 3.4
+fn reset(src: IpAddr, segment: &Tcp) {
+    let flags = segment.header.flags.get();
+    if flags & TCP_RST == TCP_RST {
+        return;
+    }
+
+    let local_port = segment.header.dst.get();
+    let peer_port = segment.header.src.get();
+    let result = if flags & TCP_ACK == TCP_ACK {
+        send_segment(src, local_port, peer_port, segment.header.ack_num.get(), 0, TCP_RST, 0, Vec::new(), &[])
+    } else {
+        // Acknowledge the whole segment, with its SYN and FIN
+        let mut len = segment.data.len() as u32;
+        if flags & TCP_SYN == TCP_SYN {
+            len += 1;
+        }
+        if flags & TCP_FIN == TCP_FIN {
+            len += 1;
+        }
+        let ack = segment.header.sequence.get().wrapping_add(len);
+        send_segment(src, local_port, peer_port, 0, ack, TCP_RST | TCP_ACK, 0, Vec::new(), &[])
+    };
+
+    if let Err(err) = result {
+        debugln!("TCP: Reset to {} failed: {}", socket::join_host(src, peer_port), err);
+    }
+}
+
+/// The connections and listeners of the TCP scheme, fed by its receive and timer contexts
+pub struct TcpTable {
+    connections: Vec<Arc<UnsafeCell<TcpConnection>>>,
+    listeners: Vec<Arc<UnsafeCell<TcpListener>>>,
+}
+
+impl TcpTable {
+    fn new() -> TcpTable {
+        TcpTable {
+            connections: Vec::new(),
+            listeners: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, connection: TcpConnection) -> Arc<UnsafeCell<TcpConnection>> {
+        let connection = Arc::new(UnsafeCell::new(connection));
+        self.connections.push(connection.clone());
+        connection
+    }
+
+    /// The listener on a port, ignoring ones no resource uses
+    fn listener(&self, local_port: u16) -> Option<Arc<UnsafeCell<TcpListener>>> {
+        self.listeners.iter().find(|listener| {
+            let listener = unsafe { & *listener.get() };
+            listener.local_port == local_port && ! listener.released
+        }).map(|listener| listener.clone())
+    }
+
+    /// Pick an unused ephemeral port
+    fn ephemeral_port(&self) -> u16 {
+        loop {
+            let port = (rand() % 32768 + 32768) as u16;
+            if ! self.connections.iter().any(|connection| unsafe { & *connection.get() }.local_port == port) &&
+               self.listener(port).is_none() {
+                return port;
+            }
+        }
+    }
+
+    /// Hand a segment to its connection, or a SYN to the listener on its port
+    fn input(&mut self, src: IpAddr, segment: &Tcp) {
+        let now = Duration::monotonic();
+        let local_port = segment.header.dst.get();
+        let peer_port = segment.header.src.get();
+
+        let found = self.connections.iter().find(|connection| {
+            let connection = unsafe { & *connection.get() };
+            connection.state != TcpState::Listen && connection.state != TcpState::Closed &&
+            connection.local_port == local_port && connection.peer_port == peer_port && connection.peer_addr.equals(src)
+        }).map(|connection| connection.clone());
+
+        let listener = self.listener(local_port);
+
+        if let Some(connection) = found {
+            let connection = unsafe { &mut *connection.get() };
+            if listener.is_none() || ! connection.reuse_time_wait(segment) {
+                connection.input(src, segment, now);
+                if let Some(ref listener) = listener {
+                    unsafe { & *listener.get() }.condition.notify("TcpTable::input");
+                }
+                return;
+            }
+        }
+
+        let flags = segment.header.flags.get();
+        if flags & (TCP_SYN | TCP_ACK | TCP_RST) == TCP_SYN {
+            if let Some(listener) = listener {
+                // A SYN the backlog has no room for is dropped, so the peer retries it
+                let listener = unsafe { &mut *listener.get() };
+                if listener.has_room() {
+                    let mut connection = TcpConnection::new(local_port, src, peer_port);
+                    connection.listen();
+                    // No resource uses the connection until it is accepted
+                    connection.released = true;
+                    connection.input(src, segment, now);
+                    listener.push(self.add(connection));
+                }
+                return;
+            }
+        }
+
+        reset(src, segment);
+    }
+
+    /// The connection an ICMP message is about
+    fn find(&self, peer_addr: IpAddr, local_port: u16, peer_port: u16) -> Option<&mut TcpConnection> {
+        self.connections.iter().map(|connection| unsafe { &mut *connection.get() }).find(|connection| {
+            connection.local_port == local_port && connection.peer_port == peer_port && connection.peer_addr.equals(peer_addr)
+        })
+    }
+
+    /// Report an ICMP destination unreachable message to the connection it is about
+    fn unreachable(&mut self, peer_addr: IpAddr, local_port: u16, peer_port: u16, errno: isize) {
+        if let Some(connection) = self.find(peer_addr, local_port, peer_port) {
+            connection.network_error(errno);
+        }
+    }
+
+    /// Report the MTU of the next hop from ICMP fragmentation needed or packet too big, which
+    /// is not an error, but limits the size of the connection's segments
+    fn packet_too_big(&mut self, peer_addr: IpAddr, local_port: u16, peer_port: u16, mtu: usize) {
+        if let Some(connection) = self.find(peer_addr, local_port, peer_port) {
+            connection.path_mtu(mtu);
+        }
+    }
+
+    /// Run the timers, and forget closed connections and listeners no resource uses
+    fn on_timer(&mut self) {
+        // Connections may be added while a timer sends, so iterate over a copy
+        let now = Duration::monotonic();
+        let connections = self.connections.clone();
+        for connection in connections.iter() {
+            unsafe { &mut *connection.get() }.on_timer(now);
+        }
+
+        self.connections.retain(|connection| {
+            let connection = unsafe { & *connection.get() };
+            ! ((connection.state == TcpState::Closed || connection.state == TcpState::Listen) && connection.released)
+        });
+
+        self.listeners.retain(|listener| ! unsafe { & *listener.get() }.released);
+    }
+
+    /// Handle an ICMP destination unreachable message, quoting the IP header and 8 bytes of a
+    /// segment we sent
+    fn icmp_error(&mut self, data: &[u8]) {
+        if data.len() >= 8 + 20 && data[0] == 3 && data[8 + 9] == 6 {
+            let quoted = &data[8..];
+            let header_len = (quoted[0] & 0xF) as usize * 4;
+            if quoted.len() >= header_len + 4 {
+                let peer_addr = IpAddr::V4(Ipv4Addr {
+                    bytes: [quoted[16], quoted[17], quoted[18], quoted[19]]
+                });
+                let local_port = (quoted[header_len] as u16) << 8 | quoted[header_len + 1] as u16;
+                let peer_port = (quoted[header_len + 2] as u16) << 8 | quoted[header_len + 3] as u16;
+                let mtu = (data[6] as usize) << 8 | data[7] as usize;
+                match data[1] {
+                    // Protocol and port unreachable
+                    2 | 3 => self.unreachable(peer_addr, local_port, peer_port, ECONNREFUSED),
+                    4 => self.packet_too_big(peer_addr, local_port, peer_port, mtu),
+                    _ => self.unreachable(peer_addr, local_port, peer_port, EHOSTUNREACH),
+                }
+            }
+        }
+    }
+
+    /// Handle an ICMPv6 destination unreachable or packet too big message, quoting a segment we sent
+    fn icmpv6_error(&mut self, data: &[u8]) {
+        if data.len() >= 8 + 40 + 4 && data[8 + 6] == 6 {
+            let quoted = &data[8..];
+            let peer_addr = IpAddr::V6(Ipv6Addr::from_slice(&quoted[24 .. 40]));
+            let local_port = (quoted[40] as u16) << 8 | quoted[41] as u16;
+            let peer_port = (quoted[42] as u16) << 8 | quoted[43] as u16;
+            let mtu = (data[4] as usize) << 24 | (data[5] as usize) << 16 |
+                      (data[6] as usize) << 8 | data[7] as usize;
+            match (data[0], data[1]) {
+                (ICMPV6_UNREACHABLE, 4) => self.unreachable(peer_addr, local_port, peer_port, ECONNREFUSED),
+                (ICMPV6_UNREACHABLE, _) => self.unreachable(peer_addr, local_port, peer_port, EHOSTUNREACH),
+                (ICMPV6_PACKET_TOO_BIG, _) => self.packet_too_big(peer_addr, local_port, peer_port, mtu),
+                _ => (),
+            }
+        }
+    }
+
+    /// Wait for TCP segments to us over IPv4 and IPv6, and errors about the segments we sent,
+    /// handing them to their connections
+    fn receive_loop(table: Arc<UnsafeCell<TcpTable>>) {
+        let receiver = Receiver::new(vec![Filter::new(false, 6), Filter::new(true, 6)], true);
+        loop {
+            let packet = receiver.queue.receive("TcpTable::receive_loop");
+            if ! packet.dst.is_local() {
+                continue;
+            }
+
+            let table = unsafe { &mut *table.get() };
+            match packet.proto {
+                6 => if let Some(segment) = Tcp::from_bytes(&packet.data) {
+                    if segment.verify(&packet.src, &packet.dst) {
+                        table.input(packet.src, &segment);
+                    }
+                },
+                ICMPV6_PROTO => table.icmpv6_error(&packet.data),
+                _ => table.icmp_error(&packet.data),
+            }
+        }
+    }
+
+    /// Check the timers every `TIMER_INTERVAL`
+    fn timer_loop(table: Arc<UnsafeCell<TcpTable>>) {
+        loop {
+            context_sleep("TcpTable::timer_loop", Duration::new(0, TIMER_INTERVAL));
+
+            unsafe { &mut *table.get() }.on_timer();
+        }
+    }
+}
+
+/// The application's end of a connection, closed when the last resource using it is dropped
+pub struct TcpStream {
+    connection: Arc<UnsafeCell<TcpConnection>>,
+}
+
+impl TcpStream {
+    fn new(connection: Arc<UnsafeCell<TcpConnection>>) -> TcpStream {
+        unsafe { &mut *connection.get() }.released = false;
+        TcpStream {
+            connection: connection,
+        }
+    }
+
+    fn connection(&self) -> &mut TcpConnection {
+        unsafe { &mut *self.connection.get() }
+    }
+
+    /// Wait for the connection to change, at most until a deadline
+    fn wait(&self, reason: &str, deadline: Option<Duration>) {
+        socket::wait(&self.connection().condition, reason, deadline);
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let connection = self.connection();
+        let path_string = format!("tcp:{}/{}", socket::join_host(connection.peer_addr, connection.peer_port), connection.local_port);
+        let path = path_string.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8], options: &SocketOptions) -> Result<usize> {
+        let deadline = socket::deadline(options.read_timeout);
+        loop {
+            {
+                let connection = self.connection();
+                if connection.readable() > 0 {
+                    return Ok(connection.recv(buf));
+                }
+                if connection.at_eof() {
+                    return Ok(0);
+                }
+                if let Some(errno) = connection.error {
+                    return Err(Error::new(errno));
+                }
+                if connection.state == TcpState::Closed {
+                    return Ok(0);
+                }
+            }
+            try!(options.may_wait(deadline));
+            self.wait("TcpStream::read", deadline);
+        }
+    }
+
+    /// Queue all of `buf`, waiting for room in the send buffer
+    ///
+    /// Without waiting, or when the timeout ends, the part of `buf` that fit is returned.
+    fn write(&mut self, buf: &[u8], options: &SocketOptions) -> Result<usize> {
+        let deadline = socket::deadline(options.write_timeout);
+        let mut written = 0;
+        loop {
+            {
+                let connection = self.connection();
+                if let Some(errno) = connection.error {
+                    return Err(Error::new(errno));
+                }
+                if ! connection.can_send() {
+                    return Err(Error::new(EPIPE));
+                }
+
+                written += connection.send(&buf[written ..], Duration::monotonic());
+                if written >= buf.len() {
+                    return Ok(written);
+                }
+            }
+            if let Err(err) = options.may_wait(deadline) {
+                return if written > 0 {
+                    Ok(written)
+                } else {
+                    Err(err)
+                };
+            }
+            self.wait("TcpStream::write", deadline);
+        }
+    }
+
+    /// Wait for everything written to be acknowledged
+    fn sync(&mut self, options: &SocketOptions) -> Result<()> {
+        let deadline = socket::deadline(options.write_timeout);
+        loop {
+            {
+                let connection = self.connection();
+                if let Some(errno) = connection.error {
+                    return Err(Error::new(errno));
+                }
+                if connection.unacknowledged() == 0 || connection.state == TcpState::Closed {
+                    return Ok(());
+                }
+            }
+            try!(options.may_wait(deadline));
+            self.wait("TcpStream::sync", deadline);
+        }
+    }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        let connection = self.connection();
+        let closed = connection.error.is_some() || connection.state == TcpState::Closed;
+
+        let mut ready = 0;
+        if connection.readable() > 0 || connection.at_eof() || closed {
+            ready |= POLLIN;
+        }
+        if connection.writable() > 0 || closed {
+            ready |= POLLOUT;
+        }
+        Ok(events & ready)
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let connection = self.connection();
+        // Unread data is lost, which the peer learns from a reset
+        if connection.readable() > 0 {
+            connection.abort();
+        } else {
+            connection.close(Duration::monotonic());
+        }
+        connection.released = true;
+    }
+}
+
+/// A TCP resource
+pub struct TcpResource {
+    stream: Arc<UnsafeCell<TcpStream>>,
+    options: SocketOptions,
+}
+
+impl Resource for TcpResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box TcpResource {
+            stream: self.stream.clone(),
+            options: self.options,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        unsafe { (*self.stream.get()).path(buf) }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        unsafe { (*self.stream.get()).read(buf, &self.options) }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        unsafe { (*self.stream.get()).write(buf, &self.options) }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        unsafe { (*self.stream.get()).sync(&self.options) }
+    }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        unsafe { (*self.stream.get()).poll(events) }
+    }
+}
+
+/// The application's end of a listener, closed when the last resource using it is dropped
+pub struct TcpAcceptor {
+    listener: Arc<UnsafeCell<TcpListener>>,
+}
+
+impl TcpAcceptor {
+    fn listener(&self) -> &mut TcpListener {
+        unsafe { &mut *self.listener.get() }
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = format!("tcp:/{}", self.listener().local_port);
+        let path = path_string.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    /// Wait for a connection, returning a resource using it
+    fn accept(&self, options: &SocketOptions) -> Result<Box<Resource>> {
+        let deadline = socket::deadline(options.read_timeout);
+        loop {
+            if let Some(connection) = self.listener().accept() {
+                return Ok(box TcpResource {
+                    stream: Arc::new(UnsafeCell::new(TcpStream::new(connection))),
+                    options: *options,
+                });
+            }
+            try!(options.may_wait(deadline));
+            socket::wait(&self.listener().condition, "TcpAcceptor::accept", deadline);
+        }
+    }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        if self.listener().ready() {
+            Ok(events & POLLIN)
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+impl Drop for TcpAcceptor {
+    fn drop(&mut self) {
+        self.listener().close();
+    }
+}
+
+/// A listening TCP resource
+///
+/// `dup` with the path `accept` waits for a connection and returns it as a new resource, with
+/// the listener's options. A plain `dup` shares the listener, so a forked server keeps it.
+pub struct TcpListenerResource {
+    acceptor: Arc<UnsafeCell<TcpAcceptor>>,
+    options: SocketOptions,
+}
+
+impl Resource for TcpListenerResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box TcpListenerResource {
+            acceptor: self.acceptor.clone(),
+            options: self.options,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        unsafe { (*self.acceptor.get()).path(buf) }
+    }
+
+    fn dup_path(&self, path: &[u8]) -> Result<Box<Resource>> {
+        if path.is_empty() {
+            self.dup()
+        } else if path == b"accept" {
+            unsafe { (*self.acceptor.get()).accept(&self.options) }
+        } else {
+            Err(Error::new(EPERM))
+        }
+    }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        unsafe { (*self.acceptor.get()).poll(events) }
+    }
+}
+
+/// A TCP scheme
+///
+/// `tcp:HOST:PORT` connects to a peer, and `tcp:/PORT` listens for peers to connect. IPv6 hosts
+/// are given in brackets, as `tcp:[ADDR]:PORT`. A port can be
+/// listened on again as soon as its listener is closed, even while its old connections finish.
+/// Timeouts are given after a `?`, as described by `SocketOptions`.
+pub struct TcpScheme {
+    table: Arc<UnsafeCell<TcpTable>>,
+}
+
+impl TcpScheme {
+    /// Create the scheme, starting the contexts that receive segments and run timers
+    pub fn new() -> Box<TcpScheme> {
+        let table = Arc::new(UnsafeCell::new(TcpTable::new()));
+
+        let receive_table = table.clone();
+        Context::spawn("ktcp".into(),
+                       box move || {
+                           TcpTable::receive_loop(receive_table);
+                       });
+
+        let timer_table = table.clone();
+        Context::spawn("ktcp_timer".into(),
+                       box move || {
+                           TcpTable::timer_loop(timer_table);
+                       });
+
+        box TcpScheme {
+            table: table,
+        }
+    }
+
+    /// Wait for a connection to leave the opening states
+    ///
+    /// A non-blocking open returns while the connection is still opening.
+    fn establish(&mut self, connection: Arc<UnsafeCell<TcpConnection>>, options: SocketOptions) -> Result<Box<Resource>> {
+        let stream = TcpStream::new(connection);
+        let deadline = socket::deadline(options.connect_timeout);
+
+        loop {
+            match stream.connection().state {
+                TcpState::SynSent | TcpState::SynReceived if ! options.nonblock => {
+                    if socket::expired(deadline) {
+                        // Dropping the stream abandons the connection
+                        return Err(Error::new(ETIMEDOUT));
+                    }
+                    stream.wait("TcpScheme::establish", deadline);
+                },
+                TcpState::Closed => return Err(Error::new(stream.connection().error.unwrap_or(ENOENT))),
+                _ => return Ok(box TcpResource {
+                    stream: Arc::new(UnsafeCell::new(stream)),
+                    options: options,
+                }),
+            }
+        }
+    }
+}
+
+impl KScheme for TcpScheme {
+    fn scheme(&self) -> &str {
+        "tcp"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let (url, options) = try!(SocketOptions::parse(url, flags));
+
+        let mut parts = url.splitn(2, ":").nth(1).unwrap_or("").split('/');
+        let remote = parts.next().unwrap_or("");
+        let path = parts.next().unwrap_or("");
+
+        let table = unsafe { &mut *self.table.get() };
+
+        if let Some((peer_addr, port)) = socket::split_host(remote) {
+            let peer_port = port.parse::<u16>().unwrap_or(0);
+
+            if ! path.is_empty() {
+                return Err(Error::new(ENOENT));
+            }
+
+            let local_port = table.ephemeral_port();
+            let connection = table.add(TcpConnection::new(local_port, peer_addr, peer_port));
+            unsafe { &mut *connection.get() }.connect(Duration::monotonic());
+            self.establish(connection, options)
+        } else if ! path.is_empty() {
+            let local_port = path.parse::<u16>().unwrap_or(0);
+            if local_port == 0 {
+                return Err(Error::new(ENOENT));
+            }
+            if table.listener(local_port).is_some() {
+                return Err(Error::new(EADDRINUSE));
+            }
+
+            let listener = Arc::new(UnsafeCell::new(TcpListener::new(local_port)));
+            table.listeners.push(listener.clone());
+            Ok(box TcpListenerResource {
+                acceptor: Arc::new(UnsafeCell::new(TcpAcceptor {
+                    listener: listener,
+                })),
+                options: options,
+            })
+        } else {
+            Err(Error::new(ENOENT))
+        }
+    }
+}
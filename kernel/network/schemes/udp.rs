@@ -1,17 +1,40 @@
+use alloc::arc::Arc;
 use alloc::boxed::Box;
 
-use collections::Vec;
+use arch::context::Context;
+
+use collections::{Vec, VecDeque};
 
 use common::random::rand;
 
-use core::{cmp, mem, ptr, slice, str};
+use core::{cmp, mem, ptr, slice};
+use core::cell::UnsafeCell;
 
 use fs::{KScheme, Resource};
 
-use network::common::{n16, Checksum, Ipv4Addr, IP_ADDR, FromBytes, ToBytes};
+use network::common::{n16, Checksum, IpAddr, Ipv4Addr, Ipv6Addr, FromBytes, ToBytes};
+use network::demux::{Filter, Receiver};
+use network::route;
+
+use sync::WaitCondition;
+
+use system::error::{Error, Result, EACCES, EADDRINUSE, ECONNREFUSED, EDESTADDRREQ, EINVAL, EMSGSIZE, ENOENT};
+use system::syscall::{O_NONBLOCK, O_RDWR, POLLIN, POLLOUT};
 
-use system::error::{Error, Result, ENOENT};
-use system::syscall::O_RDWR;
+use super::icmpv6::{ICMPV6_PROTO, ICMPV6_UNREACHABLE};
+use super::socket::{self, SocketOptions};
+
+/// Bytes of datagrams a socket holds before dropping new ones
+const RECEIVE_BUFFER: usize = 256 * 1024;
+/// Bytes each queued datagram is charged besides its data, so empty datagrams fill the buffer too
+const DATAGRAM_OVERHEAD: usize = 256;
+/// Largest payload of a datagram
+const MAX_PAYLOAD: usize = 65507;
+
+/// The datagram did not fit in the read, and the rest of it was discarded
+pub const UDP_TRUNCATED: u16 = 1;
+/// The datagram was sent to a broadcast address
+pub const UDP_BROADCAST: u16 = 1 << 1;
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -27,18 +50,51 @@ pub struct Udp {
     pub data: Vec<u8>,
 }
 
+impl Udp {
+    /// Sum the pseudo header and the datagram
+    fn sum(&self, src_addr: &IpAddr, dst_addr: &IpAddr) -> usize {
+        let datagram_len = mem::size_of::<UdpHeader>() + self.data.len();
+        unsafe {
+            Checksum::pseudo_header(src_addr, dst_addr, 0x11, datagram_len) +
+            Checksum::sum((&self.header as *const UdpHeader) as usize, mem::size_of::<UdpHeader>()) +
+            Checksum::sum(self.data.as_ptr() as usize, self.data.len())
+        }
+    }
+
+    fn checksum(&mut self, src_addr: &IpAddr, dst_addr: &IpAddr) {
+        self.header.checksum.data = 0;
+        // A checksum of zero means none was computed, so zero is sent as all ones
+        self.header.checksum.data = match Checksum::compile(self.sum(src_addr, dst_addr)) {
+            0 => 0xFFFF,
+            checksum => checksum,
+        };
+    }
+
+    /// Check the checksum of a received datagram, if it has one, which it must over IPv6
+    fn verify(&self, src_addr: &IpAddr, dst_addr: &IpAddr) -> bool {
+        let optional = match *src_addr {
+            IpAddr::V4(_) => self.header.checksum.data == 0,
+            IpAddr::V6(_) => false,
+        };
+        optional || Checksum::compile(self.sum(src_addr, dst_addr)) == 0
+    }
+}
+
 impl FromBytes for Udp {
     fn from_bytes(bytes: &[u8]) -> Option<Self> {
         if bytes.len() >= mem::size_of::<UdpHeader>() {
             unsafe {
-                Option::Some(Udp {
-                    header: ptr::read(bytes.as_ptr() as *const UdpHeader),
-                    data: bytes[mem::size_of::<UdpHeader>()..bytes.len()].to_vec(),
-                })
+                let header = ptr::read(bytes.as_ptr() as *const UdpHeader);
+                let len = header.len.get() as usize;
+                if len >= mem::size_of::<UdpHeader>() && len <= bytes.len() {
+                    return Option::Some(Udp {
+                        header: header,
+                        data: bytes[mem::size_of::<UdpHeader>()..len].to_vec(),
+                    });
+                }
             }
-        } else {
-            Option::None
         }
+        Option::None
     }
 }
 
@@ -54,33 +110,279 @@ impl ToBytes for Udp {
     }
 }
 
-/// UDP resource
-pub struct UdpResource {
-    ip: Box<Resource>,
+/// The header before each datagram read from or written to a bound socket
+///
+/// Addresses are IPv6, with IPv4 addresses mapped into it, as `::ffff:a.b.c.d`.
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct UdpFrameHeader {
+    pub addr: [u8; 16],
+    pub port: n16,
+    /// `UDP_TRUNCATED` and `UDP_BROADCAST`, ignored when writing
+    pub flags: n16,
+}
+
+impl UdpFrameHeader {
+    fn new(addr: IpAddr, port: u16, flags: u16) -> UdpFrameHeader {
+        UdpFrameHeader {
+            addr: addr.to_mapped().bytes,
+            port: n16::new(port),
+            flags: n16::new(flags),
+        }
+    }
+
+    /// The address, which is IPv4 if it is mapped from one
+    fn addr(&self) -> IpAddr {
+        IpAddr::from_mapped(Ipv6Addr::from_slice(&self.addr))
+    }
+}
+
+/// Build and send one datagram, queueing it for the next hop to resolve instead of waiting if
+/// `nonblock` is set
+pub fn send_datagram(peer_addr: IpAddr, local_port: u16, peer_port: u16, data: &[u8], nonblock: bool) -> Result<()> {
+    let mut udp = Udp {
+        header: UdpHeader {
+            src: n16::new(local_port),
+            dst: n16::new(peer_port),
+            len: n16::new((mem::size_of::<UdpHeader>() + data.len()) as u16),
+            checksum: Checksum { data: 0 },
+        },
+        data: data.to_vec(),
+    };
+
+    let src_addr = try!(route::source(peer_addr));
+    udp.checksum(&src_addr, &peer_addr);
+
+    let flags = if nonblock { O_RDWR | O_NONBLOCK } else { O_RDWR };
+    let mut ip = try!(::env().open(&route::url(peer_addr, 0x11), flags));
+    ip.write(&udp.to_bytes()).and(Ok(()))
+}
+
+/// A received datagram
+struct Datagram {
+    src: IpAddr,
+    src_port: u16,
+    broadcast: bool,
     data: Vec<u8>,
-    peer_addr: Ipv4Addr,
-    peer_port: u16,
-    host_port: u16,
 }
 
-impl Resource for UdpResource {
-    fn dup(&self) -> Result<Box<Resource>> {
-        match self.ip.dup() {
-            Ok(ip) => {
-                Ok(Box::new(UdpResource {
-                    ip: ip,
-                    data: self.data.clone(),
-                    peer_addr: self.peer_addr,
-                    peer_port: self.peer_port,
-                    host_port: self.host_port,
-                }))
+/// A bound port, with the datagrams received on it
+pub struct UdpSocket {
+    pub local_port: u16,
+    /// The only peer datagrams are exchanged with, for a connected socket
+    pub peer: Option<(IpAddr, u16)>,
+    queue: VecDeque<Datagram>,
+    /// Bytes charged for the queue, which are its data and `DATAGRAM_OVERHEAD` per datagram
+    queued: usize,
+    /// Datagrams dropped because the queue was full
+    pub dropped: u64,
+    /// An error from ICMP, returned by the next read
+    pub error: Option<isize>,
+    /// Every resource using the socket was dropped
+    pub released: bool,
+    /// Notified when a datagram or error arrives
+    pub condition: WaitCondition,
+}
+
+impl UdpSocket {
+    fn new(local_port: u16, peer: Option<(IpAddr, u16)>) -> UdpSocket {
+        UdpSocket {
+            local_port: local_port,
+            peer: peer,
+            queue: VecDeque::new(),
+            queued: 0,
+            dropped: 0,
+            error: None,
+            released: false,
+            condition: WaitCondition::new(),
+        }
+    }
+
+    /// Whether a datagram from `src` is for this socket
+    fn accepts(&self, src: IpAddr, src_port: u16) -> bool {
+        match self.peer {
+            Some((peer_addr, peer_port)) => peer_addr.equals(src) && peer_port == src_port,
+            None => true,
+        }
+    }
+
+    fn push(&mut self, datagram: Datagram) {
+        let size = DATAGRAM_OVERHEAD + datagram.data.len();
+        if self.queued + size > RECEIVE_BUFFER {
+            self.dropped += 1;
+            return;
+        }
+
+        self.queued += size;
+        self.queue.push_back(datagram);
+        self.condition.notify("UdpSocket::push");
+    }
+
+    fn pop(&mut self) -> Option<Datagram> {
+        let datagram = self.queue.pop_front();
+        if let Some(ref datagram) = datagram {
+            self.queued -= DATAGRAM_OVERHEAD + datagram.data.len();
+        }
+        datagram
+    }
+}
+
+/// The sockets of the UDP scheme, fed by its receive context
+pub struct UdpTable {
+    sockets: Vec<Arc<UnsafeCell<UdpSocket>>>,
+}
+
+impl UdpTable {
+    fn new() -> UdpTable {
+        UdpTable {
+            sockets: Vec::new(),
+        }
+    }
+
+    fn socket(&self, i: usize) -> &mut UdpSocket {
+        unsafe { &mut *self.sockets[i].get() }
+    }
+
+    /// Pick an unused ephemeral port
+    fn ephemeral_port(&self) -> u16 {
+        loop {
+            let port = (rand() % 32768 + 32768) as u16;
+            if ! self.sockets.iter().any(|socket| unsafe { & *socket.get() }.local_port == port) {
+                return port;
+            }
+        }
+    }
+
+    /// Bind a socket, forgetting sockets no resource uses
+    fn bind(&mut self, local_port: Option<u16>, peer: Option<(IpAddr, u16)>) -> Result<Arc<UnsafeCell<UdpSocket>>> {
+        self.sockets.retain(|socket| ! unsafe { & *socket.get() }.released);
+
+        let local_port = match local_port {
+            Some(port) => {
+                // Connected sockets may share a port with each other, but only one socket takes
+                // datagrams from any peer
+                let in_use = self.sockets.iter().any(|socket| {
+                    let socket = unsafe { & *socket.get() };
+                    socket.local_port == port && (socket.peer.is_none() || peer.is_none())
+                });
+                if in_use {
+                    return Err(Error::new(EADDRINUSE));
+                }
+                port
+            },
+            None => self.ephemeral_port(),
+        };
+
+        let socket = Arc::new(UnsafeCell::new(UdpSocket::new(local_port, peer)));
+        self.sockets.push(socket.clone());
+        Ok(socket)
+    }
+
+    /// Hand a datagram to the connected socket for its source, or to the bound socket on its port
+    fn input(&mut self, src: IpAddr, dst: IpAddr, datagram: Udp) {
+        let local_port = datagram.header.dst.get();
+        let src_port = datagram.header.src.get();
+
+        let mut found = None;
+        for i in 0..self.sockets.len() {
+            let socket = self.socket(i);
+            if socket.released || socket.local_port != local_port || ! socket.accepts(src, src_port) {
+                continue;
+            }
+            if socket.peer.is_some() || found.is_none() {
+                found = Some(i);
+            }
+        }
+
+        if let Some(i) = found {
+            self.socket(i).push(Datagram {
+                src: src,
+                src_port: src_port,
+                broadcast: dst.is_broadcast(),
+                data: datagram.data,
+            });
+        }
+    }
+
+    /// Report an ICMP port unreachable message to the connected socket it is about
+    fn unreachable(&mut self, peer_addr: IpAddr, local_port: u16, peer_port: u16) {
+        for i in 0..self.sockets.len() {
+            let socket = self.socket(i);
+            if socket.local_port == local_port && socket.peer.is_some() && socket.accepts(peer_addr, peer_port) {
+                socket.error = Some(ECONNREFUSED);
+                socket.condition.notify("UdpTable::unreachable");
             }
-            Err(err) => Err(err),
         }
     }
 
+    /// Handle an ICMP port unreachable message, quoting the IP header and 8 bytes of a datagram
+    /// we sent
+    fn icmp_error(&mut self, data: &[u8]) {
+        if data.len() >= 8 + 20 && data[0] == 3 && data[1] == 3 && data[8 + 9] == 0x11 {
+            let quoted = &data[8..];
+            let header_len = (quoted[0] & 0xF) as usize * 4;
+            if quoted.len() >= header_len + 4 {
+                let peer_addr = IpAddr::V4(Ipv4Addr {
+                    bytes: [quoted[16], quoted[17], quoted[18], quoted[19]]
+                });
+                let local_port = (quoted[header_len] as u16) << 8 | quoted[header_len + 1] as u16;
+                let peer_port = (quoted[header_len + 2] as u16) << 8 | quoted[header_len + 3] as u16;
+                self.unreachable(peer_addr, local_port, peer_port);
+            }
+        }
+    }
+
+    /// Handle an ICMPv6 port unreachable message, quoting a datagram we sent
+    fn icmpv6_error(&mut self, data: &[u8]) {
+        if data.len() >= 8 + 40 + 4 && data[0] == ICMPV6_UNREACHABLE && data[1] == 4 && data[8 + 6] == 0x11 {
+            let quoted = &data[8..];
+            let peer_addr = IpAddr::V6(Ipv6Addr::from_slice(&quoted[24 .. 40]));
+            let local_port = (quoted[40] as u16) << 8 | quoted[41] as u16;
+            let peer_port = (quoted[42] as u16) << 8 | quoted[43] as u16;
+            self.unreachable(peer_addr, local_port, peer_port);
+        }
+    }
+
+    /// Wait for UDP datagrams to us over IPv4 and IPv6, and errors about the datagrams we sent,
+    /// handing them to their sockets
+    fn receive_loop(table: Arc<UnsafeCell<UdpTable>>) {
+        let receiver = Receiver::new(vec![Filter::new(false, 0x11), Filter::new(true, 0x11)], true);
+        loop {
+            let packet = receiver.queue.receive("UdpTable::receive_loop");
+            let table = unsafe { &mut *table.get() };
+            match packet.proto {
+                0x11 => if packet.dst.is_local() || packet.dst.is_broadcast() {
+                    if let Some(datagram) = Udp::from_bytes(&packet.data) {
+                        if datagram.verify(&packet.src, &packet.dst) {
+                            table.input(packet.src, packet.dst, datagram);
+                        }
+                    }
+                },
+                ICMPV6_PROTO => if packet.dst.is_local() {
+                    table.icmpv6_error(&packet.data);
+                },
+                _ => table.icmp_error(&packet.data),
+            }
+        }
+    }
+}
+
+/// The application's end of a socket, released when the last resource using it is dropped
+pub struct UdpEndpoint {
+    socket: Arc<UnsafeCell<UdpSocket>>,
+}
+
+impl UdpEndpoint {
+    fn socket(&self) -> &mut UdpSocket {
+        unsafe { &mut *self.socket.get() }
+    }
+
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
-        let path_string = format!("udp:{}:{}/{}", self.peer_addr.to_string(), self.peer_port, self.host_port);
+        let socket = self.socket();
+        let path_string = match socket.peer {
+            Some((peer_addr, peer_port)) => format!("udp:{}/{}", socket::join_host(peer_addr, peer_port), socket.local_port),
+            None => format!("udp:/{}", socket.local_port),
+        };
         let path = path_string.as_bytes();
 
         for (b, p) in buf.iter_mut().zip(path.iter()) {
@@ -90,135 +392,209 @@ impl Resource for UdpResource {
         Ok(cmp::min(buf.len(), path.len()))
     }
 
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if ! self.data.is_empty() {
-            let mut bytes: Vec<u8> = Vec::new();
-            mem::swap(&mut self.data, &mut bytes);
-
-            // TODO: Allow splitting
-            let mut i = 0;
-            while i < buf.len() && i < bytes.len() {
-                buf[i] = bytes[i];
-                i += 1;
-            }
-
-            return Ok(i);
+    /// Take one datagram, with a frame header if the socket is not connected
+    fn read(&mut self, buf: &mut [u8], options: &SocketOptions) -> Result<usize> {
+        let header_len = if self.socket().peer.is_some() {
+            0
+        } else {
+            mem::size_of::<UdpFrameHeader>()
+        };
+        if buf.len() < header_len {
+            return Err(Error::new(EINVAL));
         }
 
+        let deadline = socket::deadline(options.read_timeout);
         loop {
-            let mut bytes = [0; 65536];
-            let count = try!(self.ip.read(&mut bytes));
-
-            if let Some(datagram) = Udp::from_bytes(&bytes[..count]) {
-                if datagram.header.dst.get() == self.host_port &&
-                   datagram.header.src.get() == self.peer_port {
-                    // TODO: Allow splitting
-                    let mut i = 0;
-                    while i < buf.len() && i < datagram.data.len() {
-                        buf[i] = datagram.data[i];
-                        i += 1;
+            {
+                let socket = self.socket();
+                if let Some(errno) = socket.error.take() {
+                    return Err(Error::new(errno));
+                }
+
+                if let Some(datagram) = socket.pop() {
+                    let count = cmp::min(buf.len() - header_len, datagram.data.len());
+                    for (b, d) in buf[header_len ..].iter_mut().zip(datagram.data.iter()) {
+                        *b = *d;
+                    }
+
+                    if header_len > 0 {
+                        let mut flags = 0;
+                        if count < datagram.data.len() {
+                            flags |= UDP_TRUNCATED;
+                        }
+                        if datagram.broadcast {
+                            flags |= UDP_BROADCAST;
+                        }
+
+                        let header = UdpFrameHeader::new(datagram.src, datagram.src_port, flags);
+                        let header_bytes = unsafe {
+                            slice::from_raw_parts(&header as *const UdpFrameHeader as *const u8, header_len)
+                        };
+                        for (b, h) in buf.iter_mut().zip(header_bytes.iter()) {
+                            *b = *h;
+                        }
                     }
 
-                    return Ok(i);
+                    return Ok(header_len + count);
                 }
             }
+            try!(options.may_wait(deadline));
+            socket::wait(&self.socket().condition, "UdpEndpoint::read", deadline);
         }
     }
 
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let mut udp = Udp {
-            header: UdpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                len: n16::new((mem::size_of::<UdpHeader>() + buf.len()) as u16),
-                checksum: Checksum { data: 0 },
+    /// Send one datagram, after a frame header giving its destination if the socket is not connected
+    fn write(&mut self, buf: &[u8], options: &SocketOptions) -> Result<usize> {
+        let socket = self.socket();
+        let (peer_addr, peer_port, data) = match socket.peer {
+            Some((peer_addr, peer_port)) => (peer_addr, peer_port, buf),
+            None => {
+                let header_len = mem::size_of::<UdpFrameHeader>();
+                if buf.len() < header_len {
+                    return Err(Error::new(EDESTADDRREQ));
+                }
+
+                let header = unsafe { ptr::read(buf.as_ptr() as *const UdpFrameHeader) };
+                (header.addr(), header.port.get(), &buf[header_len ..])
             },
-            data: Vec::from(buf),
         };
 
-        unsafe {
-            let proto = n16::new(0x11);
-            let datagram_len = n16::new((mem::size_of::<UdpHeader>() + udp.data.len()) as u16);
-            udp.header.checksum.data =
-                Checksum::compile(Checksum::sum((&IP_ADDR as *const Ipv4Addr) as usize,
-                                                mem::size_of::<Ipv4Addr>()) +
-                                  Checksum::sum((&self.peer_addr as *const Ipv4Addr) as usize,
-                                                mem::size_of::<Ipv4Addr>()) +
-                                  Checksum::sum((&proto as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&datagram_len as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&udp.header as *const UdpHeader) as usize,
-                                                mem::size_of::<UdpHeader>()) +
-                                  Checksum::sum(udp.data.as_ptr() as usize, udp.data.len()));
+        if peer_port == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        if data.len() > MAX_PAYLOAD {
+            return Err(Error::new(EMSGSIZE));
+        }
+        if peer_addr.is_broadcast() && ! options.broadcast {
+            return Err(Error::new(EACCES));
+        }
+
+        try!(send_datagram(peer_addr, socket.local_port, peer_port, data, options.nonblock));
+        Ok(buf.len())
+    }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        let socket = self.socket();
+        if ! socket.queue.is_empty() || socket.error.is_some() {
+            Ok(events & (POLLIN | POLLOUT))
+        } else {
+            Ok(events & POLLOUT)
         }
+    }
+}
+
+impl Drop for UdpEndpoint {
+    fn drop(&mut self) {
+        self.socket().released = true;
+    }
+}
+
+/// A UDP resource
+///
+/// Reads and writes of a connected socket are the data of one datagram. On a bound socket, each
+/// starts with a `UdpFrameHeader` giving the peer. A datagram too large for a read is truncated.
+pub struct UdpResource {
+    endpoint: Arc<UnsafeCell<UdpEndpoint>>,
+    options: SocketOptions,
+}
+
+impl Resource for UdpResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box UdpResource {
+            endpoint: self.endpoint.clone(),
+            options: self.options,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        unsafe { (*self.endpoint.get()).path(buf) }
+    }
 
-        self.ip.write(&udp.to_bytes()).and(Ok(buf.len()))
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        unsafe { (*self.endpoint.get()).read(buf, &self.options) }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        unsafe { (*self.endpoint.get()).write(buf, &self.options) }
     }
 
     fn sync(&mut self) -> Result<()> {
-        self.ip.sync()
+        Ok(())
+    }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        unsafe { (*self.endpoint.get()).poll(events) }
     }
 }
 
-/// UDP UdpScheme
-pub struct UdpScheme;
+/// A UDP scheme
+///
+/// `udp:HOST:PORT` opens a socket connected to a peer, from `udp:HOST:PORT/LOCAL` if a local
+/// port is given, with IPv6 hosts in brackets, as `udp:[ADDR]:PORT`. `udp:/PORT` binds a socket
+/// that exchanges datagrams with any peer, over IPv4 or IPv6. Sending to a broadcast address, or
+/// to all IPv6 nodes, needs the `broadcast` option, and timeouts are given after a `?`, as
+/// described by `SocketOptions`.
+pub struct UdpScheme {
+    table: Arc<UnsafeCell<UdpTable>>,
+}
+
+impl UdpScheme {
+    /// Create the scheme, starting the context that receives datagrams
+    pub fn new() -> Box<UdpScheme> {
+        let table = Arc::new(UnsafeCell::new(UdpTable::new()));
+
+        let receive_table = table.clone();
+        Context::spawn("kudp".into(),
+                       box move || {
+                           UdpTable::receive_loop(receive_table);
+                       });
+
+        box UdpScheme {
+            table: table,
+        }
+    }
+}
 
 impl KScheme for UdpScheme {
     fn scheme(&self) -> &str {
         "udp"
     }
 
-    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let (url, options) = try!(SocketOptions::parse(url, flags));
+
         let mut parts = url.splitn(2, ":").nth(1).unwrap_or("").split('/');
         let remote = parts.next().unwrap_or("");
         let path = parts.next().unwrap_or("");
 
-        // Check host and port vs path
-        if remote.is_empty() {
-            let host_port = path.parse::<u16>().unwrap_or(0);
-            if host_port > 0 {
-                while let Ok(mut ip) = ::env().open("ip:/11", O_RDWR) {
-                    let mut bytes = [0; 65536];
-                    if let Ok(count) = ip.read(&mut bytes) {
-                        if let Some(datagram) = Udp::from_bytes(&bytes[..count]) {
-                            if datagram.header.dst.get() == host_port {
-                                let mut path = [0; 256];
-                                if let Ok(path_count) = ip.path(&mut path) {
-                                    let ip_reference = unsafe { str::from_utf8_unchecked(&path[.. path_count]) }.split(':').nth(1).unwrap_or("");
-                                    let peer_addr = ip_reference.split('/').next().unwrap_or("").split(':').next().unwrap_or("");
-
-                                    return Ok(Box::new(UdpResource {
-                                        ip: ip,
-                                        data: datagram.data,
-                                        peer_addr: Ipv4Addr::from_str(peer_addr),
-                                        peer_port: datagram.header.src.get(),
-                                        host_port: host_port,
-                                    }));
-                                }
-                            }
-                        }
-                    }
-                }
+        let local_port = if path.is_empty() {
+            None
+        } else {
+            match path.parse::<u16>() {
+                Ok(port) if port > 0 => Some(port),
+                _ => return Err(Error::new(ENOENT)),
+            }
+        };
+
+        let peer = if remote.is_empty() {
+            if local_port.is_none() {
+                return Err(Error::new(ENOENT));
             }
+            None
         } else {
-            let mut remote_parts = remote.split(':');
-            let peer_addr = remote_parts.next().unwrap_or("");
-            let peer_port = remote_parts.next().unwrap_or("").parse::<u16>().unwrap_or(0);
-            if peer_port > 0 {
-                let host_port = path.parse::<u16>().unwrap_or((rand() % 32768 + 32768) as u16);
-                if let Ok(ip) = ::env().open(&format!("ip:{}/11", peer_addr), O_RDWR) {
-                    return Ok(Box::new(UdpResource {
-                        ip: ip,
-                        data: Vec::new(),
-                        peer_addr: Ipv4Addr::from_str(peer_addr),
-                        peer_port: peer_port as u16,
-                        host_port: host_port,
-                    }));
-                }
+            let (peer_addr, port) = try!(socket::split_host(remote).ok_or(Error::new(ENOENT)));
+            match port.parse::<u16>() {
+                Ok(peer_port) if peer_port > 0 => Some((peer_addr, peer_port)),
+                _ => return Err(Error::new(ENOENT)),
             }
-        }
+        };
 
-        Err(Error::new(ENOENT))
+        let socket = try!(unsafe { &mut *self.table.get() }.bind(local_port, peer));
+        Ok(box UdpResource {
+            endpoint: Arc::new(UnsafeCell::new(UdpEndpoint {
+                socket: socket,
+            })),
+            options: options,
+        })
     }
 }
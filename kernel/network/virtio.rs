@@ -0,0 +1,412 @@
+use alloc::boxed::Box;
+
+use arch::memory;
+
+use collections::slice;
+use collections::string::String;
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use common::random::rand;
+
+use core::cell::UnsafeCell;
+use core::{cmp, mem, ptr};
+
+use drivers::pci::config::PciConfig;
+use drivers::virtio::{Transport, Virtqueue, F_VERSION_1, ISR_CONFIG, ISR_QUEUE};
+
+use network::common::*;
+use network::ethernet::ETHERNET_MTU;
+use network::interface;
+use network::scheme::*;
+
+use fs::KScheme;
+
+use system::error::{Error, Result, ENOMEM};
+
+/// The device completes partial checksums of frames it receives
+const F_GUEST_CSUM: u64 = 1 << 1;
+/// The device reports its MTU
+const F_MTU: u64 = 1 << 3;
+/// The device has a MAC address
+const F_MAC: u64 = 1 << 5;
+/// Frames may be received in more than one buffer
+const F_MRG_RXBUF: u64 = 1 << 15;
+/// The device reports whether the link is up
+const F_STATUS: u64 = 1 << 16;
+
+/// The checksum of a received frame is partial, from `csum_start`, with the pseudo header
+/// summed at `csum_start + csum_offset`
+const HDR_F_NEEDS_CSUM: u8 = 1;
+
+const STATUS_LINK_UP: u16 = 1;
+
+/// Device configuration offsets
+const CONFIG_MAC: u16 = 0;
+const CONFIG_STATUS: u16 = 6;
+const CONFIG_MTU: u16 = 10;
+
+/// Largest queue used
+const QUEUE_SIZE: u16 = 256;
+/// Size of each receive buffer when frames can span several
+const RX_BUFFER: usize = 2048;
+/// Ethernet header, which the MTU does not include
+const ETHERNET_HEADER: usize = 14;
+
+/// The header before every frame, without `num_buffers` on the legacy interface unless
+/// mergeable receive buffers are used
+#[repr(packed)]
+struct NetHeader {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    num_buffers: u16,
+}
+
+/// Finish the partial checksum of a received frame
+fn complete_checksum(frame: &mut [u8], start: usize, offset: usize) {
+    if start + offset + 2 > frame.len() {
+        return;
+    }
+
+    let checksum = Checksum::compile(unsafe { Checksum::sum(frame.as_ptr() as usize + start, frame.len() - start) });
+    // The field need not be aligned, so it is written a byte at a time, in memory order
+    let bytes: [u8; 2] = unsafe { mem::transmute(checksum) };
+    frame[start + offset] = bytes[0];
+    frame[start + offset + 1] = bytes[1];
+}
+
+/// A virtio network device
+///
+/// Received frames arrive in buffers kept in the receive queue, and are taken when the device
+/// interrupts. Frames to send are copied into buffers that go back to a pool once the device
+/// has sent them.
+pub struct VirtioNet {
+    transport: Transport,
+    irq: u8,
+    name: String,
+    rx: Virtqueue,
+    tx: Virtqueue,
+    /// The buffer of each chain in the queue, by the index of its head
+    rx_buffers: Vec<usize>,
+    tx_buffers: Vec<usize>,
+    /// Transmit buffers the device has finished with
+    tx_pool: Vec<usize>,
+    header_len: usize,
+    rx_buffer_len: usize,
+    tx_buffer_len: usize,
+    mergeable: bool,
+    /// A frame whose later mergeable buffers were not used yet, with the number of buffers it
+    /// still needs, and the start and offset of the checksum to complete
+    partial: Option<(Vec<u8>, u16, Option<(usize, usize)>)>,
+    /// The device reports the link status, and whether the link was up when last checked
+    status: bool,
+    link_up: bool,
+    resources: UnsafeCell<Vec<*mut NetworkResource>>,
+    inbound: VecDeque<Vec<u8>>,
+    outbound: VecDeque<Vec<u8>>,
+}
+
+impl VirtioNet {
+    pub fn new(mut pci: PciConfig) -> Option<Box<Self>> {
+        unsafe { pci.flag(4, 4, true) }; // Bus mastering
+
+        let irq = unsafe { pci.read(0x3C) } as u8 & 0xF;
+
+        match unsafe { Transport::new(&mut pci) } {
+            Some(transport) => {
+                syslog_info!(" + Virtio Net {} IRQ: {:X}", if transport.is_modern() {
+                    "1.0"
+                } else {
+                    "Legacy"
+                }, irq);
+
+                match VirtioNet::init(transport, irq) {
+                    Ok(module) => Some(module),
+                    Err(err) => {
+                        syslog_warning!("   - Failed to initialize: {}", err);
+                        None
+                    }
+                }
+            },
+            None => {
+                syslog_warning!(" - Virtio Net without usable registers");
+                None
+            }
+        }
+    }
+
+    fn init(mut transport: Transport, irq: u8) -> Result<Box<Self>> {
+        let features = try!(transport.init(F_GUEST_CSUM | F_MTU | F_MAC | F_MRG_RXBUF | F_STATUS));
+        let rx = try!(transport.setup_queue(0, QUEUE_SIZE));
+        let tx = try!(transport.setup_queue(1, QUEUE_SIZE));
+
+        // Without an address from the device, make up a locally administered one
+        let mut mac = MacAddr { bytes: [0; 6] };
+        for i in 0..6 {
+            mac.bytes[i] = if features & F_MAC == F_MAC {
+                transport.config_u8(CONFIG_MAC + i as u16)
+            } else {
+                rand() as u8
+            };
+        }
+        if features & F_MAC != F_MAC {
+            mac.bytes[0] = mac.bytes[0] & 0xFC | 0x02;
+        }
+
+        let mtu = if features & F_MTU == F_MTU {
+            transport.config_u16(CONFIG_MTU) as usize
+        } else {
+            ETHERNET_MTU
+        };
+
+        let mergeable = features & F_MRG_RXBUF == F_MRG_RXBUF;
+        let header_len = if features & (F_VERSION_1 | F_MRG_RXBUF) != 0 { 12 } else { 10 };
+        let status = features & F_STATUS == F_STATUS;
+        let link_up = ! status || transport.config_u16(CONFIG_STATUS) & STATUS_LINK_UP == STATUS_LINK_UP;
+
+        let mut module = box VirtioNet {
+            transport: transport,
+            irq: irq,
+            name: String::new(),
+            rx_buffers: vec![0; rx.size as usize],
+            tx_buffers: vec![0; tx.size as usize],
+            rx: rx,
+            tx: tx,
+            tx_pool: Vec::new(),
+            header_len: header_len,
+            rx_buffer_len: if mergeable { RX_BUFFER } else { header_len + ETHERNET_HEADER + mtu },
+            tx_buffer_len: header_len + ETHERNET_HEADER + mtu,
+            mergeable: mergeable,
+            partial: None,
+            status: status,
+            link_up: link_up,
+            resources: UnsafeCell::new(Vec::new()),
+            inbound: VecDeque::new(),
+            outbound: VecDeque::new(),
+        };
+
+        // Without mergeable buffers, the header and the frame are in separate descriptors
+        let descriptors = if mergeable { 1 } else { 2 };
+        while module.rx.free() >= descriptors {
+            let buffer = unsafe { memory::alloc(module.rx_buffer_len) };
+            if buffer == 0 {
+                break;
+            }
+            module.give_rx(buffer);
+        }
+        if module.rx.free() == module.rx.size as usize {
+            module.transport.set_status(0);
+            return Err(Error::new(ENOMEM));
+        }
+
+        module.transport.driver_ok();
+        module.transport.notify(&module.rx);
+
+        syslog_info!("   - MAC: {}", &mac.to_string());
+        if features & F_MTU == F_MTU {
+            syslog_info!("   - MTU: {}", mtu);
+        }
+        if ! link_up {
+            syslog_info!("   - Link down");
+        }
+
+        let name = interface::add(mac, &mut *module as *mut Self);
+        if let Some(interface) = interface::find(&name) {
+            interface.mtu = mtu;
+        }
+        syslog_info!("   - Interface: {}", name);
+        module.name = name;
+
+        Ok(module)
+    }
+
+    /// Put a receive buffer in the queue
+    fn give_rx(&mut self, buffer: usize) {
+        let head = if self.mergeable {
+            self.rx.push(&[(buffer, self.rx_buffer_len, true)])
+        } else {
+            self.rx.push(&[(buffer, self.header_len, true), (buffer + self.header_len, self.rx_buffer_len - self.header_len, true)])
+        };
+        if let Some(head) = head {
+            self.rx_buffers[head as usize] = buffer;
+        }
+    }
+
+    /// Take a used receive buffer, returning its address and the bytes written
+    fn take_rx(&mut self) -> Option<(usize, usize)> {
+        self.rx.pop().map(|(head, len)| (self.rx_buffers[head as usize], cmp::min(len as usize, self.rx_buffer_len)))
+    }
+
+    /// Take the frames the device received, giving their buffers back to it
+    ///
+    /// With mergeable buffers, the header of a frame gives the number of buffers it fills. Those
+    /// not used yet are waited for, rather than read as the start of another frame.
+    unsafe fn receive_inbound(&mut self) {
+        let mut received = false;
+        while let Some((buffer, len)) = self.take_rx() {
+            received = true;
+
+            if let Some((mut frame, remaining, checksum)) = self.partial.take() {
+                frame.extend_from_slice(slice::from_raw_parts(buffer as *const u8, len));
+                self.give_rx(buffer);
+                if remaining > 1 {
+                    self.partial = Some((frame, remaining - 1, checksum));
+                } else {
+                    self.finish_frame(frame, checksum);
+                }
+                continue;
+            }
+
+            if len < self.header_len {
+                self.give_rx(buffer);
+                continue;
+            }
+
+            let header = ptr::read(buffer as *const NetHeader);
+            let frame = Vec::from(slice::from_raw_parts((buffer + self.header_len) as *const u8, len - self.header_len));
+            self.give_rx(buffer);
+
+            let checksum = if header.flags & HDR_F_NEEDS_CSUM == HDR_F_NEEDS_CSUM {
+                Some((header.csum_start as usize, header.csum_offset as usize))
+            } else {
+                None
+            };
+
+            if self.mergeable && header.num_buffers > 1 {
+                self.partial = Some((frame, header.num_buffers - 1, checksum));
+            } else {
+                self.finish_frame(frame, checksum);
+            }
+        }
+
+        if received {
+            self.transport.notify(&self.rx);
+        }
+    }
+
+    /// Complete the checksum of a whole received frame, and queue it
+    fn finish_frame(&mut self, mut frame: Vec<u8>, checksum: Option<(usize, usize)>) {
+        if let Some((start, offset)) = checksum {
+            complete_checksum(&mut frame, start, offset);
+        }
+
+        self.inbound.push_back(frame);
+    }
+
+    /// Send queued frames while the transmit queue has room, after taking back the buffers of
+    /// frames already sent
+    unsafe fn send_outbound(&mut self) {
+        while let Some((head, _)) = self.tx.pop() {
+            self.tx_pool.push(self.tx_buffers[head as usize]);
+        }
+
+        let mut sent = false;
+        while self.tx.free() >= 2 {
+            let bytes = match self.outbound.pop_front() {
+                Some(bytes) => bytes,
+                None => break,
+            };
+            if self.header_len + bytes.len() > self.tx_buffer_len {
+                debugln!("Virtio Net: Frame too long for transmit: {}", bytes.len());
+                continue;
+            }
+
+            let buffer = match self.tx_pool.pop() {
+                Some(buffer) => buffer,
+                None => memory::alloc(self.tx_buffer_len),
+            };
+            if buffer == 0 {
+                self.outbound.push_front(bytes);
+                break;
+            }
+
+            // No offloads are asked for, and on the legacy interface without mergeable buffers
+            // `num_buffers` is overwritten by the frame
+            let header = &mut *(buffer as *mut NetHeader);
+            header.flags = 0;
+            header.gso_type = 0;
+            header.hdr_len = 0;
+            header.gso_size = 0;
+            header.csum_start = 0;
+            header.csum_offset = 0;
+            header.num_buffers = 0;
+            ::memcpy((buffer + self.header_len) as *mut u8, bytes.as_ptr(), bytes.len());
+
+            if let Some(head) = self.tx.push(&[(buffer, self.header_len, false), (buffer + self.header_len, bytes.len(), false)]) {
+                self.tx_buffers[head as usize] = buffer;
+                sent = true;
+            }
+        }
+
+        if sent {
+            self.transport.notify(&self.tx);
+        }
+    }
+
+    /// Log changes of the link status
+    fn check_link(&mut self) {
+        if self.status {
+            let link_up = self.transport.config_u16(CONFIG_STATUS) & STATUS_LINK_UP == STATUS_LINK_UP;
+            if link_up != self.link_up {
+                self.link_up = link_up;
+                syslog_info!("Virtio Net: Link {} on {}", if link_up { "up" } else { "down" }, self.name);
+            }
+        }
+    }
+}
+
+impl KScheme for VirtioNet {
+    fn on_irq(&mut self, irq: u8) {
+        if irq == self.irq {
+            // Reading the ISR status acknowledges the interrupt
+            let isr = self.transport.isr();
+            if isr & ISR_CONFIG == ISR_CONFIG {
+                self.check_link();
+            }
+            if isr & ISR_QUEUE == ISR_QUEUE {
+                self.sync();
+            }
+        }
+    }
+}
+
+impl NetworkScheme for VirtioNet {
+    fn add(&mut self, resource: *mut NetworkResource) {
+        unsafe { &mut *self.resources.get() }.push(resource);
+    }
+
+    fn remove(&mut self, resource: *mut NetworkResource) {
+        unsafe { &mut *self.resources.get() }.retain(|ptr| *ptr != resource);
+    }
+
+    fn sync(&mut self) {
+        {
+            let resources = unsafe { &mut *self.resources.get() };
+
+            let nic = self as *mut Self as usize;
+            for resource in resources.iter().filter(|resource| unsafe { (***resource).sends_on(nic) }) {
+                while let Some(bytes) = unsafe { &mut *(**resource).outbound.get() }.pop_front() {
+                    self.outbound.push_back(bytes);
+                }
+            }
+        }
+
+        unsafe { self.send_outbound(); }
+
+        unsafe { self.receive_inbound(); }
+
+        {
+            let resources = unsafe { &mut *self.resources.get() };
+
+            while let Some(bytes) = self.inbound.pop_front() {
+                for resource in resources.iter() {
+                    unsafe { (**resource).deliver(&bytes, "VirtioNet::sync") };
+                }
+            }
+        }
+    }
+}
@@ -8,6 +8,7 @@ use core::cmp;
 use fs::{KScheme, Resource};
 
 use system::error::Result;
+use system::syscall::{POLLIN, POLLOUT};
 
 /// A debug resource
 pub struct DebugResource {
@@ -60,6 +61,14 @@ impl Resource for DebugResource {
         console.write(&[]);
         Ok(())
     }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        if self.command.is_empty() && unsafe { (& *::env().console.get()).commands.inner() }.is_empty() {
+            Ok(events & POLLOUT)
+        } else {
+            Ok(events & (POLLIN | POLLOUT))
+        }
+    }
 }
 
 pub struct DebugScheme;
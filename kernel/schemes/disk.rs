@@ -7,16 +7,21 @@ use collections::String;
 use core::cell::UnsafeCell;
 use core::cmp;
 use disk::Disk;
+use disk::ramdisk::{parse_size, RamDisk};
 use fs::{KScheme, Resource, ResourceSeek, VecResource};
 
-use syscall::{MODE_DIR, MODE_FILE, Stat};
+use syscall::{MODE_DIR, MODE_FILE, O_CREAT, Stat};
 
-use system::error::{Error, Result, ENOENT};
+use system::error::{Error, Result, EINVAL, ENOENT, EROFS};
 
-/// A disk resource
+/// A disk resource, covering either a whole disk or one partition
 pub struct DiskResource {
     pub path: String,
     pub disk: Arc<UnsafeCell<Box<Disk>>>,
+    /// Offset of the first byte on the disk
+    pub start: u64,
+    /// Size in bytes
+    pub size: u64,
     pub seek: u64,
 }
 
@@ -25,6 +30,8 @@ impl Resource for DiskResource {
         Ok(box DiskResource {
             path: self.path.clone(),
             disk: self.disk.clone(),
+            start: self.start,
+            size: self.size,
             seek: self.seek,
         })
     }
@@ -39,19 +46,25 @@ impl Resource for DiskResource {
     }
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let count = try!(unsafe { &mut *self.disk.get() }.read(self.seek/512, buf));
+        let len = cmp::min(buf.len() as u64, self.size - self.seek) as usize;
+        let count = try!(unsafe { &mut *::env().block_cache.get() }.read(&self.disk, self.start + self.seek, &mut buf[..len]));
         self.seek += count as u64;
         Ok(count)
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let count = try!(unsafe { &mut *self.disk.get() }.write(self.seek/512, buf));
+        if unsafe { & *self.disk.get() }.read_only() {
+            return Err(Error::new(EROFS));
+        }
+
+        let len = cmp::min(buf.len() as u64, self.size - self.seek) as usize;
+        let count = try!(unsafe { &mut *::env().block_cache.get() }.write(&self.disk, self.start + self.seek, &buf[..len]));
         self.seek += count as u64;
         Ok(count)
     }
 
     fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
-        let size = unsafe { & *self.disk.get() }.size();
+        let size = self.size;
         match pos {
             ResourceSeek::Start(offset) => self.seek = cmp::min(size, offset as u64),
             ResourceSeek::Current(offset) => self.seek = cmp::min(size, cmp::max(0, self.seek as i64 + offset as i64) as u64),
@@ -61,13 +74,17 @@ impl Resource for DiskResource {
     }
 
     fn stat(&self, stat: &mut Stat) -> Result<()> {
-        stat.st_size = unsafe { & *self.disk.get() }.size() as u32;
-        stat.st_mode = MODE_FILE;
+        stat.st_size = self.size as u32;
+        stat.st_mode = MODE_FILE | if unsafe { & *self.disk.get() }.read_only() {
+            0o444
+        } else {
+            0o666
+        };
         Ok(())
     }
 
     fn sync(&mut self) -> Result<()> {
-        Ok(())
+        unsafe { &mut *::env().block_cache.get() }.sync(&self.disk)
     }
 }
 
@@ -77,7 +94,15 @@ impl Drop for DiskResource {
     }
 }
 
+/// Check if two disk handles refer to the same disk
+fn same_disk(a: &Arc<UnsafeCell<Box<Disk>>>, b: &Arc<UnsafeCell<Box<Disk>>>) -> bool {
+    a.get() == b.get()
+}
+
 /// A disk scheme
+///
+/// `disk:/N` is the whole of disk `N`, `disk:/N/pM` is partition `M` on it, and `disk:/N/pM/info`
+/// describes that partition. Opening `disk:/ram/<size>` with `O_CREAT` creates a RAM disk.
 pub struct DiskScheme;
 
 impl KScheme for DiskScheme {
@@ -86,36 +111,82 @@ impl KScheme for DiskScheme {
     }
 
     fn on_irq(&mut self, irq: u8) {
-        for disk in unsafe { &mut *::env().disks.get() }.iter_mut() {
+        for disk in unsafe { &mut *::env().disks.get() }.values_mut() {
             unsafe { &mut *disk.get() }.on_irq(irq);
         }
     }
 
-    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
         let path = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
 
+        // disk:/ram/<size> creates a RAM disk
+        if flags & O_CREAT == O_CREAT && path.starts_with("ram/") {
+            let size = try!(parse_size(&path[4..]).ok_or(Error::new(EINVAL)));
+            if size == 0 {
+                return Err(Error::new(EINVAL));
+            }
+
+            let number = ::env().add_disk(box try!(RamDisk::new(size)));
+            let disk = try!(unsafe { & *::env().disks.get() }.get(&number).ok_or(Error::new(ENOENT)));
+            return Ok(box DiskResource {
+                path: format!("disk:/{}", number),
+                disk: disk.clone(),
+                start: 0,
+                size: unsafe { & *disk.get() }.size(),
+                seek: 0
+            });
+        }
+
         if path.is_empty() {
             let mut list = String::new();
-            for i in 0..unsafe { & *::env().disks.get() }.len() {
+            let partitions = unsafe { & *::env().partitions.get() };
+            for (i, disk) in unsafe { & *::env().disks.get() }.iter() {
                 if ! list.is_empty() {
                     list.push('\n');
                 }
                 list.push_str(&format!("{}", i));
-            }
 
-            return Ok(box VecResource::new("disk:/".to_owned(), list.into_bytes(), MODE_DIR));
-        } else {
-            if let Ok(number) = path.parse::<usize>() {
-                if let Some(disk) = unsafe { & *::env().disks.get() }.get(number) {
-                    return Ok(box DiskResource {
-                        path: format!("disk:/{}", number),
-                        disk: disk.clone(),
-                        seek: 0
-                    });
+                for partition in partitions.iter().filter(|partition| same_disk(&partition.disk, disk)) {
+                    list.push_str(&format!("\n{}/p{}", i, partition.number));
                 }
             }
+
+            return Ok(box VecResource::new("disk:/".to_owned(), list.into_bytes(), MODE_DIR));
         }
 
-        Err(Error::new(ENOENT))
+        let mut parts = path.split('/');
+
+        let number = try!(parts.next().and_then(|part| part.parse::<usize>().ok()).ok_or(Error::new(ENOENT)));
+        let disk = try!(unsafe { & *::env().disks.get() }.get(&number).ok_or(Error::new(ENOENT)));
+
+        let partition_number = match parts.next() {
+            Some(part) if part.starts_with('p') => try!(part[1..].parse::<usize>().map_err(|_| Error::new(ENOENT))),
+            Some(_) => return Err(Error::new(ENOENT)),
+            None => return Ok(box DiskResource {
+                path: format!("disk:/{}", number),
+                disk: disk.clone(),
+                start: 0,
+                size: unsafe { & *disk.get() }.size(),
+                seek: 0
+            })
+        };
+
+        let partition = try!(unsafe { & *::env().partitions.get() }.iter()
+                                 .find(|partition| partition.number == partition_number && same_disk(&partition.disk, disk))
+                                 .ok_or(Error::new(ENOENT)));
+
+        match (parts.next(), parts.next()) {
+            (None, _) => Ok(box DiskResource {
+                path: format!("disk:/{}/p{}", number, partition_number),
+                disk: disk.clone(),
+                start: partition.start * 512,
+                size: partition.size(),
+                seek: 0
+            }),
+            (Some("info"), None) => Ok(box VecResource::new(format!("disk:/{}/p{}/info", number, partition_number),
+                                                            partition.info().into_bytes(),
+                                                            MODE_FILE)),
+            _ => Err(Error::new(ENOENT))
+        }
     }
 }
@@ -10,6 +10,7 @@ use core::mem::size_of;
 use fs::{KScheme, Resource, ResourceSeek};
 
 use system::error::{Error, Result, EACCES, EBADF, ENOENT, EINVAL};
+use system::syscall::{POLLIN, POLLOUT};
 use system::graphics::fast_copy;
 
 /// A display resource
@@ -95,6 +96,14 @@ impl Resource for DisplayResource {
     fn sync(&mut self) -> Result<()> {
         Ok(())
     }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        if unsafe { ::env().events.inner() }.is_empty() {
+            Ok(events & POLLOUT)
+        } else {
+            Ok(events & (POLLIN | POLLOUT))
+        }
+    }
 }
 
 pub struct DisplayScheme;
@@ -0,0 +1,477 @@
+use collections::{String, Vec};
+
+use core::cmp;
+
+use common::endian::{le16, le32};
+
+use disk::volume::Volume;
+
+use system::error::{Error, Result, EINVAL, EIO, ELOOP, ENOENT, ENOTDIR};
+
+/// Offset of the superblock
+const SUPERBLOCK: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+
+/// The root directory inode
+pub const ROOT_INODE: u32 = 2;
+
+/// Incompatible features that can be read
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_RECOVER | INCOMPAT_EXTENTS | INCOMPAT_64BIT
+                                | INCOMPAT_MMP | INCOMPAT_FLEX_BG | INCOMPAT_EA_INODE | INCOMPAT_CSUM_SEED
+                                | INCOMPAT_LARGEDIR | INCOMPAT_INLINE_DATA;
+
+pub const S_IFMT: u16 = 0xF000;
+pub const S_IFDIR: u16 = 0x4000;
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFLNK: u16 = 0xA000;
+
+/// Inode flags
+const FLAG_HUGE_FILE: u32 = 0x40000;
+const FLAG_EXTENTS: u32 = 0x80000;
+const FLAG_INLINE_DATA: u32 = 0x10000000;
+
+const EXTENT_MAGIC: u16 = 0xF30A;
+/// Extent tree depth limit, deeper trees are corrupt
+const EXTENT_DEPTH_MAX: u16 = 5;
+/// Extents longer than this are uninitialized, and read as zeros
+const EXTENT_INIT_MAX: u16 = 32768;
+
+/// Block pointers in an inode, the last three indirect
+const DIRECT_BLOCKS: u64 = 12;
+/// Bytes of block pointers, extents or inline data in an inode
+const INODE_BLOCK_SIZE: usize = 60;
+
+/// Symbolic links followed while resolving one path
+const SYMLINKS_MAX: usize = 40;
+
+/// An inode
+#[derive(Clone)]
+pub struct Inode {
+    pub number: u32,
+    /// File type and permissions
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub links: u16,
+    /// Access, change and modification times, in seconds since 1970
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    flags: u32,
+    /// 512 byte sectors used, including metadata
+    sectors: u32,
+    /// Block pointers, extent tree root or inline data
+    block: [u8; INODE_BLOCK_SIZE],
+}
+
+impl Inode {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+/// Directory entry file types, with the filetype feature
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_DIR: u8 = 2;
+
+/// A directory entry
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u32,
+    /// The file type, `FT_UNKNOWN` without the filetype feature
+    pub file_type: u8,
+}
+
+/// A mounted ext2, ext3 or ext4 filesystem, read only
+///
+/// Journals are not replayed, so a filesystem that was not cleanly unmounted can look out of date.
+pub struct FileSystem {
+    pub volume: Volume,
+    pub block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// Offset of the group descriptor table
+    descriptors: u64,
+    descriptor_size: u64,
+    groups: u32,
+    /// Directory entries record the file type
+    filetype: bool,
+}
+
+impl FileSystem {
+    /// Mount the filesystem on a volume, failing with `EINVAL` if it is not ext2, 3 or 4
+    pub fn new(volume: Volume) -> Result<FileSystem> {
+        let sb = try!(volume.read_vec(SUPERBLOCK, 1024));
+        if le16(&sb, 56) != MAGIC {
+            return Err(Error::new(EINVAL));
+        }
+
+        let log_block_size = le32(&sb, 24);
+        if log_block_size > 6 {
+            return Err(Error::new(EINVAL));
+        }
+        let block_size = 1024 << log_block_size;
+
+        let incompat = le32(&sb, 96);
+        if incompat & ! INCOMPAT_SUPPORTED != 0 {
+            syslog_warning!("ext2: unsupported features {:X}", incompat & ! INCOMPAT_SUPPORTED);
+            return Err(Error::new(EINVAL));
+        }
+        if incompat & INCOMPAT_RECOVER == INCOMPAT_RECOVER {
+            syslog_warning!("ext2: journal needs recovery, reading without it");
+        }
+
+        let revision = le32(&sb, 76);
+        let inode_size = if revision >= 1 { le16(&sb, 88) as u64 } else { 128 };
+        let descriptor_size = if incompat & INCOMPAT_64BIT == INCOMPAT_64BIT {
+            cmp::max(32, le16(&sb, 254) as u64)
+        } else {
+            32
+        };
+
+        let mut blocks = le32(&sb, 4) as u64;
+        if incompat & INCOMPAT_64BIT == INCOMPAT_64BIT {
+            blocks |= (le32(&sb, 336) as u64) << 32;
+        }
+        let first_data_block = le32(&sb, 20) as u64;
+        let blocks_per_group = le32(&sb, 32) as u64;
+        let inodes_per_group = le32(&sb, 40);
+
+        if inode_size < 128 || blocks_per_group == 0 || inodes_per_group == 0 || blocks <= first_data_block {
+            return Err(Error::new(EINVAL));
+        }
+
+        Ok(FileSystem {
+            volume: volume,
+            block_size: block_size,
+            inodes_per_group: inodes_per_group,
+            inode_size: inode_size,
+            descriptors: (first_data_block + 1) * block_size,
+            descriptor_size: descriptor_size,
+            groups: ((blocks - first_data_block + blocks_per_group - 1) / blocks_per_group) as u32,
+            filetype: incompat & INCOMPAT_FILETYPE == INCOMPAT_FILETYPE,
+        })
+    }
+
+    /// Read an inode by number
+    pub fn inode(&self, number: u32) -> Result<Inode> {
+        if number == 0 {
+            return Err(Error::new(ENOENT));
+        }
+
+        let group = (number - 1) / self.inodes_per_group;
+        let index = (number - 1) % self.inodes_per_group;
+        if group >= self.groups {
+            return Err(Error::new(EIO));
+        }
+
+        let descriptor = try!(self.volume.read_vec(self.descriptors + group as u64 * self.descriptor_size,
+                                                   self.descriptor_size as usize));
+        let mut table = le32(&descriptor, 8) as u64;
+        if self.descriptor_size >= 64 {
+            table |= (le32(&descriptor, 0x28) as u64) << 32;
+        }
+
+        let data = try!(self.volume.read_vec(table * self.block_size + index as u64 * self.inode_size, 128));
+
+        let mut block = [0; INODE_BLOCK_SIZE];
+        for (b, d) in block.iter_mut().zip(data[40..100].iter()) {
+            *b = *d;
+        }
+
+        Ok(Inode {
+            number: number,
+            mode: le16(&data, 0),
+            uid: le16(&data, 2) as u32 | (le16(&data, 120) as u32) << 16,
+            gid: le16(&data, 24) as u32 | (le16(&data, 122) as u32) << 16,
+            size: le32(&data, 4) as u64 | (le32(&data, 108) as u64) << 32,
+            links: le16(&data, 26),
+            atime: le32(&data, 8),
+            ctime: le32(&data, 12),
+            mtime: le32(&data, 16),
+            flags: le32(&data, 32),
+            sectors: le32(&data, 28),
+            block: block,
+        })
+    }
+
+    /// Find the block holding a block of a file, or None for a hole
+    fn map(&self, inode: &Inode, logical: u64) -> Result<Option<u64>> {
+        if inode.flags & FLAG_EXTENTS == FLAG_EXTENTS {
+            self.map_extents(inode, logical)
+        } else {
+            self.map_blocks(inode, logical)
+        }
+    }
+
+    /// Walk the extent tree rooted in the inode
+    fn map_extents(&self, inode: &Inode, logical: u64) -> Result<Option<u64>> {
+        let mut node = inode.block.to_vec();
+        let mut levels = 0;
+        loop {
+            if le16(&node, 0) != EXTENT_MAGIC {
+                return Err(Error::new(EIO));
+            }
+            let entries = le16(&node, 2) as usize;
+            let depth = le16(&node, 6);
+            if depth > EXTENT_DEPTH_MAX || levels > EXTENT_DEPTH_MAX || 12 + entries * 12 > node.len() {
+                return Err(Error::new(EIO));
+            }
+
+            if depth == 0 {
+                for i in 0..entries {
+                    let entry = &node[12 + i * 12 .. 24 + i * 12];
+                    let first = le32(entry, 0) as u64;
+                    let len = le16(entry, 4);
+                    let (len, initialized) = if len > EXTENT_INIT_MAX {
+                        (len - EXTENT_INIT_MAX, false)
+                    } else {
+                        (len, true)
+                    };
+
+                    if logical >= first && logical < first + len as u64 {
+                        if ! initialized {
+                            return Ok(None);
+                        }
+                        let start = (le16(entry, 6) as u64) << 32 | le32(entry, 8) as u64;
+                        return Ok(Some(start + logical - first));
+                    }
+                }
+                return Ok(None);
+            }
+
+            // The last index starting at or before the block covers it
+            let mut next = None;
+            for i in 0..entries {
+                let entry = &node[12 + i * 12 .. 24 + i * 12];
+                if le32(entry, 0) as u64 > logical {
+                    break;
+                }
+                next = Some((le16(entry, 8) as u64) << 32 | le32(entry, 4) as u64);
+            }
+
+            match next {
+                Some(block) => node = try!(self.volume.read_vec(block * self.block_size, self.block_size as usize)),
+                None => return Ok(None),
+            }
+            levels += 1;
+        }
+    }
+
+    /// Follow the direct and indirect block pointers in the inode
+    fn map_blocks(&self, inode: &Inode, logical: u64) -> Result<Option<u64>> {
+        let per_block = self.block_size / 4;
+
+        let (root, indices) = if logical < DIRECT_BLOCKS {
+            (logical as usize, vec![])
+        } else if logical - DIRECT_BLOCKS < per_block {
+            (12, vec![logical - DIRECT_BLOCKS])
+        } else if logical - DIRECT_BLOCKS - per_block < per_block * per_block {
+            let index = logical - DIRECT_BLOCKS - per_block;
+            (13, vec![index / per_block, index % per_block])
+        } else {
+            let index = logical - DIRECT_BLOCKS - per_block - per_block * per_block;
+            if index >= per_block * per_block * per_block {
+                return Ok(None);
+            }
+            (14, vec![index / per_block / per_block, index / per_block % per_block, index % per_block])
+        };
+
+        let mut block = le32(&inode.block, root * 4) as u64;
+        for index in indices {
+            if block == 0 {
+                return Ok(None);
+            }
+            let mut pointer = [0; 4];
+            try!(self.volume.read(block * self.block_size + index * 4, &mut pointer));
+            block = le32(&pointer, 0) as u64;
+        }
+
+        if block == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(block))
+        }
+    }
+
+    /// Read from a file at an offset, returning the bytes read
+    pub fn read(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, inode.size - offset) as usize;
+
+        if inode.flags & FLAG_INLINE_DATA == FLAG_INLINE_DATA {
+            // Data past the inode is in an extended attribute, which is not read
+            let end = cmp::min(offset + len as u64, INODE_BLOCK_SIZE as u64) as usize;
+            let start = cmp::min(offset as usize, end);
+            for (b, d) in buf.iter_mut().zip(inode.block[start..end].iter()) {
+                *b = *d;
+            }
+            return Ok(end - start);
+        }
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let in_block = position % self.block_size;
+            let count = cmp::min((self.block_size - in_block) as usize, len - done);
+
+            match try!(self.map(inode, position / self.block_size)) {
+                Some(block) => try!(self.volume.read(block * self.block_size + in_block, &mut buf[done .. done + count])),
+                None => for b in buf[done .. done + count].iter_mut() {
+                    *b = 0;
+                },
+            }
+
+            done += count;
+        }
+
+        Ok(done)
+    }
+
+    /// The size of an inode that is read whole, checked against the space it uses and the size
+    /// of the filesystem so that a corrupt size cannot exhaust memory
+    fn whole_size(&self, inode: &Inode) -> Result<usize> {
+        let used = if inode.flags & FLAG_INLINE_DATA == FLAG_INLINE_DATA {
+            INODE_BLOCK_SIZE as u64
+        } else if inode.flags & FLAG_HUGE_FILE == FLAG_HUGE_FILE {
+            inode.sectors as u64 * self.block_size
+        } else {
+            inode.sectors as u64 * 512
+        };
+
+        if inode.size > used || inode.size > self.volume.size {
+            Err(Error::new(EIO))
+        } else {
+            Ok(inode.size as usize)
+        }
+    }
+
+    /// Read the target of a symbolic link
+    pub fn read_link(&self, inode: &Inode) -> Result<String> {
+        // Short targets are stored in the inode, when no blocks are used for them
+        let target = if inode.size < INODE_BLOCK_SIZE as u64 && inode.sectors == 0
+                        && inode.flags & FLAG_EXTENTS != FLAG_EXTENTS {
+            inode.block[..inode.size as usize].to_vec()
+        } else {
+            let mut data = vec![0; try!(self.whole_size(inode))];
+            let count = try!(self.read(inode, 0, &mut data));
+            data.truncate(count);
+            data
+        };
+
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    /// Read the entries of a directory, including "." and ".."
+    ///
+    /// Hashed tree directories keep their index in blocks that look like one empty entry, so a
+    /// linear scan finds every entry in the leaves.
+    fn entries(&self, inode: &Inode) -> Result<Vec<DirEntry>> {
+        if ! inode.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let mut data = vec![0; try!(self.whole_size(inode))];
+        let count = try!(self.read(inode, 0, &mut data));
+        data.truncate(count);
+
+        let mut entries = Vec::new();
+
+        let mut offset = 0;
+        if inode.flags & FLAG_INLINE_DATA == FLAG_INLINE_DATA {
+            // Inline directories start with the parent inode instead of "." and ".."
+            if data.len() >= 4 {
+                entries.push(DirEntry { name: ".".into(), inode: inode.number, file_type: FT_DIR });
+                entries.push(DirEntry { name: "..".into(), inode: le32(&data, 0), file_type: FT_DIR });
+            }
+            offset = 4;
+        }
+
+        while offset + 8 <= data.len() {
+            let number = le32(&data, offset);
+            let rec_len = le16(&data, offset + 4) as usize;
+            let name_len = data[offset + 6] as usize;
+            if rec_len < 8 || offset + rec_len > data.len() || 8 + name_len > rec_len {
+                break;
+            }
+
+            if number != 0 && name_len > 0 {
+                let name = &data[offset + 8 .. offset + 8 + name_len];
+                entries.push(DirEntry {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    inode: number,
+                    file_type: if self.filetype { data[offset + 7] } else { FT_UNKNOWN },
+                });
+            }
+
+            offset += rec_len;
+        }
+
+        Ok(entries)
+    }
+
+    /// Read the entries of a directory, without "." and ".."
+    pub fn read_dir(&self, inode: &Inode) -> Result<Vec<DirEntry>> {
+        Ok(try!(self.entries(inode)).into_iter().filter(|entry| entry.name != "." && entry.name != "..").collect())
+    }
+
+    /// Find the inode at a path relative to the root, following symbolic links
+    ///
+    /// Absolute link targets are taken relative to the root of this filesystem.
+    pub fn find(&self, path: &str) -> Result<Inode> {
+        let root = try!(self.inode(ROOT_INODE));
+        let mut current = root.clone();
+
+        // Parts still to resolve, last first
+        let mut parts: Vec<String> = path.split('/').rev().filter(|part| ! part.is_empty()).map(|part| part.into()).collect();
+        let mut links = 0;
+
+        while let Some(part) = parts.pop() {
+            if ! current.is_dir() {
+                return Err(Error::new(ENOTDIR));
+            }
+
+            let number = try!(try!(self.entries(&current)).into_iter()
+                                  .find(|entry| entry.name == part)
+                                  .map(|entry| entry.inode)
+                                  .ok_or(Error::new(ENOENT)));
+            let child = try!(self.inode(number));
+
+            if child.is_symlink() {
+                links += 1;
+                if links > SYMLINKS_MAX {
+                    return Err(Error::new(ELOOP));
+                }
+
+                let target = try!(self.read_link(&child));
+                if target.starts_with('/') {
+                    current = root.clone();
+                }
+                for part in target.split('/').rev().filter(|part| ! part.is_empty()) {
+                    parts.push(part.into());
+                }
+            } else {
+                current = child;
+            }
+        }
+
+        Ok(current)
+    }
+}
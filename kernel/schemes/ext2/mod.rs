@@ -0,0 +1,208 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use collections::borrow::ToOwned;
+use collections::{BTreeMap, String, Vec};
+
+use core::cmp;
+
+use disk::volume::Volume;
+use fs::{KScheme, Resource, ResourceSeek, VecResource};
+
+use syscall::{MODE_DIR, O_CREAT, O_RDWR, O_TRUNC, O_WRONLY, Stat};
+
+use system::error::{Error, Result, EROFS};
+
+use self::filesystem::{FileSystem, Inode, FT_DIR, FT_UNKNOWN};
+
+pub mod filesystem;
+
+/// A file or directory on an ext2, ext3 or ext4 filesystem
+///
+/// Directories read as a list of names, one per line, with a trailing `/` on subdirectories.
+pub struct Ext2Resource {
+    path: String,
+    fs: Arc<FileSystem>,
+    inode: Inode,
+    /// The listing of a directory
+    listing: Option<Vec<u8>>,
+    seek: u64,
+}
+
+impl Ext2Resource {
+    fn size(&self) -> u64 {
+        match self.listing {
+            Some(ref listing) => listing.len() as u64,
+            None => self.inode.size,
+        }
+    }
+}
+
+impl Resource for Ext2Resource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box Ext2Resource {
+            path: self.path.clone(),
+            fs: self.fs.clone(),
+            inode: self.inode.clone(),
+            listing: self.listing.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = self.path.as_bytes();
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let count = match self.listing {
+            Some(ref listing) => {
+                let start = cmp::min(self.seek, listing.len() as u64) as usize;
+                let count = cmp::min(buf.len(), listing.len() - start);
+                for (b, l) in buf.iter_mut().zip(listing[start .. start + count].iter()) {
+                    *b = *l;
+                }
+                count
+            },
+            None => try!(self.fs.read(&self.inode, self.seek, buf)),
+        };
+        self.seek += count as u64;
+        Ok(count)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(Error::new(EROFS))
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        let size = self.size();
+        match pos {
+            ResourceSeek::Start(offset) => self.seek = cmp::min(size, offset as u64),
+            ResourceSeek::Current(offset) => self.seek = cmp::min(size, cmp::max(0, self.seek as i64 + offset as i64) as u64),
+            ResourceSeek::End(offset) => self.seek = cmp::min(size, cmp::max(0, size as i64 + offset as i64) as u64),
+        }
+        Ok(self.seek as usize)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_ino = self.inode.number as u16;
+        stat.st_mode = self.inode.mode;
+        stat.st_nlink = self.inode.links;
+        stat.st_uid = self.inode.uid as u16;
+        stat.st_gid = self.inode.gid as u16;
+        stat.st_size = self.size() as u32;
+        stat.st_atime = self.inode.atime;
+        stat.st_mtime = self.inode.mtime;
+        stat.st_ctime = self.inode.ctime;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _len: usize) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+}
+
+/// A read-only ext2, ext3 and ext4 scheme
+///
+/// `ext2:/V/path` is a path on volume `V`, which is `N` for disk `N` or `NpM` for a partition.
+/// `ext2:/` lists the volumes holding an ext filesystem. Symbolic links are followed, with
+/// absolute targets taken relative to the root of the volume.
+pub struct Ext2Scheme {
+    /// Mounted filesystems, by volume name
+    filesystems: BTreeMap<String, Arc<FileSystem>>,
+}
+
+impl Ext2Scheme {
+    pub fn new() -> Box<Ext2Scheme> {
+        box Ext2Scheme {
+            filesystems: BTreeMap::new(),
+        }
+    }
+
+    /// Get the filesystem on a volume, mounting it if it is not mounted or its disk was replaced
+    fn mount(&mut self, name: &str) -> Result<Arc<FileSystem>> {
+        let volume = try!(Volume::find(name));
+
+        if let Some(fs) = self.filesystems.get(name) {
+            if fs.volume.disk.get() == volume.disk.get() && fs.volume.start == volume.start {
+                return Ok(fs.clone());
+            }
+        }
+
+        let fs = Arc::new(try!(FileSystem::new(volume)));
+        self.filesystems.insert(name.to_owned(), fs.clone());
+        Ok(fs)
+    }
+}
+
+impl KScheme for Ext2Scheme {
+    fn scheme(&self) -> &str {
+        "ext2"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        if flags & (O_WRONLY | O_RDWR | O_CREAT | O_TRUNC) != 0 {
+            return Err(Error::new(EROFS));
+        }
+
+        let path = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+
+        if path.is_empty() {
+            let mut list = String::new();
+            for volume in Volume::all() {
+                let name = volume.name.clone();
+                if self.mount(&name).is_ok() {
+                    if ! list.is_empty() {
+                        list.push('\n');
+                    }
+                    list.push_str(&name);
+                }
+            }
+
+            return Ok(box VecResource::new("ext2:/".to_owned(), list.into_bytes(), MODE_DIR));
+        }
+
+        let mut parts = path.splitn(2, '/');
+        let volume_name = parts.next().unwrap_or("");
+        let file_path = parts.next().unwrap_or("");
+
+        let fs = try!(self.mount(volume_name));
+        let inode = try!(fs.find(file_path));
+
+        let listing = if inode.is_dir() {
+            let mut list = String::new();
+            for entry in try!(fs.read_dir(&inode)) {
+                if ! list.is_empty() {
+                    list.push('\n');
+                }
+                list.push_str(&entry.name);
+                let is_dir = match entry.file_type {
+                    FT_UNKNOWN => try!(fs.inode(entry.inode)).is_dir(),
+                    file_type => file_type == FT_DIR,
+                };
+                if is_dir {
+                    list.push('/');
+                }
+            }
+            Some(list.into_bytes())
+        } else {
+            None
+        };
+
+        Ok(box Ext2Resource {
+            path: format!("ext2:/{}/{}", volume_name, file_path),
+            fs: fs,
+            inode: inode,
+            listing: listing,
+            seek: 0,
+        })
+    }
+}
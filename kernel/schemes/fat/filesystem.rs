@@ -0,0 +1,929 @@
+use alloc::arc::Weak;
+
+use collections::borrow::ToOwned;
+use collections::{BTreeMap, String, Vec};
+
+use core::cell::UnsafeCell;
+use core::cmp;
+
+use common::endian::{le16, le32, set_le16, set_le32};
+use common::time::Duration;
+
+use disk::volume::Volume;
+
+use system::error::{Error, Result, EEXIST, EINVAL, EIO, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// Long name entries set all of the low four attributes
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// Size of a directory entry
+const ENTRY_SIZE: usize = 32;
+/// First byte of a deleted entry
+const ENTRY_FREE: u8 = 0xE5;
+/// First byte of the entry that ends a directory
+const ENTRY_END: u8 = 0x00;
+
+/// Flag of the last long name entry, which comes first on disk
+const LONG_LAST: u8 = 0x40;
+/// Characters in one long name entry
+const LONG_CHARS: usize = 13;
+/// Longest long name, in UTF-16 units
+const LONG_NAME_MAX: usize = 255;
+/// Offsets of the characters in a long name entry
+const LONG_OFFSETS: [usize; LONG_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Case flags of a short name, set by Windows NT instead of a long name
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+const FSINFO_LEAD: u32 = 0x41615252;
+const FSINFO_STRUCT: u32 = 0x61417272;
+/// Unknown free count or next free hint
+const FSINFO_UNKNOWN: u32 = 0xFFFFFFFF;
+
+/// Seconds between 1970 and 1980, the FAT epoch
+const FAT_EPOCH: i64 = 315532800;
+
+/// Days since 1970 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Year, month and day of a number of days since 1970
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Convert a FAT date and time to seconds since 1970
+///
+/// FAT stores local time without a zone, which is taken to be UTC like the realtime clock.
+pub fn fat_to_unix(date: u16, time: u16) -> i64 {
+    let year = 1980 + (date >> 9) as i64;
+    let month = cmp::max(1, cmp::min(12, (date >> 5) & 0xF)) as i64;
+    let day = cmp::max(1, date & 0x1F) as i64;
+
+    let hours = (time >> 11) as i64;
+    let minutes = ((time >> 5) & 0x3F) as i64;
+    let seconds = (time & 0x1F) as i64 * 2;
+
+    days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds
+}
+
+/// Convert seconds since 1970 to a FAT date and time, clamped to the range FAT can store
+pub fn unix_to_fat(secs: i64) -> (u16, u16) {
+    // 1980-01-01 to 2107-12-31 23:59:58
+    let secs = cmp::max(FAT_EPOCH, cmp::min(4354819198, secs));
+
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let time_of_day = secs % 86400;
+
+    let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+    let time = ((time_of_day / 3600) << 11 | (time_of_day / 60 % 60) << 5 | (time_of_day % 60) / 2) as u16;
+    (date, time)
+}
+
+/// The current time as a FAT date and time
+fn now() -> (u16, u16) {
+    unix_to_fat(Duration::realtime().secs)
+}
+
+/// The current time in seconds since 1970, rounded to what FAT can store
+pub fn modified_now() -> i64 {
+    let (date, time) = now();
+    fat_to_unix(date, time)
+}
+
+fn copy(destination: &mut [u8], source: &[u8]) {
+    for (d, s) in destination.iter_mut().zip(source.iter()) {
+        *d = *s;
+    }
+}
+
+/// Checksum of a short name, stored in each of its long name entries
+fn short_checksum(short: &[u8]) -> u8 {
+    let mut sum: u8 = 0;
+    for &b in short[..11].iter() {
+        sum = ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b);
+    }
+    sum
+}
+
+/// Format a short name as `BASE.EXT`, lowercasing parts marked by the case flags
+fn short_name(short: &[u8], case: u8) -> String {
+    let mut base = String::from_utf8_lossy(&short[..8]).trim_right().to_owned();
+    let mut ext = String::from_utf8_lossy(&short[8..11]).trim_right().to_owned();
+
+    // 0x05 stands for a leading 0xE5 byte, which marks free entries
+    if base.starts_with('\u{5}') {
+        base.remove(0);
+        base.insert(0, '\u{E5}');
+    }
+
+    if case & CASE_LOWER_BASE == CASE_LOWER_BASE {
+        base = base.to_lowercase();
+    }
+    if case & CASE_LOWER_EXT == CASE_LOWER_EXT {
+        ext = ext.to_lowercase();
+    }
+
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+/// FAT names compare without case
+pub fn name_eq(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+/// Check a long name, returning its UTF-16 units
+fn check_name(name: &str) -> Result<Vec<u16>> {
+    if name.is_empty() || name == "." || name == ".." || name.ends_with('.') || name.ends_with(' ') {
+        return Err(Error::new(EINVAL));
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) {
+        return Err(Error::new(EINVAL));
+    }
+
+    let units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() > LONG_NAME_MAX {
+        return Err(Error::new(ENAMETOOLONG));
+    }
+    Ok(units)
+}
+
+/// Convert a character to one allowed in short names, or None to drop it
+fn short_char(c: char) -> Option<u8> {
+    match c {
+        'a' ... 'z' => Some(c as u8 - b'a' + b'A'),
+        'A' ... 'Z' | '0' ... '9' => Some(c as u8),
+        '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`' | '{' | '}' | '~' => Some(c as u8),
+        ' ' | '.' => None,
+        _ => Some(b'_'),
+    }
+}
+
+/// The short name of a long name, if it is a valid uppercase 8.3 name needing no long name
+fn exact_short(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || base.contains('.') {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    for (i, c) in base.chars().enumerate() {
+        match short_char(c) {
+            Some(b) if b == c as u8 && b != b'_' || c == '_' => short[i] = b,
+            _ => return None,
+        }
+    }
+    for (i, c) in ext.chars().enumerate() {
+        match short_char(c) {
+            Some(b) if b == c as u8 && b != b'_' || c == '_' => short[8 + i] = b,
+            _ => return None,
+        }
+    }
+    Some(short)
+}
+
+/// The basis of a generated short name, with room for a `~N` tail
+fn short_basis(name: &str) -> ([u8; 8], usize, [u8; 3]) {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+
+    let mut basis = [b' '; 8];
+    let mut basis_len = 0;
+    for c in base.trim_left_matches('.').chars() {
+        if basis_len == basis.len() {
+            break;
+        }
+        if let Some(b) = short_char(c) {
+            basis[basis_len] = b;
+            basis_len += 1;
+        }
+    }
+    if basis_len == 0 {
+        basis[0] = b'_';
+        basis_len = 1;
+    }
+
+    let mut short_ext = [b' '; 3];
+    let mut ext_len = 0;
+    for c in ext.chars() {
+        if ext_len == short_ext.len() {
+            break;
+        }
+        if let Some(b) = short_char(c) {
+            short_ext[ext_len] = b;
+            ext_len += 1;
+        }
+    }
+
+    (basis, basis_len, short_ext)
+}
+
+/// The FAT variant, decided by the number of clusters
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// A directory entry with its long name
+#[derive(Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub attributes: u8,
+    /// First cluster, zero for empty files and the root directory
+    pub cluster: u32,
+    pub size: u32,
+    /// Modification time in seconds since 1970
+    pub modified: i64,
+    /// Byte offset of the short entry on the volume, zero for the root directory
+    pub offset: u64,
+    /// Byte offsets of the long name entries
+    pub long: Vec<u64>,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY == ATTR_DIRECTORY
+    }
+}
+
+/// The state of an open file, shared by all of its handles
+pub struct OpenFile {
+    pub entry: DirEntry,
+    /// The cluster chain of the file
+    pub clusters: Vec<u32>,
+    /// The entry changed since it was written
+    pub dirty: bool,
+}
+
+/// A mounted FAT12, FAT16 or FAT32 filesystem
+pub struct FileSystem {
+    pub volume: Volume,
+    pub kind: FatType,
+    /// Bytes in a cluster
+    pub cluster_size: u64,
+    /// Offset of the first FAT
+    fat_start: u64,
+    /// Bytes in one FAT
+    fat_size: u64,
+    fats: u64,
+    /// Offset and entries of the fixed root directory, on FAT12 and FAT16
+    root_start: u64,
+    root_entries: u64,
+    /// Offset of cluster 2
+    data_start: u64,
+    /// Number of data clusters, numbered from 2
+    clusters: u32,
+    /// First cluster of the root directory, on FAT32
+    root_cluster: u32,
+    /// Offset of the FSInfo sector, on FAT32
+    fsinfo: Option<u64>,
+    /// Free cluster count, if known
+    free: Option<u32>,
+    /// Where to start looking for a free cluster
+    next_free: u32,
+    /// The free count or hint changed since FSInfo was written
+    fsinfo_dirty: bool,
+    /// Open files, by the offset of their short entry
+    pub open: BTreeMap<u64, Weak<UnsafeCell<OpenFile>>>,
+}
+
+impl FileSystem {
+    /// Mount the filesystem on a volume, failing with `EINVAL` if it is not FAT
+    pub fn new(volume: Volume) -> Result<FileSystem> {
+        let boot = try!(volume.read_vec(0, 512));
+
+        if boot[510] != 0x55 || boot[511] != 0xAA || (boot[0] != 0xEB && boot[0] != 0xE9) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let bytes_per_sector = le16(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = le16(&boot, 14) as u64;
+        let fats = boot[16] as u64;
+        let root_entries = le16(&boot, 17) as u64;
+        let total = match le16(&boot, 19) {
+            0 => le32(&boot, 32) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = match le16(&boot, 22) {
+            0 => le32(&boot, 36) as u64,
+            sectors => sectors as u64,
+        };
+
+        if ! bytes_per_sector.is_power_of_two() || bytes_per_sector < 512 || bytes_per_sector > 4096
+           || ! sectors_per_cluster.is_power_of_two() || reserved == 0 || fats == 0 || fat_sectors == 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64 + bytes_per_sector - 1) / bytes_per_sector;
+        let data_sector = reserved + fats * fat_sectors + root_sectors;
+        if total <= data_sector || total * bytes_per_sector > volume.size {
+            return Err(Error::new(EINVAL));
+        }
+
+        let clusters = (total - data_sector) / sectors_per_cluster;
+        let kind = if clusters < 4085 {
+            FatType::Fat12
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        // Use only the clusters the FAT has entries for, as a FAT too small for the data area
+        // would otherwise be indexed past its end
+        let entry_bits = match kind {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        let entries = fat_sectors * bytes_per_sector * 8 / entry_bits;
+        if entries <= 2 {
+            return Err(Error::new(EINVAL));
+        }
+        let clusters = cmp::min(clusters, entries - 2);
+
+        let mut fs = FileSystem {
+            volume: volume,
+            kind: kind,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_start: reserved * bytes_per_sector,
+            fat_size: fat_sectors * bytes_per_sector,
+            fats: fats,
+            root_start: (reserved + fats * fat_sectors) * bytes_per_sector,
+            root_entries: root_entries,
+            data_start: data_sector * bytes_per_sector,
+            clusters: clusters as u32,
+            root_cluster: 0,
+            fsinfo: None,
+            free: None,
+            next_free: 2,
+            fsinfo_dirty: false,
+            open: BTreeMap::new(),
+        };
+
+        if kind == FatType::Fat32 {
+            fs.root_cluster = le32(&boot, 44);
+
+            let fsinfo_sector = le16(&boot, 48) as u64;
+            if fsinfo_sector != 0 && fsinfo_sector != 0xFFFF {
+                let offset = fsinfo_sector * bytes_per_sector;
+                let fsinfo = try!(fs.volume.read_vec(offset, 512));
+                if le32(&fsinfo, 0) == FSINFO_LEAD && le32(&fsinfo, 484) == FSINFO_STRUCT {
+                    fs.fsinfo = Some(offset);
+
+                    let free = le32(&fsinfo, 488);
+                    if free <= fs.clusters {
+                        fs.free = Some(free);
+                    }
+
+                    let next_free = le32(&fsinfo, 492);
+                    if next_free != FSINFO_UNKNOWN && next_free >= 2 && next_free < fs.clusters + 2 {
+                        fs.next_free = next_free;
+                    }
+                }
+            }
+        }
+
+        Ok(fs)
+    }
+
+    /// Free space in bytes, if known
+    pub fn free_space(&self) -> Option<u64> {
+        self.free.map(|free| free as u64 * self.cluster_size)
+    }
+
+    /// The root directory
+    pub fn root(&self) -> DirEntry {
+        DirEntry {
+            name: String::new(),
+            attributes: ATTR_DIRECTORY,
+            cluster: 0,
+            size: 0,
+            modified: 0,
+            offset: 0,
+            long: Vec::new(),
+        }
+    }
+
+    /// Offset of a cluster
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    /// Value marking the end of a chain
+    fn end_of_chain(&self) -> u32 {
+        match self.kind {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFFFFFF,
+        }
+    }
+
+    /// Check if a cluster number is in the data region
+    fn valid(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.clusters + 2
+    }
+
+    /// Read the FAT entry of a cluster
+    fn get(&self, cluster: u32) -> Result<u32> {
+        let mut data = [0; 4];
+        match self.kind {
+            FatType::Fat12 => {
+                let offset = cluster as u64 + cluster as u64 / 2;
+                try!(self.volume.read(self.fat_start + offset, &mut data[..2]));
+                let value = le16(&data, 0) as u32;
+                Ok(if cluster & 1 == 1 { value >> 4 } else { value & 0xFFF })
+            },
+            FatType::Fat16 => {
+                try!(self.volume.read(self.fat_start + cluster as u64 * 2, &mut data[..2]));
+                Ok(le16(&data, 0) as u32)
+            },
+            FatType::Fat32 => {
+                try!(self.volume.read(self.fat_start + cluster as u64 * 4, &mut data));
+                Ok(le32(&data, 0) & 0x0FFFFFFF)
+            },
+        }
+    }
+
+    /// Write the FAT entry of a cluster in every FAT
+    fn set(&mut self, cluster: u32, value: u32) -> Result<()> {
+        for fat in 0..self.fats {
+            let fat_start = self.fat_start + fat * self.fat_size;
+            let mut data = [0; 4];
+            match self.kind {
+                FatType::Fat12 => {
+                    let offset = fat_start + cluster as u64 + cluster as u64 / 2;
+                    try!(self.volume.read(offset, &mut data[..2]));
+                    let old = le16(&data, 0);
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000F) | (value as u16) << 4
+                    } else {
+                        (old & 0xF000) | (value as u16 & 0xFFF)
+                    };
+                    set_le16(&mut data, 0, new);
+                    try!(self.volume.write(offset, &data[..2]));
+                },
+                FatType::Fat16 => {
+                    set_le16(&mut data, 0, value as u16);
+                    try!(self.volume.write(fat_start + cluster as u64 * 2, &data[..2]));
+                },
+                FatType::Fat32 => {
+                    // The top four bits are reserved and kept
+                    let offset = fat_start + cluster as u64 * 4;
+                    try!(self.volume.read(offset, &mut data));
+                    let old = le32(&data, 0);
+                    set_le32(&mut data, 0, (old & 0xF0000000) | (value & 0x0FFFFFFF));
+                    try!(self.volume.write(offset, &data));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// The clusters of a chain, in order
+    pub fn chain(&self, first: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while self.valid(cluster) {
+            // A chain longer than the filesystem has a loop
+            if chain.len() > self.clusters as usize {
+                return Err(Error::new(EIO));
+            }
+            chain.push(cluster);
+            cluster = try!(self.get(cluster));
+        }
+        Ok(chain)
+    }
+
+    /// Allocate a cluster, appending it to the chain ending at `previous`
+    pub fn alloc(&mut self, previous: Option<u32>, zero: bool) -> Result<u32> {
+        let mut found = None;
+        for i in 0..self.clusters {
+            let cluster = 2 + (self.next_free - 2 + i) % self.clusters;
+            if try!(self.get(cluster)) == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = try!(found.ok_or(Error::new(ENOSPC)));
+
+        let end = self.end_of_chain();
+        try!(self.set(cluster, end));
+        if let Some(previous) = previous {
+            try!(self.set(previous, cluster));
+        }
+
+        if zero {
+            let zeros = vec![0; self.cluster_size as usize];
+            let offset = self.cluster_offset(cluster);
+            try!(self.volume.write(offset, &zeros));
+        }
+
+        self.free = self.free.map(|free| free.saturating_sub(1));
+        self.next_free = if cluster + 1 < self.clusters + 2 { cluster + 1 } else { 2 };
+        self.fsinfo_dirty = true;
+
+        Ok(cluster)
+    }
+
+    /// Free a chain of clusters
+    pub fn free_chain(&mut self, first: u32) -> Result<()> {
+        let chain = try!(self.chain(first));
+        for &cluster in chain.iter() {
+            try!(self.set(cluster, 0));
+        }
+
+        self.free = self.free.map(|free| cmp::min(self.clusters, free + chain.len() as u32));
+        self.fsinfo_dirty = true;
+
+        Ok(())
+    }
+
+    /// End a chain at `last`, freeing the clusters after it
+    pub fn truncate_chain(&mut self, last: u32) -> Result<()> {
+        let next = try!(self.get(last));
+        let end = self.end_of_chain();
+        try!(self.set(last, end));
+        if self.valid(next) {
+            try!(self.free_chain(next));
+        }
+        Ok(())
+    }
+
+    /// Byte offsets and lengths of the regions holding a directory
+    fn dir_regions(&self, cluster: u32) -> Result<Vec<(u64, u64)>> {
+        if cluster == 0 && self.kind != FatType::Fat32 {
+            return Ok(vec![(self.root_start, self.root_entries * ENTRY_SIZE as u64)]);
+        }
+
+        let first = if cluster == 0 { self.root_cluster } else { cluster };
+        Ok(try!(self.chain(first)).iter().map(|&cluster| (self.cluster_offset(cluster), self.cluster_size)).collect())
+    }
+
+    /// Every entry slot of a directory, with its byte offset
+    fn slots(&self, cluster: u32) -> Result<Vec<(u64, [u8; ENTRY_SIZE])>> {
+        let mut slots = Vec::new();
+        for (offset, len) in try!(self.dir_regions(cluster)) {
+            let data = try!(self.volume.read_vec(offset, len as usize));
+            for (i, chunk) in data.chunks(ENTRY_SIZE).enumerate() {
+                let mut slot = [0; ENTRY_SIZE];
+                for (b, c) in slot.iter_mut().zip(chunk.iter()) {
+                    *b = *c;
+                }
+                slots.push((offset + (i * ENTRY_SIZE) as u64, slot));
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Read the entries of a directory, without "." and ".." or the volume label
+    pub fn read_dir(&self, directory: &DirEntry) -> Result<Vec<DirEntry>> {
+        if ! directory.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let mut entries = Vec::new();
+
+        // Long name parts collected so far, with the checksum they expect
+        let mut long_parts: Vec<Vec<u16>> = Vec::new();
+        let mut long_offsets = Vec::new();
+        let mut long_checksum = 0;
+
+        for (offset, slot) in try!(self.slots(directory.cluster)) {
+            if slot[0] == ENTRY_END {
+                break;
+            }
+            if slot[0] == ENTRY_FREE {
+                long_parts.clear();
+                long_offsets.clear();
+                continue;
+            }
+
+            let attributes = slot[11];
+            if attributes & 0x3F == ATTR_LONG_NAME {
+                let sequence = slot[0];
+                let index = (sequence & 0x1F) as usize;
+                if sequence & LONG_LAST == LONG_LAST {
+                    long_parts = vec![Vec::new(); index];
+                    long_offsets.clear();
+                    long_checksum = slot[13];
+                }
+                if index >= 1 && index <= long_parts.len() && slot[13] == long_checksum {
+                    let mut part = Vec::new();
+                    for &o in LONG_OFFSETS.iter() {
+                        let c = le16(&slot, o);
+                        if c == 0 || c == 0xFFFF {
+                            break;
+                        }
+                        part.push(c);
+                    }
+                    long_parts[index - 1] = part;
+                    long_offsets.push(offset);
+                } else {
+                    long_parts.clear();
+                    long_offsets.clear();
+                }
+                continue;
+            }
+
+            if attributes & ATTR_VOLUME_ID == ATTR_VOLUME_ID || slot[0] == b'.' {
+                long_parts.clear();
+                long_offsets.clear();
+                continue;
+            }
+
+            let long_valid = ! long_parts.is_empty() && long_offsets.len() == long_parts.len()
+                             && long_checksum == short_checksum(&slot);
+            let name = if long_valid {
+                let units: Vec<u16> = long_parts.iter().flat_map(|part| part.iter().cloned()).collect();
+                String::from_utf16_lossy(&units)
+            } else {
+                short_name(&slot, slot[12])
+            };
+
+            let cluster = if self.kind == FatType::Fat32 {
+                (le16(&slot, 20) as u32) << 16 | le16(&slot, 26) as u32
+            } else {
+                le16(&slot, 26) as u32
+            };
+
+            entries.push(DirEntry {
+                name: name,
+                attributes: attributes,
+                cluster: cluster,
+                size: le32(&slot, 28),
+                modified: fat_to_unix(le16(&slot, 24), le16(&slot, 22)),
+                offset: offset,
+                long: if long_valid { long_offsets.clone() } else { Vec::new() },
+            });
+
+            long_parts.clear();
+            long_offsets.clear();
+        }
+
+        Ok(entries)
+    }
+
+    /// Find the entry at a path relative to the root
+    pub fn find(&self, path: &str) -> Result<DirEntry> {
+        let mut entry = self.root();
+        for part in path.split('/').filter(|part| ! part.is_empty()) {
+            if ! entry.is_dir() {
+                return Err(Error::new(ENOTDIR));
+            }
+            entry = try!(try!(self.read_dir(&entry)).into_iter()
+                             .find(|child| name_eq(&child.name, part))
+                             .ok_or(Error::new(ENOENT)));
+        }
+        Ok(entry)
+    }
+
+    /// Write the cluster, size and modification time of an entry
+    pub fn update(&mut self, entry: &DirEntry) -> Result<()> {
+        if entry.offset == 0 {
+            return Ok(());
+        }
+
+        let mut slot = try!(self.volume.read_vec(entry.offset, ENTRY_SIZE));
+        let (date, time) = unix_to_fat(entry.modified);
+        set_le16(&mut slot, 20, (entry.cluster >> 16) as u16);
+        set_le16(&mut slot, 22, time);
+        set_le16(&mut slot, 24, date);
+        set_le16(&mut slot, 18, date);
+        set_le16(&mut slot, 26, entry.cluster as u16);
+        set_le32(&mut slot, 28, if entry.is_dir() { 0 } else { entry.size });
+        self.volume.write(entry.offset, &slot)
+    }
+
+    /// Mark the slots of an entry free
+    fn remove_slots(&mut self, entry: &DirEntry) -> Result<()> {
+        for &offset in entry.long.iter().chain(Some(entry.offset).iter()) {
+            try!(self.volume.write(offset, &[ENTRY_FREE]));
+        }
+        Ok(())
+    }
+
+    /// Generate a short name that no entry in a directory uses
+    fn unique_short(&self, slots: &[(u64, [u8; ENTRY_SIZE])], name: &str) -> Result<[u8; 11]> {
+        let taken = |short: &[u8; 11]| slots.iter().any(|&(_, ref slot)| {
+            slot[0] != ENTRY_FREE && slot[11] & 0x3F != ATTR_LONG_NAME && &slot[..11] == &short[..]
+        });
+
+        if let Some(short) = exact_short(name) {
+            if taken(&short) {
+                return Err(Error::new(EEXIST));
+            }
+            return Ok(short);
+        }
+
+        let (basis, basis_len, ext) = short_basis(name);
+        for n in 1..1000000 {
+            let tail = format!("~{}", n);
+            let keep = cmp::min(basis_len, 8 - tail.len());
+
+            let mut short = [b' '; 11];
+            copy(&mut short[..keep], &basis[..keep]);
+            copy(&mut short[keep .. keep + tail.len()], tail.as_bytes());
+            copy(&mut short[8..], &ext);
+
+            if ! taken(&short) {
+                return Ok(short);
+            }
+        }
+
+        Err(Error::new(EEXIST))
+    }
+
+    /// Create an entry in a directory, with long name entries if the name is not a short name
+    pub fn create(&mut self, parent: &DirEntry, name: &str, attributes: u8) -> Result<DirEntry> {
+        let units = try!(check_name(name));
+
+        if try!(self.read_dir(parent)).iter().any(|entry| name_eq(&entry.name, name)) {
+            return Err(Error::new(EEXIST));
+        }
+
+        let mut slots = try!(self.slots(parent.cluster));
+        let short = try!(self.unique_short(&slots, name));
+        let long_count = if exact_short(name).is_some() {
+            0
+        } else {
+            (units.len() + LONG_CHARS - 1) / LONG_CHARS
+        };
+        let needed = long_count + 1;
+
+        // Find a run of free slots, growing the directory if there is none
+        let mut start = None;
+        loop {
+            let mut run = 0;
+            for (i, &(_, ref slot)) in slots.iter().enumerate() {
+                if slot[0] == ENTRY_FREE || slot[0] == ENTRY_END {
+                    run += 1;
+                    if run == needed {
+                        start = Some(i + 1 - needed);
+                        break;
+                    }
+                } else {
+                    run = 0;
+                }
+            }
+
+            if start.is_some() {
+                break;
+            }
+
+            if parent.cluster == 0 && self.kind != FatType::Fat32 {
+                return Err(Error::new(ENOSPC));
+            }
+
+            let first = if parent.cluster == 0 { self.root_cluster } else { parent.cluster };
+            let last = try!(self.chain(first)).last().cloned();
+            try!(self.alloc(last, true));
+            slots = try!(self.slots(parent.cluster));
+        }
+        let start = start.unwrap_or(0);
+
+        let checksum = short_checksum(&short);
+        let mut long = Vec::new();
+        for i in 0..long_count {
+            // Long entries are stored last part first
+            let sequence = long_count - i;
+            let mut slot = [0; ENTRY_SIZE];
+            slot[0] = sequence as u8 | if i == 0 { LONG_LAST } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = checksum;
+            for (j, &o) in LONG_OFFSETS.iter().enumerate() {
+                let k = (sequence - 1) * LONG_CHARS + j;
+                let c = if k < units.len() {
+                    units[k]
+                } else if k == units.len() {
+                    0
+                } else {
+                    0xFFFF
+                };
+                set_le16(&mut slot, o, c);
+            }
+
+            let offset = slots[start + i].0;
+            try!(self.volume.write(offset, &slot));
+            long.push(offset);
+        }
+
+        let (date, time) = now();
+        let mut slot = [0; ENTRY_SIZE];
+        copy(&mut slot[..11], &short);
+        slot[11] = attributes;
+        set_le16(&mut slot, 14, time);
+        set_le16(&mut slot, 16, date);
+        set_le16(&mut slot, 18, date);
+        set_le16(&mut slot, 22, time);
+        set_le16(&mut slot, 24, date);
+
+        let offset = slots[start + long_count].0;
+        try!(self.volume.write(offset, &slot));
+
+        Ok(DirEntry {
+            name: name.to_owned(),
+            attributes: attributes,
+            cluster: 0,
+            size: 0,
+            modified: fat_to_unix(date, time),
+            offset: offset,
+            long: long,
+        })
+    }
+
+    /// Create a directory with its "." and ".." entries
+    pub fn mkdir(&mut self, parent: &DirEntry, name: &str) -> Result<DirEntry> {
+        let mut entry = try!(self.create(parent, name, ATTR_DIRECTORY));
+
+        let cluster = match self.alloc(None, true) {
+            Ok(cluster) => cluster,
+            Err(err) => {
+                let _ = self.remove_slots(&entry);
+                return Err(err);
+            }
+        };
+        entry.cluster = cluster;
+        try!(self.update(&entry));
+
+        let (date, time) = unix_to_fat(entry.modified);
+        // ".." of a directory in the root has cluster zero, even on FAT32
+        for (i, &(dots, target)) in [(&b".          "[..], cluster), (&b"..         "[..], parent.cluster)].iter().enumerate() {
+            let mut slot = [0; ENTRY_SIZE];
+            copy(&mut slot[..11], dots);
+            slot[11] = ATTR_DIRECTORY;
+            set_le16(&mut slot, 20, (target >> 16) as u16);
+            set_le16(&mut slot, 22, time);
+            set_le16(&mut slot, 24, date);
+            set_le16(&mut slot, 26, target as u16);
+            let offset = self.cluster_offset(cluster) + (i * ENTRY_SIZE) as u64;
+            try!(self.volume.write(offset, &slot));
+        }
+
+        Ok(entry)
+    }
+
+    /// Remove an entry and free its clusters
+    ///
+    /// Directories must be empty.
+    pub fn remove(&mut self, entry: &DirEntry) -> Result<()> {
+        if entry.offset == 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        try!(self.remove_slots(entry));
+        if self.valid(entry.cluster) {
+            try!(self.free_chain(entry.cluster));
+        }
+        Ok(())
+    }
+
+    /// Write back the FSInfo sector and cached blocks
+    pub fn sync(&mut self) -> Result<()> {
+        if self.fsinfo_dirty {
+            if let Some(offset) = self.fsinfo {
+                let mut data = [0; 8];
+                set_le32(&mut data, 0, self.free.unwrap_or(FSINFO_UNKNOWN));
+                set_le32(&mut data, 4, self.next_free);
+                try!(self.volume.write(offset + 488, &data));
+            }
+            self.fsinfo_dirty = false;
+        }
+        self.volume.sync()
+    }
+}
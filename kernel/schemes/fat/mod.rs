@@ -0,0 +1,427 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use collections::borrow::ToOwned;
+use collections::{BTreeMap, String, Vec};
+
+use core::cell::UnsafeCell;
+use core::cmp;
+
+use disk::volume::Volume;
+use fs::{KScheme, Resource, ResourceSeek, VecResource};
+
+use syscall::{MODE_DIR, MODE_FILE, O_APPEND, O_CREAT, O_EXCL, O_TRUNC, Stat};
+
+use system::error::{Error, Result, EBUSY, EEXIST, EFBIG, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY};
+
+use self::filesystem::{modified_now, DirEntry, FileSystem, OpenFile, ATTR_ARCHIVE, ATTR_READ_ONLY};
+
+pub mod filesystem;
+
+/// Largest file size FAT can record
+const FILE_SIZE_MAX: u64 = 0xFFFFFFFF;
+
+/// A handle to a file on a FAT filesystem
+///
+/// Handles to the same file share its entry and cluster chain through `FileSystem::open`, so a
+/// write or truncate through one is seen by the others. The directory entry is updated on `sync`,
+/// and when a handle is dropped.
+pub struct FatResource {
+    path: String,
+    fs: Arc<UnsafeCell<FileSystem>>,
+    file: Arc<UnsafeCell<OpenFile>>,
+    seek: u64,
+    /// Writes go to the end of the file
+    append: bool,
+}
+
+impl FatResource {
+    fn new(path: String, fs: Arc<UnsafeCell<FileSystem>>, entry: DirEntry) -> Result<FatResource> {
+        let file = match unsafe { & *fs.get() }.open.get(&entry.offset).and_then(|file| file.upgrade()) {
+            Some(file) => file,
+            None => {
+                let clusters = try!(unsafe { & *fs.get() }.chain(entry.cluster));
+                let offset = entry.offset;
+                let file = Arc::new(UnsafeCell::new(OpenFile {
+                    entry: entry,
+                    clusters: clusters,
+                    dirty: false,
+                }));
+                unsafe { &mut *fs.get() }.open.insert(offset, Arc::downgrade(&file));
+                file
+            }
+        };
+
+        Ok(FatResource {
+            path: path,
+            fs: fs,
+            file: file,
+            seek: 0,
+            append: false,
+        })
+    }
+
+    fn fs(&self) -> &mut FileSystem {
+        unsafe { &mut *self.fs.get() }
+    }
+
+    fn file(&self) -> &mut OpenFile {
+        unsafe { &mut *self.file.get() }
+    }
+
+    /// Allocate clusters until the file can hold `len` bytes
+    fn grow(&mut self, len: u64) -> Result<()> {
+        let cluster_size = self.fs().cluster_size;
+        let needed = ((len + cluster_size - 1) / cluster_size) as usize;
+        let file = self.file();
+        while file.clusters.len() < needed {
+            let previous = file.clusters.last().cloned();
+            let cluster = try!(self.fs().alloc(previous, false));
+            if previous.is_none() {
+                file.entry.cluster = cluster;
+            }
+            file.clusters.push(cluster);
+            file.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Write at an offset, growing the file to cover it
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<usize> {
+        if offset + buf.len() as u64 > FILE_SIZE_MAX {
+            return Err(Error::new(EFBIG));
+        }
+
+        try!(self.grow(offset + buf.len() as u64));
+
+        let cluster_size = self.fs().cluster_size;
+        let file = self.file();
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let cluster = file.clusters[(position / cluster_size) as usize];
+            let in_cluster = position % cluster_size;
+            let len = cmp::min((cluster_size - in_cluster) as usize, buf.len() - done);
+
+            let disk_offset = self.fs().cluster_offset(cluster) + in_cluster;
+            try!(self.fs().volume.write(disk_offset, &buf[done .. done + len]));
+            done += len;
+        }
+
+        if offset + done as u64 > file.entry.size as u64 {
+            file.entry.size = (offset + done as u64) as u32;
+        }
+        file.dirty = true;
+
+        Ok(done)
+    }
+
+    /// Fill with zeros from the end of the file to `len`
+    fn zero_to(&mut self, len: u64) -> Result<()> {
+        let zeros = vec![0; self.fs().cluster_size as usize];
+        while (self.file().entry.size as u64) < len {
+            let size = self.file().entry.size as u64;
+            let count = cmp::min(zeros.len() as u64, len - size) as usize;
+            try!(self.write_at(size, &zeros[..count]));
+        }
+        Ok(())
+    }
+}
+
+impl Resource for FatResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box FatResource {
+            path: self.path.clone(),
+            fs: self.fs.clone(),
+            file: self.file.clone(),
+            seek: self.seek,
+            append: self.append,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = self.path.as_bytes();
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let size = self.file().entry.size as u64;
+        let cluster_size = self.fs().cluster_size;
+
+        let mut done = 0;
+        while done < buf.len() && self.seek < size {
+            let index = (self.seek / cluster_size) as usize;
+            let cluster = match self.file().clusters.get(index) {
+                Some(&cluster) => cluster,
+                None => break,
+            };
+            let in_cluster = self.seek % cluster_size;
+            let len = cmp::min(cmp::min((cluster_size - in_cluster) as usize, buf.len() - done), (size - self.seek) as usize);
+
+            let disk_offset = self.fs().cluster_offset(cluster) + in_cluster;
+            try!(self.fs().volume.read(disk_offset, &mut buf[done .. done + len]));
+
+            done += len;
+            self.seek += len as u64;
+        }
+
+        Ok(done)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.append {
+            self.seek = self.file().entry.size as u64;
+        }
+
+        let seek = self.seek;
+        if seek > self.file().entry.size as u64 {
+            try!(self.zero_to(seek));
+        }
+
+        let count = try!(self.write_at(seek, buf));
+        self.seek += count as u64;
+        Ok(count)
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        // Seeking past the end is allowed, and a write there fills the gap with zeros
+        let size = self.file().entry.size as i64;
+        match pos {
+            ResourceSeek::Start(offset) => self.seek = offset as u64,
+            ResourceSeek::Current(offset) => self.seek = cmp::max(0, self.seek as i64 + offset as i64) as u64,
+            ResourceSeek::End(offset) => self.seek = cmp::max(0, size + offset as i64) as u64,
+        }
+        Ok(self.seek as usize)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        let entry = &self.file().entry;
+        stat.st_size = entry.size;
+        stat.st_mode = MODE_FILE | if entry.attributes & ATTR_READ_ONLY == ATTR_READ_ONLY || self.fs().volume.read_only() {
+            0o444
+        } else {
+            0o666
+        };
+        stat.st_mtime = cmp::max(0, entry.modified) as u32;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let file = self.file();
+        if file.dirty {
+            file.entry.modified = modified_now();
+            try!(self.fs().update(&file.entry));
+            file.dirty = false;
+        }
+        self.fs().sync()
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        let len = len as u64;
+        if len > FILE_SIZE_MAX {
+            return Err(Error::new(EFBIG));
+        }
+
+        if len > self.file().entry.size as u64 {
+            return self.zero_to(len);
+        }
+
+        let cluster_size = self.fs().cluster_size;
+        let file = self.file();
+        let keep = ((len + cluster_size - 1) / cluster_size) as usize;
+        if keep < file.clusters.len() {
+            if keep == 0 {
+                let first = file.clusters[0];
+                try!(self.fs().free_chain(first));
+                file.entry.cluster = 0;
+            } else {
+                let last = file.clusters[keep - 1];
+                try!(self.fs().truncate_chain(last));
+            }
+            file.clusters.truncate(keep);
+        }
+
+        file.entry.size = len as u32;
+        file.dirty = true;
+        Ok(())
+    }
+}
+
+impl Drop for FatResource {
+    fn drop(&mut self) {
+        let _ = self.sync();
+
+        // The last handle to the file closes it
+        if Arc::strong_count(&self.file) == 1 {
+            let offset = self.file().entry.offset;
+            self.fs().open.remove(&offset);
+        }
+    }
+}
+
+/// A FAT12, FAT16 and FAT32 scheme with long names
+///
+/// `fat:/V/path` is a path on volume `V`, which is `N` for disk `N` or `NpM` for a partition.
+/// `fat:/` lists the volumes holding a FAT filesystem. Directories list one name per line, with a
+/// trailing `/` on subdirectories. Names are matched without case.
+pub struct FatScheme {
+    /// Mounted filesystems, by volume name
+    filesystems: BTreeMap<String, Arc<UnsafeCell<FileSystem>>>,
+}
+
+impl FatScheme {
+    pub fn new() -> Box<FatScheme> {
+        box FatScheme {
+            filesystems: BTreeMap::new(),
+        }
+    }
+
+    /// Get the filesystem on a volume, mounting it if it is not mounted or its disk was replaced
+    fn mount(&mut self, name: &str) -> Result<Arc<UnsafeCell<FileSystem>>> {
+        let volume = try!(Volume::find(name));
+
+        if let Some(fs) = self.filesystems.get(name) {
+            let mounted = &unsafe { & *fs.get() }.volume;
+            if mounted.disk.get() == volume.disk.get() && mounted.start == volume.start {
+                return Ok(fs.clone());
+            }
+        }
+
+        let fs = Arc::new(UnsafeCell::new(try!(FileSystem::new(volume))));
+        self.filesystems.insert(name.to_owned(), fs.clone());
+        Ok(fs)
+    }
+
+    /// Split a url into the mounted filesystem and the path on it
+    fn resolve<'a>(&mut self, url: &'a str) -> Result<(String, Arc<UnsafeCell<FileSystem>>, &'a str)> {
+        let path = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+        let mut parts = path.splitn(2, '/');
+        let volume_name = parts.next().unwrap_or("");
+        let file_path = parts.next().unwrap_or("");
+        let fs = try!(self.mount(volume_name));
+        Ok((volume_name.to_owned(), fs, file_path))
+    }
+}
+
+/// Split a path into its parent and last part
+fn split_parent(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+impl KScheme for FatScheme {
+    fn scheme(&self) -> &str {
+        "fat"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        if url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/').is_empty() {
+            let mut list = String::new();
+            for volume in Volume::all() {
+                let name = volume.name.clone();
+                if self.mount(&name).is_ok() {
+                    if ! list.is_empty() {
+                        list.push('\n');
+                    }
+                    list.push_str(&name);
+                }
+            }
+
+            return Ok(box VecResource::new("fat:/".to_owned(), list.into_bytes(), MODE_DIR));
+        }
+
+        let (volume_name, fs_cell, file_path) = try!(self.resolve(url));
+        let fs = unsafe { &mut *fs_cell.get() };
+
+        let entry = match fs.find(file_path) {
+            Ok(entry) => {
+                if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
+                    return Err(Error::new(EEXIST));
+                }
+                entry
+            },
+            Err(err) => {
+                if err.errno != ENOENT || flags & O_CREAT != O_CREAT {
+                    return Err(err);
+                }
+
+                let (parent_path, name) = split_parent(file_path);
+                let parent = try!(fs.find(parent_path));
+                try!(fs.create(&parent, name, ATTR_ARCHIVE))
+            }
+        };
+
+        let resource_path = format!("fat:/{}/{}", volume_name, file_path);
+
+        if entry.is_dir() {
+            let mut list = String::new();
+            for child in try!(fs.read_dir(&entry)) {
+                if ! list.is_empty() {
+                    list.push('\n');
+                }
+                list.push_str(&child.name);
+                if child.is_dir() {
+                    list.push('/');
+                }
+            }
+
+            return Ok(box VecResource::new(resource_path, list.into_bytes(), MODE_DIR));
+        }
+
+        let mut resource = try!(FatResource::new(resource_path, fs_cell.clone(), entry));
+        resource.append = flags & O_APPEND == O_APPEND;
+        if flags & O_TRUNC == O_TRUNC {
+            try!(resource.truncate(0));
+        }
+        Ok(box resource)
+    }
+
+    fn mkdir(&mut self, url: &str, _flags: usize) -> Result<()> {
+        let (_, fs_cell, file_path) = try!(self.resolve(url));
+        let fs = unsafe { &mut *fs_cell.get() };
+
+        let (parent_path, name) = split_parent(file_path);
+        let parent = try!(fs.find(parent_path));
+        try!(fs.mkdir(&parent, name));
+        fs.sync()
+    }
+
+    fn rmdir(&mut self, url: &str) -> Result<()> {
+        let (_, fs_cell, file_path) = try!(self.resolve(url));
+        let fs = unsafe { &mut *fs_cell.get() };
+
+        let entry = try!(fs.find(file_path));
+        if ! entry.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        if ! try!(fs.read_dir(&entry)).is_empty() {
+            return Err(Error::new(ENOTEMPTY));
+        }
+
+        try!(fs.remove(&entry));
+        fs.sync()
+    }
+
+    fn unlink(&mut self, url: &str) -> Result<()> {
+        let (_, fs_cell, file_path) = try!(self.resolve(url));
+        let fs = unsafe { &mut *fs_cell.get() };
+
+        let entry = try!(fs.find(file_path));
+        if entry.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+        // Open handles would keep writing to freed clusters
+        if fs.open.get(&entry.offset).and_then(|file| file.upgrade()).is_some() {
+            return Err(Error::new(EBUSY));
+        }
+
+        try!(fs.remove(&entry));
+        fs.sync()
+    }
+}
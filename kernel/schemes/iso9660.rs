@@ -0,0 +1,433 @@
+use alloc::boxed::Box;
+
+use collections::borrow::ToOwned;
+use collections::{String, Vec};
+
+use core::{char, cmp};
+
+use common::endian::le32;
+
+use disk::volume::Volume;
+use fs::{KScheme, Resource, ResourceSeek, VecResource};
+
+use syscall::{MODE_DIR, MODE_FILE, O_CREAT, O_RDWR, O_WRONLY, Stat};
+
+use system::error::{Error, Result, EINVAL, ENOENT, ENOTDIR, EROFS};
+
+/// Size of a logical sector
+const SECTOR_SIZE: u64 = 2048;
+/// The volume descriptors start after the system area
+const DESCRIPTORS_START: u64 = 16;
+/// Descriptors read before giving up on finding the terminator
+const DESCRIPTORS_MAX: u64 = 64;
+
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+/// Offset of the root directory record in the primary and supplementary descriptors
+const ROOT_RECORD: usize = 156;
+/// Length of a directory record without its name
+const RECORD_LEN: usize = 33;
+
+const FLAG_DIRECTORY: u8 = 1 << 1;
+const FLAG_ASSOCIATED: u8 = 1 << 2;
+
+/// NM flags for names of the current and parent directory
+const NM_CURRENT: u8 = 1 << 1;
+const NM_PARENT: u8 = 1 << 2;
+/// Continuation areas followed for one record, so that a loop of them cannot hang
+const CONTINUATIONS_MAX: usize = 16;
+
+/// How file names are stored
+#[derive(Copy, Clone, PartialEq)]
+enum Names {
+    /// Uppercase ISO 9660 names with a version suffix
+    Plain,
+    /// UCS-2 big endian names in a Joliet supplementary tree
+    Joliet,
+    /// Rock Ridge NM entries, with this many bytes to skip at the start of each system use area
+    RockRidge(usize),
+}
+
+/// A file or directory
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    /// Offset of the data in bytes
+    start: u64,
+    /// Size in bytes
+    size: u64,
+    directory: bool,
+}
+
+/// A mounted ISO 9660 filesystem
+struct Iso9660 {
+    volume: Volume,
+    root: Entry,
+    names: Names,
+}
+
+impl Iso9660 {
+    /// Read the volume descriptors, preferring Rock Ridge names, then Joliet names
+    fn new(volume: Volume) -> Result<Iso9660> {
+        let mut primary = None;
+        let mut joliet = None;
+
+        for sector in DESCRIPTORS_START..DESCRIPTORS_START + DESCRIPTORS_MAX {
+            let descriptor = try!(volume.read_vec(sector * SECTOR_SIZE, SECTOR_SIZE as usize));
+            if &descriptor[1..6] != b"CD001" {
+                return Err(Error::new(EINVAL));
+            }
+
+            let root = &descriptor[ROOT_RECORD..ROOT_RECORD + RECORD_LEN + 1];
+            match descriptor[0] {
+                DESCRIPTOR_PRIMARY => if primary.is_none() {
+                    primary = Some(Iso9660::root_entry(root));
+                },
+                DESCRIPTOR_SUPPLEMENTARY => {
+                    let escape = &descriptor[88..91];
+                    if joliet.is_none() && (escape == b"%/@" || escape == b"%/C" || escape == b"%/E") {
+                        joliet = Some(Iso9660::root_entry(root));
+                    }
+                },
+                DESCRIPTOR_TERMINATOR => break,
+                _ => (),
+            }
+        }
+
+        let primary = try!(primary.ok_or(Error::new(EINVAL)));
+
+        let mut fs = Iso9660 {
+            volume: volume,
+            root: primary,
+            names: Names::Plain,
+        };
+
+        if let Some(skip) = try!(fs.rock_ridge()) {
+            fs.names = Names::RockRidge(skip);
+        } else if let Some(root) = joliet {
+            fs.root = root;
+            fs.names = Names::Joliet;
+        }
+
+        Ok(fs)
+    }
+
+    fn root_entry(record: &[u8]) -> Entry {
+        Entry {
+            name: String::new(),
+            start: le32(record, 2) as u64 * SECTOR_SIZE,
+            size: le32(record, 10) as u64,
+            directory: true,
+        }
+    }
+
+    /// Check for the SUSP indicator in the root "." record, returning the bytes it skips
+    fn rock_ridge(&self) -> Result<Option<usize>> {
+        let sector = try!(self.volume.read_vec(self.root.start, SECTOR_SIZE as usize));
+        let len = sector[0] as usize;
+        // The "." record has a one byte name, so no padding
+        let area = &sector[RECORD_LEN + 1 .. cmp::max(len, RECORD_LEN + 1)];
+        if area.len() >= 7 && &area[0..2] == b"SP" && area[4] == 0xBE && area[5] == 0xEF {
+            Ok(Some(area[6] as usize))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Collect the Rock Ridge name from a system use area and its continuations
+    fn rock_ridge_name(&self, area: &[u8]) -> Result<Option<String>> {
+        let mut name = Vec::new();
+        let mut found = false;
+
+        let mut area = area.to_vec();
+        let mut continuations = 0;
+        loop {
+            let mut next = None;
+
+            let mut i = 0;
+            while i + 4 <= area.len() {
+                let len = area[i + 2] as usize;
+                if len < 4 || i + len > area.len() {
+                    break;
+                }
+
+                match (area[i], area[i + 1]) {
+                    (b'N', b'M') if len >= 5 => if area[i + 4] & (NM_CURRENT | NM_PARENT) == 0 {
+                        name.extend_from_slice(&area[i + 5 .. i + len]);
+                        found = true;
+                    },
+                    (b'C', b'E') if len >= 28 => {
+                        // A continuation area lies within one sector
+                        let block_offset = le32(&area, i + 12) as u64;
+                        let block_len = le32(&area, i + 20) as u64;
+                        if block_offset + block_len <= SECTOR_SIZE {
+                            next = Some((le32(&area, i + 4) as u64 * SECTOR_SIZE + block_offset, block_len as usize));
+                        }
+                    },
+                    (b'S', b'T') => break,
+                    _ => (),
+                }
+
+                i += len;
+            }
+
+            match next {
+                Some((offset, len)) if continuations < CONTINUATIONS_MAX => {
+                    area = try!(self.volume.read_vec(offset, len));
+                    continuations += 1;
+                },
+                _ => break,
+            }
+        }
+
+        if found {
+            Ok(Some(String::from_utf8_lossy(&name).into_owned()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Parse a directory record, returning None for the "." and ".." records
+    fn entry(&self, record: &[u8]) -> Result<Option<Entry>> {
+        let name_len = record[32] as usize;
+        if RECORD_LEN + name_len > record.len() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let raw_name = &record[RECORD_LEN .. RECORD_LEN + name_len];
+        if raw_name == &[0] || raw_name == &[1] {
+            return Ok(None);
+        }
+
+        let flags = record[25];
+        if flags & FLAG_ASSOCIATED == FLAG_ASSOCIATED {
+            return Ok(None);
+        }
+
+        let name = match self.names {
+            Names::RockRidge(skip) => {
+                // The system use area is padded to an even offset
+                let area_start = cmp::min(RECORD_LEN + name_len + (name_len + 1) % 2 + skip, record.len());
+                match try!(self.rock_ridge_name(&record[area_start..])) {
+                    Some(name) => name,
+                    None => plain_name(raw_name),
+                }
+            },
+            Names::Joliet => joliet_name(raw_name),
+            Names::Plain => plain_name(raw_name),
+        };
+
+        // The data follows any extended attribute record
+        Ok(Some(Entry {
+            name: name,
+            start: (le32(record, 2) as u64 + record[1] as u64) * SECTOR_SIZE,
+            size: le32(record, 10) as u64,
+            directory: flags & FLAG_DIRECTORY == FLAG_DIRECTORY,
+        }))
+    }
+
+    /// Read the entries of a directory
+    ///
+    /// Records do not cross sectors, and a zero length marks the end of the records in a sector.
+    fn read_dir(&self, directory: &Entry) -> Result<Vec<Entry>> {
+        let data = try!(self.volume.read_vec(directory.start, directory.size as usize));
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = data[offset] as usize;
+            if len == 0 {
+                offset = (offset / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+                continue;
+            }
+            if len < RECORD_LEN || offset + len > data.len() {
+                break;
+            }
+
+            if let Some(entry) = try!(self.entry(&data[offset .. offset + len])) {
+                entries.push(entry);
+            }
+
+            offset += len;
+        }
+
+        Ok(entries)
+    }
+
+    /// Find the entry at a path relative to the root
+    fn find(&self, path: &str) -> Result<Entry> {
+        let mut entry = self.root.clone();
+        for part in path.split('/').filter(|part| ! part.is_empty()) {
+            if ! entry.directory {
+                return Err(Error::new(ENOTDIR));
+            }
+            entry = try!(try!(self.read_dir(&entry)).into_iter()
+                             .find(|child| child.name == part)
+                             .ok_or(Error::new(ENOENT)));
+        }
+        Ok(entry)
+    }
+}
+
+/// Strip the version and an empty extension from a plain name, and lowercase it
+fn plain_name(raw: &[u8]) -> String {
+    let mut name = String::from_utf8_lossy(raw).into_owned();
+    if let Some(i) = name.find(';') {
+        name.truncate(i);
+    }
+    if name.ends_with('.') {
+        name.pop();
+    }
+    name.to_lowercase()
+}
+
+/// Decode a UCS-2 big endian name, stripping the version
+fn joliet_name(raw: &[u8]) -> String {
+    let mut name = String::new();
+    for pair in raw.chunks(2) {
+        if pair.len() == 2 {
+            let c = (pair[0] as u32) << 8 | pair[1] as u32;
+            name.push(char::from_u32(c).unwrap_or('?'));
+        }
+    }
+    if let Some(i) = name.find(';') {
+        name.truncate(i);
+    }
+    name
+}
+
+/// A file on an ISO 9660 filesystem
+pub struct Iso9660Resource {
+    path: String,
+    volume: Volume,
+    /// Offset of the data in bytes
+    start: u64,
+    /// Size in bytes
+    size: u64,
+    seek: u64,
+}
+
+impl Resource for Iso9660Resource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box Iso9660Resource {
+            path: self.path.clone(),
+            volume: self.volume.clone(),
+            start: self.start,
+            size: self.size,
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = self.path.as_bytes();
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let count = try!(self.volume.read_to(self.start + self.seek, self.start + self.size, buf));
+        self.seek += count as u64;
+        Ok(count)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(Error::new(EROFS))
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        let size = self.size;
+        match pos {
+            ResourceSeek::Start(offset) => self.seek = cmp::min(size, offset as u64),
+            ResourceSeek::Current(offset) => self.seek = cmp::min(size, cmp::max(0, self.seek as i64 + offset as i64) as u64),
+            ResourceSeek::End(offset) => self.seek = cmp::min(size, cmp::max(0, size as i64 + offset as i64) as u64),
+        }
+        Ok(self.seek as usize)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_size = self.size as u32;
+        stat.st_mode = MODE_FILE | 0o444;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A read-only ISO 9660 scheme
+///
+/// `iso9660:/V/path` is a path on volume `V`, which is `N` for disk `N` or `NpM` for a partition.
+/// `iso9660:/` lists the volumes holding an ISO 9660 filesystem. Rock Ridge and Joliet names are
+/// used when present.
+pub struct Iso9660Scheme;
+
+impl KScheme for Iso9660Scheme {
+    fn scheme(&self) -> &str {
+        "iso9660"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        if flags & (O_WRONLY | O_RDWR | O_CREAT) != 0 {
+            return Err(Error::new(EROFS));
+        }
+
+        let path = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+
+        if path.is_empty() {
+            let mut list = String::new();
+            for volume in Volume::all() {
+                let name = volume.name.clone();
+                if Iso9660::new(volume).is_ok() {
+                    if ! list.is_empty() {
+                        list.push('\n');
+                    }
+                    list.push_str(&name);
+                }
+            }
+
+            return Ok(box VecResource::new("iso9660:/".to_owned(), list.into_bytes(), MODE_DIR));
+        }
+
+        let mut parts = path.splitn(2, '/');
+        let volume_name = parts.next().unwrap_or("");
+        let file_path = parts.next().unwrap_or("");
+
+        let fs = try!(Iso9660::new(try!(Volume::find(volume_name))));
+        let entry = try!(fs.find(file_path));
+
+        let resource_path = if file_path.is_empty() {
+            format!("iso9660:/{}/", volume_name)
+        } else {
+            format!("iso9660:/{}/{}", volume_name, file_path)
+        };
+
+        if entry.directory {
+            let mut list = String::new();
+            for child in try!(fs.read_dir(&entry)) {
+                if ! list.is_empty() {
+                    list.push('\n');
+                }
+                list.push_str(&child.name);
+                if child.directory {
+                    list.push('/');
+                }
+            }
+
+            Ok(box VecResource::new(resource_path, list.into_bytes(), MODE_DIR))
+        } else {
+            Ok(box Iso9660Resource {
+                path: resource_path,
+                volume: fs.volume,
+                start: entry.start,
+                size: entry.size,
+                seek: 0,
+            })
+        }
+    }
+}
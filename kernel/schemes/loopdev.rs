@@ -0,0 +1,101 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use collections::borrow::ToOwned;
+
+use core::cell::UnsafeCell;
+use core::cmp;
+
+use disk::Disk;
+use disk::loopdev::LoopDisk;
+
+use fs::{KScheme, Resource};
+
+use system::error::{Error, Result, ENOENT};
+use system::syscall::{MODE_FILE, O_RDONLY, O_RDWR, O_WRONLY, Stat};
+
+/// An attached loop device, detached when the last handle is dropped
+struct LoopHandle {
+    number: usize,
+    disk: Arc<UnsafeCell<Box<Disk>>>,
+}
+
+impl Drop for LoopHandle {
+    fn drop(&mut self) {
+        let attached = unsafe { & *::env().disks.get() }.get(&self.number).map_or(false, |disk| disk.get() == self.disk.get());
+        if attached {
+            syslog_info!("Loop: detaching disk:/{}", self.number);
+            ::env().remove_disk(self.number);
+        }
+    }
+}
+
+/// A handle to a loop device, its path is the disk it is attached as
+pub struct LoopResource {
+    handle: Arc<LoopHandle>,
+}
+
+impl Resource for LoopResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box LoopResource {
+            handle: self.handle.clone(),
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = format!("disk:/{}", self.handle.number);
+        let path = path_string.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_size = unsafe { & *self.handle.disk.get() }.size() as u32;
+        stat.st_mode = MODE_FILE;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        unsafe { &mut *::env().block_cache.get() }.sync(&self.handle.disk)
+    }
+}
+
+/// The loop device scheme
+///
+/// Opening `loop:<url>` attaches the resource at `<url>` as a disk, read-only unless opened for
+/// writing. The path of the returned handle is the `disk:` path of the device, which is detached
+/// when the last handle is closed.
+pub struct LoopScheme;
+
+impl KScheme for LoopScheme {
+    fn scheme(&self) -> &str {
+        "loop"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let path = url.splitn(2, ":").nth(1).unwrap_or("").trim_left_matches('/');
+        if path.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let read_only = flags & (O_WRONLY | O_RDWR) == 0;
+        let resource = try!(::env().open(path, if read_only { O_RDONLY } else { O_RDWR }));
+        let disk = try!(LoopDisk::new(path.to_owned(), resource, read_only));
+
+        let number = ::env().add_disk(box disk);
+        let disk = try!(unsafe { & *::env().disks.get() }.get(&number).ok_or(Error::new(ENOENT))).clone();
+
+        syslog_info!("Loop: attached {} as disk:/{}{}", path, number, if read_only { " (read-only)" } else { "" });
+
+        Ok(box LoopResource {
+            handle: Arc::new(LoopHandle {
+                number: number,
+                disk: disk,
+            }),
+        })
+    }
+}
@@ -6,11 +6,27 @@ pub mod disk;
 pub mod display;
 /// Environment variables scheme
 pub mod env;
+/// Read-only ext2, ext3 and ext4 filesystem scheme
+pub mod ext2;
+/// FAT filesystem scheme
+pub mod fat;
 /// Init Filesystem
 pub mod initfs;
+/// ISO 9660 filesystem scheme
+pub mod iso9660;
+/// Loop device scheme
+pub mod loopdev;
+/// Null scheme
+pub mod null;
 /// Pipes
 pub mod pipe;
 /// Psuedoterminals
 pub mod pty;
+/// Random number scheme
+pub mod rand;
 /// Sys scheme
 pub mod sys;
+/// Timer scheme
+pub mod time;
+/// Zero scheme
+pub mod zero;
@@ -0,0 +1,62 @@
+use alloc::boxed::Box;
+
+use core::cmp;
+
+use fs::{KScheme, Resource};
+
+use system::error::Result;
+use system::syscall::{MODE_FILE, Stat};
+
+/// A resource that discards writes and reads nothing
+pub struct NullResource;
+
+impl Resource for NullResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box NullResource)
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"null:";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, _: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_size = 0;
+        stat.st_mode = MODE_FILE;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The null scheme
+pub struct NullScheme;
+
+impl KScheme for NullScheme {
+    fn scheme(&self) -> &str {
+        "null"
+    }
+
+    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
+        Ok(box NullResource)
+    }
+}
@@ -8,6 +8,7 @@ use fs::Resource;
 use sync::WaitQueue;
 
 use system::error::{Error, Result, EPIPE};
+use system::syscall::POLLIN;
 
 /// Read side of a pipe
 pub struct PipeRead {
@@ -62,6 +63,14 @@ impl Resource for PipeRead {
             Ok(i)
         }
     }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        if Arc::weak_count(&self.vec) == 0 || ! unsafe { self.vec.inner() }.is_empty() {
+            Ok(events & POLLIN)
+        } else {
+            Ok(0)
+        }
+    }
 }
 
 /// Read side of a pipe
@@ -0,0 +1,70 @@
+use alloc::boxed::Box;
+
+use core::cmp;
+
+use common::random;
+
+use fs::{KScheme, Resource};
+
+use system::error::Result;
+use system::syscall::{MODE_FILE, Stat};
+
+/// A resource reading from the kernel CSPRNG
+///
+/// Writes are mixed into the entropy pool, they never replace it.
+pub struct RandResource;
+
+impl Resource for RandResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box RandResource)
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"rand:";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        random::fill(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for chunk in buf.chunks(8) {
+            let mut value = 0;
+            for (i, b) in chunk.iter().enumerate() {
+                value |= (*b as u64) << (i * 8);
+            }
+            random::add_entropy(value);
+        }
+        Ok(buf.len())
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_size = 0;
+        stat.st_mode = MODE_FILE;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The random number scheme
+pub struct RandScheme;
+
+impl KScheme for RandScheme {
+    fn scheme(&self) -> &str {
+        "rand"
+    }
+
+    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
+        Ok(box RandResource)
+    }
+}
@@ -1,16 +1,16 @@
 use alloc::boxed::Box;
 
-use collections::string::ToString;
+use collections::string::{String, ToString};
 
 use fs::{Resource, VecResource};
 
-use system::error::Result;
-use system::syscall::MODE_FILE;
+use system::error::{Error, Result, ENOENT};
+use system::syscall::{MODE_DIR, MODE_FILE};
 
 pub fn resource() -> Result<Box<Resource>> {
     let mut string = format!("{:<6}{:<10}{}\n", "PATH", "SIZE", "NAME");
 
-    for (i, disk) in unsafe { &mut *::env().disks.get() }.iter().enumerate() {
+    for (i, disk) in unsafe { &mut *::env().disks.get() }.iter() {
         let size = unsafe { & *disk.get() }.size();
         let size_string = if size >= 1024 * 1024 * 1024 {
             format!("{} GB", size / 1024 / 1024 / 1024)
@@ -26,3 +26,27 @@ pub fn resource() -> Result<Box<Resource>> {
 
     Ok(box VecResource::new("sys:/disk".to_string(), string.into_bytes(), MODE_FILE))
 }
+
+/// Open `N`, `N/identity` or `N/stats` for disk `N`
+pub fn open(path: &str) -> Result<Box<Resource>> {
+    let mut parts = path.splitn(2, '/');
+    let number = try!(parts.next().unwrap_or("").parse::<usize>().or(Err(Error::new(ENOENT))));
+    let file = parts.next().unwrap_or("").trim_matches('/');
+
+    let disks = unsafe { & *::env().disks.get() };
+    let disk = unsafe { & *try!(disks.get(&number).ok_or(Error::new(ENOENT))).get() };
+
+    match file {
+        "" => Ok(box VecResource::new(format!("sys:/disk/{}/", number), "identity\nstats".to_string().into_bytes(), MODE_DIR)),
+        "identity" => {
+            let string = format!("name: {}\nsize: {}\nread_only: {}\n{}",
+                                 disk.name(), disk.size(), disk.read_only(), disk.identity().info());
+            Ok(box VecResource::new(format!("sys:/disk/{}/identity", number), string.into_bytes(), MODE_FILE))
+        },
+        "stats" => {
+            let string = disk.stats().map(|stats| stats.info()).unwrap_or(String::new());
+            Ok(box VecResource::new(format!("sys:/disk/{}/stats", number), string.into_bytes(), MODE_FILE))
+        },
+        _ => Err(Error::new(ENOENT)),
+    }
+}
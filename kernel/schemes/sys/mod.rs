@@ -46,6 +46,8 @@ impl KScheme for SysScheme {
 
         if let Some(func) = self.files.get(reference) {
             func()
+        } else if reference.starts_with("disk/") {
+            disk::open(&reference[5..])
         } else {
             let mut list = String::new();
 
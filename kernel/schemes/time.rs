@@ -0,0 +1,229 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use core::{cmp, mem, ptr};
+use core::cell::UnsafeCell;
+
+use common::time::{Duration, NANOS_PER_SEC};
+
+use fs::{KScheme, Resource};
+
+use sync::WaitCondition;
+
+use system::error::{Error, Result, EAGAIN, EINVAL, ENOENT};
+use system::syscall::{CLOCK_MONOTONIC, CLOCK_REALTIME, MODE_FILE, O_NONBLOCK, POLLIN, Stat, TimeSpec};
+
+/// The deadline and interval of a timer
+struct TimerInner {
+    /// The next expiration, on the timer's clock
+    deadline: Option<Duration>,
+    /// The period, if this is a periodic timer
+    interval: Option<Duration>,
+}
+
+/// A non-negative duration in nanoseconds, saturating
+fn nanos(duration: Duration) -> u64 {
+    (duration.secs as u64).saturating_mul(NANOS_PER_SEC as u64).saturating_add(duration.nanos as u64)
+}
+
+/// A timer, shared between duplicated resources
+struct Timer {
+    clock: usize,
+    inner: UnsafeCell<TimerInner>,
+    /// Notified when the timer is rearmed
+    condition: WaitCondition,
+}
+
+impl Timer {
+    fn now(&self) -> Duration {
+        match self.clock {
+            CLOCK_REALTIME => Duration::realtime(),
+            _ => Duration::monotonic(),
+        }
+    }
+
+    fn inner(&self) -> &mut TimerInner {
+        unsafe { &mut *self.inner.get() }
+    }
+
+    fn expired(&self) -> bool {
+        match self.inner().deadline {
+            Some(deadline) => deadline <= self.now(),
+            None => false,
+        }
+    }
+
+    /// Consume an expiration, moving a periodic deadline past the current time
+    fn consume(&self) {
+        let now = self.now();
+        let inner = self.inner();
+        match inner.interval {
+            Some(interval) => if let Some(deadline) = inner.deadline {
+                if deadline <= now {
+                    // Skip every missed period at once, however short the interval
+                    let elapsed = nanos(now - deadline);
+                    let period = cmp::max(1, nanos(interval));
+                    let skip = (elapsed / period + 1).saturating_mul(period);
+                    inner.deadline = Some(deadline + Duration::new((skip / NANOS_PER_SEC as u64) as i64,
+                                                                   (skip % NANOS_PER_SEC as u64) as i32));
+                }
+            },
+            None => inner.deadline = None,
+        }
+    }
+}
+
+/// A timer resource
+///
+/// Writing one `TimeSpec` arms a one-shot timer with an absolute deadline on the clock. Writing
+/// two `TimeSpec`s arms a periodic timer, the second being the interval. A zero deadline disarms
+/// the timer. Reads block until the deadline passes and return the current time on the clock.
+pub struct TimerResource {
+    timer: Arc<Timer>,
+    nonblock: bool,
+}
+
+impl Resource for TimerResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box TimerResource {
+            timer: self.timer.clone(),
+            nonblock: self.nonblock,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = format!("time:{}", self.timer.clock);
+        let path = path_string.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < mem::size_of::<TimeSpec>() {
+            return Err(Error::new(EINVAL));
+        }
+
+        loop {
+            if self.timer.expired() {
+                self.timer.consume();
+
+                let now = self.timer.now();
+                let time = TimeSpec {
+                    tv_sec: now.secs,
+                    tv_nsec: now.nanos,
+                };
+                unsafe { ptr::write(buf.as_mut_ptr() as *mut TimeSpec, time) };
+
+                return Ok(mem::size_of::<TimeSpec>());
+            }
+
+            if self.nonblock {
+                return Err(Error::new(EAGAIN));
+            }
+
+            match self.timer.inner().deadline {
+                Some(deadline) => {
+                    let remaining = deadline - self.timer.now();
+                    self.timer.condition.wait_for("TimerResource::read", remaining);
+                },
+                None => self.timer.condition.wait("TimerResource::read"),
+            }
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let count = buf.len() / mem::size_of::<TimeSpec>();
+        if count < 1 || count > 2 || buf.len() % mem::size_of::<TimeSpec>() != 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let specs = buf.as_ptr() as *const TimeSpec;
+        let deadline = unsafe { ptr::read(specs) };
+        if deadline.tv_sec < 0 || deadline.tv_nsec < 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let interval = if count == 2 {
+            let interval = unsafe { ptr::read(specs.offset(1)) };
+            if interval.tv_sec < 0 || interval.tv_nsec < 0 {
+                return Err(Error::new(EINVAL));
+            }
+            if interval.tv_sec > 0 || interval.tv_nsec > 0 {
+                Some(Duration::new(interval.tv_sec, interval.tv_nsec))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        {
+            let inner = self.timer.inner();
+            if deadline.tv_sec == 0 && deadline.tv_nsec == 0 {
+                inner.deadline = None;
+                inner.interval = None;
+            } else {
+                inner.deadline = Some(Duration::new(deadline.tv_sec, deadline.tv_nsec));
+                inner.interval = interval;
+            }
+        }
+
+        self.timer.condition.notify("TimerResource::write");
+
+        Ok(buf.len())
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_size = 0;
+        stat.st_mode = MODE_FILE;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn poll(&self, events: usize) -> Result<usize> {
+        if self.timer.expired() {
+            Ok(events & POLLIN)
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+/// The timer scheme
+///
+/// `time:1` opens a timer on `CLOCK_REALTIME`, `time:4` on `CLOCK_MONOTONIC`.
+pub struct TimeScheme;
+
+impl KScheme for TimeScheme {
+    fn scheme(&self) -> &str {
+        "time"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let path = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+
+        let clock = match path.parse::<usize>() {
+            Ok(CLOCK_REALTIME) => CLOCK_REALTIME,
+            Ok(CLOCK_MONOTONIC) => CLOCK_MONOTONIC,
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        Ok(box TimerResource {
+            timer: Arc::new(Timer {
+                clock: clock,
+                inner: UnsafeCell::new(TimerInner {
+                    deadline: None,
+                    interval: None,
+                }),
+                condition: WaitCondition::new(),
+            }),
+            nonblock: flags & O_NONBLOCK == O_NONBLOCK,
+        })
+    }
+}
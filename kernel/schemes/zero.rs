@@ -0,0 +1,65 @@
+use alloc::boxed::Box;
+
+use core::cmp;
+
+use fs::{KScheme, Resource};
+
+use system::error::Result;
+use system::syscall::{MODE_FILE, Stat};
+
+/// A resource that discards writes and reads zeroes
+pub struct ZeroResource;
+
+impl Resource for ZeroResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box ZeroResource)
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"zero:";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_size = 0;
+        stat.st_mode = MODE_FILE;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The zero scheme
+pub struct ZeroScheme;
+
+impl KScheme for ZeroScheme {
+    fn scheme(&self) -> &str {
+        "zero"
+    }
+
+    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
+        Ok(box ZeroResource)
+    }
+}
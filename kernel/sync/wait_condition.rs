@@ -67,6 +67,10 @@ impl WaitCondition {
         {
             if let Ok(mut context) = unsafe { &mut *::env().contexts.get() }.current_mut() {
                 if (*context).wake.is_none() {
+                    // Timed out without a notify, so stop waiting, or a later notify would wake
+                    // this context for nothing, or after it exited
+                    let ptr = context.deref_mut() as *mut Context;
+                    unsafe { &mut *self.contexts.get() }.retain(|&waiter| waiter != ptr);
                     ret = false;
                 } else {
                     (*context).wake = None;
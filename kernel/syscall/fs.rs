@@ -1,6 +1,8 @@
 //! System calls related to files and resource management.
 
-use arch::context::ContextFile;
+use arch::context::{context_switch, ContextFile};
+
+use common::time::{Duration, NANOS_PER_MILLI};
 
 use core::str;
 
@@ -8,7 +10,7 @@ use fs::ResourceSeek;
 
 use schemes::pipe::{PipeRead, PipeWrite};
 
-use syscall::{Stat, SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::{PollFd, Stat, TimeSpec, POLLERR, POLLIN, POLLNVAL, POLLOUT, SEEK_CUR, SEEK_END, SEEK_SET};
 
 use system::error::{Error, Result, EBADF, EINVAL};
 
@@ -112,10 +114,14 @@ NAME
 
 SYNOPSIS
     sys_dup(fd: usize) -> Result<usize>;
+    sys_dup_path(fd: usize, path: &str) -> Result<usize>;
 
 DESCRIPTION
     sys_dup creates a copy of fd, using the lowest unused descriptor for the new descriptor
 
+    sys_dup_path creates a new descriptor from fd as named by path, which depends on the
+    resource. A TCP listener opens its next connection for the path "accept"
+
 RETURN VALUE
     On success, Ok(new_fd) is returned, where new_fd is the new file descriptor. On error, Err(err)
     is returned where err is one of the following errors
@@ -124,14 +130,17 @@ ERRORS
     EBADF
         fd is not a valid open file decriptor
 
+    EPERM
+        The resource does not support the path
+
     ESRCH
         Currently not running in a process context (rare, would only happen during kernel init)
 <!-- @MANEND --> */
-pub fn dup(fd: usize) -> Result<usize> {
+pub fn dup(fd: usize, path: &[u8]) -> Result<usize> {
     let contexts = unsafe { & *::env().contexts.get() };
     let current = try!(contexts.current());
     let resource = try!(current.get_file(fd));
-    let new_resource = try!(resource.dup());
+    let new_resource = try!(resource.dup_path(path));
     let new_fd = current.next_fd();
 
     unsafe {
@@ -415,6 +424,94 @@ pub fn pipe2(fds: &mut [usize; 2], _flags: usize) -> Result<usize> {
     Ok(0)
 }
 
+/// The longest `poll` sleeps before checking its descriptors again, rounded up to a timer tick
+const POLL_INTERVAL: Duration = Duration {
+    secs: 0,
+    nanos: NANOS_PER_MILLI,
+};
+
+/** <!-- @MANSTART{sys_poll} -->
+NAME
+    sys_poll - wait for some event on a set of file descriptors
+
+SYNOPSIS
+    sys_poll(fds: &mut [PollFd], timeout: Option<&TimeSpec>) -> Result<usize>;
+
+DESCRIPTION
+    sys_poll waits until one of the file descriptors in fds is ready for the events requested in
+    its events field, or until timeout, relative to CLOCK_MONOTONIC, has passed. A null timeout
+    waits forever, and a zero timeout returns immediately.
+
+    POLLIN: 0x1
+        A read would not block
+
+    POLLOUT: 0x4
+        A write would not block
+
+    The revents field of each entry is set to the ready events, to POLLERR if the resource
+    reported an error, or to POLLNVAL if fd is not open.
+
+RETURN VALUE
+    On success, Ok(count) is returned, where count is the number of entries with a non-zero
+    revents field, or 0 if the timeout passed. On error, Err(err) is returned where err is one of
+    the following errors
+
+ERRORS
+    EFAULT
+        fds or timeout is outside of the accessible address space of the process
+
+    ESRCH
+        Currently not running in a process context (rare, would only happen during kernel init)
+<!-- @MANEND --> */
+pub fn poll(fds: &mut [PollFd], timeout: Option<&TimeSpec>) -> Result<usize> {
+    let deadline = timeout.map(|timeout| Duration::monotonic() + Duration::new(timeout.tv_sec, timeout.tv_nsec));
+
+    loop {
+        let mut count = 0;
+        {
+            let contexts = unsafe { & *::env().contexts.get() };
+            let current = try!(contexts.current());
+            for pollfd in fds.iter_mut() {
+                pollfd.revents = match current.get_file(pollfd.fd) {
+                    Ok(resource) => match resource.poll(pollfd.events & (POLLIN | POLLOUT)) {
+                        Ok(revents) => revents,
+                        Err(_) => POLLERR,
+                    },
+                    Err(_) => POLLNVAL,
+                };
+
+                if pollfd.revents != 0 {
+                    count += 1;
+                }
+            }
+        }
+
+        if count > 0 {
+            return Ok(count);
+        }
+
+        let now = Duration::monotonic();
+        let mut wake = now + POLL_INTERVAL;
+        if let Some(deadline) = deadline {
+            if deadline <= now {
+                return Ok(0);
+            }
+            if deadline < wake {
+                wake = deadline;
+            }
+        }
+
+        {
+            let contexts = unsafe { &mut *::env().contexts.get() };
+            let mut current = try!(contexts.current_mut());
+            current.block("poll");
+            current.wake = Some(wake);
+        }
+
+        unsafe { context_switch(); }
+    }
+}
+
 /** <!-- @MANSTART{sys_read} -->
 NAME
     sys_read - read from a file descriptor
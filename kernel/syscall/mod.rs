@@ -8,6 +8,7 @@ pub mod execute;
 pub mod fs;
 pub mod memory;
 pub mod process;
+pub mod random;
 pub mod time;
 
 pub fn name(number: usize) -> &'static str {
@@ -30,6 +31,7 @@ pub fn name(number: usize) -> &'static str {
         SYS_FTRUNCATE => "ftruncate",
         SYS_FUTEX => "futex",
         SYS_GETPID => "getpid",
+        SYS_GETRANDOM => "getrandom",
         SYS_IOPL => "iopl",
         // TODO: link
         SYS_LSEEK => "lseek",
@@ -37,6 +39,7 @@ pub fn name(number: usize) -> &'static str {
         SYS_NANOSLEEP => "nanosleep",
         SYS_OPEN => "open",
         SYS_PIPE2 => "pipe2",
+        SYS_POLL => "poll",
         SYS_READ => "read",
         SYS_RMDIR => "rmdir",
         SYS_UNLINK => "unlink",
@@ -131,14 +134,16 @@ pub fn handle(regs: &mut Regs) {
         SYS_FSTAT => fs::fstat(regs.bx, get_ref_mut!(cx, Stat)),
         SYS_FSYNC => fs::fsync(regs.bx),
         SYS_FTRUNCATE => fs::ftruncate(regs.bx, regs.cx),
-        SYS_DUP => fs::dup(regs.bx),
+        SYS_DUP => fs::dup(regs.bx, get_slice!(cx, dx)),
         SYS_IOPL => process::iopl(regs),
         SYS_CLOCK_GETTIME => time::clock_gettime(regs.bx, get_ref_mut!(cx, TimeSpec)),
         SYS_EXECVE => process::execve(regs.bx as *const u8, regs.cx as *const *const u8),
         SYS_EXIT => process::exit(regs.bx),
         SYS_GETPID => process::getpid(),
+        SYS_GETRANDOM => random::getrandom(get_slice_mut!(bx, cx)),
         // TODO: link
         SYS_PIPE2 => fs::pipe2(get_ref_mut!(bx, [usize; 2]), regs.cx),
+        SYS_POLL => fs::poll(check!(cur.get_slice_mut(regs.bx as *mut PollFd, regs.cx)), get_ref_mut_opt!(dx, TimeSpec).map(|timeout| &*timeout)),
         SYS_RMDIR => fs::rmdir(get_slice!(bx, cx)),
         SYS_UNLINK => fs::unlink(get_slice!(bx, cx)),
         SYS_WAITPID => process::waitpid(regs.bx as isize, get_ref_mut_opt!(cx, usize), regs.dx),
@@ -0,0 +1,29 @@
+//! System calls related to randomness.
+
+use common::random;
+
+use system::error::Result;
+
+/** <!-- @MANSTART{sys_getrandom} -->
+NAME
+    sys_getrandom - obtain random bytes
+
+SYNOPSIS
+    sys_getrandom(buf: &mut [u8]) -> Result<usize>;
+
+DESCRIPTION
+    sys_getrandom fills buf with bytes from the kernel CSPRNG, the same source used by the rand:
+    scheme. It never blocks.
+
+RETURN VALUE
+    On success, Ok(count) is returned, where count is the length of buf. On error, Err(err) is
+    returned where err is one of the following errors
+
+ERRORS
+    EFAULT
+        buf points outside the accessible address space of the process
+<!-- @MANEND --> */
+pub fn getrandom(buf: &mut [u8]) -> Result<usize> {
+    random::fill(buf);
+    Ok(buf.len())
+}